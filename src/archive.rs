@@ -0,0 +1,512 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::download::{self, Downloads};
+use crate::geocode::Geocoder;
+use crate::http::HttpFile;
+use crate::locale::Names;
+use crate::{DatePriority, Timezone};
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use log::debug;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use zip::ZipArchive;
+
+type ZipReader = ZipArchive<ZipFile>;
+
+// A zip file, either on disk or on a web server (see `http`)
+enum ZipFile {
+    Local(BufReader<File>),
+    Http(HttpFile),
+}
+
+impl Read for ZipFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ZipFile::Local(file) => file.read(buf),
+            ZipFile::Http(file) => file.read(buf),
+        }
+    }
+}
+
+impl Seek for ZipFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            ZipFile::Local(file) => file.seek(pos),
+            ZipFile::Http(file) => file.seek(pos),
+        }
+    }
+}
+
+// The contents of a Facebook archive. Large exports are split by Facebook into several parts,
+// each of which holds a subset of the files, so lookups are resolved against whichever part
+// actually contains the path. All paths are relative to the root of the archive, matching the
+// "uri" fields found in the JSON. Exports in other languages are read with the English names
+// too, which are translated into whatever the archive actually holds.
+pub struct Archive {
+    parts: Vec<Part>,
+    names: Names,
+    timezone: Timezone,
+    date_priority: DatePriority,
+    geocoder: Option<Arc<dyn Geocoder>>,
+}
+
+// A single part of an archive, either extracted into a directory or still packed into the zip file
+// that Facebook provides for download.
+enum Part {
+    Directory(PathBuf),
+    Zip {
+        path: PathBuf,
+        // Readers which aren't currently in use. Each one has its own file handle, allowing
+        // entries to be decompressed concurrently.
+        readers: Mutex<Vec<ZipReader>>,
+    },
+    // The files downloaded for the items which are URLs rather than paths (see `download`)
+    Downloads(Downloads),
+}
+
+impl Archive {
+    pub fn open<P: AsRef<Path>>(paths: &[P]) -> Result<Archive> {
+        Ok(Archive {
+            parts: paths
+                .iter()
+                .map(|path| Part::open(path.as_ref()))
+                .collect::<Result<_>>()?,
+            names: Names::default(),
+            timezone: Timezone::Local,
+            date_priority: DatePriority::Taken,
+            geocoder: None,
+        })
+    }
+
+    // Uses these names for the translated folders and files, rather than the built-in ones.
+    pub fn with_names(mut self, names: Names) -> Archive {
+        self.names = names;
+        self
+    }
+
+    // Shows the times in this zone (see `timezone`) and dates the items by this one of their times.
+    pub fn with_dates(mut self, timezone: Timezone, date_priority: DatePriority) -> Archive {
+        self.timezone = timezone;
+        self.date_priority = date_priority;
+        self
+    }
+
+    // Looks up the places which the items were tagged with (see `Item::located`).
+    pub fn set_geocoder(&mut self, geocoder: Arc<dyn Geocoder>) {
+        self.geocoder = Some(geocoder);
+    }
+
+    pub fn timezone(&self) -> Timezone {
+        self.timezone
+    }
+
+    pub fn date_priority(&self) -> DatePriority {
+        self.date_priority
+    }
+
+    pub fn geocoder(&self) -> Option<&dyn Geocoder> {
+        self.geocoder.as_deref()
+    }
+
+    // Finds the items which are URLs in this directory, once they've been downloaded.
+    pub fn with_downloads(mut self, dir: PathBuf) -> Archive {
+        self.parts.push(Part::Downloads(Downloads::new(dir)));
+        self
+    }
+
+    // Downloads the file at the URL, after which it's found like any other.
+    pub fn download(&self, url: &Path) -> Result<()> {
+        match self.parts.iter().find_map(|part| match part {
+            Part::Downloads(downloads) => Some(downloads),
+            _ => None,
+        }) {
+            Some(downloads) => downloads.fetch(url),
+            None => Err(anyhow!("downloads aren't enabled")),
+        }
+    }
+
+    // Lists the directory, with the names of the entries in English.
+    pub fn list(&self, dir: &Path) -> Result<Vec<PathBuf>> {
+        let mut found = false;
+        let mut seen = HashSet::new();
+        let mut entries = Vec::new();
+        for dir in self.names.translations(dir) {
+            for part in &self.parts {
+                if let Some(listing) = part.list(&dir)? {
+                    found = true;
+                    entries.extend(
+                        listing
+                            .iter()
+                            .map(|path| self.names.english(path))
+                            .filter(|path| seen.insert(path.clone())),
+                    );
+                }
+            }
+        }
+
+        if !found {
+            return Err(anyhow!("list directory {}: not found", dir.display()));
+        }
+        Ok(entries)
+    }
+
+    // Lists every file beneath the directory, in any of the parts. Missing directories are empty.
+    pub fn list_recursive(&self, dir: &Path) -> Result<Vec<PathBuf>> {
+        let mut seen = HashSet::new();
+        let mut entries = Vec::new();
+        for dir in self.names.translations(dir) {
+            for part in &self.parts {
+                entries.extend(
+                    part.list_recursive(&dir)?
+                        .iter()
+                        .map(|path| self.names.english(path))
+                        .filter(|path| seen.insert(path.clone())),
+                );
+            }
+        }
+        entries.sort();
+        Ok(entries)
+    }
+
+    pub fn contains(&self, path: &Path) -> Result<bool> {
+        Ok(self.find(path)?.is_some())
+    }
+
+    // The name the file actually has in the archive, which is translated if the export is.
+    pub fn name_in_archive(&self, path: &Path) -> Result<Option<PathBuf>> {
+        Ok(self.find(path)?.map(|(_, path)| path))
+    }
+
+    // The part holding the file, along with its name there, which differs from the one given when
+    // the export is translated.
+    fn find(&self, path: &Path) -> Result<Option<(&Part, PathBuf)>> {
+        // URLs are only found among the downloads, and aren't translated
+        if download::is_url(path) {
+            for part in &self.parts {
+                if let Part::Downloads(downloads) = part {
+                    let name = downloads.name(path);
+                    if part.contains(&name)? {
+                        return Ok(Some((part, name)));
+                    }
+                }
+            }
+            return Ok(None);
+        }
+
+        for path in self.names.translations(path) {
+            for part in &self.parts {
+                if part.contains(&path)? {
+                    return Ok(Some((part, path)));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn read<T, F>(&self, path: &Path, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut dyn Read) -> Result<T>,
+    {
+        match self.find(path)? {
+            Some((part, path)) => part.read(&path, f),
+            None => Err(anyhow!("open {}: not found", path.display())),
+        }
+    }
+
+    // The uncompressed size of the file.
+    pub fn size(&self, path: &Path) -> Result<u64> {
+        match self.find(path)? {
+            Some((part, path)) => part.size(&path),
+            None => Err(anyhow!("open {}: not found", path.display())),
+        }
+    }
+
+    // When the file was last modified, in UTC, as recorded by the file system or the zip file.
+    pub fn modified(&self, path: &Path) -> Result<Option<NaiveDateTime>> {
+        match self.find(path)? {
+            Some((part, path)) => part.modified(&path, self.timezone),
+            None => Err(anyhow!("open {}: not found", path.display())),
+        }
+    }
+
+    // The location of the file on disk, if it's in an extracted part of the archive rather than
+    // packed into a zip file.
+    pub fn local_path(&self, path: &Path) -> Result<Option<PathBuf>> {
+        match self.find(path)? {
+            Some((Part::Directory(root), path))
+            | Some((Part::Downloads(Downloads { dir: root, .. }), path)) => {
+                Ok(Some(root.join(path)))
+            }
+            Some((Part::Zip { .. }, _)) => Ok(None),
+            None => Err(anyhow!("open {}: not found", path.display())),
+        }
+    }
+
+    pub fn read_bytes(&self, path: &Path) -> Result<Vec<u8>> {
+        self.read(path, |file| {
+            let mut contents = Vec::new();
+            file.read_to_end(&mut contents)
+                .context(format!("read {}", path.display()))?;
+            Ok(contents)
+        })
+    }
+}
+
+impl Part {
+    fn open(path: &Path) -> Result<Part> {
+        if path.is_dir() {
+            debug!("Reading extracted archive {}", path.display());
+            return Ok(Part::Directory(path.to_path_buf()));
+        }
+
+        debug!("Reading zip archive {}", path.display());
+        Ok(Part::Zip {
+            path: path.to_path_buf(),
+            readers: Mutex::new(vec![open_zip(path)?]),
+        })
+    }
+
+    // Borrows an idle reader for the duration of `f`, opening a new one if they are all in use.
+    fn with_zip<T, F>(path: &Path, readers: &Mutex<Vec<ZipReader>>, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut ZipReader) -> Result<T>,
+    {
+        let idle = readers
+            .lock()
+            .map_err(|_| anyhow!("zip lock poisoned"))?
+            .pop();
+        let mut zip = match idle {
+            Some(zip) => zip,
+            None => open_zip(path)?,
+        };
+
+        let result = f(&mut zip);
+        readers
+            .lock()
+            .map_err(|_| anyhow!("zip lock poisoned"))?
+            .push(zip);
+        result
+    }
+
+    fn contains(&self, path: &Path) -> Result<bool> {
+        match self {
+            Part::Directory(root) | Part::Downloads(Downloads { dir: root, .. }) => {
+                Ok(root.join(path).is_file())
+            }
+            Part::Zip {
+                path: zip_path,
+                readers,
+            } => Part::with_zip(zip_path, readers, |zip| {
+                Ok(zip.index_for_name(&zip_name(path)?).is_some())
+            }),
+        }
+    }
+
+    fn list(&self, dir: &Path) -> Result<Option<Vec<PathBuf>>> {
+        match self {
+            Part::Downloads(_) => Ok(None),
+            Part::Directory(root) => {
+                let full = root.join(dir);
+                if !full.is_dir() {
+                    return Ok(None);
+                }
+                fs::read_dir(&full)
+                    .context(format!("list directory {}", full.display()))?
+                    .map(|entry| Ok(dir.join(entry.context("entry")?.file_name())))
+                    .collect::<Result<_>>()
+                    .map(Some)
+            }
+            Part::Zip { path, readers } => {
+                let prefix = zip_prefix(dir)?;
+                let entries: Vec<PathBuf> = Part::with_zip(path, readers, |zip| {
+                    Ok(zip
+                        .file_names()
+                        .filter_map(|name| name.ok())
+                        .filter_map(|name| {
+                            let rest = name.strip_prefix(&prefix)?;
+                            if rest.is_empty() || rest.contains('/') {
+                                return None;
+                            }
+                            Some(dir.join(rest))
+                        })
+                        .collect())
+                })?;
+
+                if entries.is_empty() {
+                    return Ok(None);
+                }
+                Ok(Some(entries))
+            }
+        }
+    }
+
+    fn list_recursive(&self, dir: &Path) -> Result<Vec<PathBuf>> {
+        match self {
+            Part::Downloads(_) => Ok(Vec::new()),
+            Part::Directory(root) => {
+                let mut entries = Vec::new();
+                let mut pending = vec![dir.to_path_buf()];
+                while let Some(dir) = pending.pop() {
+                    let full = root.join(&dir);
+                    if !full.is_dir() {
+                        continue;
+                    }
+                    for entry in
+                        fs::read_dir(&full).context(format!("list directory {}", full.display()))?
+                    {
+                        let entry = entry.context("entry")?;
+                        let path = dir.join(entry.file_name());
+                        if entry.file_type().context("file type")?.is_dir() {
+                            pending.push(path);
+                        } else {
+                            entries.push(path);
+                        }
+                    }
+                }
+                Ok(entries)
+            }
+            Part::Zip { path, readers } => {
+                let prefix = zip_prefix(dir)?;
+                Part::with_zip(path, readers, |zip| {
+                    Ok(zip
+                        .file_names()
+                        .filter_map(|name| name.ok())
+                        .filter(|name| name.starts_with(&prefix) && !name.ends_with('/'))
+                        .map(|name| PathBuf::from(name.as_ref()))
+                        .collect())
+                })
+            }
+        }
+    }
+
+    fn size(&self, path: &Path) -> Result<u64> {
+        match self {
+            Part::Directory(root) | Part::Downloads(Downloads { dir: root, .. }) => {
+                let full = root.join(path);
+                Ok(fs::metadata(&full)
+                    .context(format!("stat {}", full.display()))?
+                    .len())
+            }
+            Part::Zip {
+                path: zip_path,
+                readers,
+            } => Part::with_zip(zip_path, readers, |zip| {
+                Ok(zip
+                    .by_name(&zip_name(path)?)
+                    .context(format!("open {} in {}", path.display(), zip_path.display()))?
+                    .size())
+            }),
+        }
+    }
+
+    fn modified(&self, path: &Path, timezone: Timezone) -> Result<Option<NaiveDateTime>> {
+        match self {
+            Part::Directory(root) | Part::Downloads(Downloads { dir: root, .. }) => {
+                let full = root.join(path);
+                let modified = fs::metadata(&full)
+                    .and_then(|metadata| metadata.modified())
+                    .context(format!("stat {}", full.display()))?;
+                Ok(Some(DateTime::<Utc>::from(modified).naive_utc()))
+            }
+            // Zip files record times without a zone, which are taken to be local times
+            Part::Zip {
+                path: zip_path,
+                readers,
+            } => Part::with_zip(zip_path, readers, |zip| {
+                let modified = zip
+                    .by_name(&zip_name(path)?)
+                    .context(format!("open {} in {}", path.display(), zip_path.display()))?
+                    .last_modified();
+                Ok(modified
+                    .and_then(|time| {
+                        NaiveDate::from_ymd_opt(
+                            time.year().into(),
+                            time.month().into(),
+                            time.day().into(),
+                        )?
+                        .and_hms_opt(
+                            time.hour().into(),
+                            time.minute().into(),
+                            time.second().into(),
+                        )
+                    })
+                    .and_then(|time| timezone.utc(time)))
+            }),
+        }
+    }
+
+    fn read<T, F>(&self, path: &Path, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut dyn Read) -> Result<T>,
+    {
+        match self {
+            Part::Directory(root) | Part::Downloads(Downloads { dir: root, .. }) => {
+                let full = root.join(path);
+                f(&mut BufReader::new(
+                    File::open(&full).context(format!("open {}", full.display()))?,
+                ))
+            }
+            Part::Zip {
+                path: zip_path,
+                readers,
+            } => Part::with_zip(zip_path, readers, |zip| {
+                let mut file = zip.by_name(&zip_name(path)?).context(format!(
+                    "open {} in {}",
+                    path.display(),
+                    zip_path.display()
+                ))?;
+                f(&mut file)
+            }),
+        }
+    }
+}
+
+fn open_zip(path: &Path) -> Result<ZipReader> {
+    let file = match path.to_str() {
+        Some(url) if download::is_url(path) => ZipFile::Http(HttpFile::open(url)?),
+        _ => ZipFile::Local(BufReader::new(
+            File::open(path).context(format!("open {}", path.display()))?,
+        )),
+    };
+    ZipArchive::new(file).context(format!("read zip {}", path.display()))
+}
+
+// The start of the names of the entries within the directory, which is empty for the root.
+fn zip_prefix(dir: &Path) -> Result<String> {
+    match zip_name(dir)? {
+        name if name.is_empty() => Ok(name),
+        name => Ok(format!("{}/", name)),
+    }
+}
+
+// Zip entries always use forward slashes, regardless of the host platform.
+fn zip_name(path: &Path) -> Result<String> {
+    let mut parts = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => parts.push(
+                part.to_str()
+                    .context(format!("non-UTF-8 path {}", path.display()))?,
+            ),
+            Component::CurDir => {}
+            _ => return Err(anyhow!("unsupported path {}", path.display())),
+        }
+    }
+    Ok(parts.join("/"))
+}
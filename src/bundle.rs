@@ -0,0 +1,240 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Output written as a single zip or tar archive rather than a directory. Each file is added to the
+// archive by the thread which wrote it, as soon as it's finished, and then removed. Photos and
+// videos are still written to a file of their own first (e.g. to be transformed by ffmpeg), which
+// are kept in a directory beside the archive while they're being written. Everything else (e.g.
+// sidecars and the manifest) is written there too, and added at the end. Tar archives are only
+// compressed as a whole (with gzip), and only if asked to be. An unfinished archive can't be
+// resumed, so it's removed if the run fails.
+
+use crate::output::{self, PARTIAL_EXTENSION};
+use crate::{sniff, Timezone, LOCK_FILE};
+use anyhow::{anyhow, Context, Error, Result};
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::debug;
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Dir,
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<OutputFormat> {
+        match format {
+            "dir" => Ok(OutputFormat::Dir),
+            "zip" => Ok(OutputFormat::Zip),
+            "tar" => Ok(OutputFormat::Tar),
+            "tar.gz" => Ok(OutputFormat::TarGz),
+            _ => Err(anyhow!("unknown output format {}", format)),
+        }
+    }
+}
+
+enum Writer {
+    Zip(Box<ZipWriter<File>>),
+    Tar(tar::Builder<File>),
+    TarGz(Box<tar::Builder<GzEncoder<File>>>),
+}
+
+pub struct Bundle {
+    path: PathBuf,
+    // Where the files are written before they're added (see `scratch_dir`)
+    scratch: PathBuf,
+    // The zone which the modification times are recorded in
+    timezone: Timezone,
+    // Taken once the archive is finished
+    writer: Mutex<Option<Writer>>,
+}
+
+impl Bundle {
+    // Creates the archive, once the run has taken the lock on its scratch directory (see
+    // `scratch_dir`). Anything left there by a run which crashed is removed.
+    pub fn create(path: &Path, format: OutputFormat, timezone: Timezone) -> Result<Bundle> {
+        let scratch = Bundle::scratch_dir(path);
+        for entry in
+            fs::read_dir(&scratch).context(format!("list directory {}", scratch.display()))?
+        {
+            let entry = entry.context("entry")?;
+            if entry.file_name() == LOCK_FILE {
+                continue;
+            }
+            let path = entry.path();
+            if entry.file_type().context("file type")?.is_dir() {
+                fs::remove_dir_all(&path)
+            } else {
+                fs::remove_file(&path)
+            }
+            .context(format!("remove {}", path.display()))?;
+        }
+
+        let file = File::create(path).context(format!("create {}", path.display()))?;
+        let writer = match format {
+            OutputFormat::Zip => Writer::Zip(Box::new(ZipWriter::new(file))),
+            OutputFormat::Tar => Writer::Tar(tar::Builder::new(file)),
+            OutputFormat::TarGz => Writer::TarGz(Box::new(tar::Builder::new(GzEncoder::new(
+                file,
+                Compression::default(),
+            )))),
+            OutputFormat::Dir => return Err(anyhow!("a directory isn't an archive")),
+        };
+        Ok(Bundle {
+            path: path.to_path_buf(),
+            scratch,
+            timezone,
+            writer: Mutex::new(Some(writer)),
+        })
+    }
+
+    // The directory beside the archive which the files are written to before they're added (e.g.
+    // "photos.zip.fb-unarchive-partial").
+    pub fn scratch_dir(path: &Path) -> PathBuf {
+        let mut scratch = path.as_os_str().to_os_string();
+        scratch.push(PARTIAL_EXTENSION);
+        PathBuf::from(scratch)
+    }
+
+    // Adds the file, which was written within the scratch directory, and then removes it.
+    pub fn add_file(&self, path: &Path) -> Result<()> {
+        self.append(path)?;
+        fs::remove_file(path).context(format!("remove {}", path.display()))
+    }
+
+    // Adds everything left in the scratch directory, other than the records which only matter to
+    // the run itself (e.g. the journal), and then finishes the archive.
+    pub fn finish(&self) -> Result<()> {
+        let mut files = Vec::new();
+        let mut pending = vec![self.scratch.clone()];
+        while let Some(dir) = pending.pop() {
+            for entry in fs::read_dir(&dir).context(format!("list directory {}", dir.display()))? {
+                let entry = entry.context("entry")?;
+                let name = entry.file_name();
+                if output::is_local(&name) {
+                    continue;
+                }
+                if entry.file_type().context("file type")?.is_dir() {
+                    pending.push(entry.path());
+                } else {
+                    files.push(entry.path());
+                }
+            }
+        }
+        files.sort();
+        for file in &files {
+            self.append(file)?;
+        }
+
+        let writer = self.writer.lock().expect("writer").take();
+        match writer {
+            Some(Writer::Zip(zip)) => zip.finish().map(drop).map_err(Error::from),
+            Some(Writer::Tar(tar)) => tar.into_inner().map(drop).map_err(Error::from),
+            Some(Writer::TarGz(tar)) => tar
+                .into_inner()
+                .and_then(GzEncoder::finish)
+                .map(drop)
+                .map_err(Error::from),
+            None => Ok(()),
+        }
+        .context(format!("finish {}", self.path.display()))
+    }
+
+    // Removes the scratch directory once the run is over, along with the archive unless it was
+    // finished.
+    pub fn close(&self) -> Result<()> {
+        if self.writer.lock().expect("writer").take().is_some() {
+            debug!("Removing the unfinished {}", self.path.display());
+            fs::remove_file(&self.path).context(format!("remove {}", self.path.display()))?;
+        }
+        fs::remove_dir_all(&self.scratch)
+            .context(format!("remove directory {}", self.scratch.display()))
+    }
+
+    fn append(&self, path: &Path) -> Result<()> {
+        let file = path
+            .strip_prefix(&self.scratch)
+            .map_err(|_| anyhow!("{} is outside of the output", path.display()))?;
+        let name = file
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(part) => Some(part.to_string_lossy()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("/");
+
+        let mut writer = self.writer.lock().expect("writer");
+        match writer.as_mut() {
+            Some(Writer::Zip(zip)) => {
+                let metadata = fs::metadata(path).context(format!("stat {}", path.display()))?;
+                let modified = metadata
+                    .modified()
+                    .context(format!("modification time of {}", path.display()))?;
+                // Photos and videos are compressed already, so they're only stored
+                let extension = file.extension().and_then(|x| x.to_str());
+                let method = match extension.and_then(sniff::from_extension) {
+                    Some(_) => CompressionMethod::Stored,
+                    None => CompressionMethod::Deflated,
+                };
+                let options = SimpleFileOptions::default()
+                    .compression_method(method)
+                    .last_modified_time(zip_time(modified.into(), self.timezone))
+                    .large_file(metadata.len() > u64::from(u32::MAX));
+                zip.start_file(name.as_str(), options)
+                    .context(format!("add {}", name))?;
+                let mut contents =
+                    BufReader::new(File::open(path).context(format!("open {}", path.display()))?);
+                io::copy(&mut contents, zip).context(format!("add {}", name))?;
+            }
+            Some(Writer::Tar(tar)) => tar
+                .append_path_with_name(path, &name)
+                .context(format!("add {}", name))?,
+            Some(Writer::TarGz(tar)) => tar
+                .append_path_with_name(path, &name)
+                .context(format!("add {}", name))?,
+            None => return Err(anyhow!("{} is already finished", self.path.display())),
+        }
+        Ok(())
+    }
+}
+
+// Zip archives record times without a zone, which are read as local times, and can't go back
+// further than 1980 (which is used for anything older).
+fn zip_time(modified: DateTime<Utc>, timezone: Timezone) -> zip::DateTime {
+    let local = timezone.local(modified.naive_utc());
+    zip::DateTime::from_date_and_time(
+        u16::try_from(local.year()).unwrap_or_default(),
+        local.month() as u8,
+        local.day() as u8,
+        local.hour() as u8,
+        local.minute() as u8,
+        local.second() as u8,
+    )
+    .unwrap_or_default()
+}
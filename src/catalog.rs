@@ -0,0 +1,144 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// A SQLite database of the metadata of every output file, which can be queried long after it's
+// been flattened into the files' comments (e.g. for every photo that Bob commented on in 2015).
+// Times are stored as ISO 8601 strings in UTC, which SQLite's date functions understand.
+
+use crate::output::Record;
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection};
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+const SCHEMA: &str = "
+CREATE TABLE albums (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE
+);
+CREATE TABLE items (
+    id INTEGER PRIMARY KEY,
+    album_id INTEGER REFERENCES albums(id),
+    source TEXT NOT NULL,
+    timestamp TEXT NOT NULL,
+    description TEXT,
+    latitude REAL,
+    longitude REAL
+);
+CREATE TABLE outputs (
+    item_id INTEGER NOT NULL REFERENCES items(id),
+    path TEXT NOT NULL,
+    thumbnail TEXT
+);
+CREATE TABLE comments (
+    item_id INTEGER NOT NULL REFERENCES items(id),
+    author TEXT NOT NULL,
+    comment TEXT NOT NULL,
+    timestamp TEXT NOT NULL
+);
+CREATE TABLE tags (
+    item_id INTEGER NOT NULL REFERENCES items(id),
+    name TEXT NOT NULL
+);
+CREATE INDEX items_album ON items(album_id);
+CREATE INDEX comments_author ON comments(author);
+CREATE INDEX tags_name ON tags(name);
+";
+
+const TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%SZ";
+
+// Writes the catalog of the records, replacing any left over from a previous run. Output paths
+// are relative to `root`.
+pub fn write(path: &Path, root: &Path, records: &[Record]) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => {
+            return Err(err).context(format!("remove {}", path.display()))
+        }
+        _ => {}
+    }
+
+    let mut db = Connection::open(path).context(format!("open {}", path.display()))?;
+    let tx = db.transaction().context("begin transaction")?;
+    tx.execute_batch(SCHEMA).context("create tables")?;
+
+    let mut albums = HashMap::new();
+    for record in records {
+        let album_id = match &record.album {
+            Some(album) => match albums.get(album) {
+                Some(id) => Some(*id),
+                None => {
+                    tx.execute("INSERT INTO albums (name) VALUES (?1)", params![album])
+                        .context(format!("insert album {}", album))?;
+                    albums.insert(album.clone(), tx.last_insert_rowid());
+                    Some(tx.last_insert_rowid())
+                }
+            },
+            None => None,
+        };
+
+        tx.execute(
+            "INSERT INTO items (album_id, source, timestamp, description, latitude, longitude) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                album_id,
+                record.source.to_string_lossy(),
+                record.timestamp.format(TIME_FORMAT).to_string(),
+                record.description,
+                record.location.map(|(latitude, _)| latitude),
+                record.location.map(|(_, longitude)| longitude),
+            ],
+        )
+        .context(format!("insert item {}", record.source.display()))?;
+        let item_id = tx.last_insert_rowid();
+
+        let output = record.output.strip_prefix(root).unwrap_or(&record.output);
+        let thumbnail = record
+            .thumbnail
+            .as_ref()
+            .map(|thumbnail| thumbnail.strip_prefix(root).unwrap_or(thumbnail));
+        tx.execute(
+            "INSERT INTO outputs (item_id, path, thumbnail) VALUES (?1, ?2, ?3)",
+            params![
+                item_id,
+                output.to_string_lossy(),
+                thumbnail.map(|thumbnail| thumbnail.to_string_lossy())
+            ],
+        )
+        .context("insert output")?;
+        for (author, comment, timestamp) in &record.comments {
+            tx.execute(
+                "INSERT INTO comments (item_id, author, comment, timestamp) \
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    item_id,
+                    author,
+                    comment,
+                    timestamp.format(TIME_FORMAT).to_string()
+                ],
+            )
+            .context("insert comment")?;
+        }
+        for name in &record.people {
+            tx.execute(
+                "INSERT INTO tags (item_id, name) VALUES (?1, ?2)",
+                params![item_id, name],
+            )
+            .context("insert tag")?;
+        }
+    }
+
+    tx.commit().context(format!("write {}", path.display()))
+}
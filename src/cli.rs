@@ -0,0 +1,399 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The subcommands of fb-unarchive, each with its own options. Processing is the default, so that
+// `fb-unarchive --input export.zip` works without naming it. The others only read the export (and,
+// for `verify`, the output), so they share the options which choose what's read but none of
+// those which decide how it's written.
+
+use crate::{config, read_items, sniff, Common, Item, Options, Source, Timezone};
+use anyhow::{anyhow, Context, Result};
+use chrono::NaiveDateTime;
+use indicatif::HumanBytes;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::OsString;
+use std::path::PathBuf;
+use structopt::clap::App;
+use structopt::StructOpt;
+
+// The subcommand used when none is given
+const DEFAULT: &str = "process";
+
+// The first arguments which don't need the default subcommand
+const NAMED: &[&str] = &[
+    "process",
+    "retry",
+    "list",
+    "verify",
+    "stats",
+    "inspect",
+    "doctor",
+    "help",
+    "-h",
+    "--help",
+    "-V",
+    "--version",
+];
+
+// Only one is ever made, so the size of the processing options doesn't matter
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "fb-unarchive",
+    about = "Transform and organize photos from a Facebook data dump (archive) according to the associated metadata"
+)]
+pub enum Command {
+    /// Write the photos and videos in the archive to the output, with their metadata (the default)
+    Process(Box<Options>),
+    /// Process only the items which failed in the last run with --keep-going (as listed in
+    /// failures.json), with whatever options are given, leaving everything else in the output as
+    /// it is
+    Retry(Box<Options>),
+    /// List the albums in the archive, along with the number of items in each, the range of dates
+    /// they span, and their total size, without writing anything
+    List(ListOptions),
+    /// Check the output of a previous run against the archive, reporting the items which are
+    /// missing or don't have the dates and descriptions they should
+    Verify(VerifyOptions),
+    /// Summarize the archive: how many photos, videos, and audio clips it has, their size, and how
+    /// many have each kind of metadata
+    Stats(StatsOptions),
+    /// Print everything the archive records about particular items
+    Inspect(InspectOptions),
+    /// Check that the input is an export which can be read, reporting what's in it, anything
+    /// which is wrong with it, and the options which might get around that
+    Doctor(DoctorOptions),
+}
+
+impl Command {
+    pub fn common(&self) -> &Common {
+        match self {
+            Command::Process(opts) | Command::Retry(opts) => &opts.common,
+            Command::List(opts) => &opts.common,
+            Command::Verify(opts) => &opts.common,
+            Command::Stats(opts) => &opts.common,
+            Command::Inspect(opts) => &opts.common,
+            Command::Doctor(opts) => &opts.common,
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ListOptions {
+    #[structopt(flatten)]
+    pub source: Source,
+
+    #[structopt(flatten)]
+    pub common: Common,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct VerifyOptions {
+    #[structopt(flatten)]
+    pub source: Source,
+
+    #[structopt(flatten)]
+    pub common: Common,
+
+    /// Output directory written by the run being checked
+    #[structopt(short, long, default_value = "./out", parse(from_os_str))]
+    pub output: PathBuf,
+
+    /// Don't check the modification times, for output written with --no-mtime
+    #[structopt(long)]
+    pub no_mtime: bool,
+
+    /// Check the dates and descriptions in the XMP sidecars rather than in the files, for output
+    /// written with --no-modify
+    #[structopt(long)]
+    pub no_modify: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct StatsOptions {
+    #[structopt(flatten)]
+    pub source: Source,
+
+    #[structopt(flatten)]
+    pub common: Common,
+
+    /// Print the statistics as JSON
+    #[structopt(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct InspectOptions {
+    #[structopt(flatten)]
+    pub source: Source,
+
+    #[structopt(flatten)]
+    pub common: Common,
+
+    /// The items to inspect, by their path within the archive (e.g.
+    /// "photos_and_videos/Vacation/1.jpg")
+    #[structopt(required = true, parse(from_os_str))]
+    pub items: Vec<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct DoctorOptions {
+    #[structopt(flatten)]
+    pub source: Source,
+
+    #[structopt(flatten)]
+    pub common: Common,
+}
+
+// The process's arguments, with the default subcommand filled in if none was given and preceded
+// by those from the configuration file.
+pub fn args(app: App) -> Result<Vec<OsString>> {
+    let mut args = std::env::args_os().collect::<Vec<_>>();
+    match args.get(1).and_then(|arg| arg.to_str()) {
+        Some(arg) if NAMED.contains(&arg) => {}
+        _ => args.insert(1, OsString::from(DEFAULT)),
+    }
+    config::args(app, args)
+}
+
+// The options for processing the items which failed last time, as `fb-unarchive retry` does. The
+// items which were written are skipped, but still recorded as part of the output.
+pub fn retry(mut opts: Box<Options>) -> Box<Options> {
+    opts.retry = true;
+    opts.skip_existing = true;
+    opts
+}
+
+// Prints the albums in each archive, as `fb-unarchive list` does.
+pub fn list(opts: &ListOptions) -> Result<()> {
+    let archives = opts.source.open()?;
+    for (input, archive) in opts.source.input.iter().zip(&archives) {
+        if archives.len() > 1 {
+            println!("{}:", input.display());
+        }
+        crate::list(&opts.source, archive)?;
+    }
+    Ok(())
+}
+
+// Checks the output of a previous run, as `fb-unarchive verify` does.
+pub fn verify(opts: &VerifyOptions) -> Result<()> {
+    crate::verify::verify(opts)
+}
+
+// Diagnoses the input, as `fb-unarchive doctor` does.
+pub fn doctor(opts: &DoctorOptions) -> Result<()> {
+    crate::doctor::doctor(opts)
+}
+
+#[derive(Default, Serialize)]
+struct Stats {
+    albums: usize,
+    photos: usize,
+    photo_bytes: u64,
+    videos: usize,
+    video_bytes: u64,
+    audio_clips: usize,
+    audio_bytes: u64,
+    other: usize,
+    first: Option<NaiveDateTime>,
+    last: Option<NaiveDateTime>,
+    with_location: usize,
+    with_description: usize,
+    with_people: usize,
+    with_comments: usize,
+    with_reactions: usize,
+}
+
+// Prints the statistics of the archives, as `fb-unarchive stats` does. Types are judged by the
+// extension, rather than the contents, so that the files don't all have to be read.
+pub fn stats(opts: &StatsOptions) -> Result<()> {
+    let mut stats = Stats::default();
+    let mut albums = HashSet::new();
+    for archive in opts.source.open()? {
+        for item in read_items(&opts.source, &archive).context("read archive")? {
+            let size = archive.size(&item.path).unwrap_or(0);
+            let extension = item.path.extension().and_then(|x| x.to_str());
+            match extension.and_then(sniff::from_extension) {
+                Some("jpg") | Some("png") | Some("webp") | Some("gif") => {
+                    stats.photos += 1;
+                    stats.photo_bytes += size;
+                }
+                Some("mp4") | Some("flv") => {
+                    stats.videos += 1;
+                    stats.video_bytes += size;
+                }
+                Some(_) => {
+                    stats.audio_clips += 1;
+                    stats.audio_bytes += size;
+                }
+                None => stats.other += 1,
+            }
+
+            stats.first = Some(
+                stats
+                    .first
+                    .map_or(item.timestamp, |first| first.min(item.timestamp)),
+            );
+            stats.last = Some(
+                stats
+                    .last
+                    .map_or(item.timestamp, |last| last.max(item.timestamp)),
+            );
+            stats.with_location += item.location().is_some() as usize;
+            stats.with_description += item.description.is_some() as usize;
+            stats.with_people += !item.tags.is_empty() as usize;
+            stats.with_comments += !item.comments.is_empty() as usize;
+            stats.with_reactions += !item.reactions.is_empty() as usize;
+            if let Some(album) = item.album {
+                albums.insert(album);
+            }
+        }
+    }
+    stats.albums = albums.len();
+
+    if opts.json {
+        println!(
+            "{}",
+            serde_json::to_string(&stats).context("serialize stats")?
+        );
+        return Ok(());
+    }
+    let range = match (stats.first, stats.last) {
+        (Some(first), Some(last)) => {
+            format!("{} - {}", first.format("%Y-%m-%d"), last.format("%Y-%m-%d"))
+        }
+        _ => "-".to_string(),
+    };
+    println!("Albums:              {}", stats.albums);
+    println!(
+        "Photos:              {} ({})",
+        stats.photos,
+        HumanBytes(stats.photo_bytes)
+    );
+    println!(
+        "Videos:              {} ({})",
+        stats.videos,
+        HumanBytes(stats.video_bytes)
+    );
+    println!(
+        "Audio clips:         {} ({})",
+        stats.audio_clips,
+        HumanBytes(stats.audio_bytes)
+    );
+    println!("Other files:         {}", stats.other);
+    println!("Dates:               {}", range);
+    println!("With a location:     {}", stats.with_location);
+    println!("With a description:  {}", stats.with_description);
+    println!("With people tagged:  {}", stats.with_people);
+    println!("With comments:       {}", stats.with_comments);
+    println!("With reactions:      {}", stats.with_reactions);
+    Ok(())
+}
+
+// Prints what's recorded about each of the items, as `fb-unarchive inspect` does. An item which is
+// in more than one album is shown once for each.
+pub fn inspect(opts: &InspectOptions) -> Result<()> {
+    let mut found = HashSet::new();
+    for archive in opts.source.open()? {
+        for item in read_items(&opts.source, &archive).context("read archive")? {
+            if !opts.items.contains(&item.path) {
+                continue;
+            }
+            let size = archive.size(&item.path).unwrap_or(0);
+            print_item(&item, size, archive.timezone());
+            found.insert(item.path);
+        }
+    }
+
+    let missing = opts
+        .items
+        .iter()
+        .filter(|path| !found.contains(*path))
+        .map(|path| path.display().to_string())
+        .collect::<Vec<_>>();
+    match missing.as_slice() {
+        [] => Ok(()),
+        missing => Err(anyhow!("not in the archive: {}", missing.join(", "))),
+    }
+}
+
+fn print_item(item: &Item, size: u64, timezone: Timezone) {
+    println!("{}", item.path.display());
+    println!("  size:         {}", HumanBytes(size));
+    if let Some(album) = &item.album {
+        println!("  album:        {}", album);
+    }
+    println!(
+        "  taken:        {}",
+        item.local_timestamp(timezone)
+            .format("%Y-%m-%d %H:%M:%S %:z")
+    );
+    if let Some(description) = &item.description {
+        println!("  description:  {}", description);
+    }
+    if let Some(author) = &item.author {
+        println!("  uploaded by:  {}", author);
+    }
+    if let Some(place) = &item.place {
+        println!("  place:        {}", place.name);
+    }
+    if let Some(location) = item.location() {
+        println!(
+            "  location:     {}, {}{}",
+            location.latitude,
+            location.longitude,
+            if location.approximate {
+                " (approximate)"
+            } else {
+                ""
+            }
+        );
+    }
+    let data = item.exif_data();
+    let camera = [&data.camera_make, &data.camera_model]
+        .iter()
+        .filter_map(|part| part.as_deref())
+        .collect::<Vec<_>>();
+    if !camera.is_empty() {
+        println!("  camera:       {}", camera.join(" "));
+    }
+    if !item.tags.is_empty() {
+        let people = item
+            .tags
+            .iter()
+            .map(|tag| tag.name.as_str())
+            .collect::<Vec<_>>();
+        println!("  people:       {}", people.join(", "));
+    }
+    if !item.reactions.is_empty() {
+        let mut reactions = BTreeMap::new();
+        for reaction in &item.reactions {
+            *reactions.entry(reaction.reaction.as_str()).or_insert(0) += 1;
+        }
+        let reactions = reactions
+            .iter()
+            .map(|(reaction, count)| format!("{} {}", count, reaction))
+            .collect::<Vec<_>>();
+        println!("  reactions:    {}", reactions.join(", "));
+    }
+    for comment in &item.comments {
+        println!(
+            "  comment:      {} ({}): {}",
+            comment.author,
+            timezone.local(comment.timestamp).format("%Y-%m-%d %H:%M"),
+            comment.comment.as_deref().unwrap_or_default()
+        );
+    }
+}
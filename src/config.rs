@@ -0,0 +1,121 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Configuration files, which hold the same options as the command line (e.g. `output = "photos"`
+// or `skip-videos = true`). Rather than duplicating every option, the file is turned into
+// arguments which are placed between the subcommand and those actually given, skipping any which
+// were given on the command line so that they take precedence.
+
+use anyhow::{anyhow, Context, Result};
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use structopt::clap::{App, ErrorKind};
+use toml::Value;
+
+// Used when `--config` isn't given, if it exists
+const DEFAULT_PATH: &str = "fb-unarchive.toml";
+
+// The arguments, which start with the subcommand, with those from the configuration file added
+// after it. Options in the file which the subcommand doesn't have are left out, so that one file
+// can serve them all.
+pub fn args(app: App, mut args: Vec<OsString>) -> Result<Vec<OsString>> {
+    // Errors in the arguments themselves are left to be reported when they are parsed for real
+    let all = match app.clone().get_matches_from_safe(&args) {
+        Ok(matches) => matches,
+        Err(_) => return Ok(args),
+    };
+    let matches = match all.subcommand() {
+        (_, Some(matches)) => matches,
+        _ => return Ok(args),
+    };
+    let has = |flag: &str, value: Option<&str>| {
+        let trial = args[..2]
+            .iter()
+            .cloned()
+            .chain(std::iter::once(OsString::from(flag)))
+            .chain(value.map(OsString::from));
+        !matches!(
+            app.clone().get_matches_from_safe(trial),
+            Err(err) if err.kind == ErrorKind::UnknownArgument
+        )
+    };
+
+    let mut config_args = Vec::new();
+    let path = match matches.value_of_os("config") {
+        Some(path) => PathBuf::from(path),
+        None if Path::new(DEFAULT_PATH).is_file() => {
+            // Passed along so that it's clear which configuration was used
+            config_args.push(format!("--config={}", DEFAULT_PATH));
+            PathBuf::from(DEFAULT_PATH)
+        }
+        None => return Ok(args),
+    };
+    let config = fs::read_to_string(&path)
+        .context(format!("read {}", path.display()))?
+        .parse::<toml::Table>()
+        .context(format!("parse {}", path.display()))?;
+
+    for (key, value) in config {
+        let name = key.replace('-', "_");
+        if matches.occurrences_of(&name) > 0 {
+            continue;
+        }
+        let flag = format!("--{}", name.replace('_', "-"));
+        let known = match &value {
+            Value::Boolean(_) => has(&flag, None),
+            Value::Integer(_) if name == "verbosity" => has(&flag, None),
+            Value::Array(values) => match values.first() {
+                Some(value) => has(&flag, Some(&scalar(&key, value.clone())?)),
+                None => true,
+            },
+            value => has(&flag, Some(&scalar(&key, value.clone())?)),
+        };
+        if !known {
+            continue;
+        }
+
+        match value {
+            Value::Boolean(true) => config_args.push(flag),
+            Value::Boolean(false) => {}
+            // Verbosity is counted, like "-vv"
+            Value::Integer(count) if name == "verbosity" => {
+                config_args.extend((0..count).map(|_| flag.clone()))
+            }
+            Value::Array(values) => {
+                for value in values {
+                    config_args.push(flag.clone());
+                    config_args.push(scalar(&key, value)?);
+                }
+            }
+            value => {
+                config_args.push(flag);
+                config_args.push(scalar(&key, value)?);
+            }
+        }
+    }
+
+    args.splice(2..2, config_args.into_iter().map(OsString::from));
+    Ok(args)
+}
+
+fn scalar(key: &str, value: Value) -> Result<String> {
+    match value {
+        Value::String(value) => Ok(value),
+        Value::Integer(value) => Ok(value.to_string()),
+        Value::Float(value) => Ok(value.to_string()),
+        Value::Datetime(value) => Ok(value.to_string()),
+        value => Err(anyhow!("unsupported value {} for {}", value, key)),
+    }
+}
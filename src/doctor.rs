@@ -0,0 +1,498 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// A look at the input before it's processed, for `fb-unarchive doctor`. Most of what goes wrong
+// with an export (the wrong path, a language which isn't built in, a part left out, a layout which
+// isn't understood) otherwise only shows up as a file which can't be found, so each section is
+// read on its own, and whatever fails is explained along with the options which get around it.
+
+use crate::cli::DoctorOptions;
+use crate::download::is_url;
+use crate::{
+    instagram, is_instagram, locale, marketplace, messages, orphans, posts, read_albums,
+    read_tagged, read_unsorted, read_videos, sniff, Album, Archive, Item, Source,
+};
+use anyhow::{anyhow, Context, Result};
+use indicatif::HumanBytes;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+// The folders of newer Facebook exports, which are laid out differently throughout
+const NEWER_LAYOUT: &[&str] = &["your_facebook_activity", "your_activity_across_facebook"];
+
+// Folders which only a Facebook or Instagram export would have at its root
+const KNOWN_DIRS: &[&str] = &[
+    "photos_and_videos",
+    "posts",
+    "messages",
+    "profile_information",
+    "content",
+    "your_instagram_activity",
+];
+
+#[derive(Default)]
+struct Doctor {
+    problems: Vec<String>,
+    suggestions: Vec<String>,
+    // Every item listed in the sections which could be read
+    items: Vec<Item>,
+}
+
+pub fn doctor(opts: &DoctorOptions) -> Result<()> {
+    let source = &opts.source;
+    let mut doctor = Doctor::default();
+    for input in &source.input {
+        doctor.check_input(input);
+    }
+
+    if doctor.problems.is_empty() {
+        match source.open() {
+            Ok(archives) => {
+                for (input, archive) in source.input.iter().zip(&archives) {
+                    let name = match archives.len() {
+                        1 => source
+                            .input
+                            .iter()
+                            .map(|input| input.display().to_string())
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                        _ => input.display().to_string(),
+                    };
+                    doctor.items.clear();
+                    doctor
+                        .diagnose(source, &name, archive)
+                        .context(format!("diagnose {}", name))?;
+                    println!();
+                }
+            }
+            Err(err) => doctor.problem(format!("The input can't be opened: {:#}", err)),
+        }
+    }
+
+    doctor.print();
+    match doctor.problems.len() {
+        0 => Ok(()),
+        1 => Err(anyhow!("found a problem")),
+        count => Err(anyhow!("found {} problems", count)),
+    }
+}
+
+impl Doctor {
+    fn problem<S: Into<String>>(&mut self, problem: S) {
+        self.problems.push(problem.into());
+    }
+
+    fn suggest<S: Into<String>>(&mut self, suggestion: S) {
+        let suggestion = suggestion.into();
+        if !self.suggestions.contains(&suggestion) {
+            self.suggestions.push(suggestion);
+        }
+    }
+
+    // Checks that the input is something which can be opened as an export, before trying to.
+    fn check_input(&mut self, input: &Path) {
+        if is_url(input) {
+            return;
+        }
+        let metadata = match fs::metadata(input) {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                self.problem(format!("{} can't be read: {}", input.display(), err));
+                self.suggest(
+                    "Give --input the zip file Facebook provided, or the directory it was \
+                     extracted into",
+                );
+                return;
+            }
+        };
+        if metadata.is_dir() {
+            return;
+        }
+
+        let mut magic = [0; 2];
+        let zip = File::open(input)
+            .and_then(|mut file| file.read_exact(&mut magic))
+            .is_ok()
+            && magic == *b"PK";
+        if !zip {
+            self.problem(format!("{} isn't a zip file", input.display()));
+            self.suggest(
+                "Give --input the zip file Facebook provided, or the directory it was extracted \
+                 into, rather than a file within it",
+            );
+        }
+    }
+
+    fn diagnose(&mut self, source: &Source, name: &str, archive: &Archive) -> Result<()> {
+        let files = archive
+            .list_recursive(Path::new(""))
+            .context("list files")?;
+        let roots = files
+            .iter()
+            .filter_map(|path| path.iter().next())
+            .map(|root| root.to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        let has_root = |dir: &str| roots.iter().any(|root| root == dir);
+
+        row("Input", name);
+        row("Files", files.len());
+        if files.is_empty() {
+            self.problem(format!("{} is empty", name));
+            return Ok(());
+        }
+
+        let instagram = is_instagram(source, archive)?;
+        let html = has_root("index.html")
+            || files
+                .iter()
+                .any(|path| path.starts_with("photos_and_videos") && is_html(path));
+        row(
+            "Format",
+            match (instagram, html) {
+                (true, _) => "Instagram",
+                (false, true) => "Facebook (HTML)",
+                (false, false) => "Facebook (JSON)",
+            },
+        );
+        // Instagram's exports are only in English
+        if !instagram {
+            row("Language", self.language(archive, &files)?);
+        }
+        self.media(archive, &files);
+
+        if !KNOWN_DIRS.iter().any(|dir| has_root(dir)) {
+            self.unrecognized(source, name, &files, has_root);
+            return Ok(());
+        }
+
+        println!("Sections:");
+        if instagram {
+            self.section("Posts", None, instagram::read_posts(archive).map(listed));
+            self.section(
+                "Stories",
+                None,
+                instagram::read_stories(archive).map(listed),
+            );
+            self.section("Reels", None, instagram::read_reels(archive).map(listed));
+        } else {
+            self.facebook(source, archive, &files, html)?;
+        }
+
+        self.listed(archive)?;
+        if !instagram && !source.include_orphans {
+            if let Some(orphans) = orphans::read_orphans(archive).unwrap_or(None) {
+                row("Unlisted media", orphans.items.len());
+                self.suggest(format!(
+                    "Add --include-orphans to include the {} photos and videos which the export \
+                     has but doesn't list",
+                    orphans.items.len()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // The language of the export, judged by the name of the first of the folders which has a
+    // built-in translation for every language.
+    fn language(&self, archive: &Archive, files: &[PathBuf]) -> Result<String> {
+        for english in ["photos_and_videos", "posts", "profile_information"] {
+            let path = match files.iter().find(|path| path.starts_with(english)) {
+                Some(path) => path,
+                None => continue,
+            };
+            let actual = archive.name_in_archive(path)?.unwrap_or_default();
+            let actual = actual
+                .iter()
+                .next()
+                .map(|name| name.to_string_lossy())
+                .unwrap_or_default();
+            return Ok(match locale::language(english, &actual) {
+                Some(language) => language.to_string(),
+                // Only names from --names can be translated without being built in
+                None => format!("from --names ({} is {})", english, actual),
+            });
+        }
+        Ok("unknown".to_string())
+    }
+
+    // Prints the number and size of the photos, videos, and audio clips, by the folder they're in.
+    fn media(&self, archive: &Archive, files: &[PathBuf]) {
+        let mut dirs = BTreeMap::<String, (usize, u64)>::new();
+        for path in files {
+            let extension = path.extension().and_then(|x| x.to_str());
+            if extension.and_then(sniff::from_extension).is_none() {
+                continue;
+            }
+            let dir = match path.parent().and_then(|parent| parent.iter().next()) {
+                Some(dir) => dir.to_string_lossy().into_owned(),
+                None => ".".to_string(),
+            };
+            let (count, size) = dirs.entry(dir).or_default();
+            *count += 1;
+            *size += archive.size(path).unwrap_or(0);
+        }
+
+        let count = dirs.values().map(|(count, _)| count).sum::<usize>();
+        let size = dirs.values().map(|(_, size)| size).sum::<u64>();
+        row("Media files", format!("{} ({})", count, HumanBytes(size)));
+        for (dir, (count, size)) in dirs {
+            row(
+                &format!("  {}", dir),
+                format!("{} ({})", count, HumanBytes(size)),
+            );
+        }
+    }
+
+    // Explains an input which holds files, but not where an export would have them.
+    fn unrecognized<F>(&mut self, source: &Source, name: &str, files: &[PathBuf], has_root: F)
+    where
+        F: Fn(&str) -> bool,
+    {
+        if NEWER_LAYOUT.iter().any(|dir| has_root(dir)) {
+            self.problem(
+                "This export uses Facebook's newer layout (with \"your_facebook_activity\"), which \
+                 can't be read yet",
+            );
+            return;
+        }
+
+        // An export extracted into a folder of its own, rather than the folder given
+        let nested = files.iter().find_map(|path| {
+            let mut components = path.iter();
+            let dir = components.next()?;
+            let known = components.next()?.to_string_lossy();
+            KNOWN_DIRS.contains(&known.as_ref()).then(|| Path::new(dir))
+        });
+        match nested {
+            Some(dir) if source.input.iter().all(|input| input.is_dir()) => {
+                self.problem(format!(
+                    "The export is in {} rather than {}",
+                    dir.display(),
+                    name
+                ));
+                self.suggest(format!(
+                    "Give the folder holding the export with --input {}",
+                    source.input[0].join(dir).display()
+                ));
+            }
+            Some(dir) => {
+                self.problem(format!(
+                    "The export is in {} within {}, rather than at its root",
+                    dir.display(),
+                    name
+                ));
+                self.suggest("Extract the zip file and give --input the folder holding the export");
+            }
+            None => {
+                self.problem(format!(
+                    "None of the folders in {} are those of a Facebook or Instagram export",
+                    name
+                ));
+                self.suggest(
+                    "If the export isn't in English, Spanish, German, French, Portuguese, or \
+                     Italian, name its folders with --names (see the README)",
+                );
+                if source.input == [PathBuf::from(".")] {
+                    self.suggest("Give the export with --input, since it defaults to this folder");
+                }
+            }
+        }
+    }
+
+    fn facebook(
+        &mut self,
+        source: &Source,
+        archive: &Archive,
+        files: &[PathBuf],
+        html: bool,
+    ) -> Result<()> {
+        let dir = Path::new("photos_and_videos");
+        if files.iter().any(|path| path.starts_with(dir.join("album"))) {
+            self.section("Albums", Some("albums"), read_albums(archive).map(Some));
+        } else {
+            self.section("Albums", None, Ok(None));
+            self.problem(
+                "There's no photos_and_videos/album folder, which processing needs; the export \
+                 may be missing a part, or may have been requested without \"Photos and videos\"",
+            );
+            self.suggest(
+                "If the export was downloaded in several parts, give each of them with --input",
+            );
+        }
+        self.section(
+            "Profile pictures",
+            Some("albums"),
+            posts::read_profile(archive).map(Some),
+        );
+        self.section("Unsorted photos", None, read_unsorted(archive).map(one));
+        self.section("Photos of you", None, read_tagged(archive).map(one));
+        if archive.contains(&dir.join("your_videos.json"))?
+            || archive.contains(&dir.join("your_videos.html"))?
+        {
+            self.section("Videos", None, read_videos(archive).map(listed));
+        } else {
+            self.section("Videos", None, Ok(None));
+            self.problem(
+                "There's no photos_and_videos/your_videos.json, which processing needs; the \
+                 export may be missing a part",
+            );
+            self.suggest(
+                "If the export was downloaded in several parts, give each of them with --input",
+            );
+        }
+        self.section("Posts", None, posts::read_posts(archive).map(listed));
+        self.section("Stories", None, posts::read_stories(archive).map(listed));
+        for (section, label) in posts::SECTIONS {
+            self.section(
+                label,
+                Some(section),
+                posts::read_section(archive, section).map(Some),
+            );
+        }
+        self.section(
+            "Messages",
+            Some("conversations"),
+            messages::read_threads(archive, source.messenger_folders).map(Some),
+        );
+        self.section(
+            "Marketplace",
+            None,
+            marketplace::read_listings(archive).map(one),
+        );
+
+        if html {
+            self.problem(
+                "This is an HTML export: only its albums and videos can be read, and without \
+                 their comments, reactions, or locations",
+            );
+            self.suggest(
+                "Request the export from Facebook again, choosing JSON as its format, to get \
+                 everything in it",
+            );
+        }
+        Ok(())
+    }
+
+    // Prints how many items (and, if it's given a unit, albums) there are in the section, or why
+    // it couldn't be read, keeping the items for the checks which follow.
+    fn section(&mut self, label: &str, unit: Option<&str>, albums: Result<Option<Vec<Album>>>) {
+        match albums {
+            Ok(Some(albums)) => {
+                let count = albums.iter().map(|album| album.items.len()).sum::<usize>();
+                match unit {
+                    _ if count == 0 => row(&format!("  {}", label), "none"),
+                    Some(unit) => {
+                        // The units are plurals (e.g. "albums"), which are trimmed for just one
+                        let unit = match albums.len() {
+                            1 => unit.trim_end_matches('s'),
+                            _ => unit,
+                        };
+                        row(
+                            &format!("  {}", label),
+                            format!("{} items in {} {}", count, albums.len(), unit),
+                        )
+                    }
+                    None => row(&format!("  {}", label), count),
+                }
+                self.items
+                    .extend(albums.into_iter().flat_map(|album| album.items));
+            }
+            Ok(None) => row(&format!("  {}", label), "none"),
+            Err(err) => {
+                row(&format!("  {}", label), "unreadable");
+                self.problem(format!("{} can't be read: {:#}", label, err));
+            }
+        }
+    }
+
+    // Checks that the items which were read can be found: in the archive, or on Facebook's
+    // servers.
+    fn listed(&mut self, archive: &Archive) -> Result<()> {
+        let mut urls = 0;
+        let mut missing = Vec::new();
+        for item in &self.items {
+            if is_url(&item.path) {
+                urls += 1;
+            } else if !archive.contains(&item.path)? {
+                missing.push(item.path.clone());
+            }
+        }
+        missing.sort();
+        missing.dedup();
+
+        if urls > 0 {
+            row("Linked media", urls);
+            self.suggest(format!(
+                "Add --download to fetch the {} photos and videos which the export links to \
+                 rather than includes",
+                urls
+            ));
+        }
+        if let Some(first) = missing.first() {
+            row("Missing media", missing.len());
+            self.problem(format!(
+                "{} of the photos and videos listed by the export aren't in it (e.g. {})",
+                missing.len(),
+                first.display()
+            ));
+            self.suggest(
+                "If the export was downloaded in several parts, give each of them with --input",
+            );
+        }
+        Ok(())
+    }
+
+    fn print(&self) {
+        if self.problems.is_empty() {
+            println!("No problems found");
+        } else {
+            println!("Problems:");
+            for problem in &self.problems {
+                println!("  - {}", problem);
+            }
+        }
+        if !self.suggestions.is_empty() {
+            println!("Suggestions:");
+            for suggestion in &self.suggestions {
+                println!("  - {}", suggestion);
+            }
+        }
+    }
+}
+
+// A section which is a single list of items, as a nameless album.
+fn listed(items: Vec<Item>) -> Option<Vec<Album>> {
+    one(Some(Album {
+        name: String::new(),
+        description: None,
+        cover_photo: None,
+        items,
+        file: None,
+    }))
+}
+
+fn one(album: Option<Album>) -> Option<Vec<Album>> {
+    album.map(|album| vec![album])
+}
+
+fn is_html(path: &Path) -> bool {
+    path.extension().and_then(|x| x.to_str()) == Some("html")
+}
+
+fn row<V: Display>(label: &str, value: V) {
+    println!("{:<24}{}", format!("{}:", label), value);
+}
@@ -0,0 +1,117 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Items whose "uri" is a URL on Facebook's servers rather than a path within the export, as some
+// versions of the export have. With `--download`, each is fetched into a directory alongside the
+// output, named after a hash of its URL, where the archive finds it like any other file (see
+// `archive`). Requests are spaced out so as not to be throttled.
+
+use crate::output;
+use anyhow::{anyhow, Context, Result};
+use log::trace;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use ureq::Agent;
+
+// The least time between the start of one request and the next
+const INTERVAL: Duration = Duration::from_millis(250);
+
+// Whether the path is actually a URL.
+pub fn is_url(path: &Path) -> bool {
+    path.to_str()
+        .is_some_and(|path| path.starts_with("https://") || path.starts_with("http://"))
+}
+
+// The path of the file, without the query if it's a URL (which otherwise ends up in its extension).
+pub fn without_query(path: &Path) -> &Path {
+    match path.to_str() {
+        Some(url) if is_url(path) => Path::new(url.split(['?', '#']).next().unwrap_or(url)),
+        _ => path,
+    }
+}
+
+pub struct Downloads {
+    pub dir: PathBuf,
+    agent: Agent,
+    // When the last request was started
+    last: Mutex<Option<Instant>>,
+}
+
+impl Downloads {
+    pub fn new(dir: PathBuf) -> Downloads {
+        let config = Agent::config_builder().http_status_as_error(false).build();
+        Downloads {
+            dir,
+            agent: Agent::new_with_config(config),
+            last: Mutex::new(None),
+        }
+    }
+
+    // The name of the downloaded file within the directory, which keeps the extension of the URL.
+    pub fn name(&self, url: &Path) -> PathBuf {
+        let hash = output::hash(&mut url.to_string_lossy().as_bytes()).unwrap_or_default();
+        let mut name = hash[..16]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+        if let Some(extension) = without_query(url).extension() {
+            name.push('.');
+            name.push_str(&extension.to_string_lossy());
+        }
+        PathBuf::from(name)
+    }
+
+    // Fetches the file, trying again if the server fails. Links which have expired (or were never
+    // valid) aren't retried.
+    pub fn fetch(&self, url: &Path) -> Result<()> {
+        fs::create_dir_all(&self.dir)
+            .context(format!("create directory {}", self.dir.display()))?;
+        let path = self.dir.join(self.name(url));
+        let url = url.to_string_lossy();
+        crate::remote::retry(&format!("download {}", url), || {
+            self.wait();
+            trace!("Downloading {}", url);
+            let mut response = self.agent.get(url.as_ref()).call()?;
+            match response.status().as_u16() {
+                200..=299 => {}
+                status @ (429 | 500..=599) => return Err(anyhow!("http status: {}", status)),
+                status => return Ok(Err(anyhow!("http status: {}", status))),
+            }
+            output::write_atomically(&path, |temp| {
+                let mut out = BufWriter::new(File::create(temp).context("create")?);
+                let written = io::copy(&mut response.body_mut().as_reader(), &mut out)?;
+                out.flush()?;
+                Ok(written)
+            })
+            .map(drop)
+            .map(Ok)
+        })?
+        .context(format!("download {}", url))
+    }
+
+    // Waits until the next request is allowed.
+    fn wait(&self) {
+        let mut last = self.last.lock().expect("last request");
+        if let Some(elapsed) = last.map(|last| last.elapsed()) {
+            if elapsed < INTERVAL {
+                thread::sleep(INTERVAL - elapsed);
+            }
+        }
+        *last = Some(Instant::now());
+    }
+}
@@ -0,0 +1,75 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+use std::convert::TryFrom;
+
+// Facebook encodes each byte of the UTF-8 text as its own code point (e.g. "ð\u009f\u0098\u0082"
+// instead of "😂"). Reassemble the bytes and decode them properly, leaving strings that
+// can't have been produced this way untouched.
+pub fn fix(text: String) -> String {
+    let bytes = text
+        .chars()
+        .map(|c| u8::try_from(u32::from(c)).ok())
+        .collect::<Option<Vec<u8>>>();
+
+    match bytes.map(String::from_utf8) {
+        Some(Ok(fixed)) => fixed,
+        _ => text,
+    }
+}
+
+// Truncates the text to at most `max` bytes without splitting a character.
+pub fn truncate(text: &str, max: usize) -> &str {
+    let mut end = text.len().min(max);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    String::deserialize(deserializer).map(fix)
+}
+
+pub fn deserialize_option<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    Option::<String>::deserialize(deserializer).map(|text| text.map(fix))
+}
+
+// Numbers are sometimes encoded as strings, which may also be fractions (e.g. "1/120").
+pub fn deserialize_number<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<f64>, D::Error> {
+    Ok(match Option::<Value>::deserialize(deserializer)? {
+        Some(Value::Number(number)) => number.as_f64(),
+        Some(Value::String(text)) => match text.trim().split_once('/') {
+            Some((numerator, denominator)) => {
+                match (
+                    numerator.trim().parse::<f64>(),
+                    denominator.trim().parse::<f64>(),
+                ) {
+                    (Ok(numerator), Ok(denominator)) if denominator != 0.0 => {
+                        Some(numerator / denominator)
+                    }
+                    _ => None,
+                }
+            }
+            None => text.trim().parse().ok(),
+        },
+        _ => None,
+    })
+}
@@ -0,0 +1,110 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// What happens to each item (processed, skipped, or failed), logged with the details attached as
+// key-value pairs. The ordinary log only shows the message, but with `--log-format json` every
+// log line becomes a JSON object including those details, so that scripts and GUIs can follow
+// along without parsing the messages. Events are always logged in that case, regardless of the
+// verbosity.
+
+use crate::output::Written;
+use crate::Item;
+use anyhow::{anyhow, Error, Result};
+use log::kv::{Key, Value, VisitSource};
+use log::{debug, error, Record};
+use serde_json::{Map, Value as Json};
+use std::str::FromStr;
+
+// The target of every event, so that they can be enabled separately
+pub const TARGET: &str = "fb_unarchive::events";
+
+// How to print the log
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<LogFormat> {
+        match format {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(anyhow!("unknown log format {:?}", format)),
+        }
+    }
+}
+
+pub fn processed(item: &Item, written: &Written) {
+    debug!(
+        target: TARGET,
+        event = "processed",
+        source = item.path.to_string_lossy().as_ref(),
+        output = written.path.to_string_lossy().as_ref(),
+        bytes = written.bytes;
+        "Wrote {} to {}",
+        item.path.display(),
+        written.path.display()
+    );
+}
+
+pub fn skipped(item: &Item, reason: &str) {
+    debug!(
+        target: TARGET,
+        event = "skipped",
+        source = item.path.to_string_lossy().as_ref(),
+        reason = reason;
+        "Skipped {} ({})",
+        item.path.display(),
+        reason
+    );
+}
+
+pub fn failed(item: &Item, err: &Error) {
+    let reason = format!("{:#}", err);
+    error!(
+        target: TARGET,
+        event = "failed",
+        source = item.path.to_string_lossy().as_ref(),
+        reason = reason.as_str();
+        "Failed to process {}: {}",
+        item.path.display(),
+        reason
+    );
+}
+
+// The record as a single line of JSON, with its level, message, and any details.
+pub fn to_json(record: &Record) -> String {
+    let mut line = Map::new();
+    line.insert("level".to_string(), record.level().as_str().into());
+    line.insert("target".to_string(), record.target().into());
+    line.insert("message".to_string(), record.args().to_string().into());
+    let _ = record.key_values().visit(&mut Fields(&mut line));
+    Json::Object(line).to_string()
+}
+
+struct Fields<'a>(&'a mut Map<String, Json>);
+
+impl<'a, 'kvs> VisitSource<'kvs> for Fields<'a> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        let value = match value.to_u64() {
+            Some(number) => number.into(),
+            None => value.to_string().into(),
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
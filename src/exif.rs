@@ -0,0 +1,753 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::encoding::truncate;
+use crate::{Item, MetadataProfile, Options};
+use anyhow::{Context, Result};
+use imagemeta::exif::{tag, Entry, EntryData, Exif, Ifd};
+use log::{trace, warn};
+use std::io::Cursor;
+
+const IMAGE_DESCRIPTION: u16 = 0x010e;
+const ARTIST: u16 = 0x013b;
+const COPYRIGHT: u16 = 0x8298;
+const EXIF_VERSION: u16 = 0x9000;
+const OFFSET_TIME: u16 = 0x9010;
+const OFFSET_TIME_ORIGINAL: u16 = 0x9011;
+const MAKER_NOTE: u16 = 0x927c;
+const EXPOSURE_TIME: u16 = 0x829a;
+const F_NUMBER: u16 = 0x829d;
+const ISO_SPEED: u16 = 0x8827;
+const FOCAL_LENGTH: u16 = 0x920a;
+const GPS_AREA_INFORMATION: u16 = 0x001c;
+const XP_COMMENT: u16 = 0x9c9c;
+const XP_KEYWORDS: u16 = 0x9c9e;
+
+// The most of the description and comments written into each tag, in bytes of UTF-8. A JPEG's EXIF
+// has to fit in a single segment of 64 KiB, and the text can be written there three times (in
+// ImageDescription, UserComment, and XPComment), twice as large in UTF-16.
+pub const MAX_TEXT: usize = 8 * 1024;
+
+// Encodes the metadata for the item, merged into the EXIF already present in the file (if any).
+// Tags from the original which aren't written here are preserved. Items which someone else uploaded
+// are credited to them, and the owner of the export's own uploads to the owner, if known.
+pub fn encode(item: &Item, existing: Option<&[u8]>, opts: &Options) -> Result<Vec<u8>> {
+    let timestamp = item.local_timestamp(opts.source.timezone);
+    let comment = crate::combined_comment(item, opts.source.timezone);
+    let comment = truncate(&comment, MAX_TEXT);
+    let mut entries = vec![Entry {
+        tag: rexif::ExifTag::DateTime as u16,
+        data: EntryData::Ascii(timestamp.format("%Y:%m:%d %H:%M:%S").to_string()),
+    }];
+
+    let data = item.exif_data();
+    entries.extend(
+        data.camera_make
+            .as_deref()
+            .and_then(|make| ascii_text(rexif::ExifTag::Make as u16, make)),
+    );
+    entries.extend(
+        data.camera_model
+            .as_deref()
+            .and_then(|model| ascii_text(rexif::ExifTag::Model as u16, model)),
+    );
+    // Many viewers (e.g. macOS' Preview) show ImageDescription rather than UserComment, whatever
+    // the profile. Descriptions which aren't ASCII are left to UserComment (and XPComment), which
+    // can hold them.
+    entries.extend(
+        item.description
+            .as_deref()
+            .and_then(|description| ascii_text(IMAGE_DESCRIPTION, truncate(description, MAX_TEXT))),
+    );
+    // Windows Explorer only shows its own tags
+    if let Some(MetadataProfile::Windows) = opts.metadata_profile {
+        if !comment.is_empty() {
+            entries.push(Entry {
+                tag: XP_COMMENT,
+                data: EntryData::Byte(utf16(comment)),
+            });
+        }
+        let keywords = item
+            .album
+            .iter()
+            .chain(item.tags.iter().map(|tag| &tag.name))
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        if !keywords.is_empty() {
+            entries.push(Entry {
+                tag: XP_KEYWORDS,
+                data: EntryData::Byte(utf16(&keywords.join(";"))),
+            });
+        }
+    }
+    if let Some(orientation) = data.orientation.filter(|o| (1.0..=8.0).contains(o)) {
+        entries.push(Entry {
+            tag: tag::ORIENTATION,
+            data: EntryData::UShort(vec![orientation as u16]),
+        });
+    }
+
+    // The tags which belong in the Exif directory rather than the primary one, where strict
+    // readers (e.g. Lightroom) won't look for them. The dates have no time zone of their own, so
+    // it's given separately, which needs version 2.31 or later.
+    let mut camera = vec![
+        Entry {
+            tag: EXIF_VERSION,
+            data: EntryData::Undef(b"0232".to_vec()),
+        },
+        Entry {
+            tag: rexif::ExifTag::DateTimeOriginal as u16,
+            data: EntryData::Ascii(timestamp.format("%Y:%m:%d %H:%M:%S").to_string()),
+        },
+        Entry {
+            tag: rexif::ExifTag::UserComment as u16,
+            data: EntryData::Undef(user_comment(comment)),
+        },
+        Entry {
+            tag: OFFSET_TIME_ORIGINAL,
+            data: EntryData::Ascii(timestamp.format("%:z").to_string()),
+        },
+        Entry {
+            tag: OFFSET_TIME,
+            data: EntryData::Ascii(timestamp.format("%:z").to_string()),
+        },
+    ];
+    if let Some(exposure) = data.exposure.filter(|e| *e > 0.0) {
+        camera.push(Entry {
+            tag: EXPOSURE_TIME,
+            data: EntryData::URational(vec![approximate(exposure)]),
+        });
+    }
+    if let Some(f_stop) = data.f_stop.filter(|f| *f > 0.0) {
+        camera.push(Entry {
+            tag: F_NUMBER,
+            data: EntryData::URational(vec![approximate(f_stop)]),
+        });
+    }
+    if let Some(iso) = data.iso.filter(|i| *i > 0.0) {
+        camera.push(Entry {
+            tag: ISO_SPEED,
+            data: EntryData::UShort(vec![iso.min(f64::from(u16::MAX)) as u16]),
+        });
+    }
+    if let Some(focal_length) = data.focal_length.filter(|f| *f > 0.0) {
+        camera.push(Entry {
+            tag: FOCAL_LENGTH,
+            data: EntryData::URational(vec![approximate(focal_length)]),
+        });
+    }
+
+    let mut children = vec![Ifd {
+        id: tag::EXIF_IFD_POINTER,
+        entries: camera,
+        children: Vec::new(),
+    }];
+    if let Some(gps) = gps(item) {
+        children.push(gps);
+    }
+
+    let mut exif = match existing.map(decode) {
+        Some(Ok(exif)) => exif,
+        Some(Err(err)) => {
+            warn!(
+                "Discarding unreadable EXIF in {}: {:#}",
+                item.path.display(),
+                err
+            );
+            empty()
+        }
+        None => empty(),
+    };
+    // The credits are only added where the original (e.g. the camera) didn't already give them.
+    // Names which aren't ASCII are left to the XMP (dc:creator and dc:rights).
+    let (artist, owned) = item.artist(opts.artist.as_deref());
+    let mut credits = Vec::new();
+    if let Some(artist) = artist {
+        credits.extend(ascii_text(ARTIST, artist));
+        if owned {
+            credits.extend(ascii_text(
+                COPYRIGHT,
+                &format!("Copyright {} {}", timestamp.format("%Y"), artist),
+            ));
+        }
+    }
+    entries.extend(
+        credits
+            .into_iter()
+            .filter(|credit| find_text(&exif.ifds, credit.tag, false).is_none()),
+    );
+    merge(&mut exif, entries, children);
+
+    trace!("Writing metadata for {}: {:#?}", item.path.display(), exif);
+    Ok(write(&exif))
+}
+
+// UserComment begins with the code of its character set. Pure ASCII is written as that, and
+// anything else as UTF-16 in the byte order of the EXIF, which is always written little-endian.
+fn user_comment(text: &str) -> Vec<u8> {
+    if text.is_ascii() {
+        [b"ASCII\0\0\0".as_slice(), text.as_bytes()].concat()
+    } else {
+        b"UNICODE\0"
+            .iter()
+            .copied()
+            .chain(text.encode_utf16().flat_map(u16::to_le_bytes))
+            .collect()
+    }
+}
+
+// The text as Windows' XP tags hold it: UTF-16, little-endian, and terminated by a null.
+fn utf16(text: &str) -> Vec<u8> {
+    text.encode_utf16()
+        .chain([0])
+        .flat_map(u16::to_le_bytes)
+        .collect()
+}
+
+// An ASCII tag holding the text, unless the text isn't ASCII, which strict readers would reject.
+fn ascii_text(tag: u16, text: &str) -> Option<Entry> {
+    if !text.is_ascii() {
+        trace!("Leaving out tag {:#x}, since {:?} isn't ASCII", tag, text);
+        return None;
+    }
+    Some(Entry {
+        tag,
+        data: EntryData::Ascii(text.to_string()),
+    })
+}
+
+// Marks the photo as upright, once it's been rotated to match its orientation.
+pub fn set_upright(raw: &[u8]) -> Result<Vec<u8>> {
+    let mut exif = decode(raw)?;
+    merge(
+        &mut exif,
+        vec![Entry {
+            tag: tag::ORIENTATION,
+            data: EntryData::UShort(vec![1]),
+        }],
+        Vec::new(),
+    );
+    Ok(write(&exif))
+}
+
+// The text of the tag, wherever it is in the EXIF. Undefined values (e.g. UserComment) are read as
+// text too, without their character code.
+pub fn read_text(raw: &[u8], tag: u16) -> Result<Option<String>> {
+    Ok(find_text(&decode(raw)?.ifds, tag, raw.starts_with(b"MM")))
+}
+
+fn find_text(ifds: &[Ifd], tag: u16, big_endian: bool) -> Option<String> {
+    for ifd in ifds {
+        for entry in ifd.entries.iter().filter(|entry| entry.tag == tag) {
+            match &entry.data {
+                EntryData::Ascii(text) => return Some(text.trim_end_matches('\0').to_string()),
+                EntryData::Undef(bytes) => {
+                    if let Some(text) = bytes.strip_prefix(b"UNICODE\0") {
+                        let units = text
+                            .chunks_exact(2)
+                            .map(|pair| match big_endian {
+                                true => u16::from_be_bytes([pair[0], pair[1]]),
+                                false => u16::from_le_bytes([pair[0], pair[1]]),
+                            })
+                            .collect::<Vec<_>>();
+                        return Some(
+                            String::from_utf16_lossy(&units)
+                                .trim_end_matches('\0')
+                                .to_string(),
+                        );
+                    }
+                    let text = bytes
+                        .strip_prefix(b"ASCII\0\0\0")
+                        .or_else(|| bytes.strip_prefix(&[0; 8]))
+                        .unwrap_or(bytes);
+                    return Some(
+                        String::from_utf8_lossy(text)
+                            .trim_end_matches('\0')
+                            .to_string(),
+                    );
+                }
+                _ => {}
+            }
+        }
+        if let Some(text) = find_text(&ifd.children, tag, big_endian) {
+            return Some(text);
+        }
+    }
+    None
+}
+
+fn empty() -> Exif {
+    Exif {
+        ifds: vec![Ifd {
+            id: 0,
+            entries: Vec::new(),
+            children: Vec::new(),
+        }],
+    }
+}
+
+fn decode(raw: &[u8]) -> Result<Exif> {
+    let mut exif = Exif::new(&mut Cursor::new(raw)).context("exif decode")?;
+
+    // Only the primary image's directory is kept. The following one describes the thumbnail,
+    // whose data is referenced by offset and isn't carried over by the decoder.
+    exif.ifds.truncate(1);
+
+    // Maker notes are full of offsets into the original encoding and are corrupted by moving them.
+    for ifd in exif.ifds.iter_mut() {
+        remove(ifd, &[MAKER_NOTE]);
+    }
+
+    // The decoder reads each rational as one integer in the file's byte order but they are always
+    // written little-endian, which would swap the numerator and denominator of big-endian files.
+    if raw.starts_with(b"MM") {
+        for ifd in exif.ifds.iter_mut() {
+            swap_rationals(ifd);
+        }
+    }
+
+    Ok(exif)
+}
+
+// Adds the entries and child directories (e.g. Exif, GPS) to the primary directory, replacing
+// any existing entries with the same tags in the same directory. Tags only mean something within
+// their own directory (e.g. 0x0001 is GPSLatitudeRef in GPS but InteroperabilityIndex in Interop).
+fn merge(exif: &mut Exif, entries: Vec<Entry>, children: Vec<Ifd>) {
+    let primary = &mut exif.ifds[0];
+    replace(&mut primary.entries, entries);
+    for child in children {
+        match primary
+            .children
+            .iter_mut()
+            .find(|existing| existing.id == child.id)
+        {
+            Some(existing) => replace(&mut existing.entries, child.entries),
+            None => primary.children.push(child),
+        }
+    }
+}
+
+fn replace(existing: &mut Vec<Entry>, entries: Vec<Entry>) {
+    existing.retain(|entry| !entries.iter().any(|new| new.tag == entry.tag));
+    existing.extend(entries);
+}
+
+fn gps(item: &Item) -> Option<Ifd> {
+    let location = item.location()?;
+    let (latitude, longitude) = (location.latitude, location.longitude);
+
+    let mut entries = vec![
+        Entry {
+            tag: 0x0000, // GPSVersionID
+            data: EntryData::Byte(vec![2, 3, 0, 0]),
+        },
+        Entry {
+            tag: tag::gps::LATITUDE_REF,
+            data: EntryData::Ascii(if latitude < 0.0 { "S" } else { "N" }.to_string()),
+        },
+        Entry {
+            tag: tag::gps::LATITUDE,
+            data: EntryData::URational(degrees(latitude)),
+        },
+        Entry {
+            tag: tag::gps::LONGITUDE_REF,
+            data: EntryData::Ascii(if longitude < 0.0 { "W" } else { "E" }.to_string()),
+        },
+        Entry {
+            tag: tag::gps::LONGITUDE,
+            data: EntryData::URational(degrees(longitude)),
+        },
+    ];
+    // Approximate locations are marked with the place they came from, in the character code
+    // prefix that GPSAreaInformation needs
+    if let (true, Some(place)) = (location.approximate, &item.place) {
+        entries.push(Entry {
+            tag: GPS_AREA_INFORMATION,
+            data: EntryData::Undef(
+                [
+                    &b"ASCII\0\0\0"[..],
+                    format!("Approximate location of {}", place.name).as_bytes(),
+                ]
+                .concat(),
+            ),
+        });
+    }
+
+    Some(Ifd {
+        id: tag::GPS_INFO_IFD_POINTER,
+        entries,
+        children: Vec::new(),
+    })
+}
+
+// Splits the coordinate into degrees, minutes, and seconds (to a thousandth).
+fn degrees(coordinate: f64) -> Vec<u64> {
+    let coordinate = coordinate.abs();
+    let degrees = coordinate.trunc();
+    let minutes = ((coordinate - degrees) * 60.0).trunc();
+    let seconds = (coordinate - degrees - minutes / 60.0) * 3600.0;
+    vec![
+        rational(degrees as u32, 1),
+        rational(minutes as u32, 1),
+        rational((seconds * 1000.0).round() as u32, 1000),
+    ]
+}
+
+// Approximates the value as a rational, preferring the 1/x form used for exposure times.
+fn approximate(value: f64) -> u64 {
+    if value < 1.0 {
+        let denominator = (1.0 / value).round();
+        if ((1.0 / denominator) - value).abs() < value * 0.01 {
+            return rational(1, denominator as u32);
+        }
+        return rational((value * 10_000.0).round() as u32, 10_000);
+    }
+    rational((value * 1000.0).round() as u32, 1000)
+}
+
+// Rationals are stored as a single little-endian integer, so the numerator goes in the low half.
+fn rational(numerator: u32, denominator: u32) -> u64 {
+    u64::from(denominator) << 32 | u64::from(numerator)
+}
+
+fn remove(ifd: &mut Ifd, tags: &[u16]) {
+    ifd.entries.retain(|entry| !tags.contains(&entry.tag));
+    for child in ifd.children.iter_mut() {
+        remove(child, tags);
+    }
+}
+
+fn swap_rationals(ifd: &mut Ifd) {
+    for entry in ifd.entries.iter_mut() {
+        match &mut entry.data {
+            EntryData::URational(values) => {
+                values.iter_mut().for_each(|v| *v = v.rotate_left(32));
+            }
+            EntryData::SRational(values) => {
+                values
+                    .iter_mut()
+                    .for_each(|v| *v = (*v as u64).rotate_left(32) as i64);
+            }
+            _ => {}
+        }
+    }
+    for child in ifd.children.iter_mut() {
+        swap_rationals(child);
+    }
+}
+
+// Encodes the EXIF as little-endian TIFF. The encoder in imagemeta points every child directory
+// at the same offset (so an image can't have both Exif and GPS directories) and doesn't sort the
+// entries, so the layout is done here instead.
+fn write(exif: &Exif) -> Vec<u8> {
+    let mut out = b"II\x2a\x00\x08\x00\x00\x00".to_vec();
+    let mut next = 4;
+    for ifd in &exif.ifds {
+        let offset = out.len() as u32;
+        out[next..next + 4].copy_from_slice(&offset.to_le_bytes());
+        next = write_ifd(&mut out, ifd);
+    }
+    out
+}
+
+// Appends the directory, followed by its data and child directories, returning the position of
+// its pointer to the next directory.
+fn write_ifd(out: &mut Vec<u8>, ifd: &Ifd) -> usize {
+    enum Field<'a> {
+        Entry(&'a Entry),
+        Child(&'a Ifd),
+    }
+
+    let mut fields = ifd
+        .entries
+        .iter()
+        .map(Field::Entry)
+        .chain(ifd.children.iter().map(Field::Child))
+        .collect::<Vec<_>>();
+    fields.sort_by_key(|field| match field {
+        Field::Entry(entry) => entry.tag,
+        Field::Child(child) => child.id,
+    });
+
+    let start = out.len();
+    let next = start + 2 + fields.len() * 12;
+    out.extend_from_slice(&(fields.len() as u16).to_le_bytes());
+    out.resize(next + 4, 0);
+
+    let mut children = Vec::new();
+    for (i, field) in fields.iter().enumerate() {
+        let header = start + 2 + i * 12;
+        match field {
+            Field::Entry(entry) => {
+                let (format, count, data) = encode_data(&entry.data);
+                out[header..header + 2].copy_from_slice(&entry.tag.to_le_bytes());
+                out[header + 2..header + 4].copy_from_slice(&format.to_le_bytes());
+                out[header + 4..header + 8].copy_from_slice(&count.to_le_bytes());
+                if data.len() <= 4 {
+                    out[header + 8..header + 8 + data.len()].copy_from_slice(&data);
+                } else {
+                    let offset = out.len() as u32;
+                    out[header + 8..header + 12].copy_from_slice(&offset.to_le_bytes());
+                    out.extend(data);
+                    // Offsets must be word-aligned
+                    if out.len() % 2 == 1 {
+                        out.push(0);
+                    }
+                }
+            }
+            Field::Child(child) => {
+                out[header..header + 2].copy_from_slice(&child.id.to_le_bytes());
+                out[header + 2..header + 4].copy_from_slice(&4u16.to_le_bytes());
+                out[header + 4..header + 8].copy_from_slice(&1u32.to_le_bytes());
+                children.push((header + 8, *child));
+            }
+        }
+    }
+
+    for (pointer, child) in children {
+        let offset = out.len() as u32;
+        out[pointer..pointer + 4].copy_from_slice(&offset.to_le_bytes());
+        write_ifd(out, child);
+    }
+
+    next
+}
+
+// Returns the TIFF format code, the number of values, and their little-endian encoding.
+fn encode_data(data: &EntryData) -> (u16, u32, Vec<u8>) {
+    fn flatten<T, const N: usize>(values: &[T], encode: fn(&T) -> [u8; N]) -> Vec<u8> {
+        values.iter().flat_map(encode).collect()
+    }
+
+    match data {
+        EntryData::Byte(v) => (1, v.len() as u32, v.clone()),
+        EntryData::Ascii(v) => {
+            let mut bytes = v.as_bytes().to_vec();
+            bytes.push(0);
+            (2, bytes.len() as u32, bytes)
+        }
+        EntryData::UShort(v) => (3, v.len() as u32, flatten(v, |x| x.to_le_bytes())),
+        EntryData::ULong(v) => (4, v.len() as u32, flatten(v, |x| x.to_le_bytes())),
+        EntryData::URational(v) => (5, v.len() as u32, flatten(v, |x| x.to_le_bytes())),
+        EntryData::SignedByte(v) => (6, v.len() as u32, flatten(v, |x| x.to_le_bytes())),
+        EntryData::Undef(v) => (7, v.len() as u32, v.clone()),
+        EntryData::SShort(v) => (8, v.len() as u32, flatten(v, |x| x.to_le_bytes())),
+        EntryData::SLong(v) => (9, v.len() as u32, flatten(v, |x| x.to_le_bytes())),
+        EntryData::SRational(v) => (10, v.len() as u32, flatten(v, |x| x.to_le_bytes())),
+        EntryData::Float32(v) => (11, v.len() as u32, flatten(v, |x| x.to_le_bytes())),
+        EntryData::Float64(v) => (12, v.len() as u32, flatten(v, |x| x.to_le_bytes())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Comment, Coordinate, Place};
+    use chrono::NaiveDate;
+    use std::path::PathBuf;
+    use structopt::StructOpt;
+
+    const INTEROPERABILITY_INDEX: u16 = 0x0001;
+    const INTEROPERABILITY_VERSION: u16 = 0x0002;
+    const GPS_DATE_STAMP: u16 = 0x001d;
+
+    fn ascii(tag: u16, text: &str) -> Entry {
+        Entry {
+            tag,
+            data: EntryData::Ascii(text.to_string()),
+        }
+    }
+
+    fn child(ifd: &Ifd, id: u16) -> &Ifd {
+        ifd.children
+            .iter()
+            .find(|child| child.id == id)
+            .unwrap_or_else(|| panic!("no directory {:#x}", id))
+    }
+
+    fn text(ifd: &Ifd, tag: u16) -> Option<String> {
+        ifd.entries
+            .iter()
+            .find(|entry| entry.tag == tag)
+            .map(|entry| match &entry.data {
+                EntryData::Ascii(text) => text.trim_end_matches('\0').to_string(),
+                EntryData::Undef(bytes) => String::from_utf8_lossy(bytes).to_string(),
+                data => panic!("unexpected {:?}", data),
+            })
+    }
+
+    // A camera's EXIF, with Exif, Interop (within Exif), and GPS directories
+    fn camera() -> Exif {
+        Exif {
+            ifds: vec![Ifd {
+                id: 0,
+                entries: vec![ascii(rexif::ExifTag::Make as u16, "Canon")],
+                children: vec![
+                    Ifd {
+                        id: tag::EXIF_IFD_POINTER,
+                        entries: vec![ascii(
+                            rexif::ExifTag::DateTimeOriginal as u16,
+                            "2014:07:04 13:42:00",
+                        )],
+                        children: vec![Ifd {
+                            id: tag::INTEROPERABILITY_IFD_POINTER,
+                            entries: vec![
+                                ascii(INTEROPERABILITY_INDEX, "R98"),
+                                Entry {
+                                    tag: INTEROPERABILITY_VERSION,
+                                    data: EntryData::Undef(b"0100".to_vec()),
+                                },
+                            ],
+                            children: Vec::new(),
+                        }],
+                    },
+                    Ifd {
+                        id: tag::GPS_INFO_IFD_POINTER,
+                        entries: vec![
+                            ascii(tag::gps::LATITUDE_REF, "S"),
+                            ascii(GPS_DATE_STAMP, "2014:07:04"),
+                        ],
+                        children: Vec::new(),
+                    },
+                ],
+            }],
+        }
+    }
+
+    #[test]
+    fn merge_keeps_interop() {
+        let mut exif = camera();
+        merge(
+            &mut exif,
+            vec![ascii(rexif::ExifTag::Make as u16, "Nikon")],
+            vec![Ifd {
+                id: tag::GPS_INFO_IFD_POINTER,
+                entries: vec![ascii(tag::gps::LATITUDE_REF, "N")],
+                children: Vec::new(),
+            }],
+        );
+
+        let primary = &exif.ifds[0];
+        assert_eq!(
+            text(primary, rexif::ExifTag::Make as u16).as_deref(),
+            Some("Nikon")
+        );
+        let gps = child(primary, tag::GPS_INFO_IFD_POINTER);
+        assert_eq!(text(gps, tag::gps::LATITUDE_REF).as_deref(), Some("N"));
+        assert_eq!(text(gps, GPS_DATE_STAMP).as_deref(), Some("2014:07:04"));
+
+        let interop = child(
+            child(primary, tag::EXIF_IFD_POINTER),
+            tag::INTEROPERABILITY_IFD_POINTER,
+        );
+        assert_eq!(
+            text(interop, INTEROPERABILITY_INDEX).as_deref(),
+            Some("R98")
+        );
+        assert_eq!(
+            text(interop, INTEROPERABILITY_VERSION).as_deref(),
+            Some("0100")
+        );
+    }
+
+    #[test]
+    fn encode_gps_into_camera_exif() {
+        let mut item = Item::new(
+            PathBuf::from("photos/1.jpg"),
+            NaiveDate::from_ymd_opt(2014, 7, 4)
+                .and_then(|date| date.and_hms_opt(13, 42, 0))
+                .unwrap(),
+        );
+        item.place = Some(Place {
+            name: "Golden Gate Park".to_string(),
+            coordinate: Some(Coordinate {
+                latitude: 37.769,
+                longitude: -122.486,
+            }),
+        });
+        let opts = Options::from_iter(&["fb-unarchive"]);
+
+        let encoded = encode(&item, Some(&write(&camera())), &opts).unwrap();
+        let exif = decode(&encoded).unwrap();
+
+        let primary = &exif.ifds[0];
+        assert_eq!(
+            text(primary, rexif::ExifTag::Make as u16).as_deref(),
+            Some("Canon")
+        );
+        let gps = child(primary, tag::GPS_INFO_IFD_POINTER);
+        assert_eq!(text(gps, tag::gps::LATITUDE_REF).as_deref(), Some("N"));
+        assert_eq!(text(gps, tag::gps::LONGITUDE_REF).as_deref(), Some("W"));
+        assert_eq!(text(gps, GPS_DATE_STAMP).as_deref(), Some("2014:07:04"));
+
+        let camera = child(primary, tag::EXIF_IFD_POINTER);
+        assert!(text(camera, rexif::ExifTag::DateTimeOriginal as u16).is_some());
+        let interop = child(camera, tag::INTEROPERABILITY_IFD_POINTER);
+        assert_eq!(
+            text(interop, INTEROPERABILITY_INDEX).as_deref(),
+            Some("R98")
+        );
+        assert_eq!(
+            text(interop, INTEROPERABILITY_VERSION).as_deref(),
+            Some("0100")
+        );
+    }
+
+    #[test]
+    fn encode_description_only_as_ascii() {
+        let mut item = Item::new(PathBuf::from("photos/1.jpg"), Default::default());
+        let opts = Options::from_iter(&["fb-unarchive"]);
+        let description = |item: &Item| {
+            let exif = decode(&encode(item, None, &opts).unwrap()).unwrap();
+            let primary = &exif.ifds[0];
+            let comment = text(
+                child(primary, tag::EXIF_IFD_POINTER),
+                rexif::ExifTag::UserComment as u16,
+            );
+            (
+                text(primary, IMAGE_DESCRIPTION),
+                comment.unwrap_or_default(),
+            )
+        };
+
+        item.description = Some("Beach day".to_string());
+        let (image_description, comment) = description(&item);
+        assert_eq!(image_description.as_deref(), Some("Beach day"));
+        assert!(comment.contains("Beach day"));
+
+        item.description = Some("Café au lait".to_string());
+        let (image_description, comment) = description(&item);
+        assert_eq!(image_description, None);
+        assert!(!comment.is_empty());
+    }
+
+    #[test]
+    fn encode_long_comments_within_a_segment() {
+        let mut item = Item::new(PathBuf::from("photos/1.jpg"), Default::default());
+        item.description = Some("Été à la plage ".repeat(500));
+        item.comments = (0..1000)
+            .map(|_| Comment {
+                timestamp: Default::default(),
+                comment: Some("Très belle photo ! 😂".to_string()),
+                author: "Zoë".to_string(),
+            })
+            .collect();
+        let opts = Options::from_iter(&["fb-unarchive", "--metadata-profile", "windows"]);
+
+        // The segment holds the "Exif\0\0" prefix as well
+        let encoded = encode(&item, None, &opts).unwrap();
+        assert!(encoded.len() + 6 <= 65533, "{} bytes", encoded.len());
+        assert!(crate::xmp::embedded_packet(&item, &opts).len() <= 65533);
+    }
+}
@@ -0,0 +1,100 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use anyhow::{anyhow, Context, Result};
+use log::trace;
+use std::io::{self, ErrorKind, Read};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+// Converts the video read from `input` into an MP4 at `output` using ffmpeg. Unless `transcode` is
+// set, the streams are only remuxed, which is fast and lossless but fails for codecs that the MP4
+// container doesn't support (e.g. Sorenson Spark, which is common in old FLVs).
+pub fn to_mp4(input: &mut dyn Read, output: &Path, transcode: bool) -> Result<()> {
+    let mut command = Command::new("ffmpeg");
+    command.args(["-hide_banner", "-loglevel", "error", "-y", "-i", "pipe:0"]);
+    if transcode {
+        command.args(["-c:v", "libx264", "-c:a", "aac"]);
+    } else {
+        command.args(["-c", "copy"]);
+    }
+    command.args(["-f", "mp4"]).arg(output);
+    trace!("Running {:?}", command);
+
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("run ffmpeg")?;
+
+    let mut stdin = child.stdin.take().context("ffmpeg stdin")?;
+    match io::copy(input, &mut stdin) {
+        // ffmpeg stops reading when it fails; the reason is reported below
+        Err(err) if err.kind() == ErrorKind::BrokenPipe => {}
+        result => {
+            result.context("write to ffmpeg")?;
+        }
+    }
+    drop(stdin);
+
+    let result = child.wait_with_output().context("wait for ffmpeg")?;
+    if !result.status.success() {
+        return Err(anyhow!(
+            "ffmpeg failed ({}): {}",
+            result.status,
+            String::from_utf8_lossy(&result.stderr).trim()
+        ));
+    }
+
+    Ok(())
+}
+
+// Writes a representative frame from the video at `input` to `output` as a JPEG, scaled down to
+// fit within `size` on either side.
+pub fn poster(input: &Path, output: &Path, size: u32) -> Result<()> {
+    let mut command = Command::new("ffmpeg");
+    command
+        .args(["-hide_banner", "-loglevel", "error", "-y", "-i"])
+        .arg(input)
+        .args([
+            "-vf",
+            &format!(
+                "thumbnail,scale={0}:{0}:force_original_aspect_ratio=decrease",
+                size
+            ),
+            "-frames:v",
+            "1",
+            "-f",
+            "image2",
+        ])
+        .arg(output);
+    trace!("Running {:?}", command);
+
+    let result = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .context("run ffmpeg")?;
+    if !result.status.success() {
+        return Err(anyhow!(
+            "ffmpeg failed ({}): {}",
+            result.status,
+            String::from_utf8_lossy(&result.stderr).trim()
+        ));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,299 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// A static HTML gallery of the output, which can be browsed without any other software. The index
+// (".fb-unarchive-gallery/index.html" in the output directory) lists the albums, each album has a
+// page of its photos and videos, and each of those has a page of its own with its description,
+// people, and comments. Everything is kept in a directory of its own, which no album can be named.
+// The pages link to the output files rather than copying them, so the gallery has to stay
+// alongside them. The grids show thumbnails, either those written with `--thumbnails` or the
+// gallery's own, rather than the full-size files.
+//
+// Each run only knows about the items it handled, so the records of earlier runs are kept with the
+// gallery and merged with the new ones, and the gallery is rebuilt from all of them.
+
+use crate::output::Record;
+use crate::remote::encode;
+use crate::thumbnail;
+use anyhow::{Context, Result};
+use log::debug;
+use rayon::prelude::*;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, ErrorKind};
+use std::path::{Path, PathBuf};
+
+pub const DIR: &str = ".fb-unarchive-gallery";
+
+// The records of every item in the gallery, with paths relative to the output directory
+const RECORDS_FILE: &str = "records.json";
+
+// The size of the gallery's own thumbnails, which is twice that of the grid for sharp displays
+const THUMBNAIL_SIZE: u32 = 400;
+
+const STYLE: &str = "body{font-family:sans-serif;margin:2em;background:#fafafa;color:#222}\
+a{color:#1a5fb4;text-decoration:none}\
+.grid{display:flex;flex-wrap:wrap;gap:8px}\
+.grid a{display:block;width:200px;text-align:center}\
+.grid img,.grid video,.grid audio{width:200px;height:200px;object-fit:cover;background:#ddd}\
+.media img,.media video{max-width:100%;max-height:80vh}\
+.comment{margin:.5em 0}.meta{color:#666}";
+
+// The link to the file, from a page `prefix` away from the output directory.
+fn src(path: &Path, root: &Path, prefix: &str) -> String {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    format!(
+        "{}{}",
+        prefix,
+        relative
+            .components()
+            .map(|part| encode(&part.as_os_str().to_string_lossy()))
+            .collect::<Vec<_>>()
+            .join("/")
+    )
+}
+
+// The element showing the output file, from a page `prefix` away from the output directory.
+// Previews in a grid leave out the controls, so that clicking them follows their link, and show
+// the thumbnail if there is one.
+fn media(record: &Record, root: &Path, prefix: &str, preview: bool) -> String {
+    if let (Some(thumbnail), true) = (&record.thumbnail, preview) {
+        return format!(
+            r#"<img src="{}" loading="lazy" alt="">"#,
+            src(thumbnail, root, prefix)
+        );
+    }
+    let src = src(&record.output, root, prefix);
+    let controls = if preview { "" } else { " controls" };
+    match record.output.extension().and_then(|x| x.to_str()) {
+        Some("mp4") | Some("flv") => format!(
+            r#"<video src="{}"{} preload="metadata"></video>"#,
+            src, controls
+        ),
+        Some("aac") | Some("m4a") | Some("mp3") => {
+            format!(r#"<audio src="{}"{}></audio>"#, src, controls)
+        }
+        _ => format!(r#"<img src="{}" loading="lazy" alt="">"#, src),
+    }
+}
+
+// Writes the gallery for the records along with those of earlier runs, replacing the pages left
+// over from them.
+pub fn write(root: &Path, records: &[Record]) -> Result<()> {
+    let dir = root.join(DIR);
+    fs::create_dir_all(&dir).context(format!("create directory {}", dir.display()))?;
+    let mut records = merge(root, &dir, records)?;
+    thumbnails(root, &dir, &mut records);
+
+    // The pages are numbered, so those of albums which no longer exist have to go
+    for entry in fs::read_dir(&dir).context(format!("list directory {}", dir.display()))? {
+        let entry = entry.context("entry")?;
+        let numbered = entry.file_name().to_string_lossy().parse::<usize>().is_ok();
+        if numbered && entry.file_type().context("file type")?.is_dir() {
+            fs::remove_dir_all(entry.path())
+                .context(format!("remove directory {}", entry.path().display()))?;
+        }
+    }
+
+    records.sort_by(|a, b| {
+        (&a.album, a.timestamp, &a.output).cmp(&(&b.album, b.timestamp, &b.output))
+    });
+    let mut albums: Vec<(&str, Vec<&Record>)> = Vec::new();
+    for record in records.iter() {
+        let name = record.album.as_deref().unwrap_or("Videos");
+        match albums.last_mut() {
+            Some((album, records)) if *album == name => records.push(record),
+            _ => albums.push((name, vec![record])),
+        }
+    }
+
+    let mut index = String::new();
+    for (number, (name, records)) in albums.iter().enumerate() {
+        let album_dir = dir.join((number + 1).to_string());
+        fs::create_dir_all(&album_dir)
+            .context(format!("create directory {}", album_dir.display()))?;
+
+        let mut grid = String::new();
+        for (n, record) in records.iter().enumerate() {
+            grid.push_str(&format!(
+                r#"<a href="{}.html">{}</a>"#,
+                n + 1,
+                media(record, root, "../../", true)
+            ));
+            write_page(&album_dir, name, records, n, root)?;
+        }
+        write_file(
+            &album_dir.join("index.html"),
+            name,
+            &format!(
+                r#"<p><a href="../index.html">Albums</a></p><h1>{}</h1><div class="grid">{}</div>"#,
+                escape(name),
+                grid
+            ),
+        )?;
+
+        index.push_str(&format!(
+            r#"<a href="{}/index.html">{}<br>{} ({})</a>"#,
+            number + 1,
+            media(records[0], root, "../", true),
+            escape(name),
+            records.len()
+        ));
+    }
+
+    write_file(
+        &dir.join("index.html"),
+        "Albums",
+        &format!(r#"<h1>Albums</h1><div class="grid">{}</div>"#, index),
+    )
+}
+
+// The records of this run along with those of earlier runs whose files are still there, which are
+// saved for the next run.
+fn merge(root: &Path, dir: &Path, records: &[Record]) -> Result<Vec<Record>> {
+    let path = dir.join(RECORDS_FILE);
+    let previous: Vec<Record> = match File::open(&path) {
+        Ok(file) => serde_json::from_reader(BufReader::new(file))
+            .context(format!("parse {}", path.display()))?,
+        Err(err) if err.kind() == ErrorKind::NotFound => Vec::new(),
+        Err(err) => return Err(err).context(format!("open {}", path.display())),
+    };
+
+    let current = records
+        .iter()
+        .map(|record| record.output.as_path())
+        .collect::<HashSet<_>>();
+    let mut merged = records.to_vec();
+    merged.extend(
+        previous
+            .into_iter()
+            .map(|record| relocate(record, |path| root.join(path)))
+            .filter(|record| !current.contains(record.output.as_path()) && record.output.exists()),
+    );
+
+    let saved = merged
+        .iter()
+        .cloned()
+        .map(|record| {
+            relocate(record, |path| {
+                path.strip_prefix(root).unwrap_or(path).to_path_buf()
+            })
+        })
+        .collect::<Vec<_>>();
+    serde_json::to_writer(
+        BufWriter::new(File::create(&path).context(format!("create {}", path.display()))?),
+        &saved,
+    )
+    .context(format!("write {}", path.display()))?;
+    Ok(merged)
+}
+
+// The record with its paths changed, to and from being relative to the output directory.
+fn relocate<F: Fn(&Path) -> PathBuf>(mut record: Record, f: F) -> Record {
+    record.output = f(&record.output);
+    record.thumbnail = record.thumbnail.as_deref().map(&f);
+    record
+}
+
+// Gives the records without a thumbnail (or whose thumbnail has gone) the gallery's own, writing
+// those which are missing or older than their files. Those which can't be written are shown at
+// full size instead.
+fn thumbnails(root: &Path, dir: &Path, records: &mut [Record]) {
+    let thumbnails = dir.join(thumbnail::DIR);
+    records.par_iter_mut().for_each(|record| {
+        if record.thumbnail.as_deref().is_some_and(Path::exists) {
+            return;
+        }
+        let path = thumbnail::path_within(&thumbnails, root, &record.output);
+        let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+        if modified(&path).is_some() && modified(&path) >= modified(&record.output) {
+            record.thumbnail = Some(path);
+            return;
+        }
+        match thumbnail::write_at(path, &record.output, THUMBNAIL_SIZE) {
+            Ok(thumbnail) => record.thumbnail = thumbnail,
+            Err(err) => {
+                debug!(
+                    "Couldn't write the thumbnail of {}: {:#}",
+                    record.output.display(),
+                    err
+                );
+                record.thumbnail = None;
+            }
+        }
+    });
+}
+
+fn write_page(
+    album_dir: &Path,
+    album: &str,
+    records: &[&Record],
+    n: usize,
+    root: &Path,
+) -> Result<()> {
+    let record = records[n];
+    let mut nav = r#"<a href="index.html">"#.to_string() + &escape(album) + "</a>";
+    if n > 0 {
+        nav.push_str(&format!(r#" · <a href="{}.html">Previous</a>"#, n));
+    }
+    if n + 1 < records.len() {
+        nav.push_str(&format!(r#" · <a href="{}.html">Next</a>"#, n + 2));
+    }
+
+    let mut body = format!(
+        r#"<p>{}</p><div class="media">{}</div><p class="meta">{}</p>"#,
+        nav,
+        media(record, root, "../../", false),
+        record.timestamp.format("%B %-d, %Y %-I:%M %p")
+    );
+    if let Some(description) = &record.description {
+        body.push_str(&format!("<p>{}</p>", escape(description)));
+    }
+    if !record.people.is_empty() {
+        body.push_str(&format!(
+            r#"<p class="meta">With {}</p>"#,
+            escape(&record.people.join(", "))
+        ));
+    }
+    for (author, text, timestamp) in &record.comments {
+        body.push_str(&format!(
+            r#"<div class="comment"><b>{}</b> {}<br><span class="meta">{}</span></div>"#,
+            escape(author),
+            escape(text),
+            timestamp.format("%B %-d, %Y %-I:%M %p")
+        ));
+    }
+
+    write_file(&album_dir.join(format!("{}.html", n + 1)), album, &body)
+}
+
+fn write_file(path: &Path, title: &str, body: &str) -> Result<()> {
+    fs::write(
+        path,
+        format!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{}</title><style>{}</style></head><body>{}</body></html>\n",
+            escape(title),
+            STYLE,
+            body
+        ),
+    )
+    .context(format!("write {}", path.display()))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
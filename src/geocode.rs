@@ -0,0 +1,99 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Resolves the names of the places that photos were tagged with (e.g. "Golden Gate Park") into
+// coordinates, for those without a location of their own. The built-in geocoder looks them up in
+// a gazetteer given by `--gazetteer`, so nothing is sent anywhere, but others can be set by users
+// of the library. Like the time zone, it's kept with the archive.
+
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+pub trait Geocoder: Send + Sync {
+    // The latitude and longitude of the named place, if it's known.
+    fn locate(&self, place: &str) -> Option<(f64, f64)>;
+}
+
+// Places and their coordinates, read from a file with a line for each, either as
+// "name,latitude,longitude" or in the tab-separated format of GeoNames' dumps (which also lists
+// other names and details).
+pub struct Gazetteer {
+    places: HashMap<String, (f64, f64)>,
+}
+
+impl Gazetteer {
+    pub fn open(path: &Path) -> Result<Gazetteer> {
+        let contents = fs::read_to_string(path).context(format!("read {}", path.display()))?;
+        let mut places = HashMap::new();
+        for (number, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, coordinates) = parse_line(line).context(format!(
+                "parse line {} of {}",
+                number + 1,
+                path.display()
+            ))?;
+            // The first of any places with the same name is kept
+            places.entry(normalize(name)).or_insert(coordinates);
+        }
+        Ok(Gazetteer { places })
+    }
+}
+
+impl Geocoder for Gazetteer {
+    fn locate(&self, place: &str) -> Option<(f64, f64)> {
+        // Facebook often adds the city (e.g. "Golden Gate Park, San Francisco, California")
+        self.places.get(&normalize(place)).copied().or_else(|| {
+            let (name, _) = place.split_once(',')?;
+            self.places.get(&normalize(name)).copied()
+        })
+    }
+}
+
+fn parse_line(line: &str) -> Result<(&str, (f64, f64))> {
+    let number = |field: Option<&str>| -> Result<f64> {
+        field
+            .ok_or_else(|| anyhow!("missing coordinate"))?
+            .trim()
+            .parse()
+            .context("parse coordinate")
+    };
+
+    if line.contains('\t') {
+        // GeoNames: id, name, ascii name, alternate names, latitude, longitude, ...
+        let fields = line.split('\t').collect::<Vec<_>>();
+        let name = fields.get(1).ok_or_else(|| anyhow!("missing name"))?;
+        return Ok((
+            name,
+            (
+                number(fields.get(4).copied())?,
+                number(fields.get(5).copied())?,
+            ),
+        ));
+    }
+
+    // Names can contain commas, but the coordinates can't
+    let mut fields = line.rsplitn(3, ',');
+    let longitude = number(fields.next())?;
+    let latitude = number(fields.next())?;
+    let name = fields.next().ok_or_else(|| anyhow!("missing name"))?;
+    Ok((name, (latitude, longitude)))
+}
+
+fn normalize(name: &str) -> String {
+    name.trim().trim_matches('"').to_lowercase()
+}
@@ -0,0 +1,127 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Parsing for exports which were requested in HTML rather than JSON. The markup uses generated
+// class names which change between exports, so rather than relying on them, each media element is
+// paired with the nearest surrounding text which looks like a timestamp.
+
+use crate::{Album, Item};
+use anyhow::{anyhow, Result};
+use chrono::naive::NaiveDateTime;
+use log::warn;
+use scraper::{ElementRef, Html, Selector};
+use std::path::{Component, Path, PathBuf};
+
+const TIMESTAMP_FORMATS: &[&str] = &[
+    "%b %d, %Y, %I:%M %p",
+    "%b %d, %Y %I:%M:%S %p",
+    "%b %d, %Y %I:%M:%S%p",
+    "%B %d, %Y at %I:%M %p",
+    "%A, %B %d, %Y at %I:%M %p",
+    "%Y-%m-%d %H:%M:%S",
+];
+
+// How many levels above a media element to search for its timestamp and description.
+const MAX_DEPTH: usize = 4;
+
+pub fn parse_album(html: &str, fallback_name: &str) -> Result<Album> {
+    let document = Html::parse_document(html);
+    let title = Selector::parse("title").map_err(|e| anyhow!("parse selector: {}", e))?;
+    let name = document
+        .select(&title)
+        .next()
+        .map(|title| title.text().collect::<String>().trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| fallback_name.to_string());
+
+    Ok(Album {
+        name,
+        description: None,
+        cover_photo: None,
+        items: parse_items(&document)?,
+        file: None,
+    })
+}
+
+pub fn parse_videos(html: &str) -> Result<Vec<Item>> {
+    parse_items(&Html::parse_document(html))
+}
+
+fn parse_items(document: &Html) -> Result<Vec<Item>> {
+    let media = Selector::parse("img[src], video[src], video source[src]")
+        .map_err(|e| anyhow!("parse selector: {}", e))?;
+
+    let mut items = Vec::new();
+    for element in document.select(&media) {
+        let src = element.value().attr("src").unwrap_or_default();
+        if src.contains("://") || src.starts_with("data:") {
+            continue;
+        }
+
+        let path = normalize(src);
+        if items.iter().any(|item: &Item| item.path == path) {
+            continue;
+        }
+
+        match describe(element) {
+            Some((timestamp, description)) => items.push(Item {
+                description,
+                ..Item::new(path, timestamp)
+            }),
+            None => warn!("Couldn't find a timestamp for {}; skipping", src),
+        }
+    }
+
+    Ok(items)
+}
+
+// Finds the timestamp and description (the first bit of text preceding the timestamp) for the
+// given media element.
+fn describe(element: ElementRef) -> Option<(NaiveDateTime, Option<String>)> {
+    for ancestor in element.ancestors().take(MAX_DEPTH) {
+        let ancestor = match ElementRef::wrap(ancestor) {
+            Some(ancestor) => ancestor,
+            None => continue,
+        };
+
+        let texts = ancestor
+            .text()
+            .map(str::trim)
+            .filter(|text| !text.is_empty())
+            .collect::<Vec<_>>();
+        for (i, text) in texts.iter().enumerate() {
+            if let Some(timestamp) = parse_timestamp(text) {
+                let description = texts[..i].first().map(|text| text.to_string());
+                return Some((timestamp, description));
+            }
+        }
+    }
+
+    None
+}
+
+fn parse_timestamp(text: &str) -> Option<NaiveDateTime> {
+    TIMESTAMP_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+}
+
+// Media references are relative to the root of the archive (the pages set a `<base>`), but older
+// exports spell them relative to the page itself.
+fn normalize(src: &str) -> PathBuf {
+    Path::new(src)
+        .components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect()
+}
@@ -0,0 +1,222 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Zip files read straight from a web server, given as a URL in place of the input, so that the
+// export never has to be stored locally. The file is read with range requests, in blocks: the
+// central directory at the end first, and then each entry as it's needed. The most recently used
+// blocks are kept and shared between all of the readers of the file, since neighbouring entries
+// (e.g. the photos in an album) are usually read around the same time. Several blocks are fetched
+// at once while the file is being read straight through (i.e. while a large entry is copied).
+
+use crate::remote::retry;
+use anyhow::{anyhow, Context, Result};
+use log::trace;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::{Arc, Mutex, OnceLock};
+use ureq::Agent;
+
+const BLOCK: u64 = 256 * 1024;
+// The most blocks to fetch at once and to keep
+const READ_AHEAD: u64 = 16;
+const CACHED: usize = 64;
+
+// The files which have been opened, by URL
+static FILES: OnceLock<Mutex<HashMap<String, Arc<Remote>>>> = OnceLock::new();
+
+struct Remote {
+    agent: Agent,
+    url: String,
+    len: u64,
+    blocks: Mutex<Blocks>,
+}
+
+// The blocks which have been fetched, by index, and the order in which they were last used
+#[derive(Default)]
+struct Blocks {
+    cached: HashMap<u64, Arc<[u8]>>,
+    order: VecDeque<u64>,
+}
+
+pub struct HttpFile {
+    remote: Arc<Remote>,
+    pos: u64,
+    // The block last read, so that it isn't looked up for every read
+    current: Option<(u64, Arc<[u8]>)>,
+}
+
+impl HttpFile {
+    // Opens the file, which the server has to allow to be read in pieces.
+    pub fn open(url: &str) -> Result<HttpFile> {
+        let mut files = FILES.get_or_init(Default::default).lock().expect("files");
+        let remote = match files.get(url) {
+            Some(remote) => remote.clone(),
+            None => {
+                let remote = Arc::new(Remote::open(url)?);
+                files.insert(url.to_string(), remote.clone());
+                remote
+            }
+        };
+        Ok(HttpFile {
+            remote,
+            pos: 0,
+            current: None,
+        })
+    }
+}
+
+impl Remote {
+    fn open(url: &str) -> Result<Remote> {
+        let config = Agent::config_builder().http_status_as_error(false).build();
+        let agent = Agent::new_with_config(config);
+        let response = retry(&format!("open {}", url), || {
+            let response = agent.get(url).header("Range", "bytes=0-0").call()?;
+            match response.status().as_u16() {
+                status @ (429 | 500..=599) => Err(anyhow!("http status: {}", status)),
+                _ => Ok(response),
+            }
+        })?;
+        match response.status().as_u16() {
+            206 => {}
+            200 => {
+                return Err(anyhow!(
+                    "{} can't be read in pieces; download it first",
+                    url
+                ))
+            }
+            status => return Err(anyhow!("open {}: http status: {}", url, status)),
+        }
+        // e.g. "bytes 0-0/1234"
+        let len = response
+            .headers()
+            .get("Content-Range")
+            .and_then(|range| range.to_str().ok())
+            .and_then(|range| range.rsplit('/').next())
+            .and_then(|len| len.parse().ok())
+            .context(format!("size of {}", url))?;
+
+        Ok(Remote {
+            agent,
+            url: url.to_string(),
+            len,
+            blocks: Mutex::new(Blocks::default()),
+        })
+    }
+
+    // The block with the index, fetching it (and, if `ahead`, those after it which haven't been)
+    // unless it's already been.
+    fn block(&self, index: u64, ahead: bool) -> Result<Arc<[u8]>> {
+        let count = {
+            let mut blocks = self.blocks.lock().expect("blocks");
+            let Blocks { cached, order } = &mut *blocks;
+            if let Some(block) = cached.get(&index).cloned() {
+                order.retain(|i| *i != index);
+                order.push_back(index);
+                return Ok(block);
+            }
+            let last = self.len.div_ceil(BLOCK);
+            if ahead {
+                (index..last)
+                    .take(READ_AHEAD as usize)
+                    .take_while(|i| *i == index || !cached.contains_key(i))
+                    .count() as u64
+            } else {
+                1
+            }
+        };
+
+        // The lock isn't held while fetching, so that the other readers can carry on
+        let start = index * BLOCK;
+        let end = ((index + count) * BLOCK).min(self.len) - 1;
+        let range = format!("bytes={}-{}", start, end);
+        trace!("Reading {} of {}", range, self.url);
+        let data = retry(&format!("read {} of {}", range, self.url), || {
+            let mut response = self.agent.get(&self.url).header("Range", &range).call()?;
+            match response.status().as_u16() {
+                206 => {}
+                status => return Err(anyhow!("http status: {}", status)),
+            }
+            let mut data = Vec::new();
+            response
+                .body_mut()
+                .as_reader()
+                .read_to_end(&mut data)
+                .context("read response")?;
+            Ok(data)
+        })?;
+        if data.len() as u64 != end - start + 1 {
+            return Err(anyhow!(
+                "read {} of {}: got {} bytes",
+                range,
+                self.url,
+                data.len()
+            ));
+        }
+
+        let mut blocks = self.blocks.lock().expect("blocks");
+        let Blocks { cached, order } = &mut *blocks;
+        let mut first = None;
+        for (i, chunk) in (index..).zip(data.chunks(BLOCK as usize)) {
+            let block = Arc::<[u8]>::from(chunk);
+            first.get_or_insert_with(|| block.clone());
+            if cached.insert(i, block).is_none() {
+                order.push_back(i);
+            }
+        }
+        while order.len() > CACHED {
+            if let Some(oldest) = order.pop_front() {
+                cached.remove(&oldest);
+            }
+        }
+        first.context("empty response")
+    }
+}
+
+impl Read for HttpFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.remote.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let index = self.pos / BLOCK;
+        let block = match &self.current {
+            Some((current, block)) if *current == index => block.clone(),
+            current => {
+                // Reading on from the end of the last block means it's being read straight through
+                let ahead = matches!(current, Some((current, _)) if current + 1 == index);
+                let block = self.remote.block(index, ahead).map_err(io::Error::other)?;
+                self.current = Some((index, block.clone()));
+                block
+            }
+        };
+
+        let offset = (self.pos - index * BLOCK) as usize;
+        let read = buf.len().min(block.len() - offset);
+        buf[..read].copy_from_slice(&block[offset..offset + read]);
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for HttpFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => self.remote.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = pos
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start"))?;
+        Ok(self.pos)
+    }
+}
@@ -0,0 +1,104 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// ID3v2.4 tags, for raw AAC (ADTS) and MP3 audio, which have no container to hold metadata. The
+// tag is placed at the start of the stream, replacing any which is already there.
+
+use crate::{Item, Timezone};
+use anyhow::{anyhow, Result};
+use std::convert::TryFrom;
+
+const HEADER_LEN: usize = 10;
+const FLAG_FOOTER: u8 = 0x10;
+
+// Text is always written as UTF-8
+const ENCODING_UTF8: u8 = 3;
+
+// The audio with its tag replaced by one describing the item.
+pub fn retag(item: &Item, audio: &[u8], timezone: Timezone) -> Result<Vec<u8>> {
+    let mut frames = Vec::new();
+    frames.extend(frame(
+        b"TDRC",
+        &text(
+            &item
+                .local_timestamp(timezone)
+                .format("%Y-%m-%dT%H:%M:%S")
+                .to_string(),
+        ),
+    )?);
+    if let Some(author) = &item.author {
+        frames.extend(frame(b"TPE1", &text(author))?);
+    }
+    if let Some(description) = &item.description {
+        frames.extend(frame(b"TIT2", &text(description))?);
+    }
+    let comment = crate::combined_comment(item, timezone);
+    if !comment.is_empty() {
+        // Language and an empty short description precede the comment itself
+        let mut data = vec![ENCODING_UTF8];
+        data.extend_from_slice(b"eng\0");
+        data.extend_from_slice(comment.as_bytes());
+        frames.extend(frame(b"COMM", &data)?);
+    }
+
+    let mut tagged = b"ID3\x04\x00\x00".to_vec();
+    tagged.extend_from_slice(&synchsafe(frames.len())?);
+    tagged.extend(frames);
+    tagged.extend_from_slice(&audio[existing_len(audio)..]);
+    Ok(tagged)
+}
+
+// The length of the tag at the start of the audio, if there is one.
+fn existing_len(audio: &[u8]) -> usize {
+    if audio.len() < HEADER_LEN || &audio[..3] != b"ID3" {
+        return 0;
+    }
+    let size = audio[6..HEADER_LEN]
+        .iter()
+        .fold(0, |size, byte| (size << 7) | usize::from(byte & 0x7f));
+    let footer = if audio[5] & FLAG_FOOTER != 0 {
+        HEADER_LEN
+    } else {
+        0
+    };
+    (HEADER_LEN + size + footer).min(audio.len())
+}
+
+fn text(value: &str) -> Vec<u8> {
+    let mut data = vec![ENCODING_UTF8];
+    data.extend_from_slice(value.as_bytes());
+    data
+}
+
+fn frame(id: &[u8; 4], data: &[u8]) -> Result<Vec<u8>> {
+    let mut frame = id.to_vec();
+    frame.extend_from_slice(&synchsafe(data.len())?);
+    frame.extend_from_slice(&[0, 0]);
+    frame.extend_from_slice(data);
+    Ok(frame)
+}
+
+// Sizes are stored in seven bits of each byte so that they can't be mistaken for a sync signal.
+fn synchsafe(len: usize) -> Result<[u8; 4]> {
+    let len = u32::try_from(len)
+        .ok()
+        .filter(|len| *len < 1 << 28)
+        .ok_or_else(|| anyhow!("tag too long"))?;
+    Ok([
+        (len >> 21) as u8 & 0x7f,
+        (len >> 14) as u8 & 0x7f,
+        (len >> 7) as u8 & 0x7f,
+        len as u8 & 0x7f,
+    ])
+}
@@ -0,0 +1,183 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Uploads to an Immich server (https://immich.app), using its REST API. Each output file is
+// uploaded as an asset and then added to an album of the same name as the one it came from,
+// creating the albums as needed. Immich recognizes files it already has, so re-running an upload
+// doesn't create duplicates.
+
+use anyhow::{Context, Result};
+use log::{debug, info, trace};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+use ureq::{Agent, SendBody};
+
+const DEVICE_ID: &str = "fb-unarchive";
+
+// Assets are added to albums in batches, to keep the requests a reasonable size
+const BATCH_SIZE: usize = 500;
+
+pub struct Immich {
+    agent: Agent,
+    server: String,
+    key: String,
+}
+
+#[derive(Deserialize)]
+struct Asset {
+    id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AlbumResponse {
+    id: String,
+    album_name: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateAlbum<'a> {
+    album_name: &'a str,
+}
+
+#[derive(Serialize)]
+struct AddAssets<'a> {
+    ids: &'a [String],
+}
+
+impl Immich {
+    pub fn new(server: &str, key: String) -> Immich {
+        Immich {
+            agent: Agent::new_with_defaults(),
+            server: server.trim_end_matches('/').to_string(),
+            key,
+        }
+    }
+
+    // Uploads each of the files (given with their albums and creation times), relative to `root`.
+    pub fn upload(&self, files: &[(PathBuf, Option<String>, String)], root: &Path) -> Result<()> {
+        info!("Uploading {} files to {}", files.len(), self.server);
+
+        let mut albums = BTreeMap::<&str, Vec<String>>::new();
+        for (path, album, timestamp) in files {
+            let id = self
+                .upload_asset(path, root, timestamp)
+                .context(format!("upload {}", path.display()))?;
+            if let Some(album) = album {
+                albums.entry(album).or_default().push(id);
+            }
+        }
+
+        let existing = self
+            .agent
+            .get(&format!("{}/api/albums", self.server))
+            .header("x-api-key", &self.key)
+            .call()
+            .context("list albums")?
+            .body_mut()
+            .read_json::<Vec<AlbumResponse>>()
+            .context("parse albums")?;
+        for (name, ids) in albums {
+            let id = match existing.iter().find(|album| album.album_name == name) {
+                Some(album) => album.id.clone(),
+                None => {
+                    debug!("Creating album {}", name);
+                    self.agent
+                        .post(&format!("{}/api/albums", self.server))
+                        .header("x-api-key", &self.key)
+                        .send_json(CreateAlbum { album_name: name })
+                        .context(format!("create album {}", name))?
+                        .body_mut()
+                        .read_json::<AlbumResponse>()
+                        .context("parse album")?
+                        .id
+                }
+            };
+
+            for batch in ids.chunks(BATCH_SIZE) {
+                trace!("Adding {} assets to album {}", batch.len(), name);
+                self.agent
+                    .put(&format!("{}/api/albums/{}/assets", self.server, id))
+                    .header("x-api-key", &self.key)
+                    .send_json(AddAssets { ids: batch })
+                    .context(format!("add assets to album {}", name))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Uploads the file as a multipart form, streaming its contents, and returns the ID of the
+    // asset.
+    fn upload_asset(&self, path: &Path, root: &Path, timestamp: &str) -> Result<String> {
+        trace!("Uploading {}", path.display());
+        let relative = path.strip_prefix(root).unwrap_or(path);
+        let name = path
+            .file_name()
+            .context("file name")?
+            .to_string_lossy()
+            .replace('"', "_");
+        let boundary = format!("----fb-unarchive-{:x}", boundary(path));
+
+        let mut head = String::new();
+        for (field, value) in &[
+            ("deviceAssetId", relative.to_string_lossy().as_ref()),
+            ("deviceId", DEVICE_ID),
+            ("fileCreatedAt", timestamp),
+            ("fileModifiedAt", timestamp),
+        ] {
+            head.push_str(&format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                boundary, field, value
+            ));
+        }
+        head.push_str(&format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"assetData\"; filename=\"{}\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+            boundary, name
+        ));
+        let tail = format!("\r\n--{}--\r\n", boundary);
+
+        let file = File::open(path).context(format!("open {}", path.display()))?;
+        let mut body = Cursor::new(head).chain(file).chain(Cursor::new(tail));
+        let asset = self
+            .agent
+            .post(&format!("{}/api/assets", self.server))
+            .header("x-api-key", &self.key)
+            .header(
+                "Content-Type",
+                &format!("multipart/form-data; boundary={}", boundary),
+            )
+            .send(SendBody::from_reader(&mut body))
+            .context("send")?
+            .body_mut()
+            .read_json::<Asset>()
+            .context("parse asset")?;
+        Ok(asset.id)
+    }
+}
+
+// Distinguishes the multipart boundary from anything likely to appear in the file.
+fn boundary(path: &Path) -> u64 {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    std::time::SystemTime::now().hash(&mut hasher);
+    hasher.finish()
+}
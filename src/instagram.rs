@@ -0,0 +1,161 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Instagram's exports come from the same download tool as Facebook's and use the same conventions
+// (including the mangled text; see `encoding`), but everything is kept in "content" (or
+// "your_instagram_activity/content" in newer exports) rather than in albums. Posts may hold
+// several photos or videos, which share the caption of the post.
+
+use crate::{encoding, Archive, Item, MediaMetadata};
+use anyhow::{Context, Result};
+use chrono::DateTime;
+use log::{debug, trace, warn};
+use serde::Deserialize;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+const CONTENT_DIRS: &[&str] = &["content", "your_instagram_activity/content"];
+
+#[derive(Deserialize, Debug)]
+struct Post {
+    #[serde(default)]
+    creation_timestamp: Option<i64>,
+    #[serde(default, deserialize_with = "encoding::deserialize_option")]
+    title: Option<String>,
+    #[serde(default = "Vec::new")]
+    media: Vec<Media>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Media {
+    uri: PathBuf,
+    #[serde(default)]
+    creation_timestamp: Option<i64>,
+    #[serde(default, deserialize_with = "encoding::deserialize_option")]
+    title: Option<String>,
+    #[serde(default)]
+    media_metadata: MediaMetadata,
+}
+
+// Whether the archive looks like it came from Instagram rather than Facebook.
+pub fn detect(archive: &Archive) -> Result<bool> {
+    for dir in CONTENT_DIRS {
+        if archive
+            .list_recursive(Path::new(dir))?
+            .iter()
+            .any(|path| path.extension().and_then(|x| x.to_str()) == Some("json"))
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// Reads the media from every post ("posts_N.json").
+pub fn read_posts(archive: &Archive) -> Result<Vec<Item>> {
+    debug!("Finding Instagram posts");
+    read(archive, "posts_", None)
+}
+
+// Reads the media from every story ("stories.json"). Each story is a single photo or video, so
+// they aren't grouped into posts.
+pub fn read_stories(archive: &Archive) -> Result<Vec<Item>> {
+    debug!("Finding Instagram stories");
+    read(archive, "stories", Some("ig_stories"))
+}
+
+// Reads the media from every reel ("reels.json").
+pub fn read_reels(archive: &Archive) -> Result<Vec<Item>> {
+    debug!("Finding Instagram reels");
+    read(archive, "reels", Some("ig_reels_media"))
+}
+
+// Reads the posts from the JSON files whose names start with the prefix, either listed directly or
+// nested under the given key. Stories are listed as bare media, which are read as posts of one.
+fn read(archive: &Archive, prefix: &str, key: Option<&str>) -> Result<Vec<Item>> {
+    let mut items = Vec::new();
+    for dir in CONTENT_DIRS {
+        for path in archive.list_recursive(Path::new(dir))? {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if !(name.starts_with(prefix) && name.ends_with(".json")) {
+                continue;
+            }
+
+            trace!("Reading {}", path.display());
+            let json = archive.read(&path, |file| {
+                serde_json::from_reader::<_, serde_json::Value>(BufReader::new(file))
+                    .context(format!("parse json {}", path.display()))
+            })?;
+            let posts = match key {
+                Some(key) => json.get(key).cloned().unwrap_or_default(),
+                None => json,
+            };
+            let posts = match Vec::<Post>::deserialize(&posts) {
+                Ok(posts) if posts.iter().any(|post| !post.media.is_empty()) => posts,
+                _ => Vec::<Media>::deserialize(&posts)
+                    .context(format!("parse json {}", path.display()))?
+                    .into_iter()
+                    .map(|media| Post {
+                        creation_timestamp: None,
+                        title: None,
+                        media: vec![media],
+                    })
+                    .collect(),
+            };
+            items.extend(posts.into_iter().flat_map(|post| media(post, archive)));
+        }
+    }
+
+    items.sort_by_key(|item| item.timestamp);
+    Ok(items)
+}
+
+fn media(post: Post, archive: &Archive) -> Vec<Item> {
+    let Post {
+        creation_timestamp,
+        title,
+        media,
+    } = post;
+    media
+        .into_iter()
+        .filter_map(|media| {
+            let timestamp = media
+                .creation_timestamp
+                .or(creation_timestamp)
+                .and_then(|timestamp| DateTime::from_timestamp(timestamp, 0))
+                .map(|timestamp| timestamp.naive_utc());
+            let timestamp = match timestamp {
+                Some(timestamp) => timestamp,
+                None => {
+                    warn!(
+                        "Couldn't find a timestamp for {}; skipping",
+                        media.uri.display()
+                    );
+                    return None;
+                }
+            };
+            Some(
+                Item {
+                    description: title
+                        .clone()
+                        .or(media.title)
+                        .filter(|title| !title.is_empty()),
+                    media_metadata: media.media_metadata,
+                    ..Item::new(media.uri, timestamp)
+                }
+                .dated(archive),
+            )
+        })
+        .collect()
+}
@@ -0,0 +1,197 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// IPTC-IIM metadata, as stored in a JPEG's APP13 segment. The segment holds a list of Photoshop
+// image resources, one of which (0x0404) is the IPTC record. Any other resources, and any other
+// IPTC datasets, are preserved, except for the identifiers Facebook adds to the photos it serves
+// when they're being stripped.
+
+use crate::encoding::truncate;
+use crate::Item;
+use anyhow::{anyhow, Context, Result};
+use log::{debug, warn};
+use std::convert::TryFrom;
+
+pub const SIGNATURE: &[u8] = b"Photoshop 3.0\0";
+
+const RESOURCE_TYPE: &[u8] = b"8BIM";
+const RESOURCE_IPTC: u16 = 0x0404;
+
+const TAG_MARKER: u8 = 0x1c;
+const CODED_CHARACTER_SET: (u8, u8) = (1, 90);
+const RECORD_VERSION: (u8, u8) = (2, 0);
+const KEYWORDS: (u8, u8) = (2, 25);
+const CAPTION: (u8, u8) = (2, 120);
+
+// The escape sequence which marks the text as UTF-8
+const UTF8: &[u8] = b"\x1b%G";
+
+// The start of the identifiers Facebook adds, usually as the Original Transmission Reference
+pub const FACEBOOK: &[u8] = b"FBMD";
+
+// Caption-Abstract is limited to 2000 bytes and each keyword to 64.
+const MAX_CAPTION: usize = 2000;
+const MAX_KEYWORD: usize = 64;
+
+// A dataset's record and dataset numbers, followed by its data
+type Dataset = ((u8, u8), Vec<u8>);
+
+struct Resource {
+    id: u16,
+    name: Vec<u8>,
+    data: Vec<u8>,
+}
+
+// Builds the contents of the APP13 segment, starting from the existing contents (without the
+// signature), if any. Facebook's identifiers are removed if `strip_facebook` is set.
+pub fn encode(item: &Item, existing: Option<&[u8]>, strip_facebook: bool) -> Result<Vec<u8>> {
+    let mut resources = match existing.map(parse_resources).transpose() {
+        Ok(resources) => resources.unwrap_or_default(),
+        Err(err) => {
+            warn!(
+                "Discarding unreadable IPTC in {}: {:#}",
+                item.path.display(),
+                err
+            );
+            Vec::new()
+        }
+    };
+
+    let mut datasets = resources
+        .iter()
+        .find(|resource| resource.id == RESOURCE_IPTC)
+        .and_then(|resource| parse_datasets(&resource.data).ok())
+        .unwrap_or_default();
+    datasets.retain(|(tag, _)| ![CODED_CHARACTER_SET, KEYWORDS, CAPTION].contains(tag));
+    if strip_facebook {
+        let count = datasets.len();
+        datasets.retain(|(_, data)| !data.starts_with(FACEBOOK));
+        if datasets.len() < count {
+            debug!(
+                "Removing Facebook's identifier from {}",
+                item.path.display()
+            );
+        }
+    }
+    if !datasets.iter().any(|(tag, _)| *tag == RECORD_VERSION) {
+        datasets.push((RECORD_VERSION, 4u16.to_be_bytes().to_vec()));
+    }
+    datasets.insert(0, (CODED_CHARACTER_SET, UTF8.to_vec()));
+
+    if let Some(album) = &item.album {
+        datasets.push((KEYWORDS, truncate(album, MAX_KEYWORD).as_bytes().to_vec()));
+    }
+    if let Some(description) = &item.description {
+        datasets.push((
+            CAPTION,
+            truncate(description, MAX_CAPTION).as_bytes().to_vec(),
+        ));
+    }
+    // Datasets need to be in increasing record order, but within a record the order is preserved.
+    datasets.sort_by_key(|((record, _), _)| *record);
+
+    let mut record = Vec::new();
+    for ((number, dataset), data) in datasets {
+        let len = u16::try_from(data.len())
+            .ok()
+            .filter(|len| len & 0x8000 == 0)
+            .context("dataset too long")?;
+        record.extend_from_slice(&[TAG_MARKER, number, dataset]);
+        record.extend_from_slice(&len.to_be_bytes());
+        record.extend_from_slice(&data);
+    }
+
+    resources.retain(|resource| resource.id != RESOURCE_IPTC);
+    resources.push(Resource {
+        id: RESOURCE_IPTC,
+        name: Vec::new(),
+        data: record,
+    });
+
+    let mut encoded = SIGNATURE.to_vec();
+    for resource in resources {
+        encoded.extend_from_slice(RESOURCE_TYPE);
+        encoded.extend_from_slice(&resource.id.to_be_bytes());
+        // Pascal string, padded to an even length
+        encoded.push(resource.name.len() as u8);
+        encoded.extend_from_slice(&resource.name);
+        if resource.name.len() % 2 == 0 {
+            encoded.push(0);
+        }
+        encoded.extend_from_slice(
+            &u32::try_from(resource.data.len())
+                .context("resource too long")?
+                .to_be_bytes(),
+        );
+        encoded.extend_from_slice(&resource.data);
+        if resource.data.len() % 2 == 1 {
+            encoded.push(0);
+        }
+    }
+
+    Ok(encoded)
+}
+
+fn parse_resources(mut data: &[u8]) -> Result<Vec<Resource>> {
+    let mut resources = Vec::new();
+    while !data.is_empty() {
+        if data.len() < 7 || &data[..4] != RESOURCE_TYPE {
+            return Err(anyhow!("invalid image resource"));
+        }
+        let id = u16::from_be_bytes([data[4], data[5]]);
+        let name_len = data[6] as usize;
+        // The length byte and the name are padded to an even length
+        let name_end = 7 + name_len + (name_len + 1) % 2;
+        if data.len() < name_end + 4 {
+            return Err(anyhow!("truncated image resource"));
+        }
+        let name = data[7..7 + name_len].to_vec();
+        let size = u32::from_be_bytes([
+            data[name_end],
+            data[name_end + 1],
+            data[name_end + 2],
+            data[name_end + 3],
+        ]) as usize;
+        let start = name_end + 4;
+        if data.len() < start + size {
+            return Err(anyhow!("truncated image resource"));
+        }
+        resources.push(Resource {
+            id,
+            name,
+            data: data[start..start + size].to_vec(),
+        });
+        data = &data[(start + size + size % 2).min(data.len())..];
+    }
+
+    Ok(resources)
+}
+
+fn parse_datasets(mut data: &[u8]) -> Result<Vec<Dataset>> {
+    let mut datasets = Vec::new();
+    while data.len() >= 5 && data[0] == TAG_MARKER {
+        let tag = (data[1], data[2]);
+        let len = u16::from_be_bytes([data[3], data[4]]) as usize;
+        if len & 0x8000 != 0 {
+            return Err(anyhow!("extended datasets are unsupported"));
+        }
+        if data.len() < 5 + len {
+            return Err(anyhow!("truncated dataset"));
+        }
+        datasets.push((tag, data[5..5 + len].to_vec()));
+        data = &data[5 + len..];
+    }
+
+    Ok(datasets)
+}
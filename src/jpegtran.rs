@@ -0,0 +1,103 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Lossless rotation of JPEGs with jpegtran (from libjpeg), which rearranges the compressed blocks
+// rather than decoding and re-encoding the photo. Only photos whose dimensions are a whole number
+// of blocks can be transformed perfectly; the rest are left as they are.
+
+use anyhow::{anyhow, Context, Result};
+use log::trace;
+use std::io::{ErrorKind, Read, Write};
+use std::process::{Command, Stdio};
+use std::thread;
+
+// The transformation which undoes the EXIF orientation (2-8), turning the photo upright.
+fn transform(orientation: u16) -> Option<&'static [&'static str]> {
+    Some(match orientation {
+        2 => &["-flip", "horizontal"],
+        3 => &["-rotate", "180"],
+        4 => &["-flip", "vertical"],
+        5 => &["-transpose"],
+        6 => &["-rotate", "90"],
+        7 => &["-transverse"],
+        8 => &["-rotate", "270"],
+        _ => return None,
+    })
+}
+
+// Whether jpegtran can be run at all, so that its absence is found once rather than for each photo.
+pub fn available() -> bool {
+    let status = Command::new("jpegtran")
+        .arg("-version")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+    match status {
+        Err(err) => {
+            trace!("Couldn't run jpegtran: {}", err);
+            false
+        }
+        Ok(_) => true,
+    }
+}
+
+// The JPEG turned upright according to its orientation, or None if it already is.
+pub fn upright(jpeg: &[u8], orientation: u16) -> Result<Option<Vec<u8>>> {
+    let transform = match transform(orientation) {
+        Some(transform) => transform,
+        None => return Ok(None),
+    };
+
+    let mut command = Command::new("jpegtran");
+    command.args(["-copy", "all", "-perfect"]).args(transform);
+    trace!("Running {:?}", command);
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("run jpegtran")?;
+
+    // The input is written from another thread, since jpegtran may start writing its output
+    // before it has read all of it
+    let mut stdin = child.stdin.take().context("jpegtran stdin")?;
+    let mut stdout = child.stdout.take().context("jpegtran stdout")?;
+    let rotated = thread::scope(|scope| {
+        let writer = scope.spawn(move || match stdin.write_all(jpeg) {
+            // jpegtran stops reading when it fails; the reason is reported below
+            Err(err) if err.kind() == ErrorKind::BrokenPipe => Ok(()),
+            result => result,
+        });
+        let mut rotated = Vec::new();
+        stdout
+            .read_to_end(&mut rotated)
+            .context("read from jpegtran")?;
+        writer
+            .join()
+            .map_err(|_| anyhow!("jpegtran writer panicked"))?
+            .context("write to jpegtran")?;
+        Ok::<_, anyhow::Error>(rotated)
+    })?;
+
+    let result = child.wait_with_output().context("wait for jpegtran")?;
+    if !result.status.success() {
+        return Err(anyhow!(
+            "jpegtran failed ({}): {}",
+            result.status,
+            String::from_utf8_lossy(&result.stderr).trim()
+        ));
+    }
+    Ok(Some(rotated))
+}
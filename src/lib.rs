@@ -0,0 +1,3242 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The library behind the fb-unarchive command, for tools which want to reuse its parsing of
+// Facebook and Instagram exports or the way it writes their metadata into the photos and videos.
+// `read_albums` and `read_videos` give the contents of an export (or `ArchiveReader` gives them one
+// item at a time, for exports too large to read up front), and a `Processor` does everything that
+// the command does, configured by `Options` (which can be built from the command's arguments
+// with `Options::from_iter`). New types of media, or other handling of the existing ones, can be
+// added to a `Processor` by registering a `MediaProcessor`.
+
+pub mod archive;
+mod bundle;
+mod catalog;
+pub mod cli;
+pub mod config;
+mod disk;
+mod doctor;
+mod download;
+mod encoding;
+pub mod events;
+mod exif;
+mod ffmpeg;
+mod gallery;
+mod geocode;
+mod html;
+mod http;
+mod id3;
+mod immich;
+mod instagram;
+mod iptc;
+mod jpegtran;
+mod locale;
+mod marketplace;
+pub mod media;
+mod messages;
+mod mp4;
+mod name;
+mod orphans;
+mod output;
+mod pipeline;
+mod posts;
+pub mod progress;
+mod reader;
+mod remote;
+mod s3;
+mod sanitize;
+mod sniff;
+mod splice;
+pub mod stats;
+mod stream;
+mod takeout;
+mod thumbnail;
+mod timezone;
+pub mod tui;
+mod verify;
+mod webdav;
+mod xmp;
+
+use anyhow::{anyhow, Context, Error, Result};
+use chrono::{
+    naive::{NaiveDate, NaiveDateTime},
+    offset::FixedOffset,
+    DateTime,
+};
+use glob::Pattern;
+use img_parts::{
+    jpeg::{markers, Jpeg, JpegSegment},
+    png::{Png, PngChunk},
+    webp::WebP,
+    ImageEXIF,
+};
+use indicatif::HumanBytes;
+use log::{debug, info, trace, warn};
+use output::{AlbumNames, Outputs};
+use pipeline::Pipeline;
+use rayon::prelude::*;
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fmt;
+use std::fs::{self, File, TryLockError};
+use std::io::{self, BufReader, BufWriter, Read};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::SystemTime;
+use structopt::StructOpt;
+
+pub use archive::Archive;
+pub use bundle::OutputFormat;
+pub use events::LogFormat;
+pub use geocode::{Gazetteer, Geocoder};
+pub use locale::Names;
+pub use media::MediaProcessor;
+pub use messages::MessengerFolders;
+pub use name::{NameFormat, Rename};
+pub use output::{Conflict, Dedupe, Link, PlanFormat, Written};
+pub use progress::Progress;
+pub use reader::ArchiveReader;
+pub use sanitize::{Normalization, Sanitize};
+pub use timezone::Timezone;
+
+// Records the items which have been written, for use with `--skip-existing`
+const STATE_FILE: &str = ".fb-unarchive-state.json";
+
+// Records the progress of the current run, for use with `--resume`
+const JOURNAL_FILE: &str = ".fb-unarchive-journal.jsonl";
+
+// Lists the items which couldn't be processed, with `--keep-going`
+const FAILURES_FILE: &str = "failures.json";
+
+// Locked by the run which is writing into the output directory
+const LOCK_FILE: &str = ".fb-unarchive.lock";
+
+// The files downloaded with `--download`, which are kept so that they're only fetched once
+const DOWNLOADS_DIR: &str = ".fb-unarchive-downloads";
+
+// The most a JPEG segment can hold, after its length
+const MAX_SEGMENT: usize = 65533;
+
+// Describes each album, with `--album-info`
+const ALBUM_INFO_FILE: &str = "album.json";
+
+// The number of items which are read ahead of those being processed, for each thread
+const QUEUED_PER_JOB: usize = 4;
+
+#[derive(Debug, StructOpt)]
+pub struct Options {
+    #[structopt(flatten)]
+    pub source: Source,
+
+    #[structopt(flatten)]
+    pub common: Common,
+
+    /// Don't write anything; instead, print what would have been written
+    #[structopt(short, long)]
+    pub dry_run: bool,
+
+    /// How to print the plan of a dry run: as a "table" or as "json"
+    #[structopt(long, default_value = "table", possible_values = &["table", "json"])]
+    pub plan_format: PlanFormat,
+
+    /// Output directory, or the URL of a WebDAV server (e.g.
+    /// "https://cloud.example.com/remote.php/dav/files/alex/Photos") or S3 bucket (e.g.
+    /// "s3://bucket/prefix")
+    #[structopt(short, long, default_value = "./out", parse(from_os_str))]
+    pub output: PathBuf,
+
+    /// Write the output as a directory ("dir"), or as a single "zip", "tar", or gzipped tar
+    /// ("tar.gz") archive at the path given by --output. Each file is added as soon as it's
+    /// written, but they're written in a directory beside the archive first (which needs room for
+    /// the files being written, and for the sidecars and reports until the end). Plain tar
+    /// archives aren't compressed.
+    #[structopt(
+        long,
+        default_value = "dir",
+        possible_values = &["dir", "zip", "tar", "tar.gz"]
+    )]
+    pub output_format: OutputFormat,
+
+    /// Where to write the output before uploading it, when the output is a URL [default: a
+    /// temporary directory]
+    #[structopt(long, parse(from_os_str))]
+    pub staging_dir: Option<PathBuf>,
+
+    /// Number of items to process concurrently [default: number of CPUs]
+    #[structopt(short, long)]
+    pub jobs: Option<usize>,
+
+    /// Number of files to upload concurrently, when the output is a URL
+    #[structopt(long, default_value = "4")]
+    pub upload_jobs: usize,
+
+    #[structopt(long)]
+    pub no_progress: bool,
+
+    /// Choose the albums and follow their progress interactively, with the option to pause the
+    /// processing or skip albums while it runs
+    #[structopt(long)]
+    pub tui: bool,
+
+    /// Also report the progress to this file descriptor (e.g. 1 for stdout), as a line of JSON
+    /// each time it changes
+    #[structopt(long)]
+    pub progress_fd: Option<i32>,
+
+    /// Leave the modification times of the output files as when they were written, rather than
+    /// setting them to when the photos and videos were taken
+    #[structopt(long)]
+    pub no_mtime: bool,
+
+    /// Give output files the extension matching their contents, where the original's is wrong
+    #[structopt(long)]
+    pub fix_extensions: bool,
+
+    /// Write XMP sidecars for formats which can't hold metadata themselves (e.g. GIF)
+    #[structopt(long)]
+    pub xmp_sidecars: bool,
+
+    /// Write an album.json into each album's directory with its description, cover photo, number
+    /// of items, and date range
+    #[structopt(long)]
+    pub album_info: bool,
+
+    /// Write a JSON sidecar next to every output file in the format of Google Photos' Takeout
+    /// ("takeout"), for use with tools which import those
+    #[structopt(long, possible_values = &["takeout"])]
+    pub sidecar: Option<Sidecar>,
+
+    /// Write extra metadata for a particular photo manager: hierarchical XMP keywords for the
+    /// album and people for digiKam ("digikam"), or the comments and keywords in the EXIF tags
+    /// which Windows Explorer shows ("windows"). The description is written to EXIF
+    /// ImageDescription (if it's ASCII) whatever the profile
+    #[structopt(long, possible_values = &["digikam", "windows"])]
+    pub metadata_profile: Option<MetadataProfile>,
+
+    /// Give photos without a location of their own the approximate coordinates of the place they
+    /// were tagged with, looked up by name in this file ("name,latitude,longitude" on each line, or
+    /// a GeoNames dump)
+    #[structopt(long, parse(from_os_str))]
+    pub gazetteer: Option<PathBuf>,
+
+    /// Record how many reactions each photo had in its XMP metadata, either as a star rating
+    /// ("rating", from one star for a single reaction up to five for twenty or more) or as a
+    /// keyword such as "reactions=12" ("keyword")
+    #[structopt(long, possible_values = &["rating", "keyword"])]
+    pub reactions: Option<Reactions>,
+
+    /// Convert FLV videos into MP4s using ffmpeg, which must be installed separately
+    #[structopt(long)]
+    pub convert_flv: bool,
+
+    /// How to arrange the output: by album ("album") or by year and month ("date")
+    #[structopt(long, default_value = "album", possible_values = &["album", "date"])]
+    pub layout: Layout,
+
+    /// Nest the album folders in a folder for the year of each album's earliest item ("year"), so
+    /// that no single folder holds too many of them (e.g. "2012/Vacation")
+    #[structopt(long, possible_values = &["year"])]
+    pub split_by: Option<SplitBy>,
+
+    /// What to do when an output file already exists: "rename" it with a numbered suffix, "skip"
+    /// the item, "overwrite" the file, or stop with an "error"
+    #[structopt(
+        long,
+        default_value = "rename",
+        possible_values = &["rename", "skip", "overwrite", "error"]
+    )]
+    pub on_conflict: Conflict,
+
+    /// Write only one copy of items with identical contents; the duplicates are either skipped
+    /// ("skip") or hard linked to that copy ("link"). The merged items are listed in
+    /// duplicates.txt.
+    #[structopt(long, possible_values = &["skip", "link"])]
+    pub dedupe: Option<Dedupe>,
+
+    /// Link files which aren't modified (e.g. GIFs) and videos into the output instead of copying
+    /// them, using hard links ("hard"), symbolic links ("sym"), or copy-on-write clones
+    /// ("reflink"). Videos which are linked don't have their metadata updated. Files in zip
+    /// archives are always copied.
+    #[structopt(long, possible_values = &["hard", "sym", "reflink"])]
+    pub link: Option<Link>,
+
+    /// Skip the items which were written by a previous run into the same output directory
+    #[structopt(long)]
+    pub skip_existing: bool,
+
+    /// Continue an interrupted run into the same output directory from where it stopped
+    #[structopt(long)]
+    pub resume: bool,
+
+    /// Write into an output directory which already has something in it (e.g. from an unrelated
+    /// run)
+    #[structopt(long)]
+    pub force: bool,
+
+    /// Carry on even though the output volume doesn't look to have enough free space
+    #[structopt(long)]
+    pub no_space_check: bool,
+
+    /// Download the items which the export only links to (as URLs on Facebook's servers) rather
+    /// than including them; otherwise they're listed in remote-media.txt
+    #[structopt(long)]
+    pub download: bool,
+
+    /// Carry on when an item can't be processed, listing the failures in failures.json
+    #[structopt(short, long)]
+    pub keep_going: bool,
+
+    /// Record the SHA-256 hash of every output file in SHA256SUMS
+    #[structopt(long)]
+    pub checksums: bool,
+
+    /// Generate a static HTML gallery of the output, starting at index.html in the output
+    /// directory
+    #[structopt(long)]
+    pub gallery: bool,
+
+    /// Write the metadata of every output file to a SQLite database, catalog.sqlite in the output
+    /// directory
+    #[structopt(long)]
+    pub catalog: bool,
+
+    /// Remove the identifiers which Facebook adds to the JPEGs it serves (FBMD), which tie each
+    /// photo back to its upload
+    #[structopt(long)]
+    pub strip_fb_metadata: bool,
+
+    /// Rotate sideways JPEGs losslessly according to their recorded orientation, using jpegtran,
+    /// rather than only recording it
+    #[structopt(long)]
+    pub rotate: bool,
+
+    /// Name to record as the photographer and copyright holder of your own photos (EXIF Artist and
+    /// Copyright) [default: the name on the export's profile]
+    #[structopt(long)]
+    pub artist: Option<String>,
+
+    /// Copy every file exactly as Facebook delivered it, writing its metadata (the date,
+    /// description, comments, and so on) into an XMP sidecar instead of into the file
+    #[structopt(long, conflicts_with_all = &["convert-flv", "strip-fb-metadata", "rotate"])]
+    pub no_modify: bool,
+
+    /// Write a thumbnail of each photo, and a frame of each video, no larger than this many pixels
+    /// on either side, into a tree of their own ("thumbnails" in the output directory)
+    #[structopt(long, value_name = "size")]
+    pub thumbnails: Option<u32>,
+
+    /// Print the summary at the end of the run as JSON
+    #[structopt(long)]
+    pub stats_json: bool,
+
+    /// Upload the output to the Immich server at this URL (e.g. "http://localhost:2283"), adding
+    /// each file to an album of the same name
+    #[structopt(long)]
+    pub immich: Option<String>,
+
+    /// API key for the Immich server [default: $IMMICH_API_KEY]
+    #[structopt(long)]
+    pub immich_api_key: Option<String>,
+
+    /// Template for output file names, without the extension; supports {date}, {time},
+    /// {timestamp}, {album}, {index}, and {original} (e.g. "{date}_{time}_{original}")
+    #[structopt(long, default_value = "{original}")]
+    pub name_format: NameFormat,
+
+    /// Name the output files after when they were taken, as cameras do (e.g.
+    /// "20160814_153012.jpg", then "20160814_153012_1.jpg" for another in the same second)
+    #[structopt(long, possible_values = &["timestamp"], conflicts_with = "name-format")]
+    pub rename: Option<Rename>,
+
+    /// How to make album and file names safe to use: "minimal" only replaces the characters which
+    /// this platform doesn't allow, while "strict" makes names which work on any file system
+    /// (replacing characters like ':' and '?', emoji, trailing dots, and names like "CON")
+    #[structopt(long, default_value = "minimal", possible_values = &["minimal", "strict"])]
+    pub sanitize: Sanitize,
+
+    /// Write album and file names in this Unicode normalization form: "nfc" (composed, as most
+    /// systems prefer) or "nfd" (decomposed, as macOS prefers) [default: as they are in the export]
+    #[structopt(long, possible_values = &["nfc", "nfd"])]
+    pub normalize: Option<Normalization>,
+
+    // Only the items which failed in the previous run are processed (see `fb-unarchive retry`)
+    #[structopt(skip)]
+    pub retry: bool,
+}
+
+// Which export to read and which of its items to include, for every subcommand which reads one
+#[derive(Debug, StructOpt)]
+pub struct Source {
+    /// Archive directory or zip file (or the http or https URL of one); repeat for each part of a
+    /// multi-part export, or for each export with --merge
+    #[structopt(
+        short,
+        long,
+        default_value = ".",
+        number_of_values = 1,
+        parse(from_os_str)
+    )]
+    pub input: Vec<PathBuf>,
+
+    /// Treat each input as a separate export (e.g. downloaded years apart) rather than a part of
+    /// one, and merge them into a single library. Items which are identical, or which have the same
+    /// album, name, and creation time, are only written once, from the first input which has them.
+    /// Implies --dedupe=skip unless it's given.
+    #[structopt(long)]
+    pub merge: bool,
+
+    /// The kind of export: "facebook", "instagram", or "auto" to detect it from the contents
+    #[structopt(
+        long,
+        default_value = "auto",
+        possible_values = &["auto", "facebook", "instagram"]
+    )]
+    pub format: Format,
+
+    /// TOML file naming the folders and files of an export in a language which isn't built in,
+    /// mapping each to its English name (e.g. `fotos_y_videos = "photos_and_videos"`)
+    #[structopt(long, parse(from_os_str))]
+    pub names: Option<PathBuf>,
+
+    #[structopt(long)]
+    pub skip_photos: bool,
+
+    #[structopt(long)]
+    pub skip_videos: bool,
+
+    /// Leave out the photos and videos sent in Messenger conversations
+    #[structopt(long)]
+    pub skip_messages: bool,
+
+    /// How to arrange the photos and videos from Messenger: in a folder for each "conversation",
+    /// or for each "person" they were with, merging all of the conversations with them
+    #[structopt(
+        long,
+        default_value = "conversation",
+        possible_values = &["conversation", "person"]
+    )]
+    pub messenger_folders: MessengerFolders,
+
+    /// Leave out the photos and videos attached to posts, both on the timeline and in groups,
+    /// events, and pages
+    #[structopt(long)]
+    pub skip_posts: bool,
+
+    /// Leave out the photos and videos from archived stories
+    #[structopt(long)]
+    pub skip_stories: bool,
+
+    /// Leave out the photos of Marketplace listings
+    #[structopt(long)]
+    pub skip_marketplace: bool,
+
+    /// Also include the photos and videos which are in the export but aren't listed anywhere in
+    /// it, in "Orphans"
+    #[structopt(long)]
+    pub include_orphans: bool,
+
+    /// Only include items created on or after this date (YYYY-MM-DD, optionally followed by
+    /// THH:MM:SS)
+    #[structopt(long, parse(try_from_str = parse_since))]
+    pub since: Option<NaiveDateTime>,
+
+    /// Only include items created on or before this date (YYYY-MM-DD, optionally followed by
+    /// THH:MM:SS)
+    #[structopt(long, parse(try_from_str = parse_until))]
+    pub until: Option<NaiveDateTime>,
+
+    /// The time zone to write dates and times in: "local", "utc", or a name from the tz database
+    /// (e.g. "America/New_York")
+    #[structopt(long, default_value = "local")]
+    pub timezone: Timezone,
+
+    /// Which time to date photos by: the date the camera recorded in the photo itself
+    /// ("embedded"), when they were "taken", if the export records it, or when they were
+    /// "uploaded"; each falls back to the next when it isn't known
+    #[structopt(
+        long,
+        default_value = "taken",
+        possible_values = &["embedded", "taken", "uploaded"]
+    )]
+    pub date_priority: DatePriority,
+
+    /// Only include the albums whose names match this pattern (e.g. "Vacation*"); may be repeated.
+    /// Videos aren't part of any album, so they're unaffected (see --skip-videos).
+    #[structopt(long = "album", number_of_values = 1, parse(try_from_str = Pattern::new))]
+    pub albums: Vec<Pattern>,
+
+    /// Leave out the albums whose names match this pattern; may be repeated
+    #[structopt(
+        long = "exclude-album",
+        number_of_values = 1,
+        parse(try_from_str = Pattern::new)
+    )]
+    pub exclude_albums: Vec<Pattern>,
+}
+
+impl Source {
+    // Opens the archives: one for each input when merging, or otherwise just one made up of all of
+    // them. The time zone and date priority are given to them too, since everything which reads an
+    // export shows times.
+    pub fn open(&self) -> Result<Vec<Archive>> {
+        let names = match &self.names {
+            Some(path) => Names::open(path).context("read names")?,
+            None => Names::default(),
+        };
+        if !self.merge {
+            return Ok(vec![Archive::open(&self.input)
+                .context("open archive")?
+                .with_names(names)
+                .with_dates(self.timezone, self.date_priority)]);
+        }
+        self.input
+            .iter()
+            .map(|input| {
+                Ok(Archive::open(std::slice::from_ref(input))
+                    .context(format!("open archive {}", input.display()))?
+                    .with_names(names.clone())
+                    .with_dates(self.timezone, self.date_priority))
+            })
+            .collect()
+    }
+}
+
+// Options shared by every subcommand
+#[derive(Debug, StructOpt)]
+pub struct Common {
+    #[structopt(short, long, parse(from_occurrences))]
+    pub verbosity: u8,
+
+    /// How to print the log: as "text" or as "json", one object per line with the details of what
+    /// happened to each item
+    #[structopt(long, default_value = "text", possible_values = &["text", "json"])]
+    pub log_format: LogFormat,
+
+    /// Read options from this TOML file [default: fb-unarchive.toml, if it exists]
+    #[structopt(long, parse(from_os_str))]
+    pub config: Option<PathBuf>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Layout {
+    Album,
+    Date,
+}
+
+impl FromStr for Layout {
+    type Err = anyhow::Error;
+
+    fn from_str(layout: &str) -> Result<Layout> {
+        match layout {
+            "album" => Ok(Layout::Album),
+            "date" => Ok(Layout::Date),
+            _ => Err(anyhow!("unknown layout {:?}", layout)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SplitBy {
+    Year,
+}
+
+impl FromStr for SplitBy {
+    type Err = anyhow::Error;
+
+    fn from_str(split: &str) -> Result<SplitBy> {
+        match split {
+            "year" => Ok(SplitBy::Year),
+            _ => Err(anyhow!("unknown split {:?}", split)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Sidecar {
+    Takeout,
+}
+
+impl FromStr for Sidecar {
+    type Err = anyhow::Error;
+
+    fn from_str(sidecar: &str) -> Result<Sidecar> {
+        match sidecar {
+            "takeout" => Ok(Sidecar::Takeout),
+            _ => Err(anyhow!("unknown sidecar format {:?}", sidecar)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum MetadataProfile {
+    Digikam,
+    Windows,
+}
+
+impl FromStr for MetadataProfile {
+    type Err = anyhow::Error;
+
+    fn from_str(profile: &str) -> Result<MetadataProfile> {
+        match profile {
+            "digikam" => Ok(MetadataProfile::Digikam),
+            "windows" => Ok(MetadataProfile::Windows),
+            _ => Err(anyhow!("unknown metadata profile {:?}", profile)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Reactions {
+    Rating,
+    Keyword,
+}
+
+impl FromStr for Reactions {
+    type Err = anyhow::Error;
+
+    fn from_str(reactions: &str) -> Result<Reactions> {
+        match reactions {
+            "rating" => Ok(Reactions::Rating),
+            "keyword" => Ok(Reactions::Keyword),
+            _ => Err(anyhow!("unknown reactions format {:?}", reactions)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Format {
+    Auto,
+    Facebook,
+    Instagram,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> Result<Format> {
+        match format {
+            "auto" => Ok(Format::Auto),
+            "facebook" => Ok(Format::Facebook),
+            "instagram" => Ok(Format::Instagram),
+            _ => Err(anyhow!("unknown format {:?}", format)),
+        }
+    }
+}
+
+// Which of an item's times it's dated by. Like the time zone, this is kept with the archive and
+// applies to everything read from it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DatePriority {
+    Embedded,
+    Taken,
+    Uploaded,
+}
+
+impl FromStr for DatePriority {
+    type Err = anyhow::Error;
+
+    fn from_str(priority: &str) -> Result<DatePriority> {
+        match priority {
+            "embedded" => Ok(DatePriority::Embedded),
+            "taken" => Ok(DatePriority::Taken),
+            "uploaded" => Ok(DatePriority::Uploaded),
+            _ => Err(anyhow!("unknown date priority {:?}", priority)),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Album {
+    #[serde(deserialize_with = "encoding::deserialize")]
+    pub name: String,
+    #[serde(default, deserialize_with = "encoding::deserialize_option")]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub cover_photo: Option<CoverPhoto>,
+    #[serde(default = "Vec::new", rename = "photos")]
+    pub items: Vec<Item>,
+    // The JSON file which the items are read from as they're processed, in place of `items`, so
+    // that albums of any size can be processed without holding them in memory (see `open_album`)
+    #[serde(skip)]
+    pub file: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CoverPhoto {
+    pub uri: PathBuf,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Item {
+    #[serde(
+        with = "chrono::naive::serde::ts_seconds",
+        rename = "creation_timestamp"
+    )]
+    pub timestamp: NaiveDateTime,
+    #[serde(rename = "uri")]
+    pub path: PathBuf,
+    #[serde(default, deserialize_with = "encoding::deserialize_option")]
+    pub description: Option<String>,
+    #[serde(default = "Vec::new")]
+    pub comments: Vec<Comment>,
+    #[serde(default)]
+    pub media_metadata: MediaMetadata,
+    #[serde(default = "Vec::new")]
+    pub tags: Vec<Tag>,
+    #[serde(default = "Vec::new")]
+    pub reactions: Vec<Reaction>,
+    // Where the item was tagged as being taken
+    #[serde(default)]
+    pub place: Option<Place>,
+    // The name of the album containing this item, if any
+    #[serde(skip)]
+    pub album: Option<String>,
+    // The position of this item within its album (or the list of videos)
+    #[serde(skip)]
+    pub index: usize,
+    // Who sent or uploaded the item, for those from Messenger conversations or uploaded by someone
+    // else
+    #[serde(
+        default,
+        rename = "uploaded_by",
+        alias = "uploader",
+        deserialize_with = "encoding::deserialize_option"
+    )]
+    pub author: Option<String>,
+    // Whether the item may have been shared by someone other than the owner of the export (e.g. in
+    // a conversation), so that the owner is only credited with it if they're its author
+    #[serde(skip)]
+    pub by_others: bool,
+}
+
+// A person tagged in the photo. Some exports include the position of their face as percentages of
+// the width and height of the photo.
+#[derive(Deserialize, Debug)]
+pub struct Tag {
+    #[serde(deserialize_with = "encoding::deserialize")]
+    pub name: String,
+    #[serde(default, deserialize_with = "encoding::deserialize_number")]
+    pub x: Option<f64>,
+    #[serde(default, deserialize_with = "encoding::deserialize_number")]
+    pub y: Option<f64>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Place {
+    #[serde(deserialize_with = "encoding::deserialize")]
+    pub name: String,
+    #[serde(default)]
+    pub coordinate: Option<Coordinate>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Coordinate {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+// Where an item was taken. Those found from its place, rather than recorded by the camera, are
+// only approximate.
+#[derive(Clone, Copy, Debug)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub approximate: bool,
+}
+
+// Someone's reaction to the item (e.g. "LIKE" or "LOVE")
+#[derive(Deserialize, Debug)]
+pub struct Reaction {
+    #[serde(deserialize_with = "encoding::deserialize")]
+    pub reaction: String,
+    #[serde(default, deserialize_with = "encoding::deserialize_option")]
+    pub actor: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct MediaMetadata {
+    #[serde(default)]
+    pub photo_metadata: PhotoMetadata,
+}
+
+// Older exports list the photo's metadata directly while newer ones nest it in "exif_data".
+#[derive(Deserialize, Debug, Default)]
+pub struct PhotoMetadata {
+    #[serde(default)]
+    pub exif_data: Vec<ExifData>,
+    #[serde(flatten)]
+    pub fields: ExifData,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct ExifData {
+    #[serde(default, deserialize_with = "encoding::deserialize_number")]
+    pub latitude: Option<f64>,
+    #[serde(default, deserialize_with = "encoding::deserialize_number")]
+    pub longitude: Option<f64>,
+    #[serde(default, deserialize_with = "encoding::deserialize_option")]
+    pub camera_make: Option<String>,
+    #[serde(default, deserialize_with = "encoding::deserialize_option")]
+    pub camera_model: Option<String>,
+    #[serde(default, deserialize_with = "encoding::deserialize_number")]
+    pub exposure: Option<f64>,
+    #[serde(default, deserialize_with = "encoding::deserialize_number")]
+    pub iso: Option<f64>,
+    #[serde(default, deserialize_with = "encoding::deserialize_number")]
+    pub focal_length: Option<f64>,
+    #[serde(default, deserialize_with = "encoding::deserialize_number")]
+    pub f_stop: Option<f64>,
+    #[serde(default, deserialize_with = "encoding::deserialize_number")]
+    pub orientation: Option<f64>,
+    // When the photo was taken, as recorded by the camera, rather than when it was uploaded
+    #[serde(default, deserialize_with = "encoding::deserialize_number")]
+    pub taken_timestamp: Option<f64>,
+}
+
+impl Item {
+    // An item with nothing but its location and timestamp, for those which aren't read directly
+    // from the JSON.
+    pub fn new(path: PathBuf, timestamp: NaiveDateTime) -> Item {
+        Item {
+            timestamp,
+            path,
+            description: None,
+            comments: Vec::new(),
+            media_metadata: Default::default(),
+            tags: Vec::new(),
+            reactions: Vec::new(),
+            place: None,
+            album: None,
+            index: 0,
+            author: None,
+            by_others: false,
+        }
+    }
+
+    // Who to credit as the artist of the item, and whether that's the owner of the export (given by
+    // `--artist`), who then holds its copyright.
+    pub fn artist<'a>(&'a self, owner: Option<&'a str>) -> (Option<&'a str>, bool) {
+        match (self.author.as_deref(), owner) {
+            (Some(author), owner) => (Some(author), Some(author) == owner),
+            (None, Some(owner)) if !self.by_others => (Some(owner), true),
+            _ => (None, false),
+        }
+    }
+
+    // When the item was created, in the time zone given by `--timezone`
+    pub fn local_timestamp(&self, timezone: Timezone) -> DateTime<FixedOffset> {
+        timezone.local(self.timestamp)
+    }
+
+    // Where the item was taken, from its metadata or, failing that, its place (either with the
+    // coordinates given by Facebook or looked up by name; see `located`).
+    pub fn location(&self) -> Option<Location> {
+        let data = self.exif_data();
+        match (data.latitude, data.longitude) {
+            // Facebook uses 0,0 for photos without a location
+            (Some(latitude), Some(longitude)) if latitude != 0.0 || longitude != 0.0 => {
+                return Some(Location {
+                    latitude,
+                    longitude,
+                    approximate: false,
+                })
+            }
+            _ => {}
+        }
+
+        let place = self.place.as_ref()?;
+        let coordinate = place.coordinate.as_ref()?;
+        Some(Location {
+            latitude: coordinate.latitude,
+            longitude: coordinate.longitude,
+            approximate: true,
+        })
+    }
+
+    // The item with the coordinates of its place looked up by the archive's geocoder, if Facebook
+    // only gave its name.
+    pub(crate) fn located(mut self, archive: &Archive) -> Item {
+        if let (Some(place), Some(geocoder)) = (&mut self.place, archive.geocoder()) {
+            if place.coordinate.is_none() {
+                place.coordinate =
+                    geocoder
+                        .locate(&place.name)
+                        .map(|(latitude, longitude)| Coordinate {
+                            latitude,
+                            longitude,
+                        });
+            }
+        }
+        self
+    }
+
+    // When the photo was taken, if it was recorded
+    pub fn taken(&self) -> Option<NaiveDateTime> {
+        self.exif_data()
+            .taken_timestamp
+            .filter(|timestamp| *timestamp > 0.0)
+            .and_then(|timestamp| DateTime::from_timestamp(timestamp as i64, 0))
+            .map(|timestamp| timestamp.naive_utc())
+    }
+
+    // The item dated according to `--date-priority`: by the date recorded in the photo itself, by
+    // when it was taken, or by when it was uploaded, falling back to the next of those which is
+    // known.
+    pub(crate) fn dated(mut self, archive: &Archive) -> Item {
+        let priority = archive.date_priority();
+        if priority == DatePriority::Embedded {
+            match embedded_date(&self.path, archive) {
+                Ok(Some(date)) => {
+                    return Item {
+                        timestamp: date,
+                        ..self
+                    }
+                }
+                Ok(None) => {}
+                Err(err) => trace!(
+                    "Couldn't read the date in {}: {:#}",
+                    self.path.display(),
+                    err
+                ),
+            }
+        }
+        if priority != DatePriority::Uploaded {
+            self.timestamp = self.taken().unwrap_or(self.timestamp);
+        }
+        self
+    }
+
+    pub fn exif_data(&self) -> &ExifData {
+        let metadata = &self.media_metadata.photo_metadata;
+        metadata.exif_data.first().unwrap_or(&metadata.fields)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Comment {
+    #[serde(with = "chrono::naive::serde::ts_seconds")]
+    pub timestamp: NaiveDateTime,
+    #[serde(default, deserialize_with = "encoding::deserialize_option")]
+    pub comment: Option<String>,
+    #[serde(deserialize_with = "encoding::deserialize")]
+    pub author: String,
+}
+
+// The DateTimeOriginal already in the JPEG, if any, in UTC. Cameras record it in local time, which
+// is assumed to be the zone given by `--timezone`.
+fn embedded_date(path: &Path, archive: &Archive) -> Result<Option<NaiveDateTime>> {
+    let extension = path.extension().and_then(|x| x.to_str());
+    if extension.and_then(sniff::from_extension) != Some("jpg") {
+        return Ok(None);
+    }
+    let raw = match archive.read(path, splice::read_header)?.jpeg.exif() {
+        Some(raw) => raw,
+        None => return Ok(None),
+    };
+    Ok(
+        exif::read_text(&raw, rexif::ExifTag::DateTimeOriginal as u16)?
+            .and_then(|date| NaiveDateTime::parse_from_str(date.trim(), "%Y:%m:%d %H:%M:%S").ok())
+            .and_then(|date| archive.timezone().utc(date)),
+    )
+}
+
+// Checks that nothing is in the way of the output, so that a mistyped path doesn't mix the output
+// into some other directory (or replace an archive). Empty directories are fine.
+fn check_unused(output: &Path) -> Result<()> {
+    if !output.exists() {
+        return Ok(());
+    }
+    if !output.is_dir() {
+        return Err(anyhow!(
+            "{} already exists; use --force to replace it",
+            output.display()
+        ));
+    }
+    let mut entries =
+        fs::read_dir(output).context(format!("list directory {}", output.display()))?;
+    if entries.next().is_some() {
+        return Err(anyhow!(
+            "{} already has files in it; use --skip-existing or --resume to add to a previous \
+             run, or --force to write into it anyway",
+            output.display()
+        ));
+    }
+    Ok(())
+}
+
+// Takes the lock on the output directory, so that two runs can't write into it at once and mix up
+// each other's files and records. It's released when the file is closed, even if the run crashes.
+fn lock_output(output: &Path) -> Result<File> {
+    fs::create_dir_all(output).context(format!("create directory {}", output.display()))?;
+    let path = output.join(LOCK_FILE);
+    let file = File::create(&path).context(format!("create {}", path.display()))?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(anyhow!(
+            "another run of fb-unarchive is already writing to {}",
+            output.display()
+        )),
+        Err(TryLockError::Error(err)) => Err(err).context(format!("lock {}", path.display())),
+    }
+}
+
+// Returned by `Processor::run` when it was interrupted (see `Progress::interrupt_flag`), once the
+// items which were under way have been finished and the progress saved. Archives can't be resumed,
+// so they're removed instead.
+#[derive(Debug)]
+pub struct Interrupted {
+    pub resumable: bool,
+}
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("interrupted")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+// Returned by `process` when some of the items couldn't be processed (with `--keep-going`), once
+// everything else has been.
+#[derive(Debug)]
+struct Failed {
+    count: usize,
+    report: PathBuf,
+}
+
+impl fmt::Display for Failed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} items couldn't be processed (see {})",
+            self.count,
+            self.report.display()
+        )
+    }
+}
+
+impl std::error::Error for Failed {}
+
+// Processes an export according to the options, as the fb-unarchive command does.
+pub struct Processor {
+    opts: Options,
+    // The exports, in order, or just one if they aren't being merged
+    archives: Vec<Archive>,
+    // Where the output is uploaded once it's been written, if it's not a local directory
+    remote: Option<Arc<remote::Remote>>,
+    // The archive which the output is written as, and its format, if it's not a directory
+    bundle: Option<(PathBuf, OutputFormat)>,
+    media: media::Registry,
+}
+
+impl Processor {
+    pub fn new(mut opts: Options) -> Result<Processor> {
+        let mut archives = opts.source.open()?;
+        if let Some(gazetteer) = &opts.gazetteer {
+            let gazetteer: Arc<dyn Geocoder> =
+                Arc::new(Gazetteer::open(gazetteer).context("open gazetteer")?);
+            for archive in &mut archives {
+                archive.set_geocoder(gazetteer.clone());
+            }
+        }
+
+        // Remote outputs are written locally first and copied as they're written (see `pipeline`)
+        let remote =
+            remote::Remote::parse(&opts.output, opts.upload_jobs).context("parse output")?;
+        if remote.is_none() && !(opts.dry_run || opts.force || opts.skip_existing || opts.resume) {
+            check_unused(&opts.output)?;
+        }
+        if remote.is_some() && opts.output_format != OutputFormat::Dir {
+            return Err(anyhow!("archives can only be written to a local path"));
+        }
+        // A dry run lists the files as if the archive were a directory
+        let bundle = match opts.output_format {
+            OutputFormat::Dir => None,
+            _ if opts.dry_run => None,
+            format => {
+                // Each file is gone from the scratch directory once it's been added
+                if opts.skip_existing || opts.resume {
+                    return Err(anyhow!("an archive can't be added to by another run"));
+                }
+                if opts.dedupe == Some(Dedupe::Link) || opts.immich.is_some() {
+                    return Err(anyhow!(
+                        "an archive can't be written with --dedupe=link or --immich"
+                    ));
+                }
+                if opts.staging_dir.is_some() {
+                    return Err(anyhow!(
+                        "an archive's files are written beside it rather than in --staging-dir"
+                    ));
+                }
+                let path = sanitize::long_path(&opts.output).context("resolve output")?;
+                opts.output = bundle::Bundle::scratch_dir(&path);
+                Some((path, format))
+            }
+        };
+        if let Some(remote) = &remote {
+            let staging = match &opts.staging_dir {
+                Some(staging) => staging.clone(),
+                None => remote.staging_dir(&opts.output),
+            };
+            info!("Staging output in {}", staging.display());
+            opts.output = staging;
+        }
+        opts.output = sanitize::long_path(&opts.output).context("resolve output")?;
+        if opts.download {
+            let downloads = opts.output.join(DOWNLOADS_DIR);
+            archives = archives
+                .into_iter()
+                .map(|archive| archive.with_downloads(downloads.clone()))
+                .collect();
+        }
+
+        if opts.source.merge && opts.dedupe.is_none() {
+            opts.dedupe = Some(Dedupe::Skip);
+        }
+        if opts.rename == Some(Rename::Timestamp) {
+            // Options which weren't parsed from the command line haven't been checked for this
+            if !opts.name_format.is_original() {
+                return Err(anyhow!(
+                    "--rename timestamp names the files itself, so it can't be used with \
+                     --name-format"
+                ));
+            }
+            // Photos taken in the same second would otherwise be dropped or overwritten
+            if opts.on_conflict != Conflict::Rename {
+                return Err(anyhow!(
+                    "--rename timestamp numbers the files taken in the same second, so it can only \
+                     be used with --on-conflict rename"
+                ));
+            }
+            opts.name_format = "{timestamp}".parse()?;
+        }
+        if opts.rotate && !jpegtran::available() {
+            warn!("jpegtran couldn't be run, so sideways photos won't be rotated (see --rotate)");
+            opts.rotate = false;
+        }
+        if opts.artist.is_none() {
+            for archive in &archives {
+                opts.artist = posts::read_owner(archive).context("read profile")?;
+                if opts.artist.is_some() {
+                    break;
+                }
+            }
+        }
+        Ok(Processor {
+            opts,
+            archives,
+            remote: remote.map(Arc::new),
+            bundle,
+            media: media::Registry::default(),
+        })
+    }
+
+    // Adds a processor for items of the types it handles, which takes precedence over the built-in
+    // ones and those added before it.
+    pub fn register<P: MediaProcessor + 'static>(&mut self, processor: P) {
+        self.media.register(Box::new(processor));
+    }
+
+    // Looks up the places which photos were tagged with using the geocoder, instead of
+    // `--gazetteer`.
+    pub fn set_geocoder<G: Geocoder + 'static>(&mut self, geocoder: G) {
+        let geocoder: Arc<dyn Geocoder> = Arc::new(geocoder);
+        for archive in &mut self.archives {
+            archive.set_geocoder(geocoder.clone());
+        }
+    }
+
+    pub fn options(&self) -> &Options {
+        &self.opts
+    }
+
+    // The first (or only) export.
+    pub fn archive(&self) -> &Archive {
+        &self.archives[0]
+    }
+
+    pub fn archives(&self) -> &[Archive] {
+        &self.archives
+    }
+
+    // Checks that the output volume has room for everything which is still to be written, so that
+    // a run which won't fit stops before it starts. Each photo and video in the archive is counted
+    // at its size there, which is close to the size it's written at, without reading any of the
+    // export; those of the types which are skipped aren't, but those which won't be included for
+    // other reasons (e.g. with `--since`) are counted all the same. Linked items, remote outputs
+    // (which only hold the files being uploaded), and retries (which only write the items which
+    // failed) aren't checked.
+    fn check_space(&self, outputs: &Outputs) -> Result<()> {
+        let opts = &self.opts;
+        if opts.dry_run || opts.link.is_some() || self.remote.is_some() || opts.retry {
+            return Ok(());
+        }
+
+        let written = outputs.previous_sources();
+        let mut needed = 0;
+        for archive in &self.archives {
+            for path in archive
+                .list_recursive(Path::new(""))
+                .context("list archive")?
+            {
+                let extension = path.extension().and_then(|x| x.to_str());
+                let included = match extension.and_then(sniff::from_extension) {
+                    Some("jpg" | "png" | "webp" | "gif") => !opts.source.skip_photos,
+                    Some("mp4" | "flv") => !opts.source.skip_videos,
+                    Some(_) => true,
+                    None => false,
+                };
+                if included && !written.contains(path.as_path()) {
+                    needed += archive.size(&path).unwrap_or(0);
+                }
+            }
+        }
+        let dir = opts
+            .output
+            .ancestors()
+            .find(|dir| dir.is_dir())
+            .unwrap_or_else(|| Path::new("."));
+        let available =
+            disk::available(dir).context(format!("find the free space on {}", dir.display()))?;
+        debug!(
+            "{} needed, {} available",
+            HumanBytes(needed),
+            HumanBytes(available)
+        );
+        if needed <= available {
+            return Ok(());
+        }
+
+        let message = format!(
+            "needs about {} but only {} is free on {}",
+            HumanBytes(needed),
+            HumanBytes(available),
+            dir.display()
+        );
+        if opts.no_space_check {
+            warn!(
+                "The output {}; carrying on because of --no-space-check",
+                message
+            );
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "the output {} (use --no-space-check to try anyway)",
+                message
+            ))
+        }
+    }
+
+    // Processes everything in the archive, reporting the progress as it goes.
+    pub fn run(&self, progress: &Progress) -> Result<()> {
+        let opts = &self.opts;
+        // Held until the output has been written
+        let lock = if opts.dry_run {
+            None
+        } else {
+            Some(lock_output(&opts.output)?)
+        };
+        let bundle = match (&self.bundle, &lock) {
+            (Some((path, format)), Some(_)) => Some(Arc::new(
+                bundle::Bundle::create(path, *format, opts.source.timezone)
+                    .context("create archive")?,
+            )),
+            _ => None,
+        };
+
+        let written = self.write(progress, bundle.as_ref());
+        drop(lock);
+        // An archive which wasn't finished can't be resumed, so nothing is kept of it
+        match bundle {
+            Some(bundle) => written.and(bundle.close().context("clean up archive")),
+            None => written,
+        }
+    }
+
+    fn write(&self, progress: &Progress, bundle: Option<&Arc<bundle::Bundle>>) -> Result<()> {
+        let opts = &self.opts;
+        let mut outputs = Outputs::new(opts.on_conflict);
+        if opts.rename == Some(Rename::Timestamp) {
+            outputs.use_camera_suffixes();
+        }
+        if opts.checksums {
+            outputs.enable_checksums();
+        }
+        if opts.gallery || opts.catalog {
+            outputs.enable_records();
+        }
+        if let Some(bundle) = bundle {
+            outputs.enable_bundle(bundle.clone());
+        }
+        // The staging directory only keeps the records of what was uploaded (see `Remote::clean`)
+        if self.remote.is_some() {
+            outputs.use_staging();
+        }
+        let state_path = opts.output.join(STATE_FILE);
+        if opts.skip_existing {
+            outputs.load_state(&state_path).context("load state")?;
+        }
+        if opts.retry {
+            outputs
+                .load_failures(&opts.output.join(FAILURES_FILE))
+                .context("load failures")?;
+        }
+        let journal_path = opts.output.join(JOURNAL_FILE);
+        let incomplete = if opts.resume {
+            outputs
+                .load_journal(&journal_path)
+                .context("load journal")?
+        } else {
+            Vec::new()
+        };
+        // Files which were being written when the last run stopped are started over
+        if opts.resume && !opts.dry_run {
+            output::remove_incomplete(&incomplete).context("remove incomplete files")?;
+            let removed = output::remove_partial(&opts.output).context("remove partial files")?;
+            if removed > 0 {
+                info!(
+                    "Removed {} files which the last run didn't finish writing",
+                    removed
+                );
+            }
+        }
+        self.check_space(&outputs)?;
+        if !opts.dry_run {
+            outputs
+                .open_journal(&journal_path, opts.resume)
+                .context("open journal")?;
+        }
+
+        // The state is saved even if processing fails, so that the items which were completed can
+        // be skipped when the run is retried. The journal is only needed if the run didn't finish.
+        let pipeline = match (&self.remote, opts.dry_run) {
+            (Some(remote), false) => {
+                let pipeline =
+                    Pipeline::start(remote.clone(), opts.output.clone(), opts.upload_jobs)
+                        .context("start uploads")?;
+                outputs.enable_uploads(pipeline.sender());
+                Some(pipeline)
+            }
+            _ => None,
+        };
+        let processed = process(opts, &self.archives, progress, &outputs, &self.media);
+        outputs.disable_uploads();
+        if pipeline.is_some() {
+            progress.set_phase("uploading");
+        }
+        let uploaded = pipeline.map(Pipeline::finish).transpose();
+        if !opts.dry_run {
+            outputs.save_state(&state_path).context("save state")?;
+        }
+        progress.finish();
+        if opts.dry_run {
+            outputs.print_plan(opts.plan_format).context("print plan")?;
+        }
+        // Progress reported on stdout is left to itself, so that each line of it is JSON
+        match opts.progress_fd {
+            Some(1) => outputs
+                .summary()
+                .print(opts.stats_json, &mut io::stderr())?,
+            _ => outputs
+                .summary()
+                .print(opts.stats_json, &mut io::stdout())?,
+        }
+
+        // A failed upload also stops the processing, so it's reported first
+        let uploaded = uploaded.context("upload output")?;
+        // Items which failed (with `--keep-going`) don't stop the rest of the output from being
+        // uploaded, but they're still reported once it has been
+        let (processed, failed) = match processed {
+            Err(err) if err.is::<Failed>() => (Ok(()), Err(err)),
+            processed => (processed, Ok(())),
+        };
+        processed?;
+        // The journal is kept so that the run can be resumed
+        if progress.is_interrupted() {
+            return Err(Interrupted {
+                resumable: bundle.is_none(),
+            }
+            .into());
+        }
+        if !opts.dry_run && failed.is_ok() {
+            fs::remove_file(&journal_path).context(format!("remove {}", journal_path.display()))?;
+        }
+
+        if let (Some(remote), false) = (&self.remote, opts.dry_run) {
+            progress.set_phase("uploading");
+            let mut uploaded = uploaded.unwrap_or_default();
+            let rest = remote
+                .upload(&opts.output, &uploaded)
+                .context("upload output")?;
+            uploaded.extend(rest);
+            remote
+                .clean(&opts.output, &uploaded)
+                .context("clean staging directory")?;
+            info!("Kept the manifest in {}", opts.output.display());
+        }
+        if let Some(bundle) = bundle {
+            progress.set_phase("archiving");
+            bundle.finish().context("finish archive")?;
+        }
+
+        failed?;
+        progress.set_phase("done");
+        Ok(())
+    }
+}
+
+// Whether the archive is an Instagram export, either as given by `--format` or detected.
+fn is_instagram(source: &Source, archive: &Archive) -> Result<bool> {
+    match source.format {
+        Format::Auto => {
+            let instagram = instagram::detect(archive).context("detect format")?;
+            debug!(
+                "Detected {} export",
+                if instagram {
+                    "an Instagram"
+                } else {
+                    "a Facebook"
+                }
+            );
+            Ok(instagram)
+        }
+        Format::Facebook => Ok(false),
+        Format::Instagram => Ok(true),
+    }
+}
+
+fn list(source: &Source, archive: &Archive) -> Result<()> {
+    if is_instagram(source, archive)? {
+        let collections = vec![
+            ("Posts".to_string(), instagram::read_posts(archive)?),
+            ("Stories".to_string(), instagram::read_stories(archive)?),
+            ("Reels".to_string(), instagram::read_reels(archive)?),
+        ];
+        return print_list(source, archive, collections);
+    }
+
+    let albums = read_albums(archive).context("read_albums")?;
+    let names = albums
+        .iter()
+        .map(|album| album.name.clone())
+        .collect::<Vec<_>>();
+    let profile = read_profile(archive, &names).context("read_profile")?;
+    let unsorted = read_unsorted(archive).context("read_unsorted")?;
+    let tagged = read_tagged(archive).context("read_tagged")?;
+    let marketplace = read_marketplace(source, archive).context("read_marketplace")?;
+    let orphans = read_orphans(source, archive).context("read_orphans")?;
+    let videos = if source.skip_videos {
+        Vec::new()
+    } else {
+        read_videos(archive).context("read_videos")?
+    };
+
+    let albums = albums
+        .into_iter()
+        .chain(profile)
+        .chain(unsorted)
+        .chain(tagged)
+        .chain(marketplace)
+        .chain(orphans)
+        .filter(|album| album_selected(&album.name, source))
+        .map(|album| (album.name, album.items))
+        .chain(std::iter::once(("Videos".to_string(), videos)));
+    print_list(source, archive, albums)
+}
+
+// Prints a row for each collection of items, in order.
+fn print_list<C: IntoIterator<Item = (String, Vec<Item>)>>(
+    source: &Source,
+    archive: &Archive,
+    collections: C,
+) -> Result<()> {
+    let mut rows = Vec::new();
+    for (name, items) in collections {
+        let items = items
+            .into_iter()
+            .filter(|item| in_range(item, source))
+            .collect::<Vec<_>>();
+        let first = items.iter().map(|item| item.timestamp).min();
+        let last = items.iter().map(|item| item.timestamp).max();
+        let mut size = 0;
+        for item in &items {
+            size += archive.size(&item.path).unwrap_or_else(|err| {
+                warn!("Couldn't find {}: {:#}", item.path.display(), err);
+                0
+            });
+        }
+
+        let range = match (first, last) {
+            (Some(first), Some(last)) => {
+                format!("{} - {}", first.format("%Y-%m-%d"), last.format("%Y-%m-%d"))
+            }
+            _ => "-".to_string(),
+        };
+        rows.push((name, items.len(), range, HumanBytes(size).to_string()));
+    }
+
+    let width = rows
+        .iter()
+        .map(|row| row.0.chars().count())
+        .max()
+        .unwrap_or(0);
+    for (name, count, range, size) in rows {
+        let padding = width - name.chars().count();
+        println!(
+            "{}{}  {:>6} items  {:23}  {:>10}",
+            name,
+            " ".repeat(padding),
+            count,
+            range,
+            size
+        );
+    }
+    Ok(())
+}
+
+fn process(
+    opts: &Options,
+    archives: &[Archive],
+    progress: &Progress,
+    outputs: &Outputs,
+    media: &media::Registry,
+) -> Result<()> {
+    // When merging, the later exports only add what the earlier ones don't have
+    for archive in archives {
+        outputs.start_export();
+        if is_instagram(&opts.source, archive)? {
+            process_instagram(opts, archive, progress, outputs, media)?;
+        } else {
+            process_facebook(opts, archive, progress, outputs, media)?;
+        }
+    }
+    // Everything else describes the whole output, so it's left for the run which finishes it (see
+    // `--resume`)
+    if progress.is_interrupted() {
+        return Ok(());
+    }
+
+    // Only the failures are retried, so the reports about everything else still stand
+    if !outputs.is_retrying() {
+        outputs
+            .report_renamed_albums(&opts.output.join("renamed-albums.txt"), opts.dry_run)
+            .context("report renamed albums")?;
+        outputs
+            .report_missing(&opts.output.join("missing-media.txt"), opts.dry_run)
+            .context("report missing media")?;
+        outputs
+            .report_remote(&opts.output.join("remote-media.txt"), opts.dry_run)
+            .context("report remote media")?;
+        outputs
+            .report_quarantined(&opts.output.join("quarantine.txt"), opts.dry_run)
+            .context("report quarantined files")?;
+
+        if let Some(dedupe) = opts.dedupe {
+            outputs
+                .merge_duplicates(dedupe, opts.dry_run, &opts.output.join("duplicates.txt"))
+                .context("merge duplicates")?;
+        }
+    }
+
+    if !opts.dry_run {
+        outputs
+            .write_manifest(&opts.output.join("manifest.json"), &opts.output)
+            .context("write manifest")?;
+        outputs
+            .write_checksums(&opts.output.join("SHA256SUMS"), &opts.output)
+            .context("write checksums")?;
+        if opts.gallery {
+            outputs
+                .write_gallery(&opts.output)
+                .context("write gallery")?;
+        }
+        if opts.catalog {
+            outputs
+                .write_catalog(&opts.output.join("catalog.sqlite"), &opts.output)
+                .context("write catalog")?;
+        }
+    }
+
+    let failures = opts.output.join(FAILURES_FILE);
+    let failed = outputs
+        .report_failures(&failures, opts.dry_run)
+        .context("report failures")?;
+
+    // Uploading comes last, so that the output is fully described even if it fails
+    if let (Some(server), false) = (&opts.immich, opts.dry_run) {
+        let key = match &opts.immich_api_key {
+            Some(key) => key.clone(),
+            None => std::env::var("IMMICH_API_KEY")
+                .context("--immich-api-key or IMMICH_API_KEY is required")?,
+        };
+        immich::Immich::new(server, key)
+            .upload(&outputs.written(), &opts.output)
+            .context("upload to Immich")?;
+    }
+
+    match failed {
+        0 => Ok(()),
+        count => Err(Failed {
+            count,
+            report: failures,
+        }
+        .into()),
+    }
+}
+
+fn process_facebook(
+    opts: &Options,
+    archive: &Archive,
+    progress: &Progress,
+    outputs: &Outputs,
+    media: &media::Registry,
+) -> Result<()> {
+    // Each album is only read once the previous ones have been queued, so that large exports
+    // don't have to be held in memory all at once
+    debug!("Finding albums");
+    let mut names = Vec::new();
+    let albums = album_paths(archive)
+        .context("read_albums")?
+        .into_iter()
+        .filter_map(|path| {
+            open_album(archive, &path)
+                .context(format!("read_album {}", path.display()))
+                .transpose()
+        })
+        .inspect(|album| {
+            if let Ok(album) = album {
+                names.push(album.name.clone());
+            }
+        });
+    process_albums(
+        opts,
+        archive,
+        progress,
+        outputs,
+        media,
+        &opts.output,
+        albums,
+    )
+    .context("process_albums")?;
+
+    let profile = read_profile(archive, &names).context("read_profile")?;
+    trace!("Profile: {:#?}", profile);
+    process_albums(
+        opts,
+        archive,
+        progress,
+        outputs,
+        media,
+        &opts.output,
+        profile.into_iter().map(Ok),
+    )
+    .context("process_albums (profile)")?;
+
+    let unsorted = read_unsorted(archive).context("read_unsorted")?;
+    trace!("Unsorted: {:#?}", unsorted);
+    process_albums(
+        opts,
+        archive,
+        progress,
+        outputs,
+        media,
+        &opts.output,
+        unsorted.into_iter().map(Ok),
+    )
+    .context("process_albums (unsorted)")?;
+
+    let tagged = read_tagged(archive).context("read_tagged")?;
+    trace!("Tagged: {:#?}", tagged);
+    process_albums(
+        opts,
+        archive,
+        progress,
+        outputs,
+        media,
+        &opts.output,
+        tagged.into_iter().map(Ok),
+    )
+    .context("process_albums (tagged)")?;
+
+    let marketplace = read_marketplace(&opts.source, archive).context("read_marketplace")?;
+    trace!("Marketplace: {:#?}", marketplace);
+    process_albums(
+        opts,
+        archive,
+        progress,
+        outputs,
+        media,
+        &opts.output,
+        marketplace.into_iter().map(Ok),
+    )
+    .context("process_albums (marketplace)")?;
+
+    let orphans = read_orphans(&opts.source, archive).context("read_orphans")?;
+    trace!("Orphans: {:#?}", orphans);
+    process_albums(
+        opts,
+        archive,
+        progress,
+        outputs,
+        media,
+        &opts.output,
+        orphans.into_iter().map(Ok),
+    )
+    .context("process_albums (orphans)")?;
+
+    let videos = read_videos(archive).context("read_videos")?;
+    trace!("Videos: {:#?}", videos);
+    process_videos(opts, archive, progress, outputs, media, videos).context("process_videos")?;
+
+    if !opts.source.skip_posts {
+        let posts = posts::read_posts(archive).context("read_posts")?;
+        trace!("Posts: {:#?}", posts);
+        process_dated(opts, archive, progress, outputs, media, "Posts", posts)
+            .context("process_dated (posts)")?;
+    }
+
+    if !opts.source.skip_stories {
+        let stories = posts::read_stories(archive).context("read_stories")?;
+        trace!("Stories: {:#?}", stories);
+        process_dated(opts, archive, progress, outputs, media, "Stories", stories)
+            .context("process_dated (stories)")?;
+    }
+
+    if !opts.source.skip_posts {
+        for (section, dir) in posts::SECTIONS {
+            let albums = posts::read_section(archive, section).context("read_section")?;
+            trace!("{}: {:#?}", dir, albums);
+            let root = opts.output.join(dir);
+            process_albums(
+                opts,
+                archive,
+                progress,
+                outputs,
+                media,
+                &root,
+                albums.into_iter().map(Ok),
+            )
+            .context(format!("process_albums ({})", section))?;
+        }
+    }
+
+    if !opts.source.skip_messages {
+        let threads = messages::read_threads(archive, opts.source.messenger_folders)
+            .context("read_threads")?;
+        trace!("Conversations: {:#?}", threads);
+        let root = opts.output.join("Messenger");
+        process_albums(
+            opts,
+            archive,
+            progress,
+            outputs,
+            media,
+            &root,
+            threads.into_iter().map(Ok),
+        )
+        .context("process_albums (messages)")?;
+    }
+
+    Ok(())
+}
+
+// Instagram exports don't have albums, so everything is arranged by date.
+fn process_instagram(
+    opts: &Options,
+    archive: &Archive,
+    progress: &Progress,
+    outputs: &Outputs,
+    media: &media::Registry,
+) -> Result<()> {
+    if !opts.source.skip_posts {
+        let posts = instagram::read_posts(archive).context("read_posts")?;
+        trace!("Posts: {:#?}", posts);
+        process_dated(opts, archive, progress, outputs, media, "Posts", posts)
+            .context("process_dated (posts)")?;
+    }
+
+    if !opts.source.skip_stories {
+        let stories = instagram::read_stories(archive).context("read_stories")?;
+        trace!("Stories: {:#?}", stories);
+        process_dated(opts, archive, progress, outputs, media, "Stories", stories)
+            .context("process_dated (stories)")?;
+    }
+
+    let reels = instagram::read_reels(archive).context("read_reels")?;
+    trace!("Reels: {:#?}", reels);
+    process_dated(opts, archive, progress, outputs, media, "Reels", reels)
+        .context("process_dated (reels)")
+}
+
+pub fn read_albums(archive: &Archive) -> Result<Vec<Album>> {
+    debug!("Finding albums");
+
+    let mut albums = Vec::new();
+    for path in album_paths(archive)? {
+        albums.extend(read_album(archive, &path)?);
+    }
+    Ok(albums)
+}
+
+// Every item in the archive which would be processed, each with the album it would be recorded
+// under, for the subcommands which look at them all at once.
+fn read_items(source: &Source, archive: &Archive) -> Result<Vec<Item>> {
+    let mut items = Vec::new();
+    let mut add = |name: Option<&str>, collection: Vec<Item>| {
+        items.extend(
+            collection
+                .into_iter()
+                .filter(|item| in_range(item, source))
+                .map(|item| Item {
+                    album: name.map(str::to_string),
+                    ..item
+                }),
+        )
+    };
+    // Albums which share a name are told apart just as they are when processing (with the default
+    // `--sanitize`)
+    let mut unique = AlbumNames::default();
+    let mut unique_name = |root: &Path, name: &str, items: &[Item]| {
+        let selected = items.iter().filter(|item| in_range(item, source)).count();
+        if selected == 0 && !items.is_empty() {
+            return name.to_string();
+        }
+        let first = items.iter().map(|item| item.timestamp).min();
+        let first = first.map(|first| source.timezone.local(first).naive_local().date());
+        unique.unique(root, name, first, &|name| Sanitize::Minimal.name(name))
+    };
+
+    if is_instagram(source, archive)? {
+        if !source.skip_posts {
+            add(Some("Posts"), instagram::read_posts(archive)?);
+        }
+        if !source.skip_stories {
+            add(Some("Stories"), instagram::read_stories(archive)?);
+        }
+        add(Some("Reels"), instagram::read_reels(archive)?);
+        return Ok(items);
+    }
+
+    let mut names = Vec::new();
+    let mut albums = Vec::new();
+    for path in album_paths(archive)? {
+        if let Some(album) = read_album(archive, &path)? {
+            names.push(album.name.clone());
+            albums.push(album);
+        }
+    }
+    let albums = albums
+        .into_iter()
+        .chain(read_profile(archive, &names)?)
+        .chain(read_unsorted(archive)?)
+        .chain(read_tagged(archive)?)
+        .chain(read_marketplace(source, archive)?)
+        .chain(read_orphans(source, archive)?)
+        .filter(|album| album_selected(&album.name, source));
+    for Album { name, items, .. } in albums {
+        add(Some(&unique_name(Path::new(""), &name, &items)), items);
+    }
+    add(None, read_videos(archive)?);
+    if !source.skip_posts {
+        add(Some("Posts"), posts::read_posts(archive)?);
+    }
+    if !source.skip_stories {
+        add(Some("Stories"), posts::read_stories(archive)?);
+    }
+    if !source.skip_posts {
+        for (section, dir) in posts::SECTIONS {
+            for album in posts::read_section(archive, section)?
+                .into_iter()
+                .filter(|album| album_selected(&album.name, source))
+            {
+                let name = unique_name(Path::new(dir), &album.name, &album.items);
+                add(Some(&name), album.items);
+            }
+        }
+    }
+    if !source.skip_messages {
+        for thread in messages::read_threads(archive, source.messenger_folders)?
+            .into_iter()
+            .filter(|thread| album_selected(&thread.name, source))
+        {
+            let name = unique_name(Path::new("Messenger"), &thread.name, &thread.items);
+            add(Some(&name), thread.items);
+        }
+    }
+    Ok(items)
+}
+
+// The files describing each of the albums, which can be read one at a time with `read_album`.
+fn album_paths(archive: &Archive) -> Result<Vec<PathBuf>> {
+    archive.list(&Path::new("photos_and_videos").join("album"))
+}
+
+// Reads the album described by the file, if it's one which is understood.
+fn read_album(archive: &Archive, path: &Path) -> Result<Option<Album>> {
+    let mut album: Album = match path.extension().and_then(|x| x.to_str()) {
+        Some("json") => archive.read(path, |file| {
+            serde_json::from_reader(BufReader::new(file)).context("parse json")
+        })?,
+        Some("html") => archive.read(path, |file| {
+            let mut contents = String::new();
+            file.read_to_string(&mut contents).context("read html")?;
+            html::parse_album(
+                &contents,
+                &path.file_stem().unwrap_or_default().to_string_lossy(),
+            )
+            .context("parse html")
+        })?,
+        _ => {
+            trace!("Skipping {}", path.display());
+            return Ok(None);
+        }
+    };
+
+    trace!("Adding {}", path.display());
+    album.items = album
+        .items
+        .into_iter()
+        .map(|item| item.dated(archive))
+        .collect();
+    Ok(Some(album))
+}
+
+// Reads the album's name, description, and cover photo, leaving its items to be read from its file
+// as they're processed (see `queue_album`). Albums in HTML are read all at once.
+fn open_album(archive: &Archive, path: &Path) -> Result<Option<Album>> {
+    if path.extension().and_then(|x| x.to_str()) != Some("json") {
+        return read_album(archive, path);
+    }
+
+    trace!("Adding {}", path.display());
+    let album: Album = archive.read(path, |file| {
+        stream::split(file, "photos", |_: IgnoredAny| Ok(())).context("parse json")
+    })?;
+    Ok(Some(Album {
+        file: Some(path.to_path_buf()),
+        ..album
+    }))
+}
+
+// Passes each of the items in the album's JSON file to `f`, as it's read.
+fn read_album_items(
+    archive: &Archive,
+    path: &Path,
+    f: &mut dyn FnMut(Item) -> Result<()>,
+) -> Result<()> {
+    archive
+        .read(path, |file| {
+            stream::try_for_each(file, &["photos"], |item: Item| f(item.dated(archive)))
+        })
+        .context(format!("read_album {}", path.display()))
+        .map(drop)
+}
+
+// Older exports include the profile pictures and cover photos as ordinary albums, in which case
+// they're left to those.
+fn read_profile(archive: &Archive, albums: &[String]) -> Result<Vec<Album>> {
+    Ok(posts::read_profile(archive)?
+        .into_iter()
+        .filter(|profile| !albums.contains(&profile.name))
+        .collect())
+}
+
+// Photos which were never put into an album are listed separately (in "your_photos.json", or
+// "your_uncategorized_photos.json" in newer exports). They're gathered into an album of their own.
+fn read_unsorted(archive: &Archive) -> Result<Option<Album>> {
+    read_photo_list(
+        archive,
+        "Unsorted",
+        &["your_photos.json", "your_uncategorized_photos.json"],
+        &["photos", "other_photos_v2", "other_photos"],
+        false,
+    )
+}
+
+// Photos which other people uploaded and tagged you in, when the export includes them. These list
+// who uploaded each one, which is recorded as its artist.
+fn read_tagged(archive: &Archive) -> Result<Option<Album>> {
+    read_photo_list(
+        archive,
+        "Photos of You",
+        &["photos_of_you.json", "tagged_photos.json"],
+        &[
+            "photos_of_you_v2",
+            "photos_of_you",
+            "tagged_photos",
+            "photos",
+        ],
+        true,
+    )
+}
+
+// The photos of Marketplace listings, unless they're left out.
+fn read_marketplace(source: &Source, archive: &Archive) -> Result<Option<Album>> {
+    if source.skip_marketplace {
+        return Ok(None);
+    }
+    marketplace::read_listings(archive)
+}
+
+// The media which nothing in the export refers to, if it's asked for.
+fn read_orphans(source: &Source, archive: &Archive) -> Result<Option<Album>> {
+    if !source.include_orphans {
+        return Ok(None);
+    }
+    orphans::read_orphans(archive)
+}
+
+// Gathers the photos listed under the first of the keys in each of the files (in
+// "photos_and_videos") into an album with the given name.
+fn read_photo_list(
+    archive: &Archive,
+    album: &str,
+    files: &[&str],
+    keys: &[&str],
+    by_others: bool,
+) -> Result<Option<Album>> {
+    let dir = Path::new("photos_and_videos");
+    let mut items = Vec::new();
+    for name in files {
+        let path = dir.join(name);
+        if !archive.contains(&path)? {
+            continue;
+        }
+
+        let found = archive.read(&path, |file| {
+            stream::for_each(file, keys, |item: Item| {
+                items.push(Item {
+                    by_others,
+                    ..item.dated(archive)
+                })
+            })
+            .context(format!("parse json ({})", name))
+        })?;
+        if !found {
+            return Err(anyhow!("photos in {}", name));
+        }
+    }
+
+    if items.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(Album {
+        name: album.to_string(),
+        description: None,
+        cover_photo: None,
+        items,
+        file: None,
+    }))
+}
+
+// Processes the albums selected by `--album` and `--exclude-album`, which are read from `albums` as
+// their items are needed. Errors reading them stop the processing like any other.
+fn process_albums<A>(
+    opts: &Options,
+    archive: &Archive,
+    progress: &Progress,
+    outputs: &Outputs,
+    media: &media::Registry,
+    root: &Path,
+    albums: A,
+) -> Result<()>
+where
+    A: IntoIterator<Item = Result<Album>>,
+    A::IntoIter: Send,
+{
+    debug!("Processing albums");
+
+    let albums = albums.into_iter().filter(|album| match album {
+        Ok(album) if !album_selected(&album.name, &opts.source) => {
+            trace!("Album {} wasn't selected; skipping", album.name);
+            false
+        }
+        _ => true,
+    });
+
+    // The albums are read on a thread of their own, which queues each item as soon as it's read so
+    // that only a few are held at a time. Directories are created as each album is read so that
+    // its items can be processed in any order. The number of outstanding items in each album is
+    // tracked so that its completion can be reported.
+    let (sender, receiver) = mpsc::sync_channel(rayon::current_num_threads() * QUEUED_PER_JOB);
+    thread::scope(|scope| {
+        scope.spawn(move || {
+            for album in albums {
+                let queued = album.and_then(|album| {
+                    queue_album(
+                        opts,
+                        archive,
+                        progress,
+                        outputs,
+                        root,
+                        album,
+                        &mut |queued| {
+                            sender
+                                .send(Ok(queued))
+                                .map_err(|_| anyhow!("processing stopped"))
+                        },
+                    )
+                });
+                if let Err(err) = queued {
+                    // Nothing is listening if the processing has already stopped
+                    let _ = sender.send(Err(err));
+                    return;
+                }
+            }
+        });
+
+        receiver.into_iter().par_bridge().try_for_each(|queued| {
+            let (item, album_dir, remaining) = queued?;
+            let item = item.located(archive);
+            process_or_skip(&item, &album_dir, archive, opts, outputs, media, progress)?;
+            if remaining.fetch_sub(1, Ordering::Relaxed) == 1 {
+                progress.album_done();
+            }
+            Ok(())
+        })
+    })
+}
+
+// An item which is ready to be processed, along with the directory to write it to and the count of
+// the album's items which are still outstanding.
+type Queued = (Item, PathBuf, Arc<AtomicUsize>);
+
+// The number of items in a range of them, and the first and last of their times
+#[derive(Default)]
+struct Span {
+    count: usize,
+    first: Option<NaiveDateTime>,
+    last: Option<NaiveDateTime>,
+}
+
+impl Span {
+    fn add(&mut self, timestamp: NaiveDateTime) {
+        self.count += 1;
+        self.first = Some(self.first.map_or(timestamp, |first| first.min(timestamp)));
+        self.last = Some(self.last.map_or(timestamp, |last| last.max(timestamp)));
+    }
+}
+
+// Passes each of the album's items to `queue`, once the album's directory has been created. Albums
+// which are read from their files as they're processed (see `open_album`) are read twice: once for
+// the dates which their directories are named by, and again as their items are queued.
+fn queue_album(
+    opts: &Options,
+    archive: &Archive,
+    progress: &Progress,
+    outputs: &Outputs,
+    root: &Path,
+    album: Album,
+    queue: &mut dyn FnMut(Queued) -> Result<()>,
+) -> Result<()> {
+    let Album {
+        name,
+        description,
+        cover_photo,
+        items,
+        file,
+    } = album;
+    let mut all = Span::default();
+    let mut selected = Span::default();
+    let mut span = |item: &Item| {
+        all.add(item.timestamp);
+        if in_range(item, &opts.source) {
+            selected.add(item.timestamp);
+        }
+    };
+    match &file {
+        Some(file) => read_album_items(archive, file, &mut |item| {
+            span(&item);
+            Ok(())
+        })?,
+        None => items.iter().for_each(span),
+    }
+    if selected.count == 0 && all.count > 0 {
+        trace!("No items from {} are in range; skipping", name);
+        return Ok(());
+    }
+
+    if progress.is_skipped(&name) {
+        debug!("Album {} was skipped", name);
+        return Ok(());
+    }
+
+    let dir_name = |name: &str| opts.sanitize.name(&normalize(name, opts));
+    let unique = outputs.album_name(
+        root,
+        &name,
+        all.first
+            .map(|first| opts.source.timezone.local(first).naive_local().date()),
+        &dir_name,
+    );
+    let album_dir = match (opts.split_by, all.first) {
+        (Some(SplitBy::Year), Some(first)) => {
+            root.join(opts.source.timezone.local(first).format("%Y").to_string())
+        }
+        _ => root.to_path_buf(),
+    }
+    .join(dir_name(&unique));
+    if !opts.dry_run && opts.layout == Layout::Album {
+        fs::create_dir_all(&album_dir)
+            .context(format!("create directory {}", &album_dir.display()))?;
+        if opts.album_info {
+            write_album_info(
+                &album_dir.join(ALBUM_INFO_FILE),
+                &name,
+                description.as_deref(),
+                cover_photo.as_ref(),
+                &selected,
+                opts,
+            )
+            .context("write album info")?;
+        }
+    }
+
+    // The album is only finished once all of its items have been queued, as well as processed
+    progress.add_albums(1);
+    let remaining = Arc::new(AtomicUsize::new(1));
+    // Items keep their position within the whole album (see `{index}`), even when some are
+    // filtered out.
+    let mut index = 0;
+    let mut add = |item: Item| {
+        index += 1;
+        if !in_range(&item, &opts.source) {
+            return Ok(());
+        }
+        let item = Item {
+            album: Some(unique.clone()),
+            index: index - 1,
+            ..item
+        };
+        if !outputs.retries(&item) {
+            return Ok(());
+        }
+        remaining.fetch_add(1, Ordering::Relaxed);
+        progress.add_items(1);
+        queue((item, album_dir.clone(), remaining.clone()))
+    };
+    match &file {
+        Some(file) => read_album_items(archive, file, &mut add)?,
+        None => items.into_iter().try_for_each(add)?,
+    }
+    if remaining.fetch_sub(1, Ordering::Relaxed) == 1 {
+        progress.album_done();
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct AlbumInfo<'a> {
+    name: &'a str,
+    description: Option<&'a str>,
+    cover_photo: Option<Cow<'a, str>>,
+    items: usize,
+    first: Option<String>,
+    last: Option<String>,
+}
+
+// Records what's known about the album itself, which would otherwise be lost. The cover photo is
+// given by its name in the export. When merging, the first export's description is kept.
+fn write_album_info(
+    path: &Path,
+    name: &str,
+    description: Option<&str>,
+    cover_photo: Option<&CoverPhoto>,
+    items: &Span,
+    opts: &Options,
+) -> Result<()> {
+    if opts.source.merge && path.exists() {
+        return Ok(());
+    }
+
+    let date = |timestamp: Option<NaiveDateTime>| {
+        timestamp.map(|timestamp| {
+            opts.source
+                .timezone
+                .local(timestamp)
+                .format("%Y-%m-%d")
+                .to_string()
+        })
+    };
+    let info = AlbumInfo {
+        name,
+        description,
+        cover_photo: cover_photo
+            .and_then(|cover| cover.uri.file_name())
+            .map(|name| name.to_string_lossy()),
+        items: items.count,
+        first: date(items.first),
+        last: date(items.last),
+    };
+    serde_json::to_writer_pretty(
+        BufWriter::new(File::create(path).context(format!("create {}", path.display()))?),
+        &info,
+    )
+    .context(format!("write {}", path.display()))
+}
+
+// Processes the item or, with `--keep-going`, records its failure and carries on. Items in albums
+// which were skipped while the processing was running are passed over.
+fn process_or_skip(
+    item: &Item,
+    out_dir: &Path,
+    archive: &Archive,
+    opts: &Options,
+    outputs: &Outputs,
+    media: &media::Registry,
+    progress: &Progress,
+) -> Result<()> {
+    // The item is left for `--resume`
+    if progress.is_interrupted() {
+        return Ok(());
+    }
+    if !progress.proceed(item) {
+        events::skipped(item, "album skipped");
+        progress.item_done(item, 0);
+        return Ok(());
+    }
+
+    let written =
+        match process_item(item, out_dir, archive, opts, outputs, media).context("process item") {
+            Err(err) if opts.keep_going => {
+                events::failed(item, &err);
+                outputs.fail(item, &err);
+                None
+            }
+            result => result?,
+        };
+    progress.item_done(item, written.map_or(0, |written| written.bytes));
+    Ok(())
+}
+
+// Whether the album was selected by `--album` (or it wasn't used) and not excluded by
+// `--exclude-album`.
+fn album_selected(name: &str, source: &Source) -> bool {
+    (source.albums.is_empty() || source.albums.iter().any(|pattern| pattern.matches(name)))
+        && !source
+            .exclude_albums
+            .iter()
+            .any(|pattern| pattern.matches(name))
+}
+
+// Whether the item was created within the range given by `--since` and `--until`.
+fn in_range(item: &Item, source: &Source) -> bool {
+    source.since.is_none_or(|since| item.timestamp >= since)
+        && source.until.is_none_or(|until| item.timestamp <= until)
+}
+
+// Parses a date (e.g. "2014-07-04") or date and time (e.g. "2014-07-04T13:42:00") for `--since`
+// and `--until`. Dates alone cover the whole day, so they're taken as the start of the day, or the
+// end of it if `end` is set.
+fn parse_date(date: &str, end: bool) -> Result<NaiveDateTime> {
+    for format in &["%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M"] {
+        if let Ok(timestamp) = NaiveDateTime::parse_from_str(date, format) {
+            return Ok(timestamp);
+        }
+    }
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .context(format!("invalid date {:?} (expected YYYY-MM-DD)", date))?;
+    Ok(if end {
+        date.and_hms_opt(23, 59, 59)
+    } else {
+        date.and_hms_opt(0, 0, 0)
+    }
+    .expect("valid time"))
+}
+
+fn parse_since(date: &str) -> Result<NaiveDateTime> {
+    parse_date(date, false)
+}
+
+fn parse_until(date: &str) -> Result<NaiveDateTime> {
+    parse_date(date, true)
+}
+
+fn process_item(
+    item: &Item,
+    out_dir: &Path,
+    archive: &Archive,
+    opts: &Options,
+    outputs: &Outputs,
+    media: &media::Registry,
+) -> Result<Option<Written>> {
+    if let Some(previous) = outputs.previous(item) {
+        events::skipped(item, "already written");
+        // The file may already have been uploaded, so its type is taken from its name
+        let kind = previous
+            .extension()
+            .and_then(|x| x.to_str())
+            .and_then(sniff::from_extension)
+            .unwrap_or_default();
+        let written = Written::planned(previous);
+        outputs.complete(item, &written, kind)?;
+        return Ok(Some(written));
+    }
+
+    // Items which are only linked to are fetched first, if they're to be downloaded
+    if download::is_url(&item.path) && !archive.contains(&item.path)? {
+        if !opts.download {
+            events::skipped(item, "not in the export");
+            outputs.remote(item);
+            return Ok(None);
+        }
+        if opts.dry_run {
+            events::skipped(item, "not downloaded");
+            return Ok(None);
+        }
+        archive.download(&item.path)?;
+    }
+
+    // Files missing from the archive are reported together at the end, rather than failing
+    if !archive.contains(&item.path)? {
+        warn!("Missing from the archive; skipping {}", item.path.display());
+        events::skipped(item, "missing");
+        outputs.missing(item);
+        return Ok(None);
+    }
+
+    // With the date layout, the directories are only known once the items are examined, so they
+    // are created as needed rather than up front.
+    let date_dir;
+    let out_dir = match opts.layout {
+        Layout::Album => out_dir,
+        Layout::Date => {
+            let timestamp = item.local_timestamp(opts.source.timezone);
+            date_dir = opts
+                .output
+                .join(timestamp.format("%Y").to_string())
+                .join(timestamp.format("%m").to_string());
+            if !opts.dry_run {
+                fs::create_dir_all(&date_dir)
+                    .context(format!("create directory {}", date_dir.display()))?;
+            }
+            &date_dir
+        }
+    };
+
+    // The type is taken from the contents where they're recognized, since some exports have files
+    // with the wrong extension or none at all. Types which aren't otherwise recognized may still be
+    // handled by a registered processor.
+    let extension = item.path.extension().and_then(|x| x.to_str());
+    let other = extension.map(str::to_lowercase);
+    let kind = match archive.read(&item.path, sniff::detect)? {
+        Some(kind) => {
+            if extension.and_then(sniff::from_extension) != Some(kind) {
+                debug!("{} is a {} file", item.path.display(), kind);
+            }
+            kind
+        }
+        None => match (extension.and_then(sniff::from_extension), &other) {
+            (Some(kind), _) => kind,
+            (None, Some(other)) if media.handles(other) => other,
+            _ => {
+                warn!("Unrecognized file type; skipping {}", item.path.display());
+                events::skipped(item, "unrecognized type");
+                outputs.skip_unrecognized();
+                return Ok(None);
+            }
+        },
+    };
+
+    // Only the first copy of each distinct file is processed; the rest are merged once everything
+    // has been written.
+    let hash = match opts.dedupe {
+        Some(_) => Some(archive.read(&item.path, output::hash)?),
+        None => None,
+    };
+    if let Some(hash) = hash {
+        // Exports downloaded at different times can have recompressed copies of the same photo
+        let key = if opts.source.merge {
+            Some(output::key(item))
+        } else {
+            None
+        };
+        if let Some((original, same)) = outputs.original_of(hash, key) {
+            events::skipped(item, "duplicate");
+            // There's no point in linking another copy of the same item under the same name
+            let target = match opts.dedupe {
+                Some(Dedupe::Link) if !same => {
+                    match out_path(
+                        item,
+                        out_dir,
+                        opts,
+                        outputs,
+                        out_extension(item, kind, opts),
+                    )? {
+                        Some(target) => target,
+                        None => return Ok(None),
+                    }
+                }
+                _ => {
+                    outputs.add_duplicate(original, &item.path, None);
+                    return Ok(None);
+                }
+            };
+            outputs.add_duplicate(original, &item.path, Some(target.clone()));
+            return Ok(Some(Written::planned(target)));
+        }
+    }
+
+    let ctx = media::Context {
+        kind,
+        out_dir,
+        archive,
+        opts,
+        outputs,
+    };
+    let mut written = media.process(item, &ctx)?;
+
+    if let Some(hash) = hash {
+        outputs.set_original(hash, written.as_ref().map(|written| written.path.clone()));
+    }
+    if let Some(written) = &mut written {
+        // Hard links share their times with the original in the export, which is left alone
+        if !opts.dry_run && !opts.no_mtime && !written.shared {
+            set_times(&written.path, item.timestamp)?;
+        }
+        if let (Some(Sidecar::Takeout), false) = (opts.sidecar, opts.dry_run) {
+            takeout::write_sidecar(item, &written.path)?;
+        }
+        if let (Some(size), false) = (opts.thumbnails, opts.dry_run) {
+            // The thumbnail is only a convenience, so the item isn't failed without one
+            match thumbnail::write(&opts.output, &written.path, size) {
+                Ok(thumbnail) => written.thumbnail = thumbnail,
+                Err(err) => warn!(
+                    "Couldn't write the thumbnail of {}: {:#}",
+                    written.path.display(),
+                    err
+                ),
+            }
+        }
+        outputs.complete(item, written, kind)?;
+        events::processed(item, written);
+    }
+    Ok(written)
+}
+
+fn process_jpeg(
+    item: &Item,
+    kind: &str,
+    dir: &Path,
+    archive: &Archive,
+    opts: &Options,
+    outputs: &Outputs,
+) -> Result<Option<Written>> {
+    if opts.source.skip_photos {
+        events::skipped(item, "photos are skipped");
+        return Ok(None);
+    }
+
+    // Rotating needs the whole of the image, which is otherwise copied without being read into
+    // memory (see `splice`).
+    let upright = match (opts.rotate, item.exif_data().orientation) {
+        (true, Some(orientation)) => {
+            match jpegtran::upright(&archive.read_bytes(&item.path)?, orientation as u16) {
+                Ok(upright) => upright,
+                // The orientation is still recorded, so viewers which follow it show it upright
+                Err(err) => {
+                    info!("Couldn't rotate {}: {:#}", item.path.display(), err);
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+
+    let written = archive.read(&item.path, |file| {
+        let mut header = match &upright {
+            Some(upright) => splice::read_header(&mut upright.as_slice()),
+            None => splice::read_header(file),
+        }
+        .context(format!("parse {}", item.path.display()))?;
+        set_metadata(&mut header.jpeg, item, opts, upright.is_some())?;
+
+        let out_path = match out_path(item, dir, opts, outputs, out_extension(item, kind, opts))? {
+            Some(out_path) => out_path,
+            None => return Ok(None),
+        };
+        if opts.dry_run {
+            return Ok(Some(Written::planned(out_path)));
+        }
+
+        trace!("Outputting {}", out_path.display());
+        let bytes = output::write_atomically(&out_path, |temp| {
+            let out = BufWriter::new(File::create(temp).context("create")?);
+            match &upright {
+                Some(upright) => splice::write(header, &mut &upright[..], out),
+                None => splice::write(header, file, out),
+            }
+            .context(format!("write file {}", out_path.display()))
+        })?;
+
+        Ok(Some(Written::new(out_path, bytes)))
+    });
+    match written {
+        Err(err) if err.downcast_ref::<splice::Corrupt>().is_some() => {
+            quarantine(item, &err, archive, opts, outputs)?;
+            Ok(None)
+        }
+        written => written,
+    }
+}
+
+// Sets the damaged item aside (see `output::QUARANTINE_DIR`), exactly as it is in the archive,
+// rather than writing a broken copy of it.
+fn quarantine(
+    item: &Item,
+    err: &Error,
+    archive: &Archive,
+    opts: &Options,
+    outputs: &Outputs,
+) -> Result<()> {
+    warn!(
+        "{} is damaged; quarantining it: {:#}",
+        item.path.display(),
+        err
+    );
+    events::skipped(item, "damaged");
+    outputs.quarantine(item, err);
+    if opts.dry_run {
+        return Ok(());
+    }
+
+    let path = output::quarantine_path(&opts.output, &item.path, opts.sanitize);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).context(format!("create directory {}", dir.display()))?;
+    }
+    copy_item(item, &path, archive).map(drop)
+}
+
+// Writes the item's metadata into the JPEG's EXIF, IPTC, and XMP.
+fn set_metadata(jpeg: &mut Jpeg, item: &Item, opts: &Options, rotated: bool) -> Result<()> {
+    let mut exif = exif::encode(item, jpeg.exif().as_deref(), opts)?;
+    if rotated {
+        exif = exif::set_upright(&exif)?;
+    }
+    jpeg.set_exif(Some(exif.into()));
+    set_iptc(jpeg, item, opts)?;
+    if opts.strip_fb_metadata {
+        jpeg.segments_mut().retain(|segment| {
+            let contents = segment.contents();
+            // Some carry the identifier in an application segment of its own
+            !((markers::APP0..=markers::APP15).contains(&segment.marker())
+                && contents.starts_with(iptc::FACEBOOK))
+        });
+    }
+    // Embedded XMP is replaced wholesale, so it's only written when there's something which can't
+    // be expressed in EXIF or IPTC.
+    if xmp::needed(item, opts) {
+        set_segment(
+            jpeg,
+            markers::APP1,
+            xmp::SIGNATURE,
+            [xmp::SIGNATURE, xmp::embedded_packet(item, opts).as_bytes()].concat(),
+        );
+    }
+    // The length of each segment is only 16 bits, which the encoder doesn't check
+    match jpeg
+        .segments()
+        .iter()
+        .find(|segment| segment.contents().len() > MAX_SEGMENT)
+    {
+        Some(segment) => Err(anyhow!(
+            "metadata too large for a JPEG (APP{} segment of {} bytes)",
+            segment.marker() - markers::APP0,
+            segment.contents().len()
+        )),
+        None => Ok(()),
+    }
+}
+
+// Replaces the IPTC record in the JPEG's APP13 segment.
+fn set_iptc(jpeg: &mut Jpeg, item: &Item, opts: &Options) -> Result<()> {
+    let existing = find_segment(jpeg, markers::APP13, iptc::SIGNATURE).map(|index| {
+        jpeg.segments()[index]
+            .contents()
+            .slice(iptc::SIGNATURE.len()..)
+    });
+    let contents = iptc::encode(item, existing.as_deref(), opts.strip_fb_metadata)?;
+    set_segment(jpeg, markers::APP13, iptc::SIGNATURE, contents);
+    Ok(())
+}
+
+fn find_segment(jpeg: &Jpeg, marker: u8, signature: &[u8]) -> Option<usize> {
+    jpeg.segments()
+        .iter()
+        .position(|segment| segment.marker() == marker && segment.contents().starts_with(signature))
+}
+
+// Replaces the application segment with the given signature, adding it after the other
+// application segments if it doesn't already exist.
+fn set_segment(jpeg: &mut Jpeg, marker: u8, signature: &[u8], contents: Vec<u8>) {
+    let existing = find_segment(jpeg, marker, signature);
+    let segment = JpegSegment::new_with_contents(marker, contents.into());
+    let segments = jpeg.segments_mut();
+    match existing {
+        Some(index) => segments[index] = segment,
+        None => {
+            let index = segments
+                .iter()
+                .position(|segment| !(markers::APP0..=markers::APP15).contains(&segment.marker()))
+                .unwrap_or(segments.len());
+            segments.insert(index, segment);
+        }
+    }
+}
+
+fn process_webp(
+    item: &Item,
+    kind: &str,
+    dir: &Path,
+    archive: &Archive,
+    opts: &Options,
+    outputs: &Outputs,
+) -> Result<Option<Written>> {
+    if opts.source.skip_photos {
+        events::skipped(item, "photos are skipped");
+        return Ok(None);
+    }
+
+    let mut webp = WebP::from_bytes(archive.read_bytes(&item.path)?.into())
+        .map_err(|e| anyhow!("Failed to parse {}: {}", item.path.display(), e))
+        .context("parse webp")?;
+    let exif = exif::encode(item, webp.exif().as_deref(), opts)?;
+    webp.set_exif(Some(exif.into()));
+
+    let out_path = match out_path(item, dir, opts, outputs, out_extension(item, kind, opts))? {
+        Some(out_path) => out_path,
+        None => return Ok(None),
+    };
+    if opts.dry_run {
+        return Ok(Some(Written::planned(out_path)));
+    }
+
+    trace!("Outputting {}", out_path.display());
+    let bytes = output::write_atomically(&out_path, |temp| {
+        let mut out = BufWriter::new(File::create(temp).context("create")?);
+        let written = webp
+            .encoder()
+            .write_to(&mut out)
+            .context(format!("write file {}", out_path.display()))?;
+        // Flushing when dropped would hide the errors, leaving a truncated file to be renamed
+        out.into_inner()
+            .map_err(|err| err.into_error())
+            .and_then(|file| file.sync_all())
+            .context(format!("write file {}", out_path.display()))?;
+        Ok(written)
+    })?;
+
+    Ok(Some(Written::new(out_path, bytes)))
+}
+
+fn process_png(
+    item: &Item,
+    kind: &str,
+    dir: &Path,
+    archive: &Archive,
+    opts: &Options,
+    outputs: &Outputs,
+) -> Result<Option<Written>> {
+    if opts.source.skip_photos {
+        events::skipped(item, "photos are skipped");
+        return Ok(None);
+    }
+
+    let mut png = Png::from_bytes(archive.read_bytes(&item.path)?.into())
+        .map_err(|e| anyhow!("Failed to parse {}: {}", item.path.display(), e))
+        .context("parse png")?;
+    let exif = exif::encode(item, png.exif().as_deref(), opts)?;
+    png.set_exif(Some(exif.into()));
+
+    // Not every reader understands eXIf, so the creation time and description are also written as
+    // the standard textual keywords. These are inserted just before the trailing IEND chunk.
+    let mut text = vec![png_text(
+        "Creation Time",
+        &item.local_timestamp(opts.source.timezone).to_rfc2822(),
+    )];
+    if let Some(description) = &item.description {
+        text.push(png_text("Description", description));
+    }
+    if xmp::needed(item, opts) {
+        text.push(png_text("XML:com.adobe.xmp", &xmp::packet(item, opts)));
+    }
+    let chunks = png.chunks_mut();
+    let end = chunks
+        .iter()
+        .position(|chunk| chunk.kind() == *b"IEND")
+        .ok_or_else(|| anyhow!("parse {}: no IEND chunk", item.path.display()))?;
+    chunks.splice(end..end, text);
+
+    let out_path = match out_path(item, dir, opts, outputs, out_extension(item, kind, opts))? {
+        Some(out_path) => out_path,
+        None => return Ok(None),
+    };
+    if opts.dry_run {
+        return Ok(Some(Written::planned(out_path)));
+    }
+
+    trace!("Outputting {}", out_path.display());
+    let bytes = output::write_atomically(&out_path, |temp| {
+        let mut out = BufWriter::new(File::create(temp).context("create")?);
+        let written = png
+            .encoder()
+            .write_to(&mut out)
+            .context(format!("write file {}", out_path.display()))?;
+        out.into_inner()
+            .map_err(|err| err.into_error())
+            .and_then(|file| file.sync_all())
+            .context(format!("write file {}", out_path.display()))?;
+        Ok(written)
+    })?;
+
+    Ok(Some(Written::new(out_path, bytes)))
+}
+
+// Builds an international text (iTXt) chunk, which, unlike tEXt, allows for UTF-8 text.
+fn png_text(keyword: &str, text: &str) -> PngChunk {
+    let mut contents = Vec::with_capacity(keyword.len() + text.len() + 5);
+    contents.extend_from_slice(keyword.as_bytes());
+    // Null separator, compression flag and method, and empty language tag and translated keyword
+    contents.extend_from_slice(&[0, 0, 0, 0, 0]);
+    contents.extend_from_slice(text.as_bytes());
+    PngChunk::new(*b"iTXt", contents.into())
+}
+
+fn process_gif(
+    item: &Item,
+    kind: &str,
+    dir: &Path,
+    archive: &Archive,
+    opts: &Options,
+    outputs: &Outputs,
+) -> Result<Option<Written>> {
+    if opts.source.skip_photos {
+        events::skipped(item, "photos are skipped");
+        return Ok(None);
+    }
+
+    let out_path = match out_path(item, dir, opts, outputs, out_extension(item, kind, opts))? {
+        Some(out_path) => out_path,
+        None => return Ok(None),
+    };
+    if opts.dry_run {
+        return Ok(Some(Written::planned(out_path)));
+    }
+
+    let link = link_item(item, &out_path, archive, opts)?;
+    let bytes = match link {
+        Some(_) => 0,
+        None => copy_item(item, &out_path, archive)?,
+    };
+    if opts.xmp_sidecars {
+        xmp::write_sidecar(item, &out_path, opts)?;
+    }
+
+    Ok(Some(Written::linked(out_path, bytes, link)))
+}
+
+// Copies the item without changing a byte of it, for `--no-modify`, so its metadata can only go
+// into the sidecar.
+fn process_verbatim(
+    item: &Item,
+    kind: &str,
+    dir: &Path,
+    archive: &Archive,
+    opts: &Options,
+    outputs: &Outputs,
+) -> Result<Option<Written>> {
+    match kind {
+        "jpg" | "png" | "webp" | "gif" if opts.source.skip_photos => {
+            events::skipped(item, "photos are skipped");
+            return Ok(None);
+        }
+        "mp4" | "flv" if opts.source.skip_videos => {
+            events::skipped(item, "videos are skipped");
+            return Ok(None);
+        }
+        _ => {}
+    }
+
+    let out_path = match out_path(item, dir, opts, outputs, out_extension(item, kind, opts))? {
+        Some(out_path) => out_path,
+        None => return Ok(None),
+    };
+    if opts.dry_run {
+        return Ok(Some(Written::planned(out_path)));
+    }
+
+    let link = link_item(item, &out_path, archive, opts)?;
+    let bytes = match link {
+        Some(_) => 0,
+        None => copy_item(item, &out_path, archive)?,
+    };
+    xmp::write_sidecar(item, &out_path, opts)?;
+
+    Ok(Some(Written::linked(out_path, bytes, link)))
+}
+
+// The path of the output file for the item, named according to `--name-format`. The extension of
+// the original is kept unless another is given. If the path is already taken, it's handled
+// according to `--on-conflict`, and None means that the item should be skipped.
+fn out_path(
+    item: &Item,
+    dir: &Path,
+    opts: &Options,
+    outputs: &Outputs,
+    extension: Option<&str>,
+) -> Result<Option<PathBuf>> {
+    let extension = match extension {
+        Some(extension) => extension.to_string(),
+        None => download::without_query(&item.path)
+            .extension()
+            .context("file extension")?
+            .to_string_lossy()
+            .into_owned(),
+    };
+    outputs.claim(
+        item,
+        dir.join(opts.sanitize.file_name(
+            &normalize(&opts.name_format.format(item, opts.source.timezone), opts),
+            &extension,
+        )),
+    )
+}
+
+// The name in the form given by `--normalize`, if any.
+fn normalize(name: &str, opts: &Options) -> String {
+    match opts.normalize {
+        Some(normalization) => normalization.apply(name),
+        None => name.to_string(),
+    }
+}
+
+// The extension of the output file, if it differs from that of the original (i.e. when the item
+// is being converted, or when the original's extension is missing or, with `--fix-extensions`,
+// doesn't match its contents).
+fn out_extension<'a>(item: &Item, kind: &'a str, opts: &Options) -> Option<&'a str> {
+    let extension = download::without_query(&item.path)
+        .extension()
+        .and_then(|x| x.to_str());
+    match kind {
+        "flv" if opts.convert_flv => Some("mp4"),
+        _ if extension.is_none() => Some(kind),
+        _ if opts.fix_extensions && extension.and_then(sniff::from_extension) != Some(kind) => {
+            Some(kind)
+        }
+        _ => None,
+    }
+}
+
+// The description followed by each of the comments, one per line.
+pub fn combined_comment(item: &Item, timezone: Timezone) -> String {
+    let description = item.description.clone().into_iter();
+    let comments = item.comments.iter().filter_map(|c| {
+        c.comment.as_ref().map(|comment| {
+            format!(
+                r#""{}" -{} ({})"#,
+                comment,
+                c.author,
+                timezone.local(c.timestamp).format("%F %r")
+            )
+        })
+    });
+    description.chain(comments).collect::<Vec<_>>().join("\n")
+}
+
+fn set_times(path: &Path, timestamp: NaiveDateTime) -> Result<()> {
+    let timestamp = Into::<SystemTime>::into(timestamp.and_utc()).into();
+    // Symbolic links (see `--link`) get their own times rather than those of their target.
+    filetime::set_symlink_file_times(path, timestamp, timestamp)
+        .context(format!("set times on {}", path.display()))
+}
+
+fn process_video(
+    item: &Item,
+    kind: &str,
+    dir: &Path,
+    archive: &Archive,
+    opts: &Options,
+    outputs: &Outputs,
+) -> Result<Option<Written>> {
+    if opts.source.skip_videos {
+        events::skipped(item, "videos are skipped");
+        return Ok(None);
+    }
+
+    let convert = kind == "flv" && opts.convert_flv;
+    let out_path = match out_path(item, dir, opts, outputs, out_extension(item, kind, opts))? {
+        Some(out_path) => out_path,
+        None => return Ok(None),
+    };
+    if opts.dry_run {
+        return Ok(Some(Written::planned(out_path)));
+    }
+
+    let link = match convert {
+        true => None,
+        false => link_item(item, &out_path, archive, opts)?,
+    };
+    let bytes = match kind {
+        _ if link.is_some() => 0,
+        "mp4" => archive.read(&item.path, |file| {
+            write_mp4(item, file, &out_path, opts.source.timezone)
+        })?,
+        "flv" if convert => convert_flv(item, &out_path, archive, opts)?,
+        _ => copy_item(item, &out_path, archive)?,
+    };
+
+    Ok(Some(Written::linked(out_path, bytes, link)))
+}
+
+fn process_audio(
+    item: &Item,
+    kind: &str,
+    dir: &Path,
+    archive: &Archive,
+    opts: &Options,
+    outputs: &Outputs,
+) -> Result<Option<Written>> {
+    let out_path = match out_path(item, dir, opts, outputs, out_extension(item, kind, opts))? {
+        Some(out_path) => out_path,
+        None => return Ok(None),
+    };
+    if opts.dry_run {
+        return Ok(Some(Written::planned(out_path)));
+    }
+
+    let bytes = match kind {
+        "m4a" => archive.read(&item.path, |file| {
+            write_mp4(item, file, &out_path, opts.source.timezone)
+        })?,
+        _ => {
+            let audio = id3::retag(item, &archive.read_bytes(&item.path)?, opts.source.timezone)?;
+            output::write_atomically(&out_path, |temp| {
+                fs::write(temp, &audio).context(format!("write file {}", out_path.display()))?;
+                Ok(audio.len() as u64)
+            })?
+        }
+    };
+
+    Ok(Some(Written::new(out_path, bytes)))
+}
+
+fn write_mp4(
+    item: &Item,
+    input: &mut dyn Read,
+    out_path: &Path,
+    timezone: Timezone,
+) -> Result<u64> {
+    let mut tags = Vec::new();
+    if let Some(description) = &item.description {
+        tags.push((mp4::TAG_DESCRIPTION, description.clone()));
+    }
+    let comment = combined_comment(item, timezone);
+    if !comment.is_empty() {
+        tags.push((mp4::TAG_COMMENT, comment));
+    }
+    if let Some(author) = &item.author {
+        tags.push((mp4::TAG_ARTIST, author.clone()));
+    }
+
+    output::write_atomically(out_path, |temp| {
+        mp4::copy(
+            input,
+            &mut BufWriter::new(File::create(temp).context("create")?),
+            &item.path,
+            item.timestamp,
+            &tags,
+        )
+        .context(format!("rewrite {}", item.path.display()))
+    })
+}
+
+// Converts the FLV into an MP4 in a temporary file next to the output and then writes it out with
+// the same metadata as any other MP4.
+fn convert_flv(item: &Item, out_path: &Path, archive: &Archive, opts: &Options) -> Result<u64> {
+    // ffmpeg writes a file of its own, which is removed whether or not the conversion succeeds
+    let temp_path = output::temp_path(&out_path.with_extension("ffmpeg"));
+    let converted = (|| {
+        let remuxed = archive.read(&item.path, |file| ffmpeg::to_mp4(file, &temp_path, false));
+        if let Err(err) = remuxed {
+            debug!(
+                "Couldn't remux {} ({:#}); transcoding instead",
+                item.path.display(),
+                err
+            );
+            archive
+                .read(&item.path, |file| ffmpeg::to_mp4(file, &temp_path, true))
+                .context(format!("convert {}", item.path.display()))?;
+        }
+
+        write_mp4(
+            item,
+            &mut BufReader::new(File::open(&temp_path).context("open")?),
+            out_path,
+            opts.source.timezone,
+        )
+    })();
+    match fs::remove_file(&temp_path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound && converted.is_ok() => {
+            Err(err).context(format!("remove {}", temp_path.display()))
+        }
+        _ => converted,
+    }
+}
+
+// Links the output to the original, if requested and possible (i.e. the original isn't packed in a
+// zip file), returning how it was linked.
+fn link_item(
+    item: &Item,
+    out_path: &Path,
+    archive: &Archive,
+    opts: &Options,
+) -> Result<Option<Link>> {
+    let link = match opts.link {
+        Some(link) => link,
+        None => return Ok(None),
+    };
+    match archive.local_path(&item.path)? {
+        Some(original) => {
+            output::link(link, &original, out_path)?;
+            Ok(Some(link))
+        }
+        None => {
+            trace!("{} is in a zip file; copying", item.path.display());
+            Ok(None)
+        }
+    }
+}
+
+fn copy_item(item: &Item, out_path: &Path, archive: &Archive) -> Result<u64> {
+    archive.read(&item.path, |file| {
+        output::write_atomically(out_path, |temp| {
+            io::copy(file, &mut File::create(temp).context("create")?).context(format!(
+                "copy {} to {}",
+                item.path.display(),
+                out_path.display()
+            ))
+        })
+    })
+}
+
+pub fn read_videos(archive: &Archive) -> Result<Vec<Item>> {
+    let dir = Path::new("photos_and_videos");
+    if archive.contains(&dir.join("your_videos.html"))? {
+        return archive.read(&dir.join("your_videos.html"), |file| {
+            let mut contents = String::new();
+            file.read_to_string(&mut contents).context("read html")?;
+            html::parse_videos(&contents).context("parse html")
+        });
+    }
+
+    let path = dir.join("your_videos.json");
+    let mut videos = Vec::new();
+    let found = archive.read(&path, |file| {
+        stream::for_each(file, &["videos"], |item: Item| {
+            videos.push(item.dated(archive))
+        })
+        .context("parse json (videos)")
+    })?;
+    if !found {
+        return Err(anyhow!("videos"));
+    }
+    Ok(videos)
+}
+
+fn process_videos<V: IntoIterator<Item = Item>>(
+    opts: &Options,
+    archive: &Archive,
+    progress: &Progress,
+    outputs: &Outputs,
+    media: &media::Registry,
+    videos: V,
+) -> Result<()> {
+    debug!("Processing videos");
+    if progress.is_skipped("Videos") {
+        return Ok(());
+    }
+
+    let out_path = opts.output.join("videos");
+    if !opts.dry_run && opts.layout == Layout::Album {
+        fs::create_dir_all(&out_path)
+            .context(format!("create directory {}", out_path.display()))?;
+    }
+
+    let videos = videos
+        .into_iter()
+        .enumerate()
+        .filter(|(_, video)| in_range(video, &opts.source))
+        .map(|(index, video)| Item {
+            index,
+            ..video.located(archive)
+        })
+        .filter(|video| outputs.retries(video))
+        .collect::<Vec<_>>();
+    progress.add_items(videos.len());
+    videos.par_iter().try_for_each(|video| {
+        process_or_skip(video, &out_path, archive, opts, outputs, media, progress)
+    })
+}
+
+// Items which don't belong to any album (e.g. those attached to posts) are arranged by the year and
+// month they were created (e.g. "Posts/2014/07") regardless of the layout.
+fn process_dated<I: IntoIterator<Item = Item>>(
+    opts: &Options,
+    archive: &Archive,
+    progress: &Progress,
+    outputs: &Outputs,
+    media: &media::Registry,
+    name: &str,
+    items: I,
+) -> Result<()> {
+    debug!("Processing {}", name);
+    if progress.is_skipped(name) {
+        return Ok(());
+    }
+
+    let root = opts.output.join(name);
+    let mut dated = Vec::new();
+    for (index, item) in items.into_iter().enumerate() {
+        if !in_range(&item, &opts.source) {
+            continue;
+        }
+
+        let item = Item {
+            album: Some(name.to_string()),
+            index,
+            ..item.located(archive)
+        };
+        if !outputs.retries(&item) {
+            continue;
+        }
+
+        let timestamp = item.local_timestamp(opts.source.timezone);
+        let dir = root
+            .join(timestamp.format("%Y").to_string())
+            .join(timestamp.format("%m").to_string());
+        if !opts.dry_run && opts.layout == Layout::Album {
+            fs::create_dir_all(&dir).context(format!("create directory {}", dir.display()))?;
+        }
+        dated.push((item, dir));
+    }
+
+    progress.add_items(dated.len());
+    dated.par_iter().try_for_each(|(item, dir)| {
+        process_or_skip(item, dir, archive, opts, outputs, media, progress)
+    })
+}
@@ -0,0 +1,281 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Exports downloaded in other languages have their folders and files named in that language (e.g.
+// "fotos_y_videos/tus_videos.json" rather than "photos_and_videos/your_videos.json"). The rest of
+// fb-unarchive only knows the English names, so the archive translates between the two: the names
+// it's asked for into those it actually holds, and those it lists back into English. Numbered
+// files (e.g. "your_posts_1.json") are translated by the name in front of the number.
+
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+// The English names, each followed by its translations
+const BUILTIN: &[(&str, &[&str])] = &[
+    (
+        "photos_and_videos",
+        &[
+            "fotos_y_videos",
+            "fotos_und_videos",
+            "photos_et_vidéos",
+            "fotos_e_vídeos",
+            "foto_e_video",
+        ],
+    ),
+    ("album", &["álbum", "alben", "álbuns"]),
+    (
+        "your_videos",
+        &[
+            "tus_videos",
+            "deine_videos",
+            "vos_vidéos",
+            "seus_vídeos",
+            "i_tuoi_video",
+        ],
+    ),
+    (
+        "your_photos",
+        &[
+            "tus_fotos",
+            "deine_fotos",
+            "vos_photos",
+            "suas_fotos",
+            "le_tue_foto",
+        ],
+    ),
+    (
+        "your_uncategorized_photos",
+        &[
+            "tus_fotos_sin_categoría",
+            "deine_nicht_kategorisierten_fotos",
+            "vos_photos_non_classées",
+            "suas_fotos_sem_categoria",
+            "le_tue_foto_senza_categoria",
+        ],
+    ),
+    (
+        "photos_of_you",
+        &[
+            "fotos_tuyas",
+            "fotos_von_dir",
+            "photos_de_vous",
+            "fotos_suas",
+            "foto_di_te",
+        ],
+    ),
+    (
+        "messages",
+        &["mensajes", "nachrichten", "mensagens", "messaggi"],
+    ),
+    (
+        "inbox",
+        &[
+            "bandeja_de_entrada",
+            "posteingang",
+            "boîte_de_réception",
+            "caixa_de_entrada",
+            "posta_in_arrivo",
+        ],
+    ),
+    (
+        "message",
+        &["mensaje", "nachricht", "mensagem", "messaggio"],
+    ),
+    (
+        "posts",
+        &[
+            "publicaciones",
+            "beiträge",
+            "publications",
+            "publicações",
+            "post",
+        ],
+    ),
+    (
+        "your_posts",
+        &[
+            "tus_publicaciones",
+            "deine_beiträge",
+            "vos_publications",
+            "suas_publicações",
+            "i_tuoi_post",
+        ],
+    ),
+    ("stories", &["historias", "storys", "storie"]),
+    (
+        "archived_stories",
+        &[
+            "historias_archivadas",
+            "archivierte_storys",
+            "stories_archivées",
+            "stories_arquivados",
+            "storie_archiviate",
+        ],
+    ),
+    (
+        "profile_information",
+        &[
+            "información_del_perfil",
+            "profilinformationen",
+            "informations_du_profil",
+            "informações_do_perfil",
+            "informazioni_sul_profilo",
+        ],
+    ),
+    (
+        "profile_update_history",
+        &[
+            "historial_de_actualizaciones_del_perfil",
+            "profilaktualisierungsverlauf",
+            "historique_des_mises_à_jour_du_profil",
+            "histórico_de_atualizações_do_perfil",
+            "cronologia_degli_aggiornamenti_del_profilo",
+        ],
+    ),
+];
+
+// The languages of the built-in translations, in the order they're given for the names which have
+// all of them
+const LANGUAGES: &[&str] = &["Spanish", "German", "French", "Portuguese", "Italian"];
+
+// The language the English name has been translated into, if it's one which is built in.
+pub fn language(english: &str, name: &str) -> Option<&'static str> {
+    if english == name {
+        return Some("English");
+    }
+    let (_, translations) = BUILTIN.iter().find(|(known, _)| *known == english)?;
+    if translations.len() != LANGUAGES.len() {
+        return None;
+    }
+    let index = translations
+        .iter()
+        .position(|translation| *translation == name)?;
+    Some(LANGUAGES[index])
+}
+
+#[derive(Clone, Debug)]
+pub struct Names {
+    translations: HashMap<String, Vec<String>>,
+    english: HashMap<String, String>,
+}
+
+impl Default for Names {
+    fn default() -> Names {
+        let mut names = Names {
+            translations: HashMap::new(),
+            english: HashMap::new(),
+        };
+        for (english, translations) in BUILTIN {
+            for translation in *translations {
+                names.add(translation, english);
+            }
+        }
+        names
+    }
+}
+
+impl Names {
+    // The built-in names along with those in the TOML file, which maps each translated name to
+    // its English one (e.g. `fotos_y_videos = "photos_and_videos"`). Those in the file take
+    // precedence.
+    pub fn open(path: &Path) -> Result<Names> {
+        let table = fs::read_to_string(path)
+            .context(format!("read {}", path.display()))?
+            .parse::<toml::Table>()
+            .context(format!("parse {}", path.display()))?;
+
+        let mut names = Names::default();
+        for (translation, english) in table {
+            let english = english
+                .as_str()
+                .ok_or_else(|| anyhow!("{} in {} isn't a string", translation, path.display()))?;
+            names.add(&translation, english);
+        }
+        Ok(names)
+    }
+
+    fn add(&mut self, translation: &str, english: &str) {
+        let translations = self.translations.entry(english.to_string()).or_default();
+        translations.retain(|known| known != translation);
+        // Added in front, so that the user's names are tried first
+        translations.insert(0, translation.to_string());
+        self.english
+            .insert(translation.to_string(), english.to_string());
+    }
+
+    // The ways the English path might be named in the archive, starting with the path itself.
+    pub fn translations(&self, path: &Path) -> Vec<PathBuf> {
+        let mut paths = vec![PathBuf::new()];
+        for component in path.components() {
+            let name = match component {
+                Component::Normal(name) => name,
+                component => {
+                    paths.iter_mut().for_each(|path| path.push(component));
+                    continue;
+                }
+            };
+            let mut names = vec![name.to_os_string()];
+            if let Some((base, rest)) = split(name) {
+                if let Some(translations) = self.translations.get(base) {
+                    names.extend(
+                        translations
+                            .iter()
+                            .map(|base| format!("{}{}", base, rest).into()),
+                    );
+                }
+            }
+            paths = paths
+                .iter()
+                .flat_map(|path| names.iter().map(move |name| path.join(name)))
+                .collect();
+        }
+        paths
+    }
+
+    // The path with its translated names replaced by the English ones.
+    pub fn english(&self, path: &Path) -> PathBuf {
+        path.components()
+            .map(|component| match component {
+                Component::Normal(name) => match split(name) {
+                    Some((base, rest)) => match self.english.get(base) {
+                        Some(english) => format!("{}{}", english, rest).into(),
+                        None => name.to_os_string(),
+                    },
+                    None => name.to_os_string(),
+                },
+                component => component.as_os_str().to_os_string(),
+            })
+            .collect()
+    }
+}
+
+// Splits the name into the part which is translated and the rest: any number and extension (e.g.
+// "your_posts" and "_1.json").
+fn split(name: &OsStr) -> Option<(&str, &str)> {
+    let name = name.to_str()?;
+    let end = name.find('.').unwrap_or(name.len());
+    let stem = &name[..end];
+    let end = match stem.rsplit_once('_') {
+        Some((base, number))
+            if !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()) =>
+        {
+            base.len()
+        }
+        _ => end,
+    };
+    Some(name.split_at(end))
+}
@@ -0,0 +1,128 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Photos of the things listed for sale on Marketplace. The listings are in "marketplace", under
+// keys which differ between exports (e.g. "items_selling_v2"), so every list of them is read. Each
+// photo takes the listing's title as its description, and the time it was listed unless it has a
+// time of its own.
+
+use crate::{encoding, Album, Archive, Item};
+use anyhow::{Context, Result};
+use chrono::DateTime;
+use log::{debug, trace, warn};
+use serde::Deserialize;
+use serde_json::Value;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+#[derive(Deserialize, Debug)]
+struct Listing {
+    #[serde(default, deserialize_with = "encoding::deserialize_option")]
+    title: Option<String>,
+    #[serde(default, deserialize_with = "encoding::deserialize_option")]
+    description: Option<String>,
+    #[serde(default, alias = "created_timestamp", alias = "creation_timestamp")]
+    timestamp: Option<i64>,
+    #[serde(default = "Vec::new", alias = "media")]
+    photos: Vec<Photo>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Photo {
+    uri: PathBuf,
+    #[serde(default)]
+    creation_timestamp: Option<i64>,
+}
+
+// Reads the photos of every listing into an album named "Marketplace", if there are any.
+pub fn read_listings(archive: &Archive) -> Result<Option<Album>> {
+    debug!("Finding Marketplace listings");
+
+    let mut items = Vec::new();
+    for path in archive.list_recursive(Path::new("marketplace"))? {
+        if path.extension().and_then(|x| x.to_str()) != Some("json") {
+            continue;
+        }
+
+        trace!("Reading {}", path.display());
+        let json = archive.read(&path, |file| {
+            serde_json::from_reader::<_, Value>(BufReader::new(file))
+                .context(format!("parse json {}", path.display()))
+        })?;
+        let lists = match &json {
+            Value::Array(_) => vec![&json],
+            Value::Object(object) => object.values().filter(|v| v.is_array()).collect(),
+            _ => Vec::new(),
+        };
+        for list in lists {
+            // Other lists (e.g. of saved searches) aren't listings
+            match Vec::<Listing>::deserialize(list) {
+                Ok(listings) => items.extend(listings.into_iter().flat_map(photos)),
+                Err(err) => trace!("Not a list of listings in {}: {}", path.display(), err),
+            }
+        }
+    }
+
+    if items.is_empty() {
+        return Ok(None);
+    }
+    items.sort_by_key(|item| item.timestamp);
+    Ok(Some(Album {
+        name: "Marketplace".to_string(),
+        description: None,
+        cover_photo: None,
+        items,
+        file: None,
+    }))
+}
+
+fn photos(listing: Listing) -> Vec<Item> {
+    let Listing {
+        title,
+        description,
+        timestamp,
+        photos,
+    } = listing;
+    let description = match (title, description) {
+        (Some(title), Some(description)) if !description.is_empty() => {
+            Some(format!("{}\n{}", title, description))
+        }
+        (title, _) => title,
+    };
+
+    photos
+        .into_iter()
+        .filter_map(|photo| {
+            let timestamp = photo
+                .creation_timestamp
+                .or(timestamp)
+                .and_then(|timestamp| DateTime::from_timestamp(timestamp, 0))
+                .map(|timestamp| timestamp.naive_utc());
+            let timestamp = match timestamp {
+                Some(timestamp) => timestamp,
+                None => {
+                    warn!(
+                        "Couldn't find a timestamp for {}; skipping",
+                        photo.uri.display()
+                    );
+                    return None;
+                }
+            };
+            Some(Item {
+                description: description.clone().filter(|text| !text.is_empty()),
+                ..Item::new(photo.uri, timestamp)
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,174 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The processors which write each type of media. The built-in ones cover the photos, videos, and
+// audio clips found in exports, and others can be registered with a `Processor` to handle new
+// types or to change what's done with the existing ones (e.g. also generating a thumbnail). Those
+// are tried before the built-in ones, in the reverse order of their registration. With
+// `--no-modify`, the built-in ones copy every file as it is.
+
+use crate::output::{Outputs, Written};
+use crate::{Archive, Item, Options};
+use anyhow::{anyhow, Context as _, Result};
+use std::path::{Path, PathBuf};
+
+pub trait MediaProcessor: Send + Sync {
+    // Whether this processes items of the kind, which is the usual extension for the type of their
+    // contents (e.g. "jpg"), or their own lowercased extension if the type isn't recognized.
+    fn handles(&self, kind: &str) -> bool;
+
+    // Writes the item into `ctx.out_dir()`, returning the file which was written, or None if the
+    // item was skipped.
+    fn process(&self, item: &Item, ctx: &Context) -> Result<Option<Written>>;
+}
+
+// Everything a processor needs to know about the item being processed.
+pub struct Context<'a> {
+    pub(crate) kind: &'a str,
+    pub(crate) out_dir: &'a Path,
+    pub(crate) archive: &'a Archive,
+    pub(crate) opts: &'a Options,
+    pub(crate) outputs: &'a Outputs,
+}
+
+impl<'a> Context<'a> {
+    pub fn kind(&self) -> &str {
+        self.kind
+    }
+
+    pub fn out_dir(&self) -> &Path {
+        self.out_dir
+    }
+
+    pub fn archive(&self) -> &Archive {
+        self.archive
+    }
+
+    pub fn options(&self) -> &Options {
+        self.opts
+    }
+
+    // The path of the output file for the item, as named by `--name-format` and with the given
+    // extension (or that of the original). None means that the item should be skipped, according
+    // to `--on-conflict`.
+    pub fn out_path(&self, item: &Item, extension: Option<&str>) -> Result<Option<PathBuf>> {
+        let extension = extension.or_else(|| crate::out_extension(item, self.kind, self.opts));
+        crate::out_path(item, self.out_dir, self.opts, self.outputs, extension)
+    }
+
+    // Processes the item as it would be without any registered processors, for those which only
+    // add to what's normally done.
+    pub fn builtin(&self, item: &Item) -> Result<Option<Written>> {
+        match BUILTIN.iter().find(|builtin| builtin.handles(self.kind)) {
+            Some(builtin) => builtin.process(item, self),
+            None => Err(anyhow!("no built-in processor for {}", self.kind)),
+        }
+    }
+}
+
+type ProcessFn = fn(&Item, &str, &Path, &Archive, &Options, &Outputs) -> Result<Option<Written>>;
+
+struct Builtin {
+    kinds: &'static [&'static str],
+    what: &'static str,
+    process: ProcessFn,
+}
+
+const BUILTIN: &[Builtin] = &[
+    Builtin {
+        kinds: &["jpg"],
+        what: "process jpeg",
+        process: crate::process_jpeg,
+    },
+    Builtin {
+        kinds: &["png"],
+        what: "process png",
+        process: crate::process_png,
+    },
+    Builtin {
+        kinds: &["webp"],
+        what: "process webp",
+        process: crate::process_webp,
+    },
+    Builtin {
+        kinds: &["gif"],
+        what: "process gif",
+        process: crate::process_gif,
+    },
+    Builtin {
+        kinds: &["mp4", "flv"],
+        what: "process video",
+        process: crate::process_video,
+    },
+    Builtin {
+        kinds: &["aac", "m4a", "mp3"],
+        what: "process audio",
+        process: crate::process_audio,
+    },
+];
+
+impl MediaProcessor for Builtin {
+    fn handles(&self, kind: &str) -> bool {
+        self.kinds.contains(&kind)
+    }
+
+    fn process(&self, item: &Item, ctx: &Context) -> Result<Option<Written>> {
+        let (process, what): (ProcessFn, _) = if ctx.opts.no_modify {
+            (crate::process_verbatim, "copy verbatim")
+        } else {
+            (self.process, self.what)
+        };
+        process(
+            item,
+            ctx.kind,
+            ctx.out_dir,
+            ctx.archive,
+            ctx.opts,
+            ctx.outputs,
+        )
+        .context(what)
+    }
+}
+
+#[derive(Default)]
+pub struct Registry {
+    // The registered processors, most recent first
+    processors: Vec<Box<dyn MediaProcessor>>,
+}
+
+impl Registry {
+    pub fn register(&mut self, processor: Box<dyn MediaProcessor>) {
+        self.processors.insert(0, processor);
+    }
+
+    pub fn handles(&self, kind: &str) -> bool {
+        self.find(kind).is_some()
+    }
+
+    // Processes the item with the first processor which handles its kind.
+    pub fn process(&self, item: &Item, ctx: &Context) -> Result<Option<Written>> {
+        match self.find(ctx.kind) {
+            Some(processor) => processor.process(item, ctx),
+            None => Err(anyhow!("no processor for {}", ctx.kind)),
+        }
+    }
+
+    fn find(&self, kind: &str) -> Option<&dyn MediaProcessor> {
+        self.processors
+            .iter()
+            .map(|processor| processor.as_ref())
+            .chain(BUILTIN.iter().map(|builtin| builtin as &dyn MediaProcessor))
+            .find(|processor| processor.handles(kind))
+    }
+}
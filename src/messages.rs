@@ -0,0 +1,262 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Photos, videos, and voice clips sent in Messenger conversations. Each conversation has its own
+// folder (e.g. "messages/inbox/johnsmith_abc123/") holding one or more "message_N.json" files,
+// which are split arbitrarily once a conversation grows large. Attachments don't have timestamps
+// of their own, so they take those of the messages they were sent with.
+//
+// Each conversation is normally an album of its own, named after its title. They can instead be
+// arranged by the people they were with, in which case all of the conversations with the same
+// person (e.g. from before and after Messenger's switch to end-to-end encryption) become one.
+
+use crate::{encoding, posts, Album, Archive, Item};
+use anyhow::{anyhow, Context, Error, Result};
+use chrono::DateTime;
+use log::{debug, trace, warn};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MessengerFolders {
+    Conversation,
+    Person,
+}
+
+impl FromStr for MessengerFolders {
+    type Err = Error;
+
+    fn from_str(folders: &str) -> Result<MessengerFolders> {
+        match folders {
+            "conversation" => Ok(MessengerFolders::Conversation),
+            "person" => Ok(MessengerFolders::Person),
+            _ => Err(anyhow!("unknown arrangement of conversations {}", folders)),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct Thread {
+    #[serde(default, deserialize_with = "encoding::deserialize_option")]
+    title: Option<String>,
+    #[serde(default = "Vec::new")]
+    participants: Vec<Participant>,
+    #[serde(default = "Vec::new")]
+    messages: Vec<Message>,
+}
+
+// A conversation's attachments along with what it can be named after, from all of its files
+struct Conversation {
+    dir: PathBuf,
+    title: Option<String>,
+    participants: Vec<String>,
+    items: Vec<Item>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Participant {
+    #[serde(deserialize_with = "encoding::deserialize")]
+    name: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Message {
+    #[serde(default, deserialize_with = "encoding::deserialize_option")]
+    sender_name: Option<String>,
+    timestamp_ms: i64,
+    #[serde(default, deserialize_with = "encoding::deserialize_option")]
+    content: Option<String>,
+    #[serde(default = "Vec::new")]
+    photos: Vec<Attachment>,
+    #[serde(default = "Vec::new")]
+    videos: Vec<Attachment>,
+    #[serde(default = "Vec::new")]
+    gifs: Vec<Attachment>,
+    #[serde(default = "Vec::new")]
+    audio_files: Vec<Attachment>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Attachment {
+    uri: PathBuf,
+}
+
+// Reads every conversation in the archive as an album, arranged as given. The items are in the
+// order they were sent.
+pub fn read_threads(archive: &Archive, folders: MessengerFolders) -> Result<Vec<Album>> {
+    debug!("Finding conversations");
+
+    // Message files, grouped by the folder of the conversation they belong to
+    let mut threads = BTreeMap::<PathBuf, Vec<PathBuf>>::new();
+    for path in archive.list_recursive(Path::new("messages"))? {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if !(name.starts_with("message") && name.ends_with(".json")) {
+            continue;
+        }
+        if let Some(dir) = path.parent() {
+            threads.entry(dir.to_path_buf()).or_default().push(path);
+        }
+    }
+
+    let mut conversations = Vec::new();
+    for (dir, paths) in threads {
+        let mut title = None;
+        let mut participants = Vec::new();
+        let mut items = Vec::new();
+        for path in paths {
+            trace!("Reading {}", path.display());
+            let thread: Thread = archive.read(&path, |file| {
+                serde_json::from_reader(BufReader::new(file))
+                    .context(format!("parse json {}", path.display()))
+            })?;
+            title = title.or(thread.title.filter(|title| !title.is_empty()));
+            for participant in thread.participants {
+                if !participants.contains(&participant.name) {
+                    participants.push(participant.name);
+                }
+            }
+            items.extend(thread.messages.into_iter().flat_map(attachments));
+        }
+
+        if items.is_empty() {
+            trace!("No attachments in {}; skipping", dir.display());
+            continue;
+        }
+        conversations.push(Conversation {
+            dir,
+            title,
+            participants,
+            items,
+        });
+    }
+
+    let owner = match folders {
+        MessengerFolders::Conversation => None,
+        MessengerFolders::Person => owner(archive, &conversations)?,
+    };
+    let mut albums = Vec::<Album>::new();
+    for mut conversation in conversations {
+        let owner = owner.as_deref();
+        let others = conversation
+            .participants
+            .iter()
+            .filter(|name| Some(name.as_str()) != owner)
+            .count();
+        // Conversations with one other person are titled with their name as it was at the time,
+        // so they're named after the participant instead, which is the same in all of them
+        let title = match folders {
+            MessengerFolders::Person if others == 1 => None,
+            _ => conversation.title.take(),
+        };
+        let name = thread_name(title, &conversation.participants, owner).unwrap_or_else(|| {
+            let dir = conversation.dir.file_name().unwrap_or_default();
+            dir.to_string_lossy().into()
+        });
+
+        match albums.iter_mut().find(|album| album.name == name) {
+            Some(album) if folders == MessengerFolders::Person => {
+                album.items.append(&mut conversation.items)
+            }
+            _ => albums.push(Album {
+                name,
+                description: None,
+                cover_photo: None,
+                items: conversation.items,
+                file: None,
+            }),
+        }
+    }
+    for album in &mut albums {
+        album.items.sort_by_key(|item| item.timestamp);
+    }
+
+    Ok(albums)
+}
+
+// The name of the export's owner, from their profile or otherwise as the one person who was in
+// every conversation.
+fn owner(archive: &Archive, conversations: &[Conversation]) -> Result<Option<String>> {
+    if let Some(owner) = posts::read_owner(archive).context("read profile")? {
+        return Ok(Some(owner));
+    }
+    if conversations.len() < 2 {
+        return Ok(None);
+    }
+    let mut common = conversations[0].participants.iter().collect::<HashSet<_>>();
+    for conversation in &conversations[1..] {
+        common.retain(|name| conversation.participants.contains(name));
+    }
+    Ok(match common.len() {
+        1 => common.into_iter().next().cloned(),
+        _ => None,
+    })
+}
+
+// The title of the conversation or, for those without one, the names of the people in it other
+// than the owner.
+fn thread_name(
+    title: Option<String>,
+    participants: &[String],
+    owner: Option<&str>,
+) -> Option<String> {
+    title.or_else(|| {
+        let names = participants
+            .iter()
+            .map(String::as_str)
+            .filter(|name| Some(*name) != owner)
+            .collect::<Vec<_>>();
+        Some(names.join(", ")).filter(|names| !names.is_empty())
+    })
+}
+
+fn attachments(message: Message) -> Vec<Item> {
+    let timestamp = match DateTime::from_timestamp(
+        message.timestamp_ms.div_euclid(1000),
+        (message.timestamp_ms.rem_euclid(1000) * 1_000_000) as u32,
+    ) {
+        Some(timestamp) => timestamp.naive_utc(),
+        None => {
+            warn!(
+                "Invalid timestamp {} on message; skipping its attachments",
+                message.timestamp_ms
+            );
+            return Vec::new();
+        }
+    };
+
+    let Message {
+        sender_name,
+        content,
+        photos,
+        videos,
+        gifs,
+        audio_files,
+        ..
+    } = message;
+    photos
+        .into_iter()
+        .chain(videos)
+        .chain(gifs)
+        .chain(audio_files)
+        .map(|attachment| Item {
+            description: content.clone().filter(|content| !content.is_empty()),
+            author: sender_name.clone(),
+            by_others: true,
+            ..Item::new(attachment.uri, timestamp)
+        })
+        .collect()
+}
@@ -0,0 +1,367 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Rewrites the metadata of an MP4 while it's being copied. Only the movie box (moov) is held in
+// memory; the media data is streamed through untouched. If the movie box precedes the media data,
+// growing it shifts the media, so the chunk offset tables are adjusted to match.
+
+use anyhow::{anyhow, Context, Result};
+use chrono::naive::NaiveDateTime;
+use log::warn;
+use std::convert::TryFrom;
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::Path;
+
+// Seconds between the MP4 epoch (1904-01-01) and the Unix epoch
+const EPOCH_OFFSET: i64 = 2_082_844_800;
+
+const CONTAINERS: &[&[u8; 4]] = &[
+    b"moov", b"trak", b"mdia", b"minf", b"stbl", b"udta", b"edts", b"dinf", b"ilst",
+];
+
+pub const TAG_DATE: [u8; 4] = *b"\xa9day";
+pub const TAG_DESCRIPTION: [u8; 4] = *b"desc";
+pub const TAG_COMMENT: [u8; 4] = *b"\xa9cmt";
+pub const TAG_ARTIST: [u8; 4] = *b"\xa9ART";
+
+struct Atom {
+    kind: [u8; 4],
+    data: Vec<u8>,
+    children: Option<Vec<Atom>>,
+}
+
+// Copies the MP4 (`name`) from `reader` to `writer`, setting the creation and modification times of
+// the movie, its tracks, and their media, and replacing the given text tags (e.g. `TAG_DATE`) in
+// the iTunes-style metadata list. Movies whose user data can't be parsed only have their times set.
+pub fn copy(
+    reader: &mut dyn Read,
+    writer: &mut dyn Write,
+    name: &Path,
+    timestamp: NaiveDateTime,
+    tags: &[([u8; 4], String)],
+) -> Result<u64> {
+    let mut position = 0;
+    loop {
+        let mut header = [0; 8];
+        match reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err).context("read atom header"),
+        }
+
+        let mut size = u64::from(u32::from_be_bytes([
+            header[0], header[1], header[2], header[3],
+        ]));
+        let kind = [header[4], header[5], header[6], header[7]];
+        let mut header = header.to_vec();
+        if size == 1 {
+            let mut large = [0; 8];
+            reader.read_exact(&mut large).context("read atom size")?;
+            size = u64::from_be_bytes(large);
+            header.extend_from_slice(&large);
+        }
+
+        if size == 0 {
+            // The atom extends to the end of the file
+            writer.write_all(&header).context("write atom header")?;
+            position += header.len() as u64;
+            position += io::copy(reader, writer).context("copy atom")?;
+            break;
+        }
+
+        let body_len = size
+            .checked_sub(header.len() as u64)
+            .ok_or_else(|| anyhow!("invalid atom size {}", size))?;
+        if &kind == b"moov" {
+            let mut body = vec![0; usize::try_from(body_len).context("moov size")?];
+            reader.read_exact(&mut body).context("read moov")?;
+
+            let mut moov = Atom {
+                kind,
+                data: Vec::new(),
+                children: Some(parse(&body).context("parse moov")?),
+            };
+            set_times(&mut moov, timestamp)?;
+            if let Err(err) = set_tags(&mut moov, timestamp, tags) {
+                warn!(
+                    "Couldn't write the tags into {} ({:#}); only its times were set",
+                    name.display(),
+                    err
+                );
+            }
+
+            let encoded = encode(&moov);
+            let delta = encoded.len() as i64 - size as i64;
+            if delta != 0 {
+                shift_offsets(&mut moov, position + size, delta)?;
+            }
+            let encoded = encode(&moov);
+            writer.write_all(&encoded).context("write moov")?;
+            position += encoded.len() as u64;
+        } else {
+            writer.write_all(&header).context("write atom header")?;
+            let copied = io::copy(&mut reader.take(body_len), writer).context("copy atom")?;
+            if copied != body_len {
+                return Err(anyhow!("truncated atom"));
+            }
+            position += header.len() as u64 + copied;
+        }
+    }
+
+    writer.flush().context("flush")?;
+    Ok(position)
+}
+
+fn parse(mut data: &[u8]) -> Result<Vec<Atom>> {
+    let mut atoms = Vec::new();
+    while data.len() >= 8 {
+        let size = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+        let kind = [data[4], data[5], data[6], data[7]];
+        let (header_len, size) = match size {
+            0 => (8, data.len()),
+            1 if data.len() >= 16 => {
+                let mut large = [0; 8];
+                large.copy_from_slice(&data[8..16]);
+                (
+                    16,
+                    usize::try_from(u64::from_be_bytes(large)).context("atom size")?,
+                )
+            }
+            size => (8, size),
+        };
+        if size < header_len || size > data.len() {
+            return Err(anyhow!(
+                "invalid size {} for atom {}",
+                size,
+                String::from_utf8_lossy(&kind)
+            ));
+        }
+
+        let body = &data[header_len..size];
+        // Containers that can't be parsed (e.g. QuickTime's older user data formats) are kept as
+        // opaque data.
+        let nested = match &kind {
+            b"moov" => Some(parse(body)?),
+            kind if CONTAINERS.contains(&kind) => parse(body).ok(),
+            _ => None,
+        };
+        atoms.push(if let Some(nested) = nested {
+            Atom {
+                kind,
+                data: Vec::new(),
+                children: Some(nested),
+            }
+        } else if &kind == b"meta" && body.len() >= 4 && body[..4] == [0; 4] {
+            // Metadata atoms are a full box (version and flags) followed by children
+            Atom {
+                kind,
+                data: body[..4].to_vec(),
+                children: Some(parse(&body[4..])?),
+            }
+        } else {
+            Atom {
+                kind,
+                data: body.to_vec(),
+                children: None,
+            }
+        });
+        data = &data[size..];
+    }
+
+    Ok(atoms)
+}
+
+fn encode(atom: &Atom) -> Vec<u8> {
+    let mut body = atom.data.clone();
+    for child in atom.children.iter().flatten() {
+        body.extend(encode(child));
+    }
+
+    let mut encoded = Vec::with_capacity(body.len() + 16);
+    match u32::try_from(body.len() + 8) {
+        Ok(size) => {
+            encoded.extend_from_slice(&size.to_be_bytes());
+            encoded.extend_from_slice(&atom.kind);
+        }
+        Err(_) => {
+            encoded.extend_from_slice(&1u32.to_be_bytes());
+            encoded.extend_from_slice(&atom.kind);
+            encoded.extend_from_slice(&(body.len() as u64 + 16).to_be_bytes());
+        }
+    }
+    encoded.extend(body);
+    encoded
+}
+
+fn children(atom: &mut Atom) -> impl Iterator<Item = &mut Atom> {
+    atom.children.iter_mut().flatten()
+}
+
+fn child<'a>(atom: &'a mut Atom, kind: &[u8; 4]) -> Option<&'a mut Atom> {
+    children(atom).find(|child| &child.kind == kind)
+}
+
+// Finds or appends the child of the given kind, creating it with `new` if needed.
+fn child_or_insert<'a>(
+    atom: &'a mut Atom,
+    kind: &[u8; 4],
+    new: fn() -> Atom,
+) -> Result<&'a mut Atom> {
+    let children = match &mut atom.children {
+        Some(children) => children,
+        None => {
+            return Err(anyhow!(
+                "unsupported {} atom",
+                String::from_utf8_lossy(&atom.kind)
+            ))
+        }
+    };
+    let index = match children.iter().position(|child| &child.kind == kind) {
+        Some(index) => index,
+        None => {
+            children.push(new());
+            children.len() - 1
+        }
+    };
+    Ok(&mut children[index])
+}
+
+fn set_times(moov: &mut Atom, timestamp: NaiveDateTime) -> Result<()> {
+    let time = timestamp.and_utc().timestamp() + EPOCH_OFFSET;
+    let time = u64::try_from(time).context("timestamp before 1904")?;
+
+    if let Some(mvhd) = child(moov, b"mvhd") {
+        set_header_times(mvhd, time)?;
+    }
+    for trak in children(moov).filter(|child| &child.kind == b"trak") {
+        if let Some(tkhd) = child(trak, b"tkhd") {
+            set_header_times(tkhd, time)?;
+        }
+        if let Some(mdhd) = child(trak, b"mdia").and_then(|mdia| child(mdia, b"mdhd")) {
+            set_header_times(mdhd, time)?;
+        }
+    }
+
+    Ok(())
+}
+
+// The movie, track, and media headers all start with the version and flags, followed by the
+// creation and modification times (32 bits each in version 0, 64 bits in version 1).
+fn set_header_times(header: &mut Atom, time: u64) -> Result<()> {
+    let data = &mut header.data;
+    match data.first() {
+        Some(0) if data.len() >= 12 => {
+            let time = u32::try_from(time).context("timestamp after 2040")?;
+            data[4..8].copy_from_slice(&time.to_be_bytes());
+            data[8..12].copy_from_slice(&time.to_be_bytes());
+        }
+        Some(1) if data.len() >= 20 => {
+            data[4..12].copy_from_slice(&time.to_be_bytes());
+            data[12..20].copy_from_slice(&time.to_be_bytes());
+        }
+        _ => {
+            return Err(anyhow!(
+                "unsupported {} atom",
+                String::from_utf8_lossy(&header.kind)
+            ))
+        }
+    }
+    Ok(())
+}
+
+fn set_tags(moov: &mut Atom, timestamp: NaiveDateTime, tags: &[([u8; 4], String)]) -> Result<()> {
+    let date = (TAG_DATE, timestamp.format("%Y-%m-%dT%H:%M:%SZ").to_string());
+
+    let udta = child_or_insert(moov, b"udta", || Atom {
+        kind: *b"udta",
+        data: Vec::new(),
+        children: Some(Vec::new()),
+    })?;
+    let meta = child_or_insert(udta, b"meta", || Atom {
+        kind: *b"meta",
+        data: vec![0; 4],
+        children: Some(vec![Atom {
+            kind: *b"hdlr",
+            data: [&[0; 8][..], b"mdir", b"appl", &[0; 8][..], &[0][..]].concat(),
+            children: None,
+        }]),
+    })?;
+    let ilst = child_or_insert(meta, b"ilst", || Atom {
+        kind: *b"ilst",
+        data: Vec::new(),
+        children: Some(Vec::new()),
+    })?;
+
+    let items = ilst.children.get_or_insert_with(Vec::new);
+    for (kind, value) in std::iter::once(&date).chain(tags) {
+        items.retain(|item| &item.kind != kind);
+        items.push(Atom {
+            kind: *kind,
+            data: Vec::new(),
+            children: Some(vec![Atom {
+                kind: *b"data",
+                // Well-known type 1 (UTF-8) and the default locale
+                data: [&1u32.to_be_bytes()[..], &[0; 4][..], value.as_bytes()].concat(),
+                children: None,
+            }]),
+        });
+    }
+
+    Ok(())
+}
+
+// Adjusts every chunk offset which points at or beyond `start` by `delta` bytes.
+fn shift_offsets(atom: &mut Atom, start: u64, delta: i64) -> Result<()> {
+    for child in children(atom) {
+        shift_offsets(child, start, delta)?;
+    }
+
+    let width = match &atom.kind {
+        b"stco" => 4,
+        b"co64" => 8,
+        _ => return Ok(()),
+    };
+    let data = &mut atom.data;
+    if data.len() < 8 {
+        return Err(anyhow!("truncated chunk offset table"));
+    }
+    let count = u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as usize;
+    if data.len() < 8 + count * width {
+        return Err(anyhow!("truncated chunk offset table"));
+    }
+
+    for entry in data[8..8 + count * width].chunks_mut(width) {
+        let offset = match width {
+            4 => u64::from(u32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]])),
+            _ => u64::from_be_bytes([
+                entry[0], entry[1], entry[2], entry[3], entry[4], entry[5], entry[6], entry[7],
+            ]),
+        };
+        if offset < start {
+            continue;
+        }
+
+        let shifted = u64::try_from(offset as i64 + delta).context("chunk offset")?;
+        match width {
+            4 => entry.copy_from_slice(
+                &u32::try_from(shifted)
+                    .context("chunk offset overflow")?
+                    .to_be_bytes(),
+            ),
+            _ => entry.copy_from_slice(&shifted.to_be_bytes()),
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,128 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Templates for the names of the output files (e.g. "{date}_{time}_{original}"). The extension
+// isn't part of the template; it's always appended to the result. `--rename timestamp` is a
+// shorthand for naming files like cameras do (e.g. "20160814_153012.jpg").
+
+use crate::{Item, Timezone};
+use anyhow::{anyhow, Error, Result};
+use std::str::FromStr;
+
+#[derive(Debug)]
+enum Token {
+    Literal(String),
+    Date,
+    Time,
+    Timestamp,
+    Album,
+    Index,
+    Original,
+}
+
+#[derive(Debug)]
+pub struct NameFormat(Vec<Token>);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rename {
+    // Named after when they were taken, with a number added to any taken in the same second
+    Timestamp,
+}
+
+impl FromStr for Rename {
+    type Err = Error;
+
+    fn from_str(rename: &str) -> Result<Rename> {
+        match rename {
+            "timestamp" => Ok(Rename::Timestamp),
+            _ => Err(anyhow!("unknown renaming mode {}", rename)),
+        }
+    }
+}
+
+impl FromStr for NameFormat {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<NameFormat> {
+        let mut tokens = Vec::new();
+        let mut rest = format;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                tokens.push(Token::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| anyhow!("unterminated token in {:?}", format))?
+                + start;
+            tokens.push(match &rest[start + 1..end] {
+                "date" => Token::Date,
+                "time" => Token::Time,
+                "timestamp" => Token::Timestamp,
+                "album" => Token::Album,
+                "index" => Token::Index,
+                "original" => Token::Original,
+                token => return Err(anyhow!("unknown token {{{}}}", token)),
+            });
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            tokens.push(Token::Literal(rest.to_string()));
+        }
+
+        if tokens.is_empty() {
+            return Err(anyhow!("empty name format"));
+        }
+        Ok(NameFormat(tokens))
+    }
+}
+
+impl NameFormat {
+    // Whether the files keep their original names, as they do unless `--name-format` is given.
+    pub fn is_original(&self) -> bool {
+        matches!(self.0.as_slice(), [Token::Original])
+    }
+
+    // The name (without extension) for the given item. Path separators are replaced so that the
+    // result is always a single file name.
+    pub fn format(&self, item: &Item, timezone: Timezone) -> String {
+        let mut name = String::new();
+        for token in &self.0 {
+            match token {
+                Token::Literal(text) => name.push_str(text),
+                Token::Date => name.push_str(
+                    &item
+                        .local_timestamp(timezone)
+                        .format("%Y-%m-%d")
+                        .to_string(),
+                ),
+                Token::Time => {
+                    name.push_str(&item.local_timestamp(timezone).format("%H%M").to_string())
+                }
+                Token::Timestamp => name.push_str(
+                    &item
+                        .local_timestamp(timezone)
+                        .format("%Y%m%d_%H%M%S")
+                        .to_string(),
+                ),
+                Token::Album => name.push_str(item.album.as_deref().unwrap_or("videos")),
+                Token::Index => name.push_str(&format!("{:04}", item.index + 1)),
+                Token::Original => {
+                    let path = crate::download::without_query(&item.path);
+                    name.push_str(&path.file_stem().unwrap_or_default().to_string_lossy())
+                }
+            }
+        }
+        name.replace(['/', '\\'], "_")
+    }
+}
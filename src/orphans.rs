@@ -0,0 +1,139 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Photos and videos in the export which none of its JSON refers to. Exports often hold media which
+// isn't listed anywhere (e.g. left over from deleted albums), which would otherwise be left behind.
+// Every section is read, regardless of which are being included, so that only the files which
+// really are unlisted are picked up. Having nothing else to go on, each is dated by the time
+// recorded in the photo itself or, failing that, the time the file was last modified.
+
+use crate::messages::{self, MessengerFolders};
+use crate::{
+    album_paths, embedded_date, marketplace, posts, read_album, read_tagged, read_unsorted,
+    read_videos, sniff, Album, Archive, Item,
+};
+use anyhow::{anyhow, Context, Result};
+use chrono::NaiveDateTime;
+use log::{debug, trace, warn};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+// The folders of a Facebook export which hold photos and videos
+const DIRS: &[&str] = &[
+    "photos_and_videos",
+    "posts",
+    "stories",
+    "groups",
+    "events",
+    "pages",
+    "marketplace",
+    "messages",
+    "profile_information",
+];
+
+// Reads the media which isn't referred to by anything into an album named "Orphans", if there is
+// any.
+pub fn read_orphans(archive: &Archive) -> Result<Option<Album>> {
+    debug!("Finding orphaned media");
+
+    let referenced = referenced(archive).context("read references")?;
+    let mut items = Vec::new();
+    for dir in DIRS {
+        for path in archive.list_recursive(Path::new(dir))? {
+            let extension = path.extension().and_then(|x| x.to_str());
+            if extension.and_then(sniff::from_extension).is_none() || referenced.contains(&path) {
+                continue;
+            }
+
+            trace!("Adding orphan {}", path.display());
+            match date(archive, &path) {
+                Ok(timestamp) => items.push(Item::new(path, timestamp)),
+                Err(err) => warn!("Skipping orphan {}: {:#}", path.display(), err),
+            }
+        }
+    }
+
+    if items.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(Album {
+        name: "Orphans".to_string(),
+        description: None,
+        cover_photo: None,
+        items,
+        file: None,
+    }))
+}
+
+// Every file which is referred to by the export, whether or not it's being included. Files which
+// can't be read are skipped, so that the files they refer to are taken to be orphans rather than
+// stopping the run.
+fn referenced(archive: &Archive) -> Result<HashSet<PathBuf>> {
+    let mut albums = Vec::new();
+    for path in album_paths(archive)? {
+        albums.extend(or_warn(
+            read_album(archive, &path).context(format!("read_album {}", path.display())),
+        ));
+    }
+    albums.extend(or_warn(
+        posts::read_profile(archive).context("read profile"),
+    ));
+    albums.extend(or_warn(read_unsorted(archive).context("read unsorted")));
+    albums.extend(or_warn(read_tagged(archive).context("read tagged")));
+    albums.extend(or_warn(
+        marketplace::read_listings(archive).context("read marketplace"),
+    ));
+    for (section, _) in posts::SECTIONS {
+        albums.extend(or_warn(
+            posts::read_section(archive, section).context(format!("read {}", section)),
+        ));
+    }
+    albums.extend(or_warn(
+        messages::read_threads(archive, MessengerFolders::Conversation).context("read messages"),
+    ));
+
+    let mut paths = HashSet::new();
+    for album in albums {
+        paths.extend(album.cover_photo.map(|cover| cover.uri));
+        paths.extend(album.items.into_iter().map(|item| item.path));
+    }
+    for items in [
+        or_warn(read_videos(archive).context("read videos")),
+        or_warn(posts::read_posts(archive).context("read posts")),
+        or_warn(posts::read_stories(archive).context("read stories")),
+    ] {
+        paths.extend(items.into_iter().map(|item| item.path));
+    }
+    Ok(paths)
+}
+
+// What was read, or nothing if it couldn't be.
+fn or_warn<T: Default>(read: Result<T>) -> T {
+    read.unwrap_or_else(|err| {
+        warn!("Some files may be taken for orphans: {:#}", err);
+        T::default()
+    })
+}
+
+// The time the photo was taken, if it records it, or else when the file was last modified.
+fn date(archive: &Archive, path: &Path) -> Result<NaiveDateTime> {
+    match embedded_date(path, archive) {
+        Ok(Some(date)) => return Ok(date),
+        Ok(None) => {}
+        Err(err) => trace!("Couldn't read the date in {}: {:#}", path.display(), err),
+    }
+    archive
+        .modified(path)?
+        .ok_or_else(|| anyhow!("no modification time"))
+}
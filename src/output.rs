@@ -0,0 +1,1491 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Bookkeeping for the files being written. Items are processed concurrently and several of them
+// can end up with the same output path (e.g. when albums are flattened by date), so each path is
+// claimed before it's written.
+
+use crate::bundle::Bundle;
+use crate::download;
+use crate::events;
+use crate::sanitize::Sanitize;
+use crate::stats::{self, Summary};
+use crate::Item;
+use crate::{catalog, gallery};
+use anyhow::{anyhow, Context, Error, Result};
+use chrono::{NaiveDate, NaiveDateTime};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+pub type Hash = [u8; 32];
+
+// Added to the names of files which are still being written
+pub const PARTIAL_EXTENSION: &str = ".fb-unarchive-partial";
+
+// Where damaged files are set aside, within the output directory. It's named like the run's own
+// records so that it can't be mistaken for an album.
+pub const QUARANTINE_DIR: &str = ".fb-unarchive-quarantine";
+
+// Identifies an item across exports by its album, file name, and creation time, since the same
+// photo isn't always byte-for-byte identical in each of them.
+pub type Key = (Option<String>, OsString, NaiveDateTime);
+
+pub fn key(item: &Item) -> Key {
+    (
+        item.album.clone(),
+        item.path.file_name().unwrap_or_default().to_os_string(),
+        item.timestamp,
+    )
+}
+
+// What to do when an output path has already been written, either earlier in this run or by a
+// previous one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Conflict {
+    Rename,
+    Skip,
+    Overwrite,
+    Error,
+}
+
+impl FromStr for Conflict {
+    type Err = Error;
+
+    fn from_str(conflict: &str) -> Result<Conflict> {
+        match conflict {
+            "rename" => Ok(Conflict::Rename),
+            "skip" => Ok(Conflict::Skip),
+            "overwrite" => Ok(Conflict::Overwrite),
+            "error" => Ok(Conflict::Error),
+            _ => Err(anyhow!("unknown conflict strategy {:?}", conflict)),
+        }
+    }
+}
+
+// What to do with items whose contents are identical to one which has already been written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dedupe {
+    Skip,
+    Link,
+}
+
+impl FromStr for Dedupe {
+    type Err = Error;
+
+    fn from_str(dedupe: &str) -> Result<Dedupe> {
+        match dedupe {
+            "skip" => Ok(Dedupe::Skip),
+            "link" => Ok(Dedupe::Link),
+            _ => Err(anyhow!("unknown deduplication strategy {:?}", dedupe)),
+        }
+    }
+}
+
+// How to link outputs to the originals in the archive, rather than copying them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Link {
+    Hard,
+    Sym,
+    Reflink,
+}
+
+impl FromStr for Link {
+    type Err = Error;
+
+    fn from_str(link: &str) -> Result<Link> {
+        match link {
+            "hard" => Ok(Link::Hard),
+            "sym" => Ok(Link::Sym),
+            "reflink" => Ok(Link::Reflink),
+            _ => Err(anyhow!("unknown link type {:?}", link)),
+        }
+    }
+}
+
+// The file written for an item. During a dry run, nothing is actually written.
+#[derive(Debug)]
+pub struct Written {
+    pub path: PathBuf,
+    pub bytes: u64,
+    // The thumbnail of the output file, if one was written
+    pub thumbnail: Option<PathBuf>,
+    // Whether the output is the original file itself, hard linked into the output, which mustn't
+    // be changed (e.g. its times)
+    pub shared: bool,
+}
+
+impl Written {
+    pub fn new(path: PathBuf, bytes: u64) -> Written {
+        Written {
+            path,
+            bytes,
+            thumbnail: None,
+            shared: false,
+        }
+    }
+
+    // The output, which was linked to the original in the archive in the given way, if it was.
+    pub fn linked(path: PathBuf, bytes: u64, link: Option<Link>) -> Written {
+        Written {
+            shared: link == Some(Link::Hard),
+            ..Written::new(path, bytes)
+        }
+    }
+
+    pub fn planned(path: PathBuf) -> Written {
+        Written::new(path, 0)
+    }
+}
+
+// An item which has been written. Items are identified by their album as well as their path,
+// since the same file can be listed in several albums.
+#[derive(Clone, Deserialize, Serialize)]
+struct Completed {
+    album: Option<String>,
+    path: PathBuf,
+    output: PathBuf,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+struct State {
+    completed: Vec<Completed>,
+}
+
+// The journal is appended to as items are processed, so that an interrupted run can be resumed.
+// Outputs are recorded when they are claimed so that any which were left half-written can be
+// cleaned up.
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Entry {
+    Claimed(PathBuf),
+    Completed(Completed),
+}
+
+// Describes an output file, for the benefit of other tools
+#[derive(Serialize)]
+struct ManifestEntry {
+    output: PathBuf,
+    source: PathBuf,
+    album: Option<String>,
+    // The type of the file, as recognized from its contents where it could be (e.g. "jpg" or "mp4")
+    kind: String,
+    timestamp: String,
+    description: Option<String>,
+    comments: usize,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    people: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    location: Option<(f64, f64)>,
+    // Whether the location was found from the place the item was tagged with
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    approximate_location: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thumbnail: Option<PathBuf>,
+}
+
+// Everything known about an output file, for the gallery and the catalog
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Record {
+    pub output: PathBuf,
+    pub source: PathBuf,
+    pub album: Option<String>,
+    pub timestamp: NaiveDateTime,
+    pub description: Option<String>,
+    pub people: Vec<String>,
+    // The author, text, and time of each comment
+    pub comments: Vec<(String, String, NaiveDateTime)>,
+    pub location: Option<(f64, f64)>,
+    pub thumbnail: Option<PathBuf>,
+}
+
+// An output path which was already taken, and what was done about it
+#[derive(Serialize)]
+struct ConflictEntry {
+    album: Option<String>,
+    source: PathBuf,
+    path: PathBuf,
+    resolution: String,
+}
+
+// How to print the plan of a dry run
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PlanFormat {
+    Table,
+    Json,
+}
+
+impl FromStr for PlanFormat {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<PlanFormat> {
+        match format {
+            "table" => Ok(PlanFormat::Table),
+            "json" => Ok(PlanFormat::Json),
+            _ => Err(anyhow!("unknown plan format {:?}", format)),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct PlannedAlbum<'a> {
+    name: Option<&'a str>,
+    items: Vec<&'a ManifestEntry>,
+    conflicts: Vec<&'a ConflictEntry>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Failure {
+    album: Option<String>,
+    path: PathBuf,
+    error: String,
+}
+
+struct Duplicate {
+    hash: Hash,
+    source: PathBuf,
+    // Where the duplicate is linked to the original, if it is
+    target: Option<PathBuf>,
+}
+
+// The names of the albums in an export, each of which gets a directory of its own.
+#[derive(Default)]
+pub struct AlbumNames {
+    // The directories used so far, in lowercase for case-insensitive file systems
+    used: HashSet<String>,
+}
+
+impl AlbumNames {
+    // The album's own name, unless another album in `root` already has its directory, as `dir`
+    // names it (e.g. several "Mobile Uploads", or names which are only told apart by characters
+    // which aren't allowed or by what's cut off of them). Those are told apart by the (local) date
+    // of their first item or, failing that, a number.
+    pub fn unique(
+        &mut self,
+        root: &Path,
+        name: &str,
+        first: Option<NaiveDate>,
+        dir: &dyn Fn(&str) -> String,
+    ) -> String {
+        let used = &mut self.used;
+        let mut claim =
+            |name: &str| used.insert(root.join(dir(name)).to_string_lossy().to_lowercase());
+        if claim(name) {
+            return name.to_string();
+        }
+
+        let dated = first.map(|first| format!("{} ({})", name, first.format("%Y-%m-%d")));
+        dated
+            .into_iter()
+            .chain((2..).map(|n| format!("{} ({})", name, n)))
+            .find(|renamed| claim(renamed))
+            .expect("unbounded range")
+    }
+}
+
+pub struct Outputs {
+    conflict: Conflict,
+    // Whether renamed files are numbered like "name_1" rather than "name (1)"
+    camera_suffixes: bool,
+    // Whether the outputs are removed from the staging directory once they've been uploaded, in
+    // which case those recorded by a previous run are taken to have been written
+    staged: bool,
+    claimed: Mutex<HashSet<PathBuf>>,
+    // The output of the first item with each hash, once it has been written
+    originals: Mutex<HashMap<Hash, Option<PathBuf>>>,
+    // The hash of the first item with each album, name, and creation time, when merging exports
+    keys: Mutex<HashMap<Key, Hash>>,
+    duplicates: Mutex<Vec<Duplicate>>,
+    // The album names used by the current export and those which had to be renamed
+    albums: Mutex<AlbumNames>,
+    renamed_albums: Mutex<Vec<(String, String)>>,
+    // The items written by a previous run and those written by this one
+    previous: HashMap<(Option<String>, PathBuf), PathBuf>,
+    completed: Mutex<Vec<Completed>>,
+    journal: Option<Mutex<File>>,
+    failures: Mutex<Vec<Failure>>,
+    // The items which failed in the previous run, when only those are being retried
+    retrying: Option<HashSet<(Option<String>, PathBuf)>>,
+    // Items whose files aren't in the archive, by album
+    missing: Mutex<Vec<(Option<String>, PathBuf)>>,
+    // Items which are URLs that weren't downloaded, by album
+    remote: Mutex<Vec<(Option<String>, PathBuf)>>,
+    // Items which were damaged and why, which are set aside rather than written
+    quarantined: Mutex<Vec<(PathBuf, String)>>,
+    manifest: Mutex<Vec<ManifestEntry>>,
+    conflicts: Mutex<Vec<ConflictEntry>>,
+    // Items which weren't processed because their type wasn't recognized
+    unrecognized: AtomicUsize,
+    bytes: AtomicU64,
+    // The hashes of the output files, if they are being checksummed
+    checksums: Option<Mutex<Vec<(PathBuf, Hash)>>>,
+    // The full metadata of the output files, if the gallery or catalog is being generated
+    records: Option<Mutex<Vec<Record>>>,
+    // Where the output files are sent to be uploaded as they're written, if the output is remote
+    uploads: Option<mpsc::Sender<PathBuf>>,
+    // The archive which the output files are added to as they're written, if it isn't a directory
+    bundle: Option<Arc<Bundle>>,
+}
+
+impl Outputs {
+    pub fn new(conflict: Conflict) -> Outputs {
+        Outputs {
+            conflict,
+            camera_suffixes: false,
+            staged: false,
+            claimed: Mutex::new(HashSet::new()),
+            originals: Mutex::new(HashMap::new()),
+            keys: Mutex::new(HashMap::new()),
+            duplicates: Mutex::new(Vec::new()),
+            albums: Mutex::new(AlbumNames::default()),
+            renamed_albums: Mutex::new(Vec::new()),
+            previous: HashMap::new(),
+            completed: Mutex::new(Vec::new()),
+            journal: None,
+            failures: Mutex::new(Vec::new()),
+            retrying: None,
+            missing: Mutex::new(Vec::new()),
+            remote: Mutex::new(Vec::new()),
+            quarantined: Mutex::new(Vec::new()),
+            manifest: Mutex::new(Vec::new()),
+            conflicts: Mutex::new(Vec::new()),
+            unrecognized: AtomicUsize::new(0),
+            bytes: AtomicU64::new(0),
+            checksums: None,
+            records: None,
+            uploads: None,
+            bundle: None,
+        }
+    }
+
+    // Numbers the files which are renamed to avoid a conflict as cameras do (e.g.
+    // "20160814_153012_1.jpg").
+    pub fn use_camera_suffixes(&mut self) {
+        self.camera_suffixes = true;
+    }
+
+    pub fn use_staging(&mut self) {
+        self.staged = true;
+    }
+
+    pub fn enable_checksums(&mut self) {
+        self.checksums = Some(Mutex::new(Vec::new()));
+    }
+
+    pub fn enable_uploads(&mut self, uploads: mpsc::Sender<PathBuf>) {
+        self.uploads = Some(uploads);
+    }
+
+    // Stops sending the output files to be uploaded, so that the uploads can finish.
+    pub fn disable_uploads(&mut self) {
+        self.uploads = None;
+    }
+
+    pub fn enable_bundle(&mut self, bundle: Arc<Bundle>) {
+        self.bundle = Some(bundle);
+    }
+
+    pub fn enable_records(&mut self) {
+        self.records = Some(Mutex::new(Vec::new()));
+    }
+
+    // Writes the gallery of everything written so far.
+    pub fn write_gallery(&self, root: &Path) -> Result<()> {
+        match &self.records {
+            Some(records) => gallery::write(root, &records.lock().expect("records")),
+            None => Ok(()),
+        }
+    }
+
+    // Writes the catalog of everything written so far, with paths relative to the output
+    // directory.
+    pub fn write_catalog(&self, path: &Path, root: &Path) -> Result<()> {
+        match &self.records {
+            Some(records) => catalog::write(path, root, &records.lock().expect("records")),
+            None => Ok(()),
+        }
+    }
+
+    // Hashes the output file, if checksums are enabled. Outputs which don't exist yet (e.g. during
+    // a dry run) are skipped.
+    fn checksum(&self, output: &Path) -> Result<()> {
+        let checksums = match &self.checksums {
+            Some(checksums) => checksums,
+            None => return Ok(()),
+        };
+        let mut file = match File::open(output) {
+            Ok(file) => BufReader::new(file),
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err).context(format!("open {}", output.display())),
+        };
+        let hash = hash(&mut file).context(format!("hash {}", output.display()))?;
+        checksums
+            .lock()
+            .expect("checksums")
+            .push((output.to_path_buf(), hash));
+        Ok(())
+    }
+
+    // Writes the checksums in the format understood by `sha256sum --check`, with paths relative
+    // to the output directory (where the file is expected to be).
+    pub fn write_checksums(&self, path: &Path, root: &Path) -> Result<()> {
+        let checksums = match &self.checksums {
+            Some(checksums) => checksums.lock().expect("checksums"),
+            None => return Ok(()),
+        };
+        let mut lines = checksums
+            .iter()
+            .map(|(output, hash)| {
+                let hex = hash
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<String>();
+                let output = output.strip_prefix(root).unwrap_or(output);
+                format!("{}  {}\n", hex, output.display())
+            })
+            .collect::<Vec<_>>();
+        lines.sort_by(|a, b| a[66..].cmp(&b[66..]));
+
+        fs::write(path, lines.concat()).context(format!("write {}", path.display()))
+    }
+
+    // Loads the items completed by an interrupted run, returning the outputs which it didn't finish
+    // writing (see `remove_incomplete`).
+    pub fn load_journal(&mut self, path: &Path) -> Result<Vec<PathBuf>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                debug!("Nothing to resume at {}", path.display());
+                return Ok(Vec::new());
+            }
+            Err(err) => return Err(err).context(format!("open {}", path.display())),
+        };
+        let mut claimed = HashSet::new();
+        for line in BufReader::new(file).lines() {
+            let line = line.context("read journal")?;
+            // The last line may have been cut short when the run was interrupted
+            match serde_json::from_str(&line) {
+                Ok(Entry::Claimed(output)) => {
+                    claimed.insert(output);
+                }
+                Ok(Entry::Completed(completed)) => {
+                    claimed.remove(&completed.output);
+                    self.previous
+                        .insert((completed.album, completed.path), completed.output);
+                }
+                Err(err) => warn!("Ignoring journal entry {:?}: {}", line, err),
+            }
+        }
+
+        Ok(claimed.into_iter().collect())
+    }
+
+    // Starts journaling to the given file, appending to it if a run is being resumed.
+    pub fn open_journal(&mut self, path: &Path, resume: bool) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).context(format!("create directory {}", dir.display()))?;
+        }
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(resume)
+            .truncate(!resume)
+            .open(path)
+            .context(format!("open {}", path.display()))?;
+        self.journal = Some(Mutex::new(file));
+        Ok(())
+    }
+
+    fn journal(&self, entry: &Entry) -> Result<()> {
+        if let Some(journal) = &self.journal {
+            // Each entry is written with a single call so that a crash can only cut short the last
+            let mut line = serde_json::to_vec(entry).context("serialize journal entry")?;
+            line.push(b'\n');
+            journal
+                .lock()
+                .expect("journal")
+                .write_all(&line)
+                .context("write journal")?;
+        }
+        Ok(())
+    }
+
+    // Loads the items written by a previous run, if there was one.
+    pub fn load_state(&mut self, path: &Path) -> Result<()> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                debug!("No previous state at {}", path.display());
+                return Ok(());
+            }
+            Err(err) => return Err(err).context(format!("open {}", path.display())),
+        };
+        let state: State = serde_json::from_reader(BufReader::new(file))
+            .context(format!("parse {}", path.display()))?;
+
+        self.previous = state
+            .completed
+            .into_iter()
+            .map(|completed| ((completed.album, completed.path), completed.output))
+            .collect();
+        Ok(())
+    }
+
+    // Loads the items which failed in the previous run, after which only those are retried.
+    pub fn load_failures(&mut self, path: &Path) -> Result<()> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                return Err(anyhow!(
+                    "there's nothing to retry (there's no {})",
+                    path.display()
+                ))
+            }
+            Err(err) => return Err(err).context(format!("open {}", path.display())),
+        };
+        let failures: Vec<Failure> = serde_json::from_reader(BufReader::new(file))
+            .context(format!("parse {}", path.display()))?;
+
+        info!("Retrying {} items which failed", failures.len());
+        self.retrying = Some(
+            failures
+                .into_iter()
+                .map(|failure| (failure.album, failure.path))
+                .collect(),
+        );
+        Ok(())
+    }
+
+    // Whether the item is to be processed: either it failed before, or nothing is being retried.
+    // Those written before are kept too, and passed over as they are with `--skip-existing`, so
+    // that the state, manifest, and checksums still cover them.
+    pub fn retries(&self, item: &Item) -> bool {
+        let key = (item.album.clone(), item.path.clone());
+        match &self.retrying {
+            Some(retrying) => retrying.contains(&key) || self.previous.contains_key(&key),
+            None => true,
+        }
+    }
+
+    // Whether only the items which failed before are being processed, in which case the reports
+    // about the rest are left as the previous run wrote them.
+    pub fn is_retrying(&self) -> bool {
+        self.retrying.is_some()
+    }
+
+    pub fn save_state(&self, path: &Path) -> Result<()> {
+        let state = State {
+            completed: self.completed.lock().expect("completed").clone(),
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).context(format!("create directory {}", dir.display()))?;
+        }
+        serde_json::to_writer(
+            BufWriter::new(File::create(path).context(format!("create {}", path.display()))?),
+            &state,
+        )
+        .context(format!("write {}", path.display()))
+    }
+
+    // The output written for the item by a previous run, whether or not it still exists.
+    pub fn recorded(&self, item: &Item) -> Option<PathBuf> {
+        self.previous
+            .get(&(item.album.clone(), item.path.clone()))
+            .cloned()
+    }
+
+    // The output written for the item by a previous run, as long as it still exists (or was
+    // uploaded, which is all that the staging directory records).
+    pub fn previous(&self, item: &Item) -> Option<PathBuf> {
+        self.previous
+            .get(&(item.album.clone(), item.path.clone()))
+            .filter(|output| self.staged || output.exists())
+            .cloned()
+    }
+
+    // The files in the archive which were written by a previous run.
+    pub fn previous_sources(&self) -> HashSet<&Path> {
+        self.previous
+            .keys()
+            .map(|(_, path)| path.as_path())
+            .collect()
+    }
+
+    // Records the item as written, as a file of the given type.
+    pub fn complete(&self, item: &Item, written: &Written, kind: &str) -> Result<()> {
+        let completed = Completed {
+            album: item.album.clone(),
+            path: item.path.clone(),
+            output: written.path.clone(),
+        };
+        self.checksum(&written.path)?;
+        self.bytes.fetch_add(written.bytes, Ordering::Relaxed);
+        self.journal(&Entry::Completed(completed.clone()))?;
+        // Outputs which a previous run uploaded are already gone from the staging directory
+        if let (Some(uploads), true) = (&self.uploads, written.path.exists()) {
+            uploads
+                .blocking_send(written.path.clone())
+                .map_err(|_| anyhow!("uploads stopped"))?;
+        }
+        if let Some(bundle) = &self.bundle {
+            bundle.add_file(&written.path)?;
+        }
+        self.completed.lock().expect("completed").push(completed);
+        if let Some(records) = &self.records {
+            records.lock().expect("records").push(Record {
+                output: written.path.clone(),
+                source: item.path.clone(),
+                album: item.album.clone(),
+                timestamp: item.timestamp,
+                description: item.description.clone(),
+                people: item.tags.iter().map(|tag| tag.name.clone()).collect(),
+                comments: item
+                    .comments
+                    .iter()
+                    .filter_map(|comment| {
+                        let text = comment.comment.clone()?;
+                        Some((comment.author.clone(), text, comment.timestamp))
+                    })
+                    .collect(),
+                location: item
+                    .location()
+                    .map(|location| (location.latitude, location.longitude)),
+                thumbnail: written.thumbnail.clone(),
+            });
+        }
+
+        self.manifest.lock().expect("manifest").push(ManifestEntry {
+            output: written.path.clone(),
+            source: item.path.clone(),
+            album: item.album.clone(),
+            kind: kind.to_string(),
+            timestamp: item.timestamp.format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+            description: item.description.clone(),
+            comments: item.comments.len(),
+            people: item.tags.iter().map(|tag| tag.name.clone()).collect(),
+            location: item
+                .location()
+                .map(|location| (location.latitude, location.longitude)),
+            approximate_location: item.location().is_some_and(|location| location.approximate),
+            thumbnail: written.thumbnail.clone(),
+        });
+        Ok(())
+    }
+
+    // The files which have been written so far, along with their albums and timestamps.
+    pub fn written(&self) -> Vec<(PathBuf, Option<String>, String)> {
+        self.manifest
+            .lock()
+            .expect("manifest")
+            .iter()
+            .map(|entry| {
+                (
+                    entry.output.clone(),
+                    entry.album.clone(),
+                    entry.timestamp.clone(),
+                )
+            })
+            .collect()
+    }
+
+    // Writes the manifest of every output file, with paths relative to the output directory.
+    pub fn write_manifest(&self, path: &Path, root: &Path) -> Result<()> {
+        let mut manifest = self.manifest.lock().expect("manifest");
+        for entry in manifest.iter_mut() {
+            if let Ok(relative) = entry.output.strip_prefix(root) {
+                entry.output = relative.to_path_buf();
+            }
+            if let Some(Ok(relative)) = entry.thumbnail.as_ref().map(|x| x.strip_prefix(root)) {
+                entry.thumbnail = Some(relative.to_path_buf());
+            }
+        }
+        manifest.sort_by(|a, b| a.output.cmp(&b.output));
+
+        serde_json::to_writer_pretty(
+            BufWriter::new(File::create(path).context(format!("create {}", path.display()))?),
+            &*manifest,
+        )
+        .context(format!("write {}", path.display()))
+    }
+
+    // Claims the path for writing, returning the path to use instead (when renaming) or None if
+    // the item should be skipped.
+    pub fn claim(&self, item: &Item, path: PathBuf) -> Result<Option<PathBuf>> {
+        let mut claimed = self.claimed.lock().expect("claimed paths");
+        let taken = |path: &Path| claimed.contains(path) || path.exists();
+        // Another item of this run, as opposed to a file which was there before it
+        let in_run = claimed.contains(&path);
+        if !taken(&path) {
+            claimed.insert(path.clone());
+            self.journal(&Entry::Claimed(path.clone()))?;
+            return Ok(Some(path));
+        }
+
+        let conflict = |resolution: String| {
+            self.conflicts
+                .lock()
+                .expect("conflicts")
+                .push(ConflictEntry {
+                    album: item.album.clone(),
+                    source: item.path.clone(),
+                    path: path.clone(),
+                    resolution,
+                })
+        };
+        match self.conflict {
+            // Two items of this run would be written over each other at the same time, so only
+            // the files which were there before it are overwritten. They aren't journaled, since
+            // they're replaced all at once and resuming mustn't remove them.
+            Conflict::Overwrite if !in_run => {
+                warn!("Overwriting {}", path.display());
+                conflict("overwritten".to_string());
+                claimed.insert(path.clone());
+                Ok(Some(path))
+            }
+            Conflict::Skip => {
+                info!("{} already exists; skipping", path.display());
+                events::skipped(item, "already exists");
+                conflict("skipped".to_string());
+                Ok(None)
+            }
+            Conflict::Error => Err(anyhow!("{} already exists", path.display())),
+            Conflict::Rename | Conflict::Overwrite => {
+                let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+                let extension = path.extension().map(|x| x.to_string_lossy());
+                let renamed = (1..)
+                    .map(|n| {
+                        let stem = if self.camera_suffixes {
+                            format!("{}_{}", stem, n)
+                        } else {
+                            format!("{} ({})", stem, n)
+                        };
+                        path.with_file_name(match &extension {
+                            Some(extension) => format!("{}.{}", stem, extension),
+                            None => stem,
+                        })
+                    })
+                    .find(|path| !taken(path))
+                    .expect("unbounded range");
+                info!(
+                    "{} already exists; using {}",
+                    path.display(),
+                    renamed.display()
+                );
+                conflict(format!("renamed to {}", renamed.display()));
+                claimed.insert(renamed.clone());
+                self.journal(&Entry::Claimed(renamed.clone()))?;
+                Ok(Some(renamed))
+            }
+        }
+    }
+
+    // Forgets the album names used so far, when moving on to the next export. The same album in
+    // each export then ends up with the same name, so that their items are merged.
+    pub fn start_export(&self) {
+        *self.albums.lock().expect("albums") = AlbumNames::default();
+    }
+
+    // The name to give the album in `root` (see `AlbumNames::unique`), recording it if it had to
+    // be renamed.
+    pub fn album_name(
+        &self,
+        root: &Path,
+        name: &str,
+        first: Option<NaiveDate>,
+        dir: &dyn Fn(&str) -> String,
+    ) -> String {
+        let unique = self
+            .albums
+            .lock()
+            .expect("albums")
+            .unique(root, name, first, dir);
+        if unique != name {
+            info!("There's already an album like {}; using {}", name, unique);
+            self.renamed_albums
+                .lock()
+                .expect("renamed albums")
+                .push((name.to_string(), unique.clone()));
+        }
+        unique
+    }
+
+    // Writes a report listing each album which was renamed and its new name (removing any left
+    // over from a previous run if there were none).
+    pub fn report_renamed_albums(&self, report: &Path, dry_run: bool) -> Result<()> {
+        let renamed = self.renamed_albums.lock().expect("renamed albums");
+        if dry_run {
+            return Ok(());
+        }
+        let rows = renamed
+            .iter()
+            .map(|(name, renamed)| format!("{}\t{}", name, renamed))
+            .collect::<Vec<_>>();
+        write_report(
+            report,
+            &rows,
+            &format!(
+                "{} albums shared a name with another and were renamed",
+                rows.len()
+            ),
+        )
+    }
+
+    // The hash of the original which the item duplicates, either by its key (if given) or by its
+    // contents, and whether it was by the key (i.e. it's the same item from another export). None
+    // means that this is the original.
+    pub fn original_of(&self, hash: Hash, key: Option<Key>) -> Option<(Hash, bool)> {
+        let mut originals = self.originals.lock().expect("originals");
+        let mut keys = self.keys.lock().expect("keys");
+        if let Some(original) = key.as_ref().and_then(|key| keys.get(key)) {
+            return Some((*original, true));
+        }
+        if let Some(key) = key {
+            keys.insert(key, hash);
+        }
+        if originals.contains_key(&hash) {
+            return Some((hash, false));
+        }
+        originals.insert(hash, None);
+        None
+    }
+
+    pub fn set_original(&self, hash: Hash, path: Option<PathBuf>) {
+        self.originals.lock().expect("originals").insert(hash, path);
+    }
+
+    // Records an item which duplicates an original, to be merged by `merge_duplicates` once all
+    // of the originals have been written.
+    pub fn add_duplicate(&self, hash: Hash, source: &Path, target: Option<PathBuf>) {
+        self.duplicates.lock().expect("duplicates").push(Duplicate {
+            hash,
+            source: source.to_path_buf(),
+            target,
+        });
+    }
+
+    // Prints what would have been written, grouped by album.
+    pub fn print_plan(&self, format: PlanFormat) -> Result<()> {
+        let manifest = self.manifest.lock().expect("manifest");
+        let conflicts = self.conflicts.lock().expect("conflicts");
+
+        let mut albums: Vec<PlannedAlbum> = Vec::new();
+        for entry in manifest.iter() {
+            match albums
+                .iter_mut()
+                .find(|album| album.name == entry.album.as_deref())
+            {
+                Some(album) => album.items.push(entry),
+                None => albums.push(PlannedAlbum {
+                    name: entry.album.as_deref(),
+                    items: vec![entry],
+                    conflicts: Vec::new(),
+                }),
+            }
+        }
+        for conflict in conflicts.iter() {
+            match albums
+                .iter_mut()
+                .find(|album| album.name == conflict.album.as_deref())
+            {
+                Some(album) => album.conflicts.push(conflict),
+                None => albums.push(PlannedAlbum {
+                    name: conflict.album.as_deref(),
+                    items: Vec::new(),
+                    conflicts: vec![conflict],
+                }),
+            }
+        }
+        for album in &mut albums {
+            album.items.sort_by(|a, b| a.output.cmp(&b.output));
+        }
+
+        if format == PlanFormat::Json {
+            return serde_json::to_writer_pretty(std::io::stdout(), &albums).context("write plan");
+        }
+
+        for album in albums {
+            println!(
+                "{} ({} items)",
+                album.name.unwrap_or("Videos"),
+                album.items.len()
+            );
+            for entry in album.items {
+                println!("  {} -> {}", entry.source.display(), entry.output.display());
+                let mut metadata = vec![entry.timestamp.clone()];
+                metadata.extend(entry.description.clone());
+                if entry.comments > 0 {
+                    metadata.push(format!("{} comments", entry.comments));
+                }
+                if !entry.people.is_empty() {
+                    metadata.push(format!("people: {}", entry.people.join(", ")));
+                }
+                if let Some((latitude, longitude)) = entry.location {
+                    metadata.push(format!("location: {}, {}", latitude, longitude));
+                }
+                println!("      {}", metadata.join("; "));
+            }
+            for conflict in album.conflicts {
+                println!(
+                    "  ! {} ({}) already exists; {}",
+                    conflict.path.display(),
+                    conflict.source.display(),
+                    conflict.resolution
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub fn skip_unrecognized(&self) {
+        self.unrecognized.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn summary(&self) -> Summary {
+        let manifest = self.manifest.lock().expect("manifest");
+        let mut albums = manifest
+            .iter()
+            .filter_map(|entry| entry.album.as_ref())
+            .collect::<Vec<_>>();
+        albums.sort();
+        albums.dedup();
+        let count = |kinds: &[&str]| {
+            manifest
+                .iter()
+                .filter(|entry| kinds.contains(&entry.kind.as_str()))
+                .count()
+        };
+        let videos = count(&["mp4", "flv"]);
+        let audio = count(&["aac", "m4a", "mp3"]);
+
+        Summary {
+            albums: albums.len(),
+            photos: manifest.len() - videos - audio,
+            videos,
+            audio,
+            bytes: self.bytes.load(Ordering::Relaxed),
+            skipped: self.unrecognized.load(Ordering::Relaxed),
+            warnings: stats::warnings(),
+            failures: self.failures.lock().expect("failures").len(),
+        }
+    }
+
+    pub fn fail(&self, item: &Item, err: &Error) {
+        self.failures.lock().expect("failures").push(Failure {
+            album: item.album.clone(),
+            path: item.path.clone(),
+            error: format!("{:#}", err),
+        });
+    }
+
+    pub fn missing(&self, item: &Item) {
+        self.missing
+            .lock()
+            .expect("missing")
+            .push((item.album.clone(), item.path.clone()));
+    }
+
+    // Writes a report listing each item whose file was missing from the archive, along with its
+    // album (removing any left over from a previous run if there were none).
+    pub fn report_missing(&self, report: &Path, dry_run: bool) -> Result<()> {
+        let missing = self.missing.lock().expect("missing");
+        if dry_run {
+            return Ok(());
+        }
+        let rows = missing
+            .iter()
+            .map(|(album, path)| format!("{}\t{}", album.as_deref().unwrap_or(""), path.display()))
+            .collect::<Vec<_>>();
+        write_report(
+            report,
+            &rows,
+            &format!(
+                "{} files listed in the export are missing from it, so it's likely incomplete; \
+                 download it again",
+                rows.len()
+            ),
+        )
+    }
+
+    pub fn remote(&self, item: &Item) {
+        self.remote
+            .lock()
+            .expect("remote")
+            .push((item.album.clone(), item.path.clone()));
+    }
+
+    // Writes a report listing each item which is only a URL, along with its album (removing any
+    // left over from a previous run if there were none).
+    pub fn report_remote(&self, report: &Path, dry_run: bool) -> Result<()> {
+        let remote = self.remote.lock().expect("remote");
+        if dry_run {
+            return Ok(());
+        }
+        let rows = remote
+            .iter()
+            .map(|(album, url)| format!("{}\t{}", album.as_deref().unwrap_or(""), url.display()))
+            .collect::<Vec<_>>();
+        write_report(
+            report,
+            &rows,
+            &format!(
+                "{} items are only linked to by the export rather than included in it; use \
+                 --download to fetch them",
+                rows.len()
+            ),
+        )
+    }
+
+    pub fn quarantine(&self, item: &Item, err: &Error) {
+        self.quarantined
+            .lock()
+            .expect("quarantined")
+            .push((item.path.clone(), format!("{:#}", err)));
+    }
+
+    // Writes a report listing each damaged item and what's wrong with it (removing any left over
+    // from a previous run if there were none).
+    pub fn report_quarantined(&self, report: &Path, dry_run: bool) -> Result<()> {
+        let quarantined = self.quarantined.lock().expect("quarantined");
+        if dry_run {
+            return Ok(());
+        }
+        let rows = quarantined
+            .iter()
+            .map(|(path, error)| format!("{}\t{}", path.display(), error))
+            .collect::<Vec<_>>();
+        write_report(
+            report,
+            &rows,
+            &format!(
+                "{} files in the export are damaged and were set aside in {}",
+                rows.len(),
+                QUARANTINE_DIR
+            ),
+        )
+    }
+
+    // Writes the items which couldn't be processed to the report (removing any left over from a
+    // previous run if there were none), returning the number of failures.
+    pub fn report_failures(&self, report: &Path, dry_run: bool) -> Result<usize> {
+        let failures = self.failures.lock().expect("failures");
+        if dry_run {
+            return Ok(failures.len());
+        }
+        if failures.is_empty() {
+            return remove_report(report).map(|()| 0);
+        }
+
+        serde_json::to_writer_pretty(
+            BufWriter::new(File::create(report).context(format!("create {}", report.display()))?),
+            &*failures,
+        )
+        .context(format!("write {}", report.display()))?;
+        Ok(failures.len())
+    }
+
+    // Links the duplicates to their originals (if requested) and writes a report listing each
+    // duplicate and the output it was merged into (removing any left over from a previous run if
+    // there were none).
+    pub fn merge_duplicates(&self, dedupe: Dedupe, dry_run: bool, report: &Path) -> Result<()> {
+        let originals = self.originals.lock().expect("originals");
+        let duplicates = self.duplicates.lock().expect("duplicates");
+
+        let mut rows = Vec::new();
+        for duplicate in duplicates.iter() {
+            let original = match originals.get(&duplicate.hash) {
+                Some(Some(original)) => original,
+                _ => {
+                    info!(
+                        "Original of {} wasn't written; skipping",
+                        duplicate.source.display()
+                    );
+                    continue;
+                }
+            };
+            rows.push(format!(
+                "{}\t{}",
+                duplicate.source.display(),
+                original.display()
+            ));
+            let target = match (&duplicate.target, dedupe, dry_run) {
+                (Some(target), Dedupe::Link, false) => target,
+                _ => continue,
+            };
+
+            link(Link::Hard, original, target)?;
+            self.checksum(target)?;
+        }
+
+        if dry_run {
+            info!("Merged {} duplicates", rows.len());
+            return Ok(());
+        }
+        write_report(
+            report,
+            &rows,
+            &format!("Merged {} duplicates into their originals", rows.len()),
+        )
+    }
+}
+
+// Whether the file is one of the run's own records (e.g. the journal), which only matter locally,
+// rather than part of the output. The gallery and the quarantined files are named like them, to
+// keep them apart from the albums, but are part of the output.
+pub fn is_local(name: &OsStr) -> bool {
+    name.to_string_lossy().starts_with(".fb-unarchive")
+        && name != gallery::DIR
+        && name != QUARANTINE_DIR
+}
+
+// Where the damaged item is set aside: at its path in the export, within the quarantine directory.
+// Only the names in the path are kept, so that a root, a drive, or ".." can't lead out of it, and
+// a URL is kept as its host and path.
+pub fn quarantine_path(output: &Path, source: &Path, sanitize: Sanitize) -> PathBuf {
+    let source = download::without_query(source);
+    let source = match source.to_str().and_then(|url| url.split_once("://")) {
+        Some((_, rest)) if download::is_url(source) => Path::new(rest),
+        _ => source,
+    };
+    let mut names = source
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(sanitize.name(&name.to_string_lossy())),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if names.is_empty() {
+        names.push(sanitize.name(""));
+    }
+    let mut path = output.join(QUARANTINE_DIR);
+    path.extend(names);
+    path
+}
+
+// Writes the report, a line for each of the rows, and warns about them with the message (followed
+// by where the report is). Any report left over from a previous run is removed if there are no
+// rows.
+fn write_report(report: &Path, rows: &[String], message: &str) -> Result<()> {
+    if rows.is_empty() {
+        return remove_report(report);
+    }
+    warn!("{} (see {})", message, report.display());
+    let lines = rows
+        .iter()
+        .map(|row| format!("{}\n", row))
+        .collect::<String>();
+    fs::write(report, lines).context(format!("write {}", report.display()))
+}
+
+// Removes the report left over from a previous run, if there is one.
+fn remove_report(report: &Path) -> Result<()> {
+    match fs::remove_file(report) {
+        Err(err) if err.kind() != ErrorKind::NotFound => {
+            Err(err).context(format!("remove {}", report.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+pub fn link(link: Link, original: &Path, target: &Path) -> Result<()> {
+    match fs::remove_file(target) {
+        Err(err) if err.kind() != ErrorKind::NotFound => {
+            return Err(err).context(format!("remove {}", target.display()))
+        }
+        _ => {}
+    }
+
+    match link {
+        Link::Hard => fs::hard_link(original, target).context("hard link"),
+        Link::Sym => {
+            // The link is relative to its own directory, so the original is made absolute.
+            let original = original
+                .canonicalize()
+                .context(format!("canonicalize {}", original.display()))?;
+            #[cfg(unix)]
+            let linked = std::os::unix::fs::symlink(original, target);
+            #[cfg(windows)]
+            let linked = std::os::windows::fs::symlink_file(original, target);
+            linked.context("symlink")
+        }
+        Link::Reflink => reflink_copy::reflink(original, target).context("reflink"),
+    }
+    .context(format!(
+        "link {} to {}",
+        target.display(),
+        original.display()
+    ))
+}
+
+// Writes the file under a temporary name in the same directory (e.g.
+// "1.jpg.fb-unarchive-partial"), which is only renamed once it's complete, so that an interrupted
+// run never leaves behind a file which looks finished. The temporary file is removed if writing
+// fails.
+pub fn write_atomically<F>(path: &Path, write: F) -> Result<u64>
+where
+    F: FnOnce(&Path) -> Result<u64>,
+{
+    let temp = temp_path(path);
+    match write(&temp) {
+        Ok(bytes) => {
+            fs::rename(&temp, path).context(format!(
+                "rename {} to {}",
+                temp.display(),
+                path.display()
+            ))?;
+            Ok(bytes)
+        }
+        Err(err) => {
+            let _ = fs::remove_file(&temp);
+            Err(err)
+        }
+    }
+}
+
+// The temporary file which the file at the path is written to, which is removed by
+// `remove_partial` if the run is interrupted.
+pub fn temp_path(path: &Path) -> PathBuf {
+    let mut temp = path.as_os_str().to_os_string();
+    temp.push(PARTIAL_EXTENSION);
+    PathBuf::from(temp)
+}
+
+// Removes the outputs which an interrupted run claimed but didn't finish writing (see
+// `Outputs::load_journal`).
+pub fn remove_incomplete(outputs: &[PathBuf]) -> Result<()> {
+    for output in outputs.iter().filter(|output| output.exists()) {
+        info!("Removing incomplete {}", output.display());
+        fs::remove_file(output).context(format!("remove {}", output.display()))?;
+    }
+    Ok(())
+}
+
+// Removes the temporary files left in the directory by a run which was interrupted while writing
+// them (see `write_atomically`), returning how many there were.
+pub fn remove_partial(dir: &Path) -> Result<usize> {
+    let mut removed = 0;
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err).context(format!("list directory {}", dir.display())),
+    };
+    for entry in entries {
+        let entry = entry.context(format!("list directory {}", dir.display()))?;
+        let path = entry.path();
+        let file_type = entry
+            .file_type()
+            .context(format!("stat {}", path.display()))?;
+        if file_type.is_dir() {
+            removed += remove_partial(&path)?;
+        } else if path.to_string_lossy().ends_with(PARTIAL_EXTENSION) {
+            debug!("Removing {}", path.display());
+            fs::remove_file(&path).context(format!("remove {}", path.display()))?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+// Hashes the contents of the reader.
+pub fn hash(reader: &mut dyn Read) -> Result<Hash> {
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 64 * 1024];
+    loop {
+        match reader.read(&mut buffer).context("read")? {
+            0 => return Ok(hasher.finalize().into()),
+            len => hasher.update(&buffer[..len]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An empty directory of its own for each test, holding the files which exist before the run
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "fb-unarchive-output-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn item() -> Item {
+        Item::new(PathBuf::from("photos/1.jpg"), NaiveDateTime::default())
+    }
+
+    fn claim(outputs: &Outputs, path: &Path) -> Option<PathBuf> {
+        outputs.claim(&item(), path.to_path_buf()).unwrap()
+    }
+
+    #[test]
+    fn claim_unused() {
+        let dir = dir("unused");
+        let path = dir.join("a.jpg");
+        for conflict in &[
+            Conflict::Rename,
+            Conflict::Skip,
+            Conflict::Overwrite,
+            Conflict::Error,
+        ] {
+            let outputs = Outputs::new(*conflict);
+            assert_eq!(claim(&outputs, &path), Some(path.clone()));
+        }
+    }
+
+    #[test]
+    fn rename_in_run() {
+        let dir = dir("rename-in-run");
+        let outputs = Outputs::new(Conflict::Rename);
+        let path = dir.join("a.jpg");
+        assert_eq!(claim(&outputs, &path), Some(path.clone()));
+        assert_eq!(claim(&outputs, &path), Some(dir.join("a (1).jpg")));
+        assert_eq!(claim(&outputs, &path), Some(dir.join("a (2).jpg")));
+    }
+
+    #[test]
+    fn rename_existing() {
+        let dir = dir("rename-existing");
+        let path = dir.join("a.jpg");
+        fs::write(&path, "before").unwrap();
+        fs::write(dir.join("a (1).jpg"), "before").unwrap();
+
+        let outputs = Outputs::new(Conflict::Rename);
+        assert_eq!(claim(&outputs, &path), Some(dir.join("a (2).jpg")));
+    }
+
+    #[test]
+    fn skip_in_run() {
+        let dir = dir("skip-in-run");
+        let outputs = Outputs::new(Conflict::Skip);
+        let path = dir.join("a.jpg");
+        assert_eq!(claim(&outputs, &path), Some(path.clone()));
+        assert_eq!(claim(&outputs, &path), None);
+    }
+
+    #[test]
+    fn skip_existing() {
+        let dir = dir("skip-existing");
+        let path = dir.join("a.jpg");
+        fs::write(&path, "before").unwrap();
+
+        let outputs = Outputs::new(Conflict::Skip);
+        assert_eq!(claim(&outputs, &path), None);
+    }
+
+    #[test]
+    fn error_in_run() {
+        let dir = dir("error-in-run");
+        let outputs = Outputs::new(Conflict::Error);
+        let path = dir.join("a.jpg");
+        assert_eq!(claim(&outputs, &path), Some(path.clone()));
+        assert!(outputs.claim(&item(), path).is_err());
+    }
+
+    #[test]
+    fn error_existing() {
+        let dir = dir("error-existing");
+        let path = dir.join("a.jpg");
+        fs::write(&path, "before").unwrap();
+
+        let outputs = Outputs::new(Conflict::Error);
+        assert!(outputs.claim(&item(), path).is_err());
+    }
+
+    #[test]
+    fn overwrite_in_run() {
+        let dir = dir("overwrite-in-run");
+        let outputs = Outputs::new(Conflict::Overwrite);
+        let path = dir.join("a.jpg");
+        assert_eq!(claim(&outputs, &path), Some(path.clone()));
+        // The first item hasn't been written yet, so the second mustn't share its file
+        assert_eq!(claim(&outputs, &path), Some(dir.join("a (1).jpg")));
+    }
+
+    #[test]
+    fn overwrite_existing() {
+        let dir = dir("overwrite-existing");
+        let path = dir.join("a.jpg");
+        fs::write(&path, "before").unwrap();
+
+        let mut outputs = Outputs::new(Conflict::Overwrite);
+        let journal = dir.join("journal");
+        outputs.open_journal(&journal, false).unwrap();
+        assert_eq!(claim(&outputs, &path), Some(path.clone()));
+        // Once it's been overwritten, it's one of the run's own files
+        assert_eq!(claim(&outputs, &path), Some(dir.join("a (1).jpg")));
+        drop(outputs);
+
+        // Resuming an interrupted run leaves the overwritten file alone
+        let incomplete = Outputs::new(Conflict::Overwrite)
+            .load_journal(&journal)
+            .unwrap();
+        remove_incomplete(&incomplete).unwrap();
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn journal_finds_incomplete() {
+        let dir = dir("journal-finds-incomplete");
+        let path = dir.join("a.jpg");
+
+        let mut outputs = Outputs::new(Conflict::Rename);
+        let journal = dir.join("journal");
+        outputs.open_journal(&journal, false).unwrap();
+        assert_eq!(claim(&outputs, &path), Some(path.clone()));
+        fs::write(&path, "partial").unwrap();
+        drop(outputs);
+
+        // Loading the journal only finds the unfinished output, which is removed separately
+        let incomplete = Outputs::new(Conflict::Rename)
+            .load_journal(&journal)
+            .unwrap();
+        assert_eq!(incomplete, vec![path.clone()]);
+        assert!(path.exists());
+        remove_incomplete(&incomplete).unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn quarantine_within_output() {
+        let out = Path::new("out");
+        let quarantine = out.join(QUARANTINE_DIR);
+        let path = |source: &str| quarantine_path(out, Path::new(source), Sanitize::Minimal);
+
+        assert_eq!(
+            path("photos_and_videos/album/1.jpg"),
+            quarantine.join("photos_and_videos/album/1.jpg")
+        );
+        assert_eq!(path("/etc/passwd"), quarantine.join("etc/passwd"));
+        assert_eq!(path("../../1.jpg"), quarantine.join("1.jpg"));
+        assert_eq!(path("photos/../../1.jpg"), quarantine.join("photos/1.jpg"));
+        assert_eq!(path("/"), quarantine.join("_"));
+        assert_eq!(
+            path("https://scontent.xx.fbcdn.net/v/t1/1.jpg?oh=abc&oe=def"),
+            quarantine.join("scontent.xx.fbcdn.net/v/t1/1.jpg")
+        );
+    }
+}
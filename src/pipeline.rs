@@ -0,0 +1,112 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Uploads to a remote output as the files are written, rather than once everything has been
+// processed, so that the network is kept busy while the rest of the photos and videos are being
+// transformed. Files are handed over on a bounded channel, which holds up the processing whenever
+// the uploads fall behind, and at most `--upload-jobs` of them are uploaded at once. Anything
+// written outside of the items (e.g. sidecars or the manifest) is left for the final upload.
+//
+// Only the uploads are done this way. Remote inputs are still read by the workers processing the
+// items, as they need them: a zip file given as a URL through `http`, with its own read-ahead, and
+// the files fetched with `--download` through `download`, whose requests are spaced out so as not
+// to be throttled, which limits them more than the number at once would.
+
+use crate::remote::Remote;
+use anyhow::{anyhow, Context, Result};
+use log::debug;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::runtime::{self, Runtime};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::{JoinHandle, JoinSet};
+
+// How many written files can be waiting for each upload slot
+const BACKLOG: usize = 8;
+
+pub struct Pipeline {
+    runtime: Runtime,
+    sender: mpsc::Sender<PathBuf>,
+    uploads: JoinHandle<Result<HashSet<PathBuf>>>,
+}
+
+impl Pipeline {
+    pub fn start(remote: Arc<Remote>, staging: PathBuf, jobs: usize) -> Result<Pipeline> {
+        let runtime = runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .max_blocking_threads(jobs.max(1))
+            .build()
+            .context("start runtime")?;
+        let (sender, receiver) = mpsc::channel(jobs.max(1) * BACKLOG);
+        let uploads = runtime.spawn(upload(remote, staging, receiver, jobs.max(1)));
+        Ok(Pipeline {
+            runtime,
+            sender,
+            uploads,
+        })
+    }
+
+    // The end of the channel which the written files are sent to.
+    pub fn sender(&self) -> mpsc::Sender<PathBuf> {
+        self.sender.clone()
+    }
+
+    // Waits for the remaining uploads, once every sender has been dropped, returning the files
+    // which were uploaded (relative to the staging directory).
+    pub fn finish(self) -> Result<HashSet<PathBuf>> {
+        let Pipeline {
+            runtime,
+            sender,
+            uploads,
+        } = self;
+        drop(sender);
+        runtime.block_on(uploads).context("wait for uploads")?
+    }
+}
+
+async fn upload(
+    remote: Arc<Remote>,
+    staging: PathBuf,
+    mut receiver: mpsc::Receiver<PathBuf>,
+    jobs: usize,
+) -> Result<HashSet<PathBuf>> {
+    let slots = Arc::new(Semaphore::new(jobs));
+    let mut tasks = JoinSet::new();
+    let mut uploaded = HashSet::new();
+
+    while let Some(path) = receiver.recv().await {
+        let file = path
+            .strip_prefix(&staging)
+            .map_err(|_| anyhow!("{} is outside of the output", path.display()))?
+            .to_path_buf();
+        let slot = slots.clone().acquire_owned().await.context("upload slot")?;
+        let remote = remote.clone();
+        let staging = staging.clone();
+        tasks.spawn_blocking(move || {
+            let _slot = slot;
+            remote.upload_file(&staging, &file).map(|_| file)
+        });
+
+        while let Some(done) = tasks.try_join_next() {
+            uploaded.insert(done.context("upload task")??);
+        }
+    }
+    while let Some(done) = tasks.join_next().await {
+        uploaded.insert(done.context("upload task")??);
+    }
+
+    debug!("Uploaded {} files while processing", uploaded.len());
+    Ok(uploaded)
+}
@@ -0,0 +1,343 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Photos and videos attached to timeline posts. These are listed in "posts/your_posts_N.json" and,
+// unless they were also added to an album, don't appear anywhere else in the archive. Newer
+// exports list the posts directly while older ones nest them in "status_updates".
+//
+// Archived stories are listed the same way as posts, in "stories/archived_stories.json" (or
+// "story_archive.json" in older exports), as are changes to the profile picture and cover photo,
+// in "profile_information/profile_update_history.json". The name of the export's owner is read
+// from "profile_information/profile_information.json".
+//
+// Posts made in groups, events, and the pages you manage are in folders of their own ("groups",
+// "events", and "pages"), under keys which vary between exports, so every list of posts in them is
+// read. They don't name the group, event, or page except in their titles (e.g. "Alex posted in
+// Hiking Club."), so each is grouped by the name found there.
+
+use crate::{encoding, stream, Album, Archive, Item, MediaMetadata};
+use anyhow::{Context, Result};
+use chrono::DateTime;
+use log::{debug, trace, warn};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum Posts {
+    List(Vec<Post>),
+    StatusUpdates { status_updates: Vec<Post> },
+}
+
+#[derive(Deserialize, Debug)]
+struct Post {
+    #[serde(default)]
+    timestamp: Option<i64>,
+    #[serde(default, deserialize_with = "encoding::deserialize_option")]
+    title: Option<String>,
+    #[serde(default = "Vec::new")]
+    data: Vec<PostData>,
+    #[serde(default = "Vec::new")]
+    attachments: Vec<Attachment>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PostData {
+    #[serde(default, deserialize_with = "encoding::deserialize_option")]
+    post: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Attachment {
+    #[serde(default = "Vec::new")]
+    data: Vec<AttachmentData>,
+}
+
+#[derive(Deserialize, Debug)]
+struct AttachmentData {
+    // Other kinds of attachments (e.g. links and places) don't have any media
+    media: Option<Media>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Media {
+    uri: PathBuf,
+    #[serde(default)]
+    creation_timestamp: Option<i64>,
+    #[serde(default, deserialize_with = "encoding::deserialize_option")]
+    description: Option<String>,
+    #[serde(default)]
+    media_metadata: MediaMetadata,
+}
+
+// Reads the media attached to every post. Each item takes the text of its post as its description
+// (or its own description, if the post had no text), and its own timestamp (or that of the post).
+pub fn read_posts(archive: &Archive) -> Result<Vec<Item>> {
+    debug!("Finding posts");
+
+    let mut items = Vec::new();
+    for path in archive.list_recursive(Path::new("posts"))? {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if !(name.starts_with("your_posts") && name.ends_with(".json")) {
+            continue;
+        }
+
+        trace!("Reading {}", path.display());
+        let posts = match archive.read(&path, |file| {
+            serde_json::from_reader(BufReader::new(file))
+                .context(format!("parse json {}", path.display()))
+        })? {
+            Posts::List(posts) => posts,
+            Posts::StatusUpdates { status_updates } => status_updates,
+        };
+        items.extend(
+            posts
+                .into_iter()
+                .flat_map(|post| attachments(post, archive)),
+        );
+    }
+
+    items.sort_by_key(|item| item.timestamp);
+    Ok(items)
+}
+
+// Reads the media from every archived story, in the same way as for posts.
+pub fn read_stories(archive: &Archive) -> Result<Vec<Item>> {
+    debug!("Finding stories");
+
+    let mut items = Vec::new();
+    for path in archive.list_recursive(Path::new("stories"))? {
+        if path.extension().and_then(|x| x.to_str()) != Some("json") {
+            continue;
+        }
+
+        let stories = read_nested(archive, &path, &["archived_stories_v2", "archived_stories"])?;
+        items.extend(
+            stories
+                .into_iter()
+                .flat_map(|post| attachments(post, archive)),
+        );
+    }
+
+    items.sort_by_key(|item| item.timestamp);
+    Ok(items)
+}
+
+// The sections with posts made elsewhere: the folder each is in within the export and the one its
+// albums are written to within the output
+pub const SECTIONS: &[(&str, &str)] = &[
+    ("groups", "Groups"),
+    ("events", "Events"),
+    ("pages", "Pages"),
+];
+
+// Where posts without a recognizable destination in their titles are put
+const UNKNOWN: &str = "Other";
+
+// Reads the media attached to the posts in the section (e.g. "groups"), as an album for each
+// group, event, or page, in the same way as for posts.
+pub fn read_section(archive: &Archive, section: &str) -> Result<Vec<Album>> {
+    debug!("Finding posts in {}", section);
+
+    let mut albums = BTreeMap::<String, Vec<Item>>::new();
+    for path in archive.list_recursive(Path::new(section))? {
+        if path.extension().and_then(|x| x.to_str()) != Some("json") {
+            continue;
+        }
+
+        trace!("Reading {}", path.display());
+        let json = archive.read(&path, |file| {
+            serde_json::from_reader::<_, serde_json::Value>(BufReader::new(file))
+                .context(format!("parse json {}", path.display()))
+        })?;
+        let lists = match &json {
+            serde_json::Value::Array(_) => vec![&json],
+            serde_json::Value::Object(object) => object.values().filter(|v| v.is_array()).collect(),
+            _ => Vec::new(),
+        };
+        for list in lists {
+            // Other lists (e.g. of memberships or responses to events) aren't posts
+            let posts = match Vec::<Post>::deserialize(list) {
+                Ok(posts) => posts,
+                Err(err) => {
+                    trace!("Not a list of posts in {}: {}", path.display(), err);
+                    continue;
+                }
+            };
+            for post in posts {
+                let name = post
+                    .title
+                    .as_deref()
+                    .and_then(destination)
+                    .unwrap_or_else(|| UNKNOWN.to_string());
+                albums
+                    .entry(name)
+                    .or_default()
+                    .extend(attachments(post, archive));
+            }
+        }
+    }
+
+    Ok(albums
+        .into_iter()
+        .filter(|(_, items)| !items.is_empty())
+        .map(|(name, mut items)| {
+            items.sort_by_key(|item| item.timestamp);
+            Album {
+                name,
+                description: None,
+                cover_photo: None,
+                items,
+                file: None,
+            }
+        })
+        .collect())
+}
+
+// The group, event, or page named in the title of a post, which follows a colon (e.g. "Alex shared
+// a link to the group: Hiking Club.") or otherwise the first preposition (e.g. "Alex posted in
+// Hiking Club." or "Alex wrote on Summer BBQ's timeline.").
+fn destination(title: &str) -> Option<String> {
+    let start = match title.find(": ") {
+        Some(colon) => colon + 2,
+        None => [" in ", " to ", " on ", " for "]
+            .iter()
+            .filter_map(|word| title.find(word).map(|start| start + word.len()))
+            .min()?,
+    };
+    let name = title[start..].trim_end_matches('.');
+    let name = name.strip_suffix("'s timeline").unwrap_or(name).trim();
+    Some(name.to_string()).filter(|name| !name.is_empty())
+}
+
+// Reads the history of the profile picture and cover photo, as an album for each. The updates
+// don't say which they were except in their titles (e.g. "Alex updated his cover photo.").
+pub fn read_profile(archive: &Archive) -> Result<Vec<Album>> {
+    debug!("Finding profile updates");
+
+    let mut pictures = Vec::new();
+    let mut covers = Vec::new();
+    for path in archive.list_recursive(Path::new("profile_information"))? {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if !(name.starts_with("profile_update_history") && name.ends_with(".json")) {
+            continue;
+        }
+
+        for update in read_nested(archive, &path, &["profile_updates_v2", "profile_updates"])? {
+            let cover = update
+                .title
+                .as_deref()
+                .is_some_and(|title| title.to_lowercase().contains("cover photo"));
+            let items = attachments(update, archive);
+            if cover {
+                covers.extend(items);
+            } else {
+                pictures.extend(items);
+            }
+        }
+    }
+
+    Ok(
+        vec![("Profile Pictures", pictures), ("Cover Photos", covers)]
+            .into_iter()
+            .filter(|(_, items)| !items.is_empty())
+            .map(|(name, mut items)| {
+                items.sort_by_key(|item| item.timestamp);
+                Album {
+                    name: name.to_string(),
+                    description: None,
+                    cover_photo: None,
+                    items,
+                    file: None,
+                }
+            })
+            .collect(),
+    )
+}
+
+// The full name of the export's owner, if the export includes their profile. Newer exports nest it
+// under "profile_v2" and older ones under "profile".
+pub fn read_owner(archive: &Archive) -> Result<Option<String>> {
+    let path = Path::new("profile_information/profile_information.json");
+    if !archive.contains(path)? {
+        return Ok(None);
+    }
+
+    trace!("Reading {}", path.display());
+    let json = archive.read(path, |file| {
+        serde_json::from_reader::<_, serde_json::Value>(BufReader::new(file))
+            .context(format!("parse json {}", path.display()))
+    })?;
+    Ok(["profile_v2", "profile"]
+        .iter()
+        .find_map(|key| json.get(key)?.get("name")?.get("full_name")?.as_str())
+        .filter(|name| !name.is_empty())
+        .map(|name| encoding::fix(name.to_string())))
+}
+
+// Reads the list of posts nested under the first of the keys present in the JSON file.
+fn read_nested(archive: &Archive, path: &Path, keys: &[&str]) -> Result<Vec<Post>> {
+    trace!("Reading {}", path.display());
+    let mut posts = Vec::new();
+    let found = archive.read(path, |file| {
+        stream::for_each(file, keys, |post| posts.push(post))
+            .context(format!("parse json {}", path.display()))
+    })?;
+    if !found {
+        trace!("Nothing in {}; skipping", path.display());
+    }
+    Ok(posts)
+}
+
+fn attachments(post: Post, archive: &Archive) -> Vec<Item> {
+    let text = post
+        .data
+        .into_iter()
+        .find_map(|data| data.post)
+        .filter(|text| !text.is_empty());
+    let timestamp = post.timestamp;
+
+    post.attachments
+        .into_iter()
+        .flat_map(|attachment| attachment.data)
+        .filter_map(|data| data.media)
+        .filter_map(|media| {
+            let timestamp = media
+                .creation_timestamp
+                .or(timestamp)
+                .and_then(|timestamp| DateTime::from_timestamp(timestamp, 0))
+                .map(|timestamp| timestamp.naive_utc());
+            let timestamp = match timestamp {
+                Some(timestamp) => timestamp,
+                None => {
+                    warn!(
+                        "Couldn't find a timestamp for {}; skipping",
+                        media.uri.display()
+                    );
+                    return None;
+                }
+            };
+            Some(
+                Item {
+                    description: text.clone().or(media.description),
+                    media_metadata: media.media_metadata,
+                    ..Item::new(media.uri, timestamp)
+                }
+                .dated(archive),
+            )
+        })
+        .collect()
+}
@@ -0,0 +1,285 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::Item;
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use log::warn;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+
+// Progress bars tracking the albums and items that have been processed. When disabled (or when
+// stderr isn't a terminal), nothing is drawn.
+//
+// The progress can also be reported to frontends (e.g. with `--progress-fd`), as a line of JSON
+// each time it changes. The totals grow as the albums are read, so the percentage can go down as
+// well as up.
+pub struct Progress {
+    bars: MultiProgress,
+    albums: ProgressBar,
+    items: ProgressBar,
+    bytes: AtomicU64,
+    phase: Mutex<&'static str>,
+    report: Option<Mutex<Box<dyn Write + Send>>>,
+    control: Option<Arc<Control>>,
+    // Set (e.g. by Ctrl-C) to stop the processing once the items under way are finished
+    interrupted: Arc<AtomicBool>,
+}
+
+// Lets the processing be paused, or albums skipped, while it's running (e.g. from the TUI). Albums
+// are named as they are in the output; videos which aren't in any album are "Videos".
+#[derive(Default)]
+pub struct Control {
+    paused: Mutex<bool>,
+    resumed: Condvar,
+    skipped: Mutex<HashSet<String>>,
+    // How many items of each album have been finished
+    finished: Mutex<HashMap<String, usize>>,
+}
+
+impl Control {
+    pub fn pause(&self) {
+        *self.paused.lock().expect("paused") = true;
+    }
+
+    pub fn resume(&self) {
+        *self.paused.lock().expect("paused") = false;
+        self.resumed.notify_all();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.lock().expect("paused")
+    }
+
+    // Skips the rest of the album's items.
+    pub fn skip(&self, album: &str) {
+        self.skipped
+            .lock()
+            .expect("skipped")
+            .insert(album.to_string());
+    }
+
+    pub fn unskip(&self, album: &str) {
+        self.skipped.lock().expect("skipped").remove(album);
+    }
+
+    pub fn is_skipped(&self, album: &str) -> bool {
+        self.skipped.lock().expect("skipped").contains(album)
+    }
+
+    pub fn finished(&self, album: &str) -> usize {
+        self.finished
+            .lock()
+            .expect("finished")
+            .get(album)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    // Waits while the processing is paused, returning whether the item should then be processed.
+    fn proceed(&self, item: &Item) -> bool {
+        let mut paused = self.paused.lock().expect("paused");
+        while *paused {
+            paused = self.resumed.wait(paused).expect("paused");
+        }
+        !self.is_skipped(album_name(item))
+    }
+}
+
+fn album_name(item: &Item) -> &str {
+    item.album.as_deref().unwrap_or("Videos")
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    phase: &'a str,
+    albums: u64,
+    albums_total: u64,
+    items: u64,
+    items_total: u64,
+    bytes: u64,
+    percent: f64,
+    // The item which was just finished, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    item: Option<&'a Path>,
+}
+
+impl Progress {
+    pub fn new(enabled: bool) -> Progress {
+        let bars = MultiProgress::with_draw_target(if enabled {
+            ProgressDrawTarget::stderr()
+        } else {
+            ProgressDrawTarget::hidden()
+        });
+
+        let albums = bars.add(
+            ProgressBar::new(0).with_style(
+                ProgressStyle::with_template("Albums {pos:>7}/{len:7} {wide_bar}")
+                    .expect("albums template"),
+            ),
+        );
+        let items = bars.add(
+            ProgressBar::new(0).with_style(
+                ProgressStyle::with_template(
+                    "Items  {pos:>7}/{len:7} {wide_bar} {msg} copied, {eta} remaining",
+                )
+                .expect("items template"),
+            ),
+        );
+        items.set_message(HumanBytes(0).to_string());
+
+        Progress {
+            bars,
+            albums,
+            items,
+            bytes: AtomicU64::new(0),
+            phase: Mutex::new("processing"),
+            report: None,
+            control: None,
+            interrupted: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn set_control(&mut self, control: Arc<Control>) {
+        self.control = Some(control);
+    }
+
+    // Waits while the processing is paused, returning whether the item should then be processed.
+    pub fn proceed(&self, item: &Item) -> bool {
+        self.control
+            .as_ref()
+            .is_none_or(|control| control.proceed(item))
+    }
+
+    // Whether the album has been skipped before any of it was processed, or the processing was
+    // interrupted before it was reached.
+    pub fn is_skipped(&self, album: &str) -> bool {
+        self.is_interrupted()
+            || self
+                .control
+                .as_ref()
+                .is_some_and(|control| control.is_skipped(album))
+    }
+
+    // The flag which interrupts the processing when it's set, for signal handlers.
+    pub fn interrupt_flag(&self) -> Arc<AtomicBool> {
+        self.interrupted.clone()
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::Relaxed)
+    }
+
+    pub fn phase(&self) -> &'static str {
+        *self.phase.lock().expect("phase")
+    }
+
+    // The albums finished so far, and how many there are.
+    pub fn albums(&self) -> (u64, u64) {
+        (self.albums.position(), self.albums.length().unwrap_or(0))
+    }
+
+    // The items finished so far, and how many there are.
+    pub fn items(&self) -> (u64, u64) {
+        (self.items.position(), self.items.length().unwrap_or(0))
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    // Also reports the progress, one line of JSON at a time, to the writer.
+    pub fn report_to<W: Write + Send + 'static>(&mut self, writer: W) {
+        self.report = Some(Mutex::new(Box::new(writer)));
+    }
+
+    // Names what's currently being done (e.g. "uploading"), for the reports.
+    pub fn set_phase(&self, phase: &'static str) {
+        *self.phase.lock().expect("phase") = phase;
+        self.report(None);
+    }
+
+    // Progress bars need to be suspended while log messages are written.
+    pub fn bars(&self) -> MultiProgress {
+        self.bars.clone()
+    }
+
+    pub fn add_albums(&self, count: usize) {
+        self.albums.inc_length(count as u64);
+    }
+
+    pub fn add_items(&self, count: usize) {
+        self.items.inc_length(count as u64);
+    }
+
+    pub fn album_done(&self) {
+        self.albums.inc(1);
+        self.report(None);
+    }
+
+    pub fn item_done(&self, item: &Item, bytes: u64) {
+        let total = self.bytes.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        self.items.set_message(HumanBytes(total).to_string());
+        self.items.inc(1);
+        if let Some(control) = &self.control {
+            *control
+                .finished
+                .lock()
+                .expect("finished")
+                .entry(album_name(item).to_string())
+                .or_default() += 1;
+        }
+        self.report(Some(&item.path));
+    }
+
+    pub fn finish(&self) {
+        self.albums.finish();
+        self.items.finish();
+    }
+
+    fn report(&self, item: Option<&Path>) {
+        let report = match &self.report {
+            Some(report) => report,
+            None => return,
+        };
+        let phase = *self.phase.lock().expect("phase");
+        let (items, items_total) = (self.items.position(), self.items.length().unwrap_or(0));
+        let line = Report {
+            phase,
+            albums: self.albums.position(),
+            albums_total: self.albums.length().unwrap_or(0),
+            items,
+            items_total,
+            bytes: self.bytes.load(Ordering::Relaxed),
+            percent: if items_total == 0 {
+                0.0
+            } else {
+                (items as f64 * 1000.0 / items_total as f64).round() / 10.0
+            },
+            item,
+        };
+
+        let mut writer = report.lock().expect("report");
+        let written = serde_json::to_writer(&mut *writer, &line)
+            .map_err(std::io::Error::from)
+            .and_then(|_| writeln!(writer))
+            .and_then(|_| writer.flush());
+        if let Err(err) = written {
+            warn!("Failed to report progress: {}", err);
+        }
+    }
+}
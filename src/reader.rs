@@ -0,0 +1,102 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The items of a Facebook export, read lazily. Only one album's description is held at a time, so
+// the largest exports can be walked without first reading all of them into memory. Each item has
+// its album and position within it filled in, and the videos which aren't in any album follow the
+// albums.
+
+use crate::{read_album, read_videos, Archive, Item};
+use anyhow::{Context, Result};
+use std::path::PathBuf;
+use std::vec;
+
+pub struct ArchiveReader<'a> {
+    archive: &'a Archive,
+    // The album files which haven't been read yet
+    paths: vec::IntoIter<PathBuf>,
+    // The remaining items of the current album
+    items: vec::IntoIter<Item>,
+    videos_read: bool,
+}
+
+impl<'a> ArchiveReader<'a> {
+    pub fn new(archive: &'a Archive) -> Result<ArchiveReader<'a>> {
+        Ok(ArchiveReader {
+            archive,
+            paths: crate::album_paths(archive)
+                .context("read_albums")?
+                .into_iter(),
+            items: Vec::new().into_iter(),
+            videos_read: false,
+        })
+    }
+
+    // Refills `items` from the next album or, once they've all been read, the videos. Returns
+    // false when there's nothing left.
+    fn next_batch(&mut self) -> Result<bool> {
+        for path in self.paths.by_ref() {
+            let album = match read_album(self.archive, &path)
+                .context(format!("read_album {}", path.display()))?
+            {
+                Some(album) => album,
+                None => continue,
+            };
+            let name = album.name;
+            self.items = album
+                .items
+                .into_iter()
+                .enumerate()
+                .map(|(index, item)| Item {
+                    album: Some(name.clone()),
+                    index,
+                    ..item
+                })
+                .collect::<Vec<_>>()
+                .into_iter();
+            return Ok(true);
+        }
+
+        if self.videos_read {
+            return Ok(false);
+        }
+        self.videos_read = true;
+        self.items = read_videos(self.archive)
+            .context("read_videos")?
+            .into_iter();
+        Ok(true)
+    }
+}
+
+impl<'a> Iterator for ArchiveReader<'a> {
+    type Item = Result<Item>;
+
+    fn next(&mut self) -> Option<Result<Item>> {
+        loop {
+            if let Some(item) = self.items.next() {
+                return Some(Ok(item));
+            }
+            match self.next_batch() {
+                Ok(true) => continue,
+                Ok(false) => return None,
+                Err(err) => {
+                    // Stop after an error, rather than reporting it again on the next call
+                    self.paths = Vec::new().into_iter();
+                    self.videos_read = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
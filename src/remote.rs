@@ -0,0 +1,214 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Outputs which aren't on the local filesystem (e.g. a WebDAV server or an S3 bucket), given as a
+// URL in place of the output directory. Everything is still written locally first, into a staging
+// directory. The output files are uploaded as they're written (see `pipeline`), and then whatever
+// remains is copied once the run has finished.
+
+use crate::output;
+use crate::s3::S3;
+use crate::webdav::WebDav;
+use anyhow::{anyhow, Context, Result};
+use log::{debug, warn};
+use std::collections::HashSet;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+// How many times to try each request before giving up
+const ATTEMPTS: u32 = 4;
+
+pub enum Remote {
+    WebDav(WebDav),
+    S3(S3),
+}
+
+impl Remote {
+    // The remote named by the output, if it's a URL rather than a path, uploading at most `jobs`
+    // files at once.
+    pub fn parse(output: &Path, jobs: usize) -> Result<Option<Remote>> {
+        let url = match output.to_str() {
+            Some(url) if url.contains("://") => url,
+            _ => return Ok(None),
+        };
+        let (scheme, rest) = url.split_once("://").context("url scheme")?;
+        match scheme {
+            "http" | "https" => Ok(Some(Remote::WebDav(WebDav::new(scheme, rest, jobs)?))),
+            "webdav" => Ok(Some(Remote::WebDav(WebDav::new("http", rest, jobs)?))),
+            "webdavs" => Ok(Some(Remote::WebDav(WebDav::new("https", rest, jobs)?))),
+            "s3" => Ok(Some(Remote::S3(S3::new(rest, jobs)?))),
+            _ => Err(anyhow!("unsupported output {}", url)),
+        }
+    }
+
+    // Where the output is written before being copied to the remote, unless `--staging-dir` is
+    // given. This stays the same for the same remote so that interrupted runs can be resumed.
+    pub fn staging_dir(&self, output: &Path) -> PathBuf {
+        let hash =
+            crate::output::hash(&mut output.to_string_lossy().as_bytes()).unwrap_or_default();
+        let name = hash[..8]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+        std::env::temp_dir().join(format!("fb-unarchive-{}", name))
+    }
+
+    // Copies everything in the staging directory to the remote, except for the files which only
+    // matter locally (e.g. the journal) and those which were already uploaded, returning the files
+    // which it copied.
+    pub fn upload(&self, staging: &Path, uploaded: &HashSet<PathBuf>) -> Result<Vec<PathBuf>> {
+        let mut dirs = Vec::new();
+        let mut files = Vec::new();
+        let mut pending = vec![PathBuf::new()];
+        while let Some(dir) = pending.pop() {
+            let full = staging.join(&dir);
+            for entry in
+                fs::read_dir(&full).context(format!("list directory {}", full.display()))?
+            {
+                let entry = entry.context("entry")?;
+                let name = entry.file_name();
+                let path = dir.join(&name);
+                if output::is_local(&name) {
+                    continue;
+                }
+                if entry.file_type().context("file type")?.is_dir() {
+                    dirs.push(path.clone());
+                    pending.push(path);
+                } else if !uploaded.contains(&path) {
+                    files.push(path);
+                }
+            }
+        }
+        // Parents sort before their children
+        dirs.sort();
+        files.sort();
+
+        match self {
+            Remote::WebDav(webdav) => webdav.upload(staging, &dirs, &files)?,
+            Remote::S3(s3) => s3.upload(staging, &files)?,
+        }
+        Ok(files)
+    }
+
+    // Copies a single file from the staging directory, creating its parents first if needed.
+    pub fn upload_file(&self, staging: &Path, file: &Path) -> Result<()> {
+        match self {
+            Remote::WebDav(webdav) => webdav.upload_file(staging, file),
+            Remote::S3(s3) => s3.upload_file(staging, file),
+        }
+    }
+
+    // Removes the files which were uploaded from the staging directory, along with the directories
+    // left empty. The records of the run (e.g. the manifest) which are directly within it are
+    // kept, as is anything which only matters locally (e.g. the downloads).
+    pub fn clean(&self, staging: &Path, uploaded: &HashSet<PathBuf>) -> Result<()> {
+        for file in uploaded {
+            let top = file.components().next().map(|top| top.as_os_str());
+            if file.parent() == Some(Path::new("")) || top.is_some_and(output::is_local) {
+                continue;
+            }
+            let path = staging.join(file);
+            match fs::remove_file(&path) {
+                Err(err) if err.kind() != ErrorKind::NotFound => {
+                    return Err(err).context(format!("remove {}", path.display()))
+                }
+                _ => {}
+            }
+        }
+
+        // The albums whose items were all uploaded by an earlier run are left empty too
+        let mut dirs = Vec::new();
+        let mut pending = vec![PathBuf::new()];
+        while let Some(dir) = pending.pop() {
+            let full = staging.join(&dir);
+            for entry in
+                fs::read_dir(&full).context(format!("list directory {}", full.display()))?
+            {
+                let entry = entry.context("entry")?;
+                let name = entry.file_name();
+                if !output::is_local(&name) && entry.file_type().context("file type")?.is_dir() {
+                    dirs.push(dir.join(&name));
+                    pending.push(dir.join(&name));
+                }
+            }
+        }
+        // Children sort after their parents, so they're removed first
+        dirs.sort();
+        for dir in dirs.iter().rev() {
+            let path = staging.join(dir);
+            let mut entries =
+                fs::read_dir(&path).context(format!("list directory {}", path.display()))?;
+            if entries.next().is_none() {
+                fs::remove_dir(&path).context(format!("remove directory {}", path.display()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Percent-encodes everything but the unreserved characters, for use in a URL.
+pub fn encode(part: &str) -> String {
+    part.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            byte => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+// Decodes the percent-encoded characters in part of a URL (e.g. a password given in it).
+pub fn decode(part: &str) -> Result<String> {
+    let mut decoded = Vec::with_capacity(part.len());
+    let mut bytes = part.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte != b'%' {
+            decoded.push(byte);
+            continue;
+        }
+        let hex = [bytes.next(), bytes.next()]
+            .iter()
+            .map(|byte| byte.map(char::from))
+            .collect::<Option<String>>()
+            .context(format!("incomplete escape in {:?}", part))?;
+        decoded.push(
+            u8::from_str_radix(&hex, 16)
+                .context(format!("invalid escape %{} in {:?}", hex, part))?,
+        );
+    }
+    String::from_utf8(decoded).context(format!("invalid UTF-8 in {:?}", part))
+}
+
+// Runs the request, trying again after a growing delay if it fails.
+pub fn retry<T, F: FnMut() -> Result<T>>(what: &str, mut f: F) -> Result<T> {
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Ok(result) => return Ok(result),
+            Err(err) if attempt < ATTEMPTS => {
+                warn!("Failed to {} ({:#}); retrying", what, err);
+                thread::sleep(Duration::from_secs(1 << attempt));
+                attempt += 1;
+            }
+            Err(err) => {
+                debug!("Giving up on {} after {} attempts", what, attempt);
+                return Err(err).context(what.to_string());
+            }
+        }
+    }
+}
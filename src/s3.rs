@@ -0,0 +1,394 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// S3 and compatible object stores (e.g. Backblaze B2 or MinIO). Requests are signed with AWS
+// Signature Version 4, leaving the payload unsigned so that files can be streamed. Small files are
+// uploaded with a single PUT and larger ones in parts, at most `--upload-jobs` parts at once.
+//
+// The credentials and region are taken from the usual environment variables (AWS_ACCESS_KEY_ID,
+// AWS_SECRET_ACCESS_KEY, AWS_SESSION_TOKEN, and AWS_REGION). AWS_ENDPOINT_URL selects another
+// provider, which is addressed by path (e.g. "http://localhost:9000/bucket/key") rather than by
+// host name.
+
+use crate::remote::{encode, retry};
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use hmac::{KeyInit, Mac};
+use log::{info, trace, warn};
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use ureq::http::Request;
+use ureq::{Agent, SendBody};
+
+type Hmac = hmac::Hmac<Sha256>;
+
+// Files larger than this are uploaded in parts of this size
+const PART_SIZE: u64 = 16 * 1024 * 1024;
+
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+pub struct S3 {
+    agent: Agent,
+    // Uploads the files and the parts of large files
+    pool: ThreadPool,
+    scheme: String,
+    host: String,
+    // The bucket, when it's addressed by path rather than by host name
+    bucket_path: String,
+    // The prefix of every key, ending with a slash if it's not empty
+    prefix: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+}
+
+impl S3 {
+    // Creates the client from the part of the URL after "s3://" (i.e. "bucket/prefix"), uploading
+    // at most `jobs` files or parts at once.
+    pub fn new(rest: &str, jobs: usize) -> Result<S3> {
+        let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        if bucket.is_empty() {
+            return Err(anyhow!("missing bucket in s3://{}", rest));
+        }
+        let prefix = prefix.trim_matches('/');
+        let region = std::env::var("AWS_REGION")
+            .or_else(|_| std::env::var("AWS_DEFAULT_REGION"))
+            .unwrap_or_else(|_| "us-east-1".to_string());
+
+        let (scheme, host, bucket_path) = match std::env::var("AWS_ENDPOINT_URL") {
+            Ok(endpoint) => {
+                let (scheme, host) = endpoint
+                    .trim_end_matches('/')
+                    .split_once("://")
+                    .context(format!("invalid AWS_ENDPOINT_URL {}", endpoint))?;
+                (
+                    scheme.to_string(),
+                    host.to_string(),
+                    format!("/{}", encode(bucket)),
+                )
+            }
+            Err(_) => (
+                "https".to_string(),
+                format!("{}.s3.{}.amazonaws.com", bucket, region),
+                String::new(),
+            ),
+        };
+
+        let config = Agent::config_builder().http_status_as_error(false).build();
+        Ok(S3 {
+            agent: Agent::new_with_config(config),
+            pool: ThreadPoolBuilder::new()
+                .num_threads(jobs.max(1))
+                .build()
+                .context("start upload threads")?,
+            scheme,
+            host,
+            bucket_path,
+            prefix: if prefix.is_empty() {
+                String::new()
+            } else {
+                format!("{}/", prefix)
+            },
+            region,
+            access_key: std::env::var("AWS_ACCESS_KEY_ID").context("AWS_ACCESS_KEY_ID")?,
+            secret_key: std::env::var("AWS_SECRET_ACCESS_KEY").context("AWS_SECRET_ACCESS_KEY")?,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+
+    // Object stores don't have directories, so only the files are uploaded.
+    pub fn upload(&self, root: &Path, files: &[PathBuf]) -> Result<()> {
+        info!(
+            "Uploading {} files to {}://{}{}/{}",
+            files.len(),
+            self.scheme,
+            self.host,
+            self.bucket_path,
+            self.prefix
+        );
+        self.pool.install(|| {
+            files
+                .par_iter()
+                .try_for_each(|file| self.upload_file(root, file))
+        })
+    }
+
+    pub fn upload_file(&self, root: &Path, file: &Path) -> Result<()> {
+        let key = self.key(file)?;
+        let path = root.join(file);
+        let len = path
+            .metadata()
+            .context(format!("stat {}", path.display()))?
+            .len();
+        trace!("Uploading {} to {}", path.display(), key);
+        if len > PART_SIZE {
+            self.put_multipart(&path, &key, len)
+        } else {
+            retry(&format!("upload {}", key), || {
+                self.put_part(&path, &key, 0, len, &[])
+            })
+            .map(|_| ())
+        }
+    }
+
+    fn put_multipart(&self, path: &Path, key: &str, len: u64) -> Result<()> {
+        let response = retry(&format!("start upload of {}", key), || {
+            self.send("POST", key, &[("uploads", "")], SendBody::none(), None)
+        })?;
+        let upload_id = element(&response, "UploadId")
+            .context("upload ID")?
+            .to_string();
+
+        // The parts which were already uploaded are kept by the store (and billed) until the upload
+        // is either completed or aborted
+        let completed = self.put_parts(path, key, len, &upload_id);
+        if let Err(err) = &completed {
+            warn!("Aborting upload of {}: {:#}", key, err);
+            if let Err(err) = retry(&format!("abort upload of {}", key), || {
+                self.send(
+                    "DELETE",
+                    key,
+                    &[("uploadId", &upload_id)],
+                    SendBody::none(),
+                    None,
+                )
+            }) {
+                warn!("Failed to abort upload of {}: {:#}", key, err);
+            }
+        }
+        completed
+    }
+
+    fn put_parts(&self, path: &Path, key: &str, len: u64, upload_id: &str) -> Result<()> {
+        let parts = (0..len.div_ceil(PART_SIZE))
+            .map(|index| (index + 1, index * PART_SIZE))
+            .collect::<Vec<_>>();
+        let parts = self.pool.install(|| {
+            parts
+                .par_iter()
+                .map(|&(number, offset)| {
+                    let size = PART_SIZE.min(len - offset);
+                    let number = number.to_string();
+                    let query = [("partNumber", number.as_str()), ("uploadId", upload_id)];
+                    let etag = retry(&format!("upload part {} of {}", number, key), || {
+                        self.put_part(path, key, offset, size, &query)
+                    })?;
+                    Ok(format!(
+                        "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                        number, etag
+                    ))
+                })
+                .collect::<Result<String>>()
+        })?;
+
+        let body = format!(
+            "<CompleteMultipartUpload>{}</CompleteMultipartUpload>",
+            parts
+        );
+        retry(&format!("finish upload of {}", key), || {
+            let response = self.send(
+                "POST",
+                key,
+                &[("uploadId", upload_id)],
+                SendBody::from_reader(&mut body.as_bytes()),
+                Some(body.len() as u64),
+            )?;
+            // Failures can be reported after the response has started, with a status of 200
+            match element(&response, "Error") {
+                Some(_) => Err(anyhow!("{}", response)),
+                None => Ok(()),
+            }
+        })
+    }
+
+    // Uploads `size` bytes of the file, starting at `offset`, returning the ETag of the object or
+    // part.
+    fn put_part(
+        &self,
+        path: &Path,
+        key: &str,
+        offset: u64,
+        size: u64,
+        query: &[(&str, &str)],
+    ) -> Result<String> {
+        let mut file = File::open(path).context(format!("open {}", path.display()))?;
+        file.seek(SeekFrom::Start(offset))
+            .context(format!("seek {}", path.display()))?;
+        let mut reader = BufReader::new(file).take(size);
+
+        let request = self.request("PUT", key, query, Some(size))?;
+        let response = self
+            .agent
+            .run(
+                request
+                    .body(SendBody::from_reader(&mut reader))
+                    .context("build request")?,
+            )
+            .context("send")?;
+        match response.status().as_u16() {
+            200..=299 => Ok(response
+                .headers()
+                .get("ETag")
+                .and_then(|etag| etag.to_str().ok())
+                .unwrap_or_default()
+                .to_string()),
+            status => Err(anyhow!("http status: {}", status)),
+        }
+    }
+
+    // Sends the request, returning the body of the response.
+    fn send(
+        &self,
+        method: &str,
+        key: &str,
+        query: &[(&str, &str)],
+        body: SendBody,
+        len: Option<u64>,
+    ) -> Result<String> {
+        let request = self.request(method, key, query, len.or(Some(0)))?;
+        let mut response = self
+            .agent
+            .run(request.body(body).context("build request")?)
+            .context("send")?;
+        let status = response.status().as_u16();
+        let body = response
+            .body_mut()
+            .read_to_string()
+            .context("read response")?;
+        match status {
+            200..=299 => Ok(body),
+            status => Err(anyhow!("http status: {}: {}", status, body)),
+        }
+    }
+
+    // Builds a signed request for the object.
+    fn request(
+        &self,
+        method: &str,
+        key: &str,
+        query: &[(&str, &str)],
+        len: Option<u64>,
+    ) -> Result<ureq::http::request::Builder> {
+        let now = Utc::now();
+        let timestamp = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+
+        let path = format!(
+            "{}/{}",
+            self.bucket_path,
+            key.split('/').map(encode).collect::<Vec<_>>().join("/")
+        );
+        let mut query = query
+            .iter()
+            .map(|(name, value)| format!("{}={}", encode(name), encode(value)))
+            .collect::<Vec<_>>();
+        query.sort();
+        let query = query.join("&");
+
+        let mut headers = vec![
+            ("host", self.host.clone()),
+            ("x-amz-content-sha256", UNSIGNED_PAYLOAD.to_string()),
+            ("x-amz-date", timestamp.clone()),
+        ];
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method,
+            path,
+            query,
+            headers
+                .iter()
+                .map(|(name, value)| format!("{}:{}\n", name, value))
+                .collect::<String>(),
+            signed_headers,
+            UNSIGNED_PAYLOAD
+        );
+
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            timestamp,
+            scope,
+            hex(&Sha256::digest(canonical.as_bytes()))
+        );
+        let mut key_bytes = hmac(format!("AWS4{}", self.secret_key).as_bytes(), &date)?;
+        for part in &[self.region.as_str(), "s3", "aws4_request"] {
+            key_bytes = hmac(&key_bytes, part)?;
+        }
+        let signature = hex(&hmac(&key_bytes, &to_sign)?);
+
+        let mut uri = format!("{}://{}{}", self.scheme, self.host, path);
+        if !query.is_empty() {
+            uri = format!("{}?{}", uri, query);
+        }
+        let mut request = Request::builder().method(method).uri(uri).header(
+            "Authorization",
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                self.access_key, scope, signed_headers, signature
+            ),
+        );
+        for (name, value) in headers.into_iter().filter(|(name, _)| *name != "host") {
+            request = request.header(name, value);
+        }
+        if let Some(len) = len {
+            request = request.header("Content-Length", len.to_string());
+        }
+        Ok(request)
+    }
+
+    // The key of the object for the path within the output.
+    fn key(&self, path: &Path) -> Result<String> {
+        let mut parts = Vec::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(part) => parts.push(
+                    part.to_str()
+                        .context(format!("non-UTF-8 path {}", path.display()))?,
+                ),
+                _ => return Err(anyhow!("unsupported path {}", path.display())),
+            }
+        }
+        Ok(format!("{}{}", self.prefix, parts.join("/")))
+    }
+}
+
+fn hmac(key: &[u8], data: &str) -> Result<Vec<u8>> {
+    let mut mac = Hmac::new_from_slice(key).map_err(|_| anyhow!("invalid signing key"))?;
+    mac.update(data.as_bytes());
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// The text of the first element with the given name in the XML response.
+fn element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let end = xml[start..].find(&format!("</{}>", name))? + start;
+    Some(&xml[start..end])
+}
@@ -0,0 +1,313 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Album and file names come from whatever was typed into Facebook, so they can hold characters
+// which file systems don't allow. The minimal policy only changes what the file systems of the
+// platform running fb-unarchive reject, while the strict one makes names which are safe anywhere
+// (e.g. for output which will be copied onto a FAT-formatted drive). On Windows, the output
+// directory is also given as an extended-length path, so that deeply nested albums aren't limited
+// to 260 characters in all.
+//
+// Names can also be normalized to a single Unicode form, since the same name can be written in
+// more than one (e.g. "é" as one character or as "e" and an accent). macOS prefers the decomposed
+// form (NFD) and most other systems the composed one (NFC), so the same album can otherwise end up
+// looking like two.
+
+use anyhow::{anyhow, Error, Result};
+use icu_normalizer::{ComposingNormalizerBorrowed, DecomposingNormalizerBorrowed};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// Replaces each character which isn't allowed
+const REPLACEMENT: char = '_';
+
+// Characters which Windows doesn't allow in names
+const WINDOWS_RESERVED: &[char] = &['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+
+// Names of devices on Windows, which can't be used even with an extension (e.g. "CON.jpg")
+const WINDOWS_DEVICES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+// The longest name, in bytes, which most file systems allow
+const MAX_LEN: usize = 255;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sanitize {
+    Minimal,
+    Strict,
+}
+
+impl FromStr for Sanitize {
+    type Err = Error;
+
+    fn from_str(sanitize: &str) -> Result<Sanitize> {
+        match sanitize {
+            "minimal" => Ok(Sanitize::Minimal),
+            "strict" => Ok(Sanitize::Strict),
+            _ => Err(anyhow!("unknown sanitization policy {}", sanitize)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Normalization {
+    Nfc,
+    Nfd,
+}
+
+impl FromStr for Normalization {
+    type Err = Error;
+
+    fn from_str(normalization: &str) -> Result<Normalization> {
+        match normalization {
+            "nfc" => Ok(Normalization::Nfc),
+            "nfd" => Ok(Normalization::Nfd),
+            _ => Err(anyhow!("unknown normalization form {}", normalization)),
+        }
+    }
+}
+
+impl Normalization {
+    pub fn apply(self, name: &str) -> String {
+        match self {
+            Normalization::Nfc => ComposingNormalizerBorrowed::new_nfc()
+                .normalize(name)
+                .into_owned(),
+            Normalization::Nfd => DecomposingNormalizerBorrowed::new_nfd()
+                .normalize(name)
+                .into_owned(),
+        }
+    }
+}
+
+impl Sanitize {
+    // The name with anything the policy disallows replaced, so that it can be used as the name of
+    // a single file or directory.
+    pub fn name(self, name: &str) -> String {
+        self.sanitize(name, MAX_LEN)
+    }
+
+    // The name of a file with the given stem and extension. Names which are too long are shortened
+    // without losing the extension.
+    pub fn file_name(self, stem: &str, extension: &str) -> String {
+        let max = MAX_LEN.saturating_sub(extension.len() + 1);
+        format!("{}.{}", self.sanitize(stem, max), self.name(extension))
+    }
+
+    fn sanitize(self, name: &str, max: usize) -> String {
+        let windows = self == Sanitize::Strict || cfg!(windows);
+        let mut sanitized = name
+            .chars()
+            .map(|c| match c {
+                '/' | '\0' => REPLACEMENT,
+                c if c.is_control() => REPLACEMENT,
+                c if windows && WINDOWS_RESERVED.contains(&c) => REPLACEMENT,
+                // Characters outside of the Basic Multilingual Plane (e.g. most emoji) can't be
+                // stored by file systems which use UCS-2, like FAT
+                c if self == Sanitize::Strict && c.len_utf16() > 1 => REPLACEMENT,
+                c => c,
+            })
+            .collect::<String>();
+
+        if windows {
+            // Windows drops trailing dots and spaces, so names which differ by them would collide
+            sanitized = sanitized.trim_end_matches(['.', ' ']).to_string();
+            let stem = sanitized.split('.').next().unwrap_or_default();
+            if WINDOWS_DEVICES
+                .iter()
+                .any(|device| device.eq_ignore_ascii_case(stem.trim_end()))
+            {
+                sanitized.insert(stem.len(), REPLACEMENT);
+            }
+        }
+        if self == Sanitize::Strict {
+            sanitized = sanitized.trim_start().to_string();
+        }
+        sanitized = truncate(&sanitized, max);
+
+        match sanitized.as_str() {
+            "" | "." | ".." => REPLACEMENT.to_string(),
+            _ => sanitized,
+        }
+    }
+}
+
+// The name shortened to no more than `len` bytes, without splitting a character. Any suffix which
+// sets it apart from others (e.g. " (2016-08-14)") is kept.
+fn truncate(name: &str, len: usize) -> String {
+    if name.len() <= len {
+        return name.to_string();
+    }
+    let suffix = match name.rfind(" (") {
+        Some(start) if name.ends_with(')') && name.len() - start <= 16 => &name[start..],
+        _ => "",
+    };
+    let len = len.saturating_sub(suffix.len());
+    let end = (0..=len)
+        .rev()
+        .find(|end| name.is_char_boundary(*end))
+        .unwrap_or(0);
+    format!("{}{}", name[..end].trim_end(), suffix)
+}
+
+// The directory as an extended-length path (e.g. "\\?\C:\Photos"), which isn't limited to 260
+// characters. These can't be relative, so it's made absolute first.
+#[cfg(windows)]
+pub fn long_path(path: &Path) -> Result<PathBuf> {
+    use anyhow::Context;
+
+    let absolute = std::path::absolute(path).context(format!("resolve {}", path.display()))?;
+    let absolute = absolute.as_os_str().to_string_lossy();
+    Ok(PathBuf::from(if absolute.starts_with(r"\\?\") {
+        absolute.into_owned()
+    } else if let Some(share) = absolute.strip_prefix(r"\\") {
+        format!(r"\\?\UNC\{}", share)
+    } else {
+        format!(r"\\?\{}", absolute)
+    }))
+}
+
+// Other platforms don't limit the length of paths in the same way.
+#[cfg(not(windows))]
+pub fn long_path(path: &Path) -> Result<PathBuf> {
+    Ok(path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncate_short() {
+        assert_eq!(truncate("Vacation", 255), "Vacation");
+    }
+
+    #[test]
+    fn truncate_long() {
+        assert_eq!(truncate("Summer vacation", 9), "Summer va");
+        assert_eq!(truncate("Summer vacation", 7), "Summer");
+        // "é" is two bytes, so cutting after the first of them leaves it out entirely
+        assert_eq!(truncate("Café", 4), "Caf");
+    }
+
+    #[test]
+    fn truncate_keeps_suffix() {
+        let name = format!("{} (2016-08-14)", "x".repeat(300));
+        let truncated = truncate(&name, MAX_LEN);
+        assert_eq!(truncated.len(), MAX_LEN);
+        assert!(truncated.ends_with("x (2016-08-14)"));
+
+        let name = format!("{} (2)", "x".repeat(300));
+        assert!(truncate(&name, MAX_LEN).ends_with("x (2)"));
+        // Only short suffixes are taken to tell names apart
+        let name = format!("{} ({})", "x".repeat(300), "y".repeat(20));
+        assert!(truncate(&name, MAX_LEN).ends_with('x'));
+    }
+
+    #[test]
+    fn file_name_keeps_extension() {
+        let name = Sanitize::Minimal.file_name(&"x".repeat(300), "jpg");
+        assert_eq!(name.len(), MAX_LEN);
+        assert!(name.ends_with("x.jpg"));
+    }
+
+    #[test]
+    fn minimal() {
+        assert_eq!(Sanitize::Minimal.name("a/b\0c\n"), "a_b_c_");
+        assert_eq!(Sanitize::Minimal.name(""), "_");
+        assert_eq!(Sanitize::Minimal.name(".."), "_");
+        assert_eq!(Sanitize::Minimal.name("Vacation 😂"), "Vacation 😂");
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn minimal_keeps_windows_names() {
+        assert_eq!(Sanitize::Minimal.name("A:B?"), "A:B?");
+        assert_eq!(Sanitize::Minimal.name("CON"), "CON");
+        assert_eq!(Sanitize::Minimal.name("Trip..."), "Trip...");
+    }
+
+    // Windows doesn't allow these names at all, so they're changed whatever the policy
+    #[cfg(windows)]
+    #[test]
+    fn minimal_changes_windows_names() {
+        assert_eq!(Sanitize::Minimal.name("A:B?"), "A_B_");
+        assert_eq!(Sanitize::Minimal.name("CON"), "CON_");
+        assert_eq!(Sanitize::Minimal.name("Trip..."), "Trip");
+    }
+
+    #[test]
+    fn strict_reserved_characters() {
+        assert_eq!(Sanitize::Strict.name(r#"<a>:"b"|c?*\"#), "_a___b__c___");
+    }
+
+    #[test]
+    fn strict_devices() {
+        assert_eq!(Sanitize::Strict.name("CON"), "CON_");
+        assert_eq!(Sanitize::Strict.name("con"), "con_");
+        assert_eq!(Sanitize::Strict.name("Lpt1 "), "Lpt1_");
+        assert_eq!(Sanitize::Strict.file_name("nul", "jpg"), "nul_.jpg");
+        assert_eq!(Sanitize::Strict.name("CONSOLE"), "CONSOLE");
+        assert_eq!(Sanitize::Strict.name("COM10"), "COM10");
+    }
+
+    #[test]
+    fn strict_trims() {
+        assert_eq!(Sanitize::Strict.name("Trip. . "), "Trip");
+        assert_eq!(Sanitize::Strict.name("  Trip"), "Trip");
+        assert_eq!(Sanitize::Strict.name("..."), "_");
+    }
+
+    #[test]
+    fn strict_emoji() {
+        assert_eq!(Sanitize::Strict.name("Vacation 😂"), "Vacation _");
+        assert_eq!(Sanitize::Strict.name("Café"), "Café");
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn long_path_unchanged() {
+        assert_eq!(
+            long_path(Path::new("out/Vacation")).unwrap(),
+            Path::new("out/Vacation")
+        );
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn long_path_absolute() {
+        assert_eq!(
+            long_path(Path::new(r"C:\Photos\Vacation")).unwrap(),
+            Path::new(r"\\?\C:\Photos\Vacation")
+        );
+        assert_eq!(
+            long_path(Path::new(r"\\server\share\Photos")).unwrap(),
+            Path::new(r"\\?\UNC\server\share\Photos")
+        );
+        assert_eq!(
+            long_path(Path::new(r"\\?\C:\Photos")).unwrap(),
+            Path::new(r"\\?\C:\Photos")
+        );
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn long_path_relative() {
+        let path = long_path(Path::new("out")).unwrap();
+        let expected = std::env::current_dir().unwrap().join("out");
+        assert_eq!(path, Path::new(&format!(r"\\?\{}", expected.display())));
+    }
+}
@@ -0,0 +1,123 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Recognizes the type of a file from its first few bytes, since some of the files in exports have
+// the wrong extension or none at all. Types are named by their usual extension.
+
+use anyhow::{Context, Result};
+use std::io::{self, Read};
+
+// Enough to reach the brand of an MP4 and the format of a WebP
+const HEADER_LEN: u64 = 12;
+
+// An ID3v2 tag's header (and footer, if its flag is set), which aren't counted in its size
+const ID3_HEADER_LEN: u64 = 10;
+const ID3_FOOTER_FLAG: u8 = 0x10;
+
+pub fn detect(file: &mut dyn Read) -> Result<Option<&'static str>> {
+    let mut header = Vec::new();
+    file.take(HEADER_LEN)
+        .read_to_end(&mut header)
+        .context("read header")?;
+
+    Ok(match header.as_slice() {
+        [0xff, 0xd8, 0xff, ..] => Some("jpg"),
+        [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n', ..] => Some("png"),
+        [b'G', b'I', b'F', b'8', b'7', b'a', ..] | [b'G', b'I', b'F', b'8', b'9', b'a', ..] => {
+            Some("gif")
+        }
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("webp"),
+        [_, _, _, _, b'f', b't', b'y', b'p', brand @ ..] if brand.len() >= 4 => ftyp(&brand[..4]),
+        [b'F', b'L', b'V', ..] => Some("flv"),
+        [b'I', b'D', b'3', ..] => return after_id3(&header, file),
+        [0xff, sync, ..] => audio(*sync),
+        _ => None,
+    })
+}
+
+// The type of an ISO media file from its major brand. Photos share the container (e.g. HEIC and
+// AVIF), so only the brands of videos and audio are recognized; the rest are left to their
+// extensions.
+fn ftyp(brand: &[u8]) -> Option<&'static str> {
+    match brand {
+        b"M4A " => Some("m4a"),
+        b"isom" | b"mp41" | b"mp42" | b"M4V " | b"qt  " | b"avc1" => Some("mp4"),
+        [b'i', b's', b'o', version] if version.is_ascii_digit() => Some("mp4"),
+        [b'3', b'g', ..] => Some("mp4"),
+        _ => None,
+    }
+}
+
+// An ID3 tag can be in front of either AAC or MP3, so it's the frame after it which tells them
+// apart.
+fn after_id3(header: &[u8], file: &mut dyn Read) -> Result<Option<&'static str>> {
+    let (flags, size) = match header {
+        [_, _, _, _, _, flags, size @ ..] if size.len() >= 4 => (*flags, &size[..4]),
+        _ => return Ok(None),
+    };
+    // Each byte of the size holds 7 bits
+    let size = size
+        .iter()
+        .fold(0, |size, byte| size << 7 | u64::from(byte & 0x7f));
+    let footer = if flags & ID3_FOOTER_FLAG != 0 {
+        ID3_HEADER_LEN
+    } else {
+        0
+    };
+    let start = ID3_HEADER_LEN + size + footer;
+
+    let read = header.len() as u64;
+    let mut rest = header[start.min(read) as usize..].chain(file);
+    io::copy(
+        &mut (&mut rest).take(start.saturating_sub(read)),
+        &mut io::sink(),
+    )
+    .context("read ID3 tag")?;
+    let mut frame = Vec::new();
+    rest.take(2)
+        .read_to_end(&mut frame)
+        .context("read header")?;
+
+    Ok(match frame.as_slice() {
+        [0xff, sync] => audio(*sync),
+        _ => None,
+    })
+}
+
+// The type of an audio stream from the byte after the 0xff which starts its frames
+fn audio(sync: u8) -> Option<&'static str> {
+    match sync {
+        // AAC in an ADTS stream
+        0xf1 | 0xf9 => Some("aac"),
+        // MPEG audio, layer III
+        _ if sync & 0xe0 == 0xe0 && sync & 0x06 == 0x02 => Some("mp3"),
+        _ => None,
+    }
+}
+
+// The type of a file with the given extension, for those whose contents aren't recognized.
+pub fn from_extension(extension: &str) -> Option<&'static str> {
+    match extension.to_lowercase().as_str() {
+        "jpg" | "jpeg" => Some("jpg"),
+        "png" => Some("png"),
+        "gif" => Some("gif"),
+        "webp" => Some("webp"),
+        "mp4" => Some("mp4"),
+        "flv" => Some("flv"),
+        "aac" => Some("aac"),
+        "m4a" => Some("m4a"),
+        "mp3" => Some("mp3"),
+        _ => None,
+    }
+}
@@ -0,0 +1,140 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// JPEGs written by splicing new metadata onto the original image data. All of the metadata is in
+// the segments in front of the first scan, so only those are read and rewritten; everything from
+// the start of the scan onwards is copied straight from the archive into the output. The image
+// data is never held in memory, and comes out exactly as it went in. Along the way, the file is
+// checked to be a JPEG which is whole, but the image data itself isn't decoded.
+
+use anyhow::{Error, Result};
+use img_parts::jpeg::{markers, Jpeg};
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+
+// The file is damaged: it's been cut short, or isn't a JPEG at all
+#[derive(Debug)]
+pub struct Corrupt(String);
+
+impl fmt::Display for Corrupt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "corrupt jpeg: {}", self.0)
+    }
+}
+
+impl std::error::Error for Corrupt {}
+
+// The segments in front of the image data
+pub struct Header {
+    pub jpeg: Jpeg,
+    // Whether the image data follows, as opposed to the end of the file
+    scan: bool,
+}
+
+// Reads the segments up to the start of the first scan, leaving the file at the image data.
+pub fn read_header(file: &mut dyn Read) -> Result<Header> {
+    let mut header = vec![0; 2];
+    read_exact(file, &mut header)?;
+    if header != [markers::P, markers::SOI] {
+        return Err(Corrupt("not a jpeg".to_string()).into());
+    }
+
+    let scan = loop {
+        let mut byte = [0; 1];
+        read_exact(file, &mut byte)?;
+        if byte[0] != markers::P {
+            return Err(Corrupt(format!("expected a marker, found {:#04x}", byte[0])).into());
+        }
+        // Markers may be padded with any number of fill bytes
+        let marker = loop {
+            read_exact(file, &mut byte)?;
+            if byte[0] != markers::P {
+                break byte[0];
+            }
+        };
+        match marker {
+            markers::SOS => break true,
+            markers::EOI => break false,
+            // TEM and the restart markers stand alone, without a length
+            0x01 | markers::RST0..=markers::RST7 => {
+                header.extend([markers::P, marker]);
+                continue;
+            }
+            _ => {}
+        }
+
+        let mut len = [0; 2];
+        read_exact(file, &mut len)?;
+        let mut contents = vec![0; usize::from(u16::from_be_bytes(len).saturating_sub(2))];
+        read_exact(file, &mut contents)?;
+        header.extend([markers::P, marker]);
+        header.extend(len);
+        header.extend(contents);
+    };
+
+    // The header is ended as though it were the whole image, so that it can be parsed on its own
+    header.extend([markers::P, markers::EOI]);
+    Ok(Header {
+        jpeg: Jpeg::from_bytes(header.into()).map_err(|e| Corrupt(e.to_string()))?,
+        scan,
+    })
+}
+
+// Writes the header followed by the rest of the file, returning the number of bytes written. The
+// image data has to end (with an EOI marker) for the file to be whole; what's written is left for
+// the caller to remove if it isn't.
+pub fn write<W: Write>(header: Header, file: &mut dyn Read, mut out: W) -> Result<u64> {
+    let mut written = header.jpeg.encoder().write_to(&mut out)?;
+    if header.scan {
+        out.write_all(&[markers::P, markers::SOS])?;
+        let mut scan = Scan {
+            file,
+            marker: false,
+            ended: false,
+        };
+        written += 2 + io::copy(&mut scan, &mut out)?;
+        if !scan.ended {
+            return Err(Corrupt("the image data is cut short".to_string()).into());
+        }
+    }
+    out.flush()?;
+    Ok(written)
+}
+
+fn read_exact(file: &mut dyn Read, buf: &mut [u8]) -> Result<()> {
+    file.read_exact(buf).map_err(|err| match err.kind() {
+        ErrorKind::UnexpectedEof => Corrupt("the file is cut short".to_string()).into(),
+        _ => Error::new(err).context("read jpeg"),
+    })
+}
+
+// The image data, watching for its end. Within the data, a 0xFF byte is always followed by a zero
+// or a restart marker, so the EOI marker can't appear until the end.
+struct Scan<'a> {
+    file: &'a mut dyn Read,
+    // Whether the last byte was 0xFF
+    marker: bool,
+    ended: bool,
+}
+
+impl Read for Scan<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.file.read(buf)?;
+        for byte in &buf[..read] {
+            self.ended |= self.marker && *byte == markers::EOI;
+            self.marker = *byte == markers::P;
+        }
+        Ok(read)
+    }
+}
@@ -0,0 +1,86 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use anyhow::{Context, Result};
+use indicatif::HumanBytes;
+use log::{Level, Log, Metadata, Record};
+use serde::Serialize;
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static WARNINGS: AtomicUsize = AtomicUsize::new(0);
+
+// Wraps a logger, counting the warnings which pass through it.
+pub struct CountWarnings<L>(pub L);
+
+impl<L: Log> Log for CountWarnings<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if record.level() == Level::Warn {
+            WARNINGS.fetch_add(1, Ordering::Relaxed);
+        }
+        self.0.log(record)
+    }
+
+    fn flush(&self) {
+        self.0.flush()
+    }
+}
+
+pub fn warnings() -> usize {
+    WARNINGS.load(Ordering::Relaxed)
+}
+
+// The totals for a run, printed once it finishes
+#[derive(Debug, Default, Serialize)]
+pub struct Summary {
+    pub albums: usize,
+    pub photos: usize,
+    pub videos: usize,
+    pub audio: usize,
+    pub bytes: u64,
+    pub skipped: usize,
+    pub warnings: usize,
+    pub failures: usize,
+}
+
+impl Summary {
+    pub fn print(&self, json: bool, out: &mut dyn Write) -> Result<()> {
+        if json {
+            serde_json::to_writer(&mut *out, self).context("write summary")?;
+            writeln!(out).context("write summary")?;
+            return Ok(());
+        }
+
+        writeln!(
+            out,
+            "Processed {} albums: {} photos, {} videos, and {} audio clips ({} written)",
+            self.albums,
+            self.photos,
+            self.videos,
+            self.audio,
+            HumanBytes(self.bytes)
+        )
+        .context("write summary")?;
+        writeln!(
+            out,
+            "{} items skipped (unrecognized type), {} warnings, {} failures",
+            self.skipped, self.warnings, self.failures
+        )
+        .context("write summary")
+    }
+}
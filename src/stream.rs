@@ -0,0 +1,197 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Lists of items read from JSON files one at a time. Some exports put tens of thousands of items in
+// a single file, and reading the whole document before picking out the list (as a
+// `serde_json::Value`) takes many times the size of the file in memory. Instead, each element is
+// handed over as soon as it's been read and everything else in the file is skipped over (or, for
+// `split`, kept).
+
+use anyhow::{Context, Result};
+use serde::de::{
+    self, DeserializeOwned, DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor,
+};
+use serde::Deserializer;
+use serde_json::{Map, Value};
+use std::fmt;
+use std::io::{BufReader, Read};
+use std::marker::PhantomData;
+
+// Reads the list nested under the first of the keys (in their order) in the JSON object, passing
+// each of its elements to `f`, or the elements of the document itself if there are no keys.
+// Returns whether the list was found.
+pub fn for_each<T, F>(file: &mut dyn Read, keys: &[&str], mut f: F) -> Result<bool>
+where
+    T: DeserializeOwned,
+    F: FnMut(T),
+{
+    try_for_each(file, keys, |element| {
+        f(element);
+        Ok(())
+    })
+}
+
+// Like `for_each`, but stops at the first error from `f`.
+pub fn try_for_each<T, F>(file: &mut dyn Read, keys: &[&str], f: F) -> Result<bool>
+where
+    T: DeserializeOwned,
+    F: FnMut(T) -> Result<()>,
+{
+    let mut deserializer = serde_json::Deserializer::from_reader(BufReader::new(file));
+    let found = deserializer
+        .deserialize_any(Document {
+            keys,
+            elements: Elements {
+                f,
+                element: PhantomData,
+            },
+        })
+        .context("parse json")?;
+    deserializer.end().context("parse json")?;
+    Ok(found)
+}
+
+// Reads the JSON object, passing each element of the list under the key to `f`, and returns the
+// rest of the object (which is expected to be small).
+pub fn split<R, T, F>(file: &mut dyn Read, key: &str, f: F) -> Result<R>
+where
+    R: DeserializeOwned,
+    T: DeserializeOwned,
+    F: FnMut(T) -> Result<()>,
+{
+    let mut deserializer = serde_json::Deserializer::from_reader(BufReader::new(file));
+    let rest = deserializer
+        .deserialize_map(Rest {
+            key,
+            elements: Elements {
+                f,
+                element: PhantomData,
+            },
+        })
+        .context("parse json")?;
+    deserializer.end().context("parse json")?;
+    serde_json::from_value(Value::Object(rest)).context("parse json")
+}
+
+struct Document<'a, T, F> {
+    keys: &'a [&'a str],
+    elements: Elements<T, F>,
+}
+
+impl<'de, T: DeserializeOwned, F: FnMut(T) -> Result<()>> Visitor<'de> for Document<'_, T, F> {
+    type Value = bool;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self.keys {
+            [] => formatter.write_str("a list"),
+            keys => write!(formatter, "an object with one of {}", keys.join(", ")),
+        }
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(mut self, seq: A) -> Result<bool, A::Error> {
+        if !self.keys.is_empty() {
+            return Err(de::Error::invalid_type(de::Unexpected::Seq, &self));
+        }
+        self.elements.each(seq)
+    }
+
+    // The list under the first key is handed over as it's read, wherever it is in the object. Those
+    // under the others are held until the end, in case one of the keys before them comes later.
+    fn visit_map<A: MapAccess<'de>>(mut self, mut map: A) -> Result<bool, A::Error> {
+        let mut found: Option<usize> = None;
+        let mut held = Vec::new();
+        while let Some(key) = map.next_key::<String>()? {
+            match self.keys.iter().position(|candidate| *candidate == key) {
+                Some(0) if found != Some(0) => {
+                    held.clear();
+                    map.next_value_seed(&mut self.elements)?;
+                    found = Some(0);
+                }
+                Some(rank) if found.is_none_or(|found| rank < found) => {
+                    held = map.next_value::<Vec<T>>()?;
+                    found = Some(rank);
+                }
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        for element in held {
+            (self.elements.f)(element).map_err(|err| de::Error::custom(format!("{:#}", err)))?;
+        }
+        Ok(found.is_some())
+    }
+}
+
+struct Rest<'a, T, F> {
+    key: &'a str,
+    elements: Elements<T, F>,
+}
+
+impl<'de, T: DeserializeOwned, F: FnMut(T) -> Result<()>> Visitor<'de> for Rest<'_, T, F> {
+    type Value = Map<String, Value>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "an object with {}", self.key)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(mut self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut rest = Map::new();
+        while let Some(key) = map.next_key::<String>()? {
+            if key == self.key {
+                map.next_value_seed(&mut self.elements)?;
+            } else {
+                let value = map.next_value()?;
+                rest.insert(key, value);
+            }
+        }
+        Ok(rest)
+    }
+}
+
+struct Elements<T, F> {
+    f: F,
+    element: PhantomData<T>,
+}
+
+impl<T: DeserializeOwned, F: FnMut(T) -> Result<()>> Elements<T, F> {
+    fn each<'de, A: SeqAccess<'de>>(&mut self, mut seq: A) -> Result<bool, A::Error> {
+        while let Some(element) = seq.next_element()? {
+            (self.f)(element).map_err(|err| de::Error::custom(format!("{:#}", err)))?;
+        }
+        Ok(true)
+    }
+}
+
+impl<'de, T: DeserializeOwned, F: FnMut(T) -> Result<()>> DeserializeSeed<'de>
+    for &mut Elements<T, F>
+{
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, T: DeserializeOwned, F: FnMut(T) -> Result<()>> Visitor<'de> for &mut Elements<T, F> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a list")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<(), A::Error> {
+        self.each(seq).map(drop)
+    }
+}
@@ -0,0 +1,99 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// JSON sidecars in the format used by Google Photos' Takeout exports, which many tools already know
+// how to import. Each sidecar is named after the full name of its file (e.g. "123.jpg.json").
+
+use crate::Item;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Sidecar<'a> {
+    title: String,
+    description: &'a str,
+    creation_time: Time,
+    photo_taken_time: Time,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    geo_data: Option<GeoData>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    people: Vec<Person<'a>>,
+}
+
+#[derive(Serialize)]
+struct Time {
+    // Seconds since the epoch, as a string
+    timestamp: String,
+    formatted: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GeoData {
+    latitude: f64,
+    longitude: f64,
+    altitude: f64,
+    latitude_span: f64,
+    longitude_span: f64,
+}
+
+#[derive(Serialize)]
+struct Person<'a> {
+    name: &'a str,
+}
+
+pub fn write_sidecar(item: &Item, out_path: &Path) -> Result<()> {
+    let title = out_path
+        .file_name()
+        .context("file name")?
+        .to_string_lossy()
+        .into_owned();
+    let path = out_path.with_file_name(format!("{}.json", title));
+
+    let time = || Time {
+        timestamp: item.timestamp.and_utc().timestamp().to_string(),
+        formatted: item
+            .timestamp
+            .format("%b %-d, %Y, %-I:%M:%S %p UTC")
+            .to_string(),
+    };
+    let sidecar = Sidecar {
+        title,
+        description: item.description.as_deref().unwrap_or_default(),
+        creation_time: time(),
+        photo_taken_time: time(),
+        geo_data: item.location().map(|location| GeoData {
+            latitude: location.latitude,
+            longitude: location.longitude,
+            altitude: 0.0,
+            latitude_span: 0.0,
+            longitude_span: 0.0,
+        }),
+        people: item
+            .tags
+            .iter()
+            .map(|tag| Person { name: &tag.name })
+            .collect(),
+    };
+
+    serde_json::to_writer_pretty(
+        BufWriter::new(File::create(&path).context(format!("create {}", path.display()))?),
+        &sidecar,
+    )
+    .context(format!("write sidecar {}", path.display()))
+}
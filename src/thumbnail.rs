@@ -0,0 +1,93 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Small JPEG copies of the output files, in a tree of their own ("thumbnails" in the output
+// directory) which mirrors the output, so that the gallery and other indexes can show them without
+// decoding the full-size photos. Videos get a frame chosen by ffmpeg as their poster, while audio
+// clips have nothing to show.
+
+use crate::ffmpeg;
+use anyhow::{Context, Result};
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, ImageDecoder, ImageReader};
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+pub const DIR: &str = "thumbnails";
+
+const QUALITY: u8 = 85;
+
+// Where the thumbnail of the output file goes. Those which aren't already JPEGs keep their
+// extension in the name, since a photo and a video can otherwise share one (e.g. "1.jpg" and
+// "1.mp4").
+pub fn path(root: &Path, output: &Path) -> PathBuf {
+    path_within(&root.join(DIR), root, output)
+}
+
+// Where the thumbnail of the output file goes in a tree other than the usual one (e.g. the
+// gallery's own).
+pub fn path_within(dir: &Path, root: &Path, output: &Path) -> PathBuf {
+    let relative = output.strip_prefix(root).unwrap_or(output);
+    let mut thumbnail = dir.join(relative).into_os_string();
+    match output.extension().and_then(|x| x.to_str()) {
+        Some("jpg") | Some("jpeg") => {}
+        _ => thumbnail.push(".jpg"),
+    }
+    PathBuf::from(thumbnail)
+}
+
+// Writes the thumbnail of the output file, no larger than `size` on either side, returning its
+// path, or None if there's nothing to show.
+pub fn write(root: &Path, output: &Path, size: u32) -> Result<Option<PathBuf>> {
+    write_at(path(root, output), output, size)
+}
+
+// Writes the thumbnail of the output file to the given path, as `write` does.
+pub fn write_at(thumbnail: PathBuf, output: &Path, size: u32) -> Result<Option<PathBuf>> {
+    if let Some(dir) = thumbnail.parent() {
+        fs::create_dir_all(dir).context(format!("create directory {}", dir.display()))?;
+    }
+
+    match output.extension().and_then(|x| x.to_str()) {
+        Some("mp4") | Some("flv") => ffmpeg::poster(output, &thumbnail, size)?,
+        Some("aac") | Some("m4a") | Some("mp3") => return Ok(None),
+        _ => {
+            let mut decoder = ImageReader::open(output)
+                .context(format!("open {}", output.display()))?
+                .with_guessed_format()
+                .context(format!("read {}", output.display()))?
+                .into_decoder()
+                .context(format!("decode {}", output.display()))?;
+            let orientation = decoder
+                .orientation()
+                .context(format!("read orientation of {}", output.display()))?;
+            let mut image = DynamicImage::from_decoder(decoder)
+                .context(format!("decode {}", output.display()))?;
+            image.apply_orientation(orientation);
+
+            let file =
+                File::create(&thumbnail).context(format!("create {}", thumbnail.display()))?;
+            // Small images are only copied, rather than enlarged
+            if image.width() > size || image.height() > size {
+                image = image.thumbnail(size, size);
+            }
+            image
+                .into_rgb8()
+                .write_with_encoder(JpegEncoder::new_with_quality(BufWriter::new(file), QUALITY))
+                .context(format!("write {}", thumbnail.display()))?;
+        }
+    }
+    Ok(Some(thumbnail))
+}
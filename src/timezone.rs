@@ -0,0 +1,68 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The time zone that times are shown in. Facebook records when things happened in UTC, but photo
+// managers expect EXIF dates (which have no zone of their own) and file names to be in local time.
+// The zone is chosen by `--timezone` and kept with the archive, whose times it applies to.
+
+use anyhow::{anyhow, Error, Result};
+use chrono::{DateTime, FixedOffset, Local, NaiveDateTime, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Timezone {
+    Local,
+    Utc,
+    Named(Tz),
+}
+
+impl FromStr for Timezone {
+    type Err = Error;
+
+    fn from_str(timezone: &str) -> Result<Timezone> {
+        match timezone {
+            "local" => Ok(Timezone::Local),
+            "utc" | "UTC" => Ok(Timezone::Utc),
+            _ => timezone
+                .parse()
+                .map(Timezone::Named)
+                .map_err(|_| anyhow!("unknown time zone {:?}", timezone)),
+        }
+    }
+}
+
+impl Timezone {
+    // Converts the time, in UTC, into this time zone.
+    pub fn local(self, timestamp: NaiveDateTime) -> DateTime<FixedOffset> {
+        let offset = match self {
+            Timezone::Local => Local.offset_from_utc_datetime(&timestamp),
+            Timezone::Utc => Utc.offset_from_utc_datetime(&timestamp).fix(),
+            Timezone::Named(tz) => tz.offset_from_utc_datetime(&timestamp).fix(),
+        };
+        DateTime::from_naive_utc_and_offset(timestamp, offset)
+    }
+
+    // Converts the time, in this time zone, into UTC. None means that it doesn't exist here (i.e.
+    // it was skipped when the clocks went forward); those which happened twice are taken as the
+    // first.
+    pub fn utc(self, local: NaiveDateTime) -> Option<NaiveDateTime> {
+        let utc = match self {
+            Timezone::Local => Local.from_local_datetime(&local).earliest()?.naive_utc(),
+            Timezone::Utc => local,
+            Timezone::Named(tz) => tz.from_local_datetime(&local).earliest()?.naive_utc(),
+        };
+        Some(utc)
+    }
+}
@@ -0,0 +1,531 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// An interactive view of the processing (`--tui`), for very large archives. The albums are listed
+// first so that some of them can be left out, and then each album's progress is shown alongside
+// the log while everything is processed. Albums can still be skipped, and the whole run paused,
+// while it's going.
+
+use crate::progress::{Control, Progress};
+use crate::{
+    album_selected, in_range, instagram, is_instagram, messages, posts, read_albums,
+    read_marketplace, read_orphans, read_profile, read_tagged, read_unsorted, read_videos, Archive,
+    Item, Processor,
+};
+use anyhow::{anyhow, Context, Result};
+use indicatif::HumanBytes;
+use log::{Log, Metadata, Record};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Gauge, List, ListItem, ListState, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// How many lines of the log are kept for the log pane
+const LOG_LINES: usize = 500;
+
+// How often the screen is redrawn while waiting for a key
+const TICK: Duration = Duration::from_millis(100);
+
+pub type LogLines = Arc<Mutex<VecDeque<String>>>;
+
+// A logger which keeps the most recent lines for the log pane, rather than writing them to the
+// terminal which is being drawn on. Records are filtered as they would be by `filter`.
+pub struct Logs {
+    filter: env_logger::Logger,
+    lines: LogLines,
+}
+
+impl Logs {
+    pub fn new(filter: env_logger::Logger) -> Logs {
+        Logs {
+            filter,
+            lines: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    pub fn lines(&self) -> LogLines {
+        self.lines.clone()
+    }
+}
+
+impl Log for Logs {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.filter.matches(record) {
+            return;
+        }
+        let mut lines = self.lines.lock().expect("log lines");
+        if lines.len() == LOG_LINES {
+            lines.pop_front();
+        }
+        lines.push_back(format!(
+            "[{:<5} {}] {}",
+            record.level(),
+            record.target(),
+            record.args()
+        ));
+    }
+
+    fn flush(&self) {}
+}
+
+// A row of the album tree: either an album (or conversation, etc.), or a heading grouping them
+struct Row {
+    name: String,
+    heading: bool,
+    // Whether the row is indented under a heading
+    nested: bool,
+    items: usize,
+    selected: bool,
+}
+
+impl Row {
+    fn album(name: &str, items: &[Item], opts: &crate::Options, nested: bool) -> Row {
+        Row {
+            name: name.to_string(),
+            heading: false,
+            nested,
+            items: items
+                .iter()
+                .filter(|item| in_range(item, &opts.source))
+                .count(),
+            selected: true,
+        }
+    }
+
+    fn heading(name: &str) -> Row {
+        Row {
+            name: name.to_string(),
+            heading: true,
+            nested: false,
+            items: 0,
+            selected: true,
+        }
+    }
+}
+
+// Lets the albums be chosen, processes them while showing the progress, and then waits for a key
+// before returning the result of the run.
+pub fn run(
+    processor: &Processor,
+    progress: &Progress,
+    control: &Control,
+    logs: LogLines,
+) -> Result<()> {
+    let mut rows = Vec::new();
+    for archive in processor.archives() {
+        let contents = contents(processor, archive).context("read contents")?;
+        merge_rows(&mut rows, contents);
+    }
+    let mut terminal = ratatui::try_init().context("initialize terminal")?;
+    let result = interact(
+        &mut terminal,
+        processor,
+        progress,
+        control,
+        &logs,
+        &mut rows,
+    );
+    ratatui::restore();
+
+    let (items, _) = progress.items();
+    if let Ok(true) = result {
+        println!(
+            "Processed {} items ({} written)",
+            items,
+            HumanBytes(progress.bytes())
+        );
+    }
+    result.map(|_| ())
+}
+
+// Adds the rows of another export being merged, counting the items of albums which are in both
+// only once (as they're mostly the same items).
+// New ones are placed after the row before them, so that they stay under the same heading.
+fn merge_rows(rows: &mut Vec<Row>, others: Vec<Row>) {
+    let mut next = 0;
+    for other in others {
+        match rows
+            .iter()
+            .position(|row| row.name == other.name && row.heading == other.heading)
+        {
+            Some(index) => {
+                rows[index].items = rows[index].items.max(other.items);
+                next = index + 1;
+            }
+            None => {
+                rows.insert(next, other);
+                next += 1;
+            }
+        }
+    }
+}
+
+// The albums and other collections in the archive, in the order they're processed.
+fn contents(processor: &Processor, archive: &Archive) -> Result<Vec<Row>> {
+    let opts = processor.options();
+    let mut rows = Vec::new();
+
+    if is_instagram(&opts.source, archive)? {
+        if !opts.source.skip_posts {
+            rows.push(Row::album(
+                "Posts",
+                &instagram::read_posts(archive)?,
+                opts,
+                false,
+            ));
+        }
+        if !opts.source.skip_stories {
+            rows.push(Row::album(
+                "Stories",
+                &instagram::read_stories(archive)?,
+                opts,
+                false,
+            ));
+        }
+        rows.push(Row::album(
+            "Reels",
+            &instagram::read_reels(archive)?,
+            opts,
+            false,
+        ));
+        return Ok(rows);
+    }
+
+    rows.push(Row::heading("Albums"));
+    let albums = read_albums(archive).context("read_albums")?;
+    let names = albums
+        .iter()
+        .map(|album| album.name.clone())
+        .collect::<Vec<_>>();
+    let others = read_profile(archive, &names)
+        .context("read_profile")?
+        .into_iter()
+        .chain(read_unsorted(archive).context("read_unsorted")?)
+        .chain(read_tagged(archive).context("read_tagged")?)
+        .chain(read_marketplace(&opts.source, archive).context("read_marketplace")?)
+        .chain(read_orphans(&opts.source, archive).context("read_orphans")?);
+    for album in albums
+        .into_iter()
+        .chain(others)
+        .filter(|album| album_selected(&album.name, &opts.source))
+    {
+        rows.push(Row::album(&album.name, &album.items, opts, true));
+    }
+
+    if !opts.source.skip_videos {
+        let videos = read_videos(archive).context("read_videos")?;
+        rows.push(Row::album("Videos", &videos, opts, false));
+    }
+    if !opts.source.skip_posts {
+        let posts = posts::read_posts(archive).context("read_posts")?;
+        rows.push(Row::album("Posts", &posts, opts, false));
+    }
+    if !opts.source.skip_stories {
+        let stories = posts::read_stories(archive).context("read_stories")?;
+        rows.push(Row::album("Stories", &stories, opts, false));
+    }
+    if !opts.source.skip_posts {
+        for (section, dir) in posts::SECTIONS {
+            let albums = posts::read_section(archive, section)
+                .context("read_section")?
+                .into_iter()
+                .filter(|album| album_selected(&album.name, &opts.source))
+                .collect::<Vec<_>>();
+            if albums.is_empty() {
+                continue;
+            }
+            rows.push(Row::heading(dir));
+            for album in albums {
+                rows.push(Row::album(&album.name, &album.items, opts, true));
+            }
+        }
+    }
+    if !opts.source.skip_messages {
+        let threads = messages::read_threads(archive, opts.source.messenger_folders)
+            .context("read_threads")?
+            .into_iter()
+            .filter(|thread| album_selected(&thread.name, &opts.source))
+            .collect::<Vec<_>>();
+        if !threads.is_empty() {
+            rows.push(Row::heading("Messenger"));
+        }
+        for thread in threads {
+            rows.push(Row::album(&thread.name, &thread.items, opts, true));
+        }
+    }
+    Ok(rows)
+}
+
+// Returns whether the processing was started and finished, or false if the user quit before it
+// started.
+fn interact(
+    terminal: &mut DefaultTerminal,
+    processor: &Processor,
+    progress: &Progress,
+    control: &Control,
+    logs: &LogLines,
+    rows: &mut [Row],
+) -> Result<bool> {
+    let mut state = ListState::default().with_selected(Some(0));
+
+    // Choosing the albums
+    loop {
+        terminal
+            .draw(|frame| draw_choice(frame, rows, &mut state))
+            .context("draw")?;
+        let key = match next_key()? {
+            Some(key) => key,
+            None => continue,
+        };
+        let selected = state.selected().unwrap_or(0);
+        match key {
+            KeyCode::Up => state.select_previous(),
+            KeyCode::Down => state.select_next(),
+            KeyCode::Char(' ') => toggle(rows, selected),
+            KeyCode::Enter => break,
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(false),
+            _ => {}
+        }
+    }
+    for row in rows.iter().filter(|row| !row.heading && !row.selected) {
+        control.skip(&row.name);
+    }
+
+    // Processing them
+    let result = thread::scope(|scope| {
+        let worker = scope.spawn(|| processor.run(progress));
+        while !worker.is_finished() {
+            terminal
+                .draw(|frame| draw_progress(frame, rows, &mut state, progress, control, logs))
+                .context("draw")?;
+            let selected = state.selected().unwrap_or(0);
+            match next_key()? {
+                Some(KeyCode::Up) => state.select_previous(),
+                Some(KeyCode::Down) => state.select_next(),
+                Some(KeyCode::Char('p')) if control.is_paused() => control.resume(),
+                Some(KeyCode::Char('p')) => control.pause(),
+                Some(KeyCode::Char('s')) => skip(rows, selected, control),
+                Some(KeyCode::Char('q')) | Some(KeyCode::Esc) => {
+                    for row in rows.iter() {
+                        control.skip(&row.name);
+                    }
+                    control.resume();
+                }
+                _ => {}
+            }
+        }
+        worker.join().map_err(|_| anyhow!("processing panicked"))?
+    });
+
+    // Waiting to be dismissed, so that the final state can be seen
+    let message = match &result {
+        Ok(()) => "Finished; press any key to exit".to_string(),
+        Err(err) => format!("Failed: {:#}; press any key to exit", err),
+    };
+    // The summary of the run is printed over the screen, so it's redrawn from scratch
+    terminal.clear().context("clear")?;
+    loop {
+        terminal
+            .draw(|frame| {
+                draw_progress(frame, rows, &mut state, progress, control, logs);
+                let area = frame.area();
+                frame.render_widget(
+                    Paragraph::new(message.as_str())
+                        .style(Style::new().add_modifier(Modifier::BOLD)),
+                    ratatui::layout::Rect::new(
+                        area.x,
+                        area.bottom().saturating_sub(1),
+                        area.width,
+                        1,
+                    ),
+                );
+            })
+            .context("draw")?;
+        if next_key()?.is_some() {
+            break;
+        }
+    }
+    result.map(|_| true)
+}
+
+// The next key which was pressed, if one was within a tick.
+fn next_key() -> Result<Option<KeyCode>> {
+    if !event::poll(TICK).context("poll terminal")? {
+        return Ok(None);
+    }
+    match event::read().context("read terminal")? {
+        Event::Key(key) if key.kind == KeyEventKind::Press => Ok(Some(key.code)),
+        _ => Ok(None),
+    }
+}
+
+// Selects or deselects the row, along with everything under it if it's a heading.
+fn toggle(rows: &mut [Row], index: usize) {
+    let selected = !rows[index].selected;
+    rows[index].selected = selected;
+    if rows[index].heading {
+        for row in rows[index + 1..].iter_mut().take_while(|row| row.nested) {
+            row.selected = selected;
+        }
+    }
+}
+
+// Skips the rest of the row's album, or every album under it if it's a heading.
+fn skip(rows: &mut [Row], index: usize, control: &Control) {
+    let end = if rows[index].heading {
+        index
+            + 1
+            + rows[index + 1..]
+                .iter()
+                .take_while(|row| row.nested)
+                .count()
+    } else {
+        index + 1
+    };
+    for row in &mut rows[index..end] {
+        row.selected = false;
+        control.skip(&row.name);
+    }
+}
+
+fn draw_choice(frame: &mut Frame, rows: &[Row], state: &mut ListState) {
+    let [list, help] =
+        Layout::vertical([Constraint::Min(1), Constraint::Length(1)]).areas(frame.area());
+    let items = rows.iter().map(|row| {
+        let mark = if row.selected { "[x]" } else { "[ ]" };
+        let count = if row.heading {
+            String::new()
+        } else {
+            format!(" ({})", row.items)
+        };
+        ListItem::new(format!("{}{} {}{}", indent(row), mark, row.name, count))
+    });
+    frame.render_stateful_widget(
+        List::new(items)
+            .block(Block::bordered().title("Choose the albums to process"))
+            .highlight_style(Style::new().add_modifier(Modifier::REVERSED)),
+        list,
+        state,
+    );
+    frame.render_widget(
+        Paragraph::new("↑/↓ move · space select · enter start · q quit"),
+        help,
+    );
+}
+
+fn draw_progress(
+    frame: &mut Frame,
+    rows: &[Row],
+    state: &mut ListState,
+    progress: &Progress,
+    control: &Control,
+    logs: &LogLines,
+) {
+    let [overall, list, log, help] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Percentage(50),
+        Constraint::Min(3),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+
+    let (items, total) = progress.items();
+    let (albums, albums_total) = progress.albums();
+    let phase = if control.is_paused() {
+        "paused"
+    } else {
+        progress.phase()
+    };
+    frame.render_widget(
+        Gauge::default()
+            .block(Block::bordered().title(format!(
+                "{}: {}/{} albums, {}/{} items, {} written",
+                phase,
+                albums,
+                albums_total,
+                items,
+                total,
+                HumanBytes(progress.bytes())
+            )))
+            .ratio(if total == 0 {
+                0.0
+            } else {
+                (items as f64 / total as f64).min(1.0)
+            }),
+        overall,
+    );
+
+    let items = rows.iter().map(|row| {
+        if row.heading {
+            return ListItem::new(row.name.clone());
+        }
+        let status = if control.is_skipped(&row.name) {
+            "skipped".to_string()
+        } else {
+            let finished = control.finished(&row.name);
+            let width = 20;
+            let filled = (finished * width).checked_div(row.items).unwrap_or(width);
+            format!(
+                "{}{} {}/{}",
+                "█".repeat(filled.min(width)),
+                "░".repeat(width - filled.min(width)),
+                finished,
+                row.items
+            )
+        };
+        ListItem::new(format!("{}{:<40} {}", indent(row), row.name, status))
+    });
+    frame.render_stateful_widget(
+        List::new(items)
+            .block(Block::bordered().title("Albums"))
+            .highlight_style(Style::new().add_modifier(Modifier::REVERSED)),
+        list,
+        state,
+    );
+
+    let lines = logs.lock().expect("log lines");
+    let shown = log.height.saturating_sub(2) as usize;
+    let lines = lines
+        .iter()
+        .skip(lines.len().saturating_sub(shown))
+        .map(|line| Line::from(line.as_str()))
+        .collect::<Vec<_>>();
+    frame.render_widget(
+        Paragraph::new(lines).block(Block::bordered().title("Log")),
+        log,
+    );
+
+    frame.render_widget(
+        Paragraph::new("↑/↓ move · s skip album · p pause/resume · q stop"),
+        help,
+    );
+}
+
+fn indent(row: &Row) -> &'static str {
+    if row.nested {
+        "  "
+    } else {
+        ""
+    }
+}
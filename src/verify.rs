@@ -0,0 +1,252 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Checks the output of a previous run against the archive, as `fb-unarchive verify` does. The
+// archive is read again and each item is found in the output through the state that every run
+// leaves behind, so the check still works when the files were renamed by `--name-format`. Items
+// which were merged by `--dedupe` are passed over, as are those of types which were skipped. Output
+// written with `--no-modify` is checked by its XMP sidecars instead.
+
+use crate::cli::VerifyOptions;
+use crate::output::{Conflict, Outputs};
+use crate::{exif, read_items, sniff, xmp, Archive, Item, JOURNAL_FILE, STATE_FILE};
+use anyhow::{anyhow, Context, Result};
+use img_parts::{jpeg::Jpeg, ImageEXIF};
+use log::{debug, info, warn};
+use rayon::prelude::*;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+#[derive(Debug)]
+enum Problem {
+    // The item was never written
+    NotWritten,
+    // The item was written, but the file is gone
+    Missing(PathBuf),
+    WrongDate(PathBuf, String),
+    WrongTime(PathBuf),
+    NoDescription(PathBuf),
+    // The output was written with `--no-modify`, but without its sidecar
+    NoSidecar(PathBuf),
+}
+
+pub fn verify(opts: &VerifyOptions) -> Result<()> {
+    let state = opts.output.join(STATE_FILE);
+    if !state.exists() {
+        return Err(anyhow!(
+            "{} wasn't written by fb-unarchive (there's no {})",
+            opts.output.display(),
+            STATE_FILE
+        ));
+    }
+    let mut outputs = Outputs::new(Conflict::Rename);
+    outputs.load_state(&state).context("load state")?;
+    if opts.output.join(JOURNAL_FILE).exists() {
+        warn!("The last run into {} didn't finish", opts.output.display());
+    }
+    // Duplicates and damaged files are deliberately left out
+    let mut left_out = listed(&opts.output.join("duplicates.txt")).context("read duplicates")?;
+    left_out.extend(listed(&opts.output.join("quarantine.txt")).context("read quarantine")?);
+
+    let archives = opts.source.open()?;
+    let mut items = Vec::new();
+    for archive in &archives {
+        for item in read_items(&opts.source, archive).context("read archive")? {
+            items.push((archive, item));
+        }
+    }
+    info!("Verifying {} items", items.len());
+
+    let mut problems = items
+        .par_iter()
+        .filter(|(_, item)| !left_out.contains(&item.path))
+        .map(|(archive, item)| Ok((item, check(item, archive, opts, &outputs)?)))
+        .filter_map(|checked| match checked {
+            Ok((item, Some(problem))) => Some(Ok((item, problem))),
+            Ok((_, None)) => None,
+            Err(err) => Some(Err(err)),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    problems.sort_by(|a, b| a.0.path.cmp(&b.0.path));
+
+    for (item, problem) in &problems {
+        let source = item.path.display();
+        match problem {
+            Problem::NotWritten => println!("not written: {}", source),
+            Problem::Missing(output) => {
+                println!("missing: {} (was {})", source, output.display())
+            }
+            Problem::WrongDate(output, date) => println!(
+                "wrong date: {} ({} instead of {})",
+                output.display(),
+                date,
+                item.local_timestamp(opts.source.timezone)
+                    .format("%Y:%m:%d %H:%M:%S")
+            ),
+            Problem::WrongTime(output) => {
+                println!("wrong modification time: {}", output.display())
+            }
+            Problem::NoDescription(output) => {
+                println!("missing description: {}", output.display())
+            }
+            Problem::NoSidecar(output) => {
+                println!("missing sidecar: {}", output.display())
+            }
+        }
+    }
+
+    match problems.len() {
+        0 => {
+            println!("Verified {} items", items.len());
+            Ok(())
+        }
+        count => Err(anyhow!(
+            "{} of {} items don't match the archive",
+            count,
+            items.len()
+        )),
+    }
+}
+
+fn check(
+    item: &Item,
+    archive: &Archive,
+    opts: &VerifyOptions,
+    outputs: &Outputs,
+) -> Result<Option<Problem>> {
+    let output = match outputs.recorded(item) {
+        Some(output) => output,
+        None if expected_kind(item, archive, opts) => return Ok(Some(Problem::NotWritten)),
+        None => return Ok(None),
+    };
+    let metadata = match fs::symlink_metadata(&output) {
+        Ok(metadata) => metadata,
+        Err(_) => return Ok(Some(Problem::Missing(output))),
+    };
+
+    if !opts.no_mtime {
+        let expected: SystemTime = item.timestamp.and_utc().into();
+        if metadata.modified().ok() != Some(expected) {
+            return Ok(Some(Problem::WrongTime(output)));
+        }
+    }
+
+    if opts.no_modify {
+        return check_sidecar(item, output, opts);
+    }
+
+    let extension = output.extension().and_then(|x| x.to_str());
+    if extension.and_then(sniff::from_extension) != Some("jpg") || metadata.file_type().is_symlink()
+    {
+        return Ok(None);
+    }
+    let jpeg = Jpeg::from_bytes(
+        fs::read(&output)
+            .context(format!("read {}", output.display()))?
+            .into(),
+    )
+    .map_err(|e| anyhow!("Failed to parse {}: {}", output.display(), e))?;
+    let exif = match jpeg.exif() {
+        Some(exif) => exif,
+        None => return Ok(Some(Problem::WrongDate(output, "none".to_string()))),
+    };
+
+    let date = exif::read_text(&exif, rexif::ExifTag::DateTimeOriginal as u16)
+        .context(format!("read exif from {}", output.display()))?
+        .unwrap_or_else(|| "none".to_string());
+    if date
+        != item
+            .local_timestamp(opts.source.timezone)
+            .format("%Y:%m:%d %H:%M:%S")
+            .to_string()
+    {
+        return Ok(Some(Problem::WrongDate(output, date)));
+    }
+
+    if let Some(description) = &item.description {
+        let comment = exif::read_text(&exif, rexif::ExifTag::UserComment as u16)
+            .context(format!("read exif from {}", output.display()))?
+            .unwrap_or_default();
+        // Long descriptions are cut short in the EXIF
+        if !comment.contains(crate::encoding::truncate(description, exif::MAX_TEXT)) {
+            return Ok(Some(Problem::NoDescription(output)));
+        }
+    }
+    Ok(None)
+}
+
+// Checks the date and description in the output's XMP sidecar, where `--no-modify` puts them.
+fn check_sidecar(item: &Item, output: PathBuf, opts: &VerifyOptions) -> Result<Option<Problem>> {
+    let sidecar = xmp::sidecar_path(&output)?;
+    let packet = match fs::read_to_string(&sidecar) {
+        Ok(packet) => packet,
+        Err(_) => return Ok(Some(Problem::NoSidecar(output))),
+    };
+
+    // The date is recorded with its offset, which isn't compared
+    let date = packet
+        .split("<exif:DateTimeOriginal>")
+        .nth(1)
+        .and_then(|rest| rest.get(..19))
+        .unwrap_or("none");
+    if date
+        != item
+            .local_timestamp(opts.source.timezone)
+            .format("%Y-%m-%dT%H:%M:%S")
+            .to_string()
+    {
+        return Ok(Some(Problem::WrongDate(output, date.to_string())));
+    }
+
+    if let Some(description) = &item.description {
+        if !packet.contains(&xmp::escape(description)) {
+            return Ok(Some(Problem::NoDescription(output)));
+        }
+    }
+    Ok(None)
+}
+
+// Whether an item of this type would have been written, given the options.
+fn expected_kind(item: &Item, archive: &Archive, opts: &VerifyOptions) -> bool {
+    let extension = item.path.extension().and_then(|x| x.to_str());
+    let kind = match archive.read(&item.path, sniff::detect) {
+        Ok(kind) => kind.or_else(|| extension.and_then(sniff::from_extension)),
+        Err(err) => {
+            debug!("Couldn't read {}: {:#}", item.path.display(), err);
+            return false;
+        }
+    };
+    match kind {
+        Some("jpg") | Some("png") | Some("webp") | Some("gif") => !opts.source.skip_photos,
+        Some("mp4") | Some("flv") => !opts.source.skip_videos,
+        Some(_) => true,
+        None => false,
+    }
+}
+
+// The items listed in the first column of the report (e.g. those which were merged into others by
+// `--dedupe`, in duplicates.txt).
+fn listed(path: &Path) -> Result<HashSet<PathBuf>> {
+    if !path.exists() {
+        return Ok(HashSet::new());
+    }
+    Ok(fs::read_to_string(path)
+        .context(format!("read {}", path.display()))?
+        .lines()
+        .filter_map(|line| line.split('\t').next())
+        .map(PathBuf::from)
+        .collect())
+}
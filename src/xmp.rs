@@ -0,0 +1,238 @@
+// Copyright 2020 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::encoding::truncate;
+use crate::{exif, Item, MetadataProfile, Options, Reactions};
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Writes an XMP sidecar next to the given output file, for formats which have no room for
+// metadata of their own. The sidecar keeps the full name of the file (e.g. "123.gif.xmp") so that
+// files differing only by extension don't share a sidecar.
+pub fn write_sidecar(item: &Item, out_path: &Path, opts: &Options) -> Result<()> {
+    let path = sidecar_path(out_path)?;
+    fs::write(&path, packet(item, opts)).context(format!("write sidecar {}", path.display()))
+}
+
+pub fn sidecar_path(out_path: &Path) -> Result<PathBuf> {
+    let mut name = out_path.file_name().context("file name")?.to_os_string();
+    name.push(".xmp");
+    Ok(out_path.with_file_name(name))
+}
+
+// Identifies XMP embedded in a JPEG's APP1 segment
+pub const SIGNATURE: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+
+// Whether the item has anything which can only be recorded in XMP, for formats which can also hold
+// their metadata elsewhere.
+pub fn needed(item: &Item, opts: &Options) -> bool {
+    // EXIF can only credit those whose names are ASCII
+    let artist = item.artist(opts.artist.as_deref()).0;
+    !item.tags.is_empty()
+        || artist.is_some_and(|artist| !artist.is_ascii())
+        || matches!(opts.metadata_profile, Some(MetadataProfile::Digikam))
+        || (opts.reactions.is_some() && !item.reactions.is_empty())
+}
+
+pub fn packet(item: &Item, opts: &Options) -> String {
+    build(item, opts, usize::MAX)
+}
+
+// The packet to embed in a JPEG, which has to fit in a single segment of 64 KiB, with the
+// description and comments cut short as they are in the EXIF.
+pub fn embedded_packet(item: &Item, opts: &Options) -> String {
+    build(item, opts, exif::MAX_TEXT)
+}
+
+// The packet, with at most `max_text` bytes of the description and of the comments.
+fn build(item: &Item, opts: &Options, max_text: usize) -> String {
+    let date = item
+        .local_timestamp(opts.source.timezone)
+        .format("%Y-%m-%dT%H:%M:%S%:z");
+    let mut properties = format!(
+        "   <xmp:CreateDate>{date}</xmp:CreateDate>\n   <exif:DateTimeOriginal>{date}</exif:DateTimeOriginal>\n",
+        date = date
+    );
+    if let Some(description) = &item.description {
+        properties.push_str(&format!(
+            "   <dc:description><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></dc:description>\n",
+            escape(truncate(description, max_text))
+        ));
+    }
+    // As in the EXIF, the owner of the export is only credited with their own uploads
+    let (artist, owned) = item.artist(opts.artist.as_deref());
+    if let Some(artist) = artist {
+        properties.push_str(&format!(
+            "   <dc:creator><rdf:Seq><rdf:li>{}</rdf:li></rdf:Seq></dc:creator>\n",
+            escape(artist)
+        ));
+    }
+    if let (Some(artist), true) = (artist, owned) {
+        properties.push_str(&format!(
+            "   <dc:rights><rdf:Alt><rdf:li xml:lang=\"x-default\">Copyright {} {}</rdf:li></rdf:Alt></dc:rights>\n",
+            item.local_timestamp(opts.source.timezone).format("%Y"),
+            escape(artist)
+        ));
+    }
+    let comment = crate::combined_comment(item, opts.source.timezone);
+    if !comment.is_empty() {
+        properties.push_str(&format!(
+            "   <exif:UserComment><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></exif:UserComment>\n",
+            escape(truncate(&comment, max_text))
+        ));
+    }
+    if !item.tags.is_empty() {
+        properties.push_str(&people(item));
+    }
+    let reactions = item.reactions.len();
+    if let (Some(Reactions::Rating), true) = (opts.reactions, reactions > 0) {
+        properties.push_str(&format!(
+            "   <xmp:Rating>{}</xmp:Rating>\n",
+            rating(reactions)
+        ));
+    }
+    let mut extra = Vec::new();
+    if let (Some(Reactions::Keyword), true) = (opts.reactions, reactions > 0) {
+        extra.push(format!("reactions={}", reactions));
+    }
+    properties.push_str(&keywords(item, opts.metadata_profile, &extra));
+
+    format!(
+        r#"<?xpacket begin="" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:dc="http://purl.org/dc/elements/1.1/"
+    xmlns:digiKam="http://www.digikam.org/ns/1.0/"
+    xmlns:exif="http://ns.adobe.com/exif/1.0/"
+    xmlns:Iptc4xmpExt="http://iptc.org/std/Iptc4xmpExt/2008-02-29/"
+    xmlns:lr="http://ns.adobe.com/lightroom/1.0/"
+    xmlns:mwg-rs="http://www.metadataworkinggroup.com/schemas/regions/"
+    xmlns:stArea="http://ns.adobe.com/xmp/sType/Area#"
+    xmlns:xmp="http://ns.adobe.com/xap/1.0/">
+{}  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>
+"#,
+        properties
+    )
+}
+
+// The tagged people, as a plain list and, for those whose position is known, as Metadata Working
+// Group face regions. Facebook only records the center of each face, so the regions are points
+// rather than boxes.
+fn people(item: &Item) -> String {
+    let names = item
+        .tags
+        .iter()
+        .map(|tag| format!("     <rdf:li>{}</rdf:li>\n", escape(&tag.name)))
+        .collect::<String>();
+    let mut people = format!(
+        "   <Iptc4xmpExt:PersonInImage><rdf:Bag>\n{}    </rdf:Bag></Iptc4xmpExt:PersonInImage>\n",
+        names
+    );
+
+    let regions = item
+        .tags
+        .iter()
+        .filter_map(|tag| Some((&tag.name, tag.x?, tag.y?)))
+        .map(|(name, x, y)| {
+            format!(
+                "      <rdf:li rdf:parseType=\"Resource\">\n       <mwg-rs:Type>Face</mwg-rs:Type>\n       <mwg-rs:Name>{}</mwg-rs:Name>\n       <mwg-rs:Area stArea:x=\"{:.4}\" stArea:y=\"{:.4}\" stArea:unit=\"normalized\"/>\n      </rdf:li>\n",
+                escape(name),
+                (x / 100.0).clamp(0.0, 1.0),
+                (y / 100.0).clamp(0.0, 1.0)
+            )
+        })
+        .collect::<String>();
+    if !regions.is_empty() {
+        people.push_str(&format!(
+            "   <mwg-rs:RegionInfo rdf:parseType=\"Resource\">\n    <mwg-rs:RegionList><rdf:Bag>\n{}    </rdf:Bag></mwg-rs:RegionList>\n   </mwg-rs:RegionInfo>\n",
+            regions
+        ));
+    }
+
+    people
+}
+
+// One star for a single reaction, up to five for twenty or more
+fn rating(reactions: usize) -> u8 {
+    match reactions {
+        0 => 0,
+        1 => 1,
+        2..=4 => 2,
+        5..=9 => 3,
+        10..=19 => 4,
+        _ => 5,
+    }
+}
+
+// With digiKam's profile, the album and tagged people as hierarchical keywords (e.g.
+// "Facebook/Albums/Vacation 2014"), in both digiKam's and Lightroom's forms, along with the plain
+// keywords at the leaves of the tree. Any extra keywords are only plain ones.
+fn keywords(item: &Item, profile: Option<MetadataProfile>, extra: &[String]) -> String {
+    let mut tree = Vec::new();
+    if let Some(MetadataProfile::Digikam) = profile {
+        if let Some(album) = &item.album {
+            tree.push(("Albums", album.as_str()));
+        }
+        for tag in &item.tags {
+            tree.push(("People", tag.name.as_str()));
+        }
+    }
+    if tree.is_empty() && extra.is_empty() {
+        return String::new();
+    }
+
+    // Both separators would split the name into another level of the tree
+    let list = |separator: &str| {
+        tree.iter()
+            .map(|(category, name)| {
+                let name = name.replace(['/', '|'], "-");
+                format!(
+                    "     <rdf:li>{}</rdf:li>\n",
+                    escape(&["Facebook", category, &name].join(separator))
+                )
+            })
+            .collect::<String>()
+    };
+    let leaves = tree
+        .iter()
+        .map(|(_, name)| *name)
+        .chain(extra.iter().map(String::as_str))
+        .map(|name| format!("     <rdf:li>{}</rdf:li>\n", escape(name)))
+        .collect::<String>();
+    let subject = format!(
+        "   <dc:subject><rdf:Bag>\n{}    </rdf:Bag></dc:subject>\n",
+        leaves
+    );
+    if tree.is_empty() {
+        return subject;
+    }
+    format!(
+        "   <digiKam:TagsList><rdf:Seq>\n{}    </rdf:Seq></digiKam:TagsList>\n   <lr:hierarchicalSubject><rdf:Bag>\n{}    </rdf:Bag></lr:hierarchicalSubject>\n{}",
+        list("/"),
+        list("|"),
+        subject
+    )
+}
+
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}